@@ -0,0 +1,204 @@
+use anyhow::{ensure, Result};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Log target for access log lines. The logger prints these without its usual
+/// timestamp/level prefix so each line is a standalone JSON object.
+pub const TARGET: &str = "vostok::access";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub client_ip: IpAddr,
+    /// `None` when no response was produced.
+    pub status: Option<u16>,
+    pub latency_ms: f64,
+    /// Set with `access_log_body_bytes`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_bytes: Option<u64>,
+    /// `false` when the client went away, or the upstream failed, before the whole body
+    /// was sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_complete: Option<bool>,
+}
+
+impl<'a> AccessLogEntry<'a> {
+    pub fn new(
+        method: &'a str,
+        path: &'a str,
+        client_ip: IpAddr,
+        status: Option<u16>,
+        latency: Duration,
+    ) -> AccessLogEntry<'a> {
+        AccessLogEntry {
+            method,
+            path,
+            client_ip,
+            status,
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            body_bytes: None,
+            body_complete: None,
+        }
+    }
+
+    pub fn with_body(self, bytes: u64, complete: bool) -> AccessLogEntry<'a> {
+        AccessLogEntry {
+            body_bytes: Some(bytes),
+            body_complete: Some(complete),
+            ..self
+        }
+    }
+
+    pub fn log(&self) {
+        match serde_json::to_string(self) {
+            Ok(line) => log::info!(target: TARGET, "{}", line),
+            Err(err) => log::warn!("Serializing access log entry: {}", err),
+        }
+    }
+}
+
+/// Decides which requests get an access log line. Requests that failed, with an error
+/// status or no response at all, always do; of the rest, exactly `rate` of them do, e.g.
+/// the 1st, 101st, 201st and so on with a rate of `0.01`. Counting instead of drawing
+/// random numbers keeps it deterministic.
+#[derive(Debug)]
+pub struct Sampler {
+    rate: f64,
+    count: AtomicU64,
+}
+
+impl Sampler {
+    pub fn new(rate: f64) -> Result<Sampler> {
+        ensure!(
+            (0.0..=1.0).contains(&rate),
+            "access_log_sample_rate must be between 0 and 1, not {}",
+            rate
+        );
+        Ok(Sampler {
+            rate,
+            count: AtomicU64::new(0),
+        })
+    }
+
+    pub fn sample(&self, status: Option<StatusCode>) -> bool {
+        match status {
+            Some(status) if status.is_success() || status.is_redirection() => {}
+            _ => return true,
+        }
+        // Logged whenever the running total of `rate` reaches the next whole number.
+        let count = self.count.fetch_add(1, Ordering::Relaxed) as f64;
+        ((count + 1.0) * self.rate).ceil() > (count * self.rate).ceil()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(status: Option<u16>) -> AccessLogEntry<'static> {
+        AccessLogEntry::new(
+            "GET",
+            "/users",
+            "192.0.2.1".parse().unwrap(),
+            status,
+            Duration::from_micros(12_500),
+        )
+    }
+
+    #[test]
+    fn serializes_one_flat_object() {
+        let json = serde_json::to_value(entry(Some(200))).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "method": "GET",
+                "path": "/users",
+                "client_ip": "192.0.2.1",
+                "status": 200,
+                "latency_ms": 12.5,
+            })
+        );
+    }
+
+    #[test]
+    fn writes_null_without_a_response() {
+        let json = serde_json::to_value(entry(None)).unwrap();
+        assert_eq!(json["status"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn adds_the_body_size_when_asked() {
+        let json = serde_json::to_value(entry(Some(200)).with_body(512, false)).unwrap();
+        assert_eq!(json["body_bytes"], 512);
+        assert_eq!(json["body_complete"], false);
+    }
+
+    #[test]
+    fn parses_the_format() {
+        #[derive(Deserialize)]
+        struct Config {
+            log_format: LogFormat,
+        }
+        let parse = |value| toml::from_str::<Config>(value).map(|config| config.log_format);
+        assert_eq!(parse("log_format = \"json\"").unwrap(), LogFormat::Json);
+        assert_eq!(parse("log_format = \"text\"").unwrap(), LogFormat::Text);
+        assert!(parse("log_format = \"xml\"").is_err());
+    }
+
+    fn sampled(sampler: &Sampler, requests: usize, status: Option<StatusCode>) -> Vec<usize> {
+        (0..requests).filter(|_| sampler.sample(status)).collect()
+    }
+
+    #[test]
+    fn samples_exactly_the_rate_of_successes() {
+        let sampler = Sampler::new(0.01).unwrap();
+        assert_eq!(sampled(&sampler, 300, Some(StatusCode::OK)), [0, 100, 200]);
+
+        let sampler = Sampler::new(0.25).unwrap();
+        assert_eq!(sampled(&sampler, 12, Some(StatusCode::FOUND)), [0, 4, 8]);
+
+        let sampler = Sampler::new(1.0).unwrap();
+        assert_eq!(sampled(&sampler, 5, Some(StatusCode::OK)).len(), 5);
+        let sampler = Sampler::new(0.0).unwrap();
+        assert!(sampled(&sampler, 5, Some(StatusCode::OK)).is_empty());
+    }
+
+    #[test]
+    fn always_samples_failures() {
+        let sampler = Sampler::new(0.0).unwrap();
+        for status in [
+            Some(StatusCode::INTERNAL_SERVER_ERROR),
+            Some(StatusCode::BAD_GATEWAY),
+            Some(StatusCode::NOT_FOUND),
+            None,
+        ] {
+            assert_eq!(sampled(&sampler, 3, status).len(), 3, "{:?}", status);
+        }
+
+        // Failures don't count towards the successes sampled.
+        let sampler = Sampler::new(0.5).unwrap();
+        assert!(sampler.sample(Some(StatusCode::OK)));
+        assert!(sampler.sample(Some(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(!sampler.sample(Some(StatusCode::OK)));
+        assert!(sampler.sample(Some(StatusCode::OK)));
+    }
+
+    #[test]
+    fn the_rate_must_be_a_fraction() {
+        assert!(Sampler::new(-0.1).is_err());
+        assert!(Sampler::new(1.5).is_err());
+        assert!(Sampler::new(f64::NAN).is_err());
+    }
+}
@@ -0,0 +1,373 @@
+use crate::auth::{self, BasicAuth, BasicAuthConfig};
+use crate::internal_headers::InternalHeaders;
+use crate::middleware::EarlyResponse;
+use crate::routing::SharedRouting;
+use anyhow::*;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use log::{error, info};
+use routerify::prelude::*;
+use routerify::{Middleware, RequestInfo, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Keep this off the public network; it's served over plain HTTP.
+    pub listen_addr: SocketAddr,
+    /// Every admin request needs one of these users' credentials.
+    pub basic_auth: BasicAuthConfig,
+}
+
+struct AdminEnv {
+    routing: Arc<SharedRouting>,
+    basic_auth: Arc<BasicAuth>,
+}
+
+pub fn router(
+    config: &AdminConfig,
+    routing: Arc<SharedRouting>,
+    internal: Arc<InternalHeaders>,
+) -> Result<Router<Body, Error>> {
+    let basic_auth = BasicAuth::new(&config.basic_auth).context("admin.basic_auth")?;
+    Router::builder()
+        .data(AdminEnv {
+            routing,
+            basic_auth: Arc::new(basic_auth),
+        })
+        .middleware(Middleware::pre(admin_auth))
+        .get("/admin/upstreams", internal.handler(upstreams_handler))
+        .post(
+            "/admin/upstreams/:id/drain",
+            internal.handler(drain_handler),
+        )
+        .post(
+            "/admin/upstreams/:id/undrain",
+            internal.handler(undrain_handler),
+        )
+        .get("/admin/config", internal.handler(config_handler))
+        .post("/admin/maintenance", internal.handler(maintenance_handler))
+        .err_handler_with_info(error_handler)
+        .build()
+        .map_err(|err| anyhow!(err))
+        .context("Building admin router")
+}
+
+async fn admin_auth(req: Request<Body>) -> Result<Request<Body>> {
+    let basic_auth = req.data::<AdminEnv>().unwrap().basic_auth.clone();
+    auth::require_basic_auth(req, basic_auth).await
+}
+
+async fn error_handler(err: routerify::RouteError, req_info: RequestInfo) -> Response<Body> {
+    if let Some(response) = req_info.context::<EarlyResponse>() {
+        return response.into_response();
+    }
+    error!("Admin request to {} failed: {:#}", req_info.uri(), err);
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Body::from("Something went wrong"))
+        .unwrap()
+}
+
+/// Every upstream of the active routing, with its id, the route it serves (`null` for
+/// the top-level default upstreams), its health, whether it's draining and, with circuit
+/// breaking on, its circuit state.
+async fn upstreams_handler(req: Request<Body>) -> Result<Response<Body>> {
+    let routing = req.data::<AdminEnv>().unwrap().routing.current();
+    let mut upstreams = Vec::new();
+    for (route, balancer) in routing.routes() {
+        for (index, upstream) in balancer.upstreams().iter().enumerate() {
+            let circuit = routing
+                .circuit_breakers
+                .as_ref()
+                .and_then(|circuit_breakers| circuit_breakers.state(upstream));
+            upstreams.push(json!({
+                "id": balancer.ids()[index],
+                "url": upstream.to_string(),
+                "route": route,
+                "healthy": balancer.is_healthy(index),
+                "draining": balancer.is_draining(index),
+                "circuit": circuit,
+            }));
+        }
+    }
+    Ok(json_response(
+        StatusCode::OK,
+        &json!({ "upstreams": upstreams }),
+    ))
+}
+
+async fn drain_handler(req: Request<Body>) -> Result<Response<Body>> {
+    set_draining(req, true)
+}
+
+async fn undrain_handler(req: Request<Body>) -> Result<Response<Body>> {
+    set_draining(req, false)
+}
+
+/// Drains or restores the upstream with the id from the path, under every route that
+/// uses it. Reloads keep it for upstreams that are still configured.
+fn set_draining(req: Request<Body>, draining: bool) -> Result<Response<Body>> {
+    let routing = req.data::<AdminEnv>().unwrap().routing.current();
+    let id = req.param("id").unwrap();
+    let mut url = None;
+    for (_, balancer) in routing.routes() {
+        if let Some(index) = balancer.ids().iter().position(|known| known == id) {
+            balancer.set_draining(index, draining);
+            url = Some(balancer.upstreams()[index].to_string());
+        }
+    }
+    let url = match url {
+        Some(url) => url,
+        None => {
+            return Ok(json_response(
+                StatusCode::NOT_FOUND,
+                &json!({ "error": format!("No upstream with id {}", id) }),
+            ))
+        }
+    };
+
+    info!(
+        "Upstream {} {} from {}",
+        url,
+        if draining { "drained" } else { "undrained" },
+        req.remote_addr()
+    );
+    Ok(json_response(
+        StatusCode::OK,
+        &json!({ "id": id, "url": url, "draining": draining }),
+    ))
+}
+
+/// The config file as last (re)loaded, with secrets redacted. Only the reloadable
+/// settings change without a restart.
+async fn config_handler(req: Request<Body>) -> Result<Response<Body>> {
+    let routing = req.data::<AdminEnv>().unwrap().routing.current();
+    Ok(json_response(StatusCode::OK, &routing.config))
+}
+
+/// Turns maintenance mode on or off with `?enabled=true|false`, or flips it without a
+/// query. The change lasts until the next reload.
+async fn maintenance_handler(req: Request<Body>) -> Result<Response<Body>> {
+    let routing = req.data::<AdminEnv>().unwrap().routing.current();
+    let enabled = match req.uri().query() {
+        None => Some(!routing.maintenance.is_enabled()),
+        Some("enabled=true") => Some(true),
+        Some("enabled=false") => Some(false),
+        Some(_) => None,
+    };
+    let enabled = match enabled {
+        Some(enabled) => enabled,
+        None => {
+            return Ok(json_response(
+                StatusCode::BAD_REQUEST,
+                &json!({ "error": "Expected ?enabled=true or ?enabled=false" }),
+            ))
+        }
+    };
+
+    routing.maintenance.set_enabled(enabled);
+    info!(
+        "Maintenance mode turned {} from {}",
+        if enabled { "on" } else { "off" },
+        req.remote_addr()
+    );
+    Ok(json_response(
+        StatusCode::OK,
+        &json!({ "maintenance": enabled }),
+    ))
+}
+
+fn json_response(status: StatusCode, value: &Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(value.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, body_json, body_string, echo_upstream, password_hash, Proxy};
+    use hyper::header::AUTHORIZATION;
+    use hyper::{Client, Method};
+
+    async fn proxy() -> (Proxy, String, String) {
+        let (base, api) = (echo_upstream().await, echo_upstream().await);
+        let proxy = Proxy::start(&format!(
+            r#"
+            upstreams = "http://{}"
+            [[routes]]
+            path_prefix = "/api"
+            upstreams = "http://{}"
+            [admin]
+            listen_addr = "127.0.0.1:0"
+            [[admin.basic_auth.users]]
+            username = "ops"
+            password_hash = "{}"
+            "#,
+            base,
+            api,
+            password_hash("secret")
+        ))
+        .await;
+        (
+            proxy,
+            format!("http://{}/", base),
+            format!("http://{}/", api),
+        )
+    }
+
+    async fn admin(proxy: &Proxy, method: Method, path_and_query: &str) -> Response<Body> {
+        let credentials = format!("Basic {}", base64::encode("ops:secret"));
+        let req = Request::builder()
+            .method(method)
+            .uri(format!(
+                "http://{}{}",
+                proxy.admin_addr.unwrap(),
+                path_and_query
+            ))
+            .header(AUTHORIZATION, credentials)
+            .body(Body::empty())
+            .unwrap();
+        Client::new().request(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn lists_upstreams_with_their_state() {
+        let (proxy, base, api) = proxy().await;
+        let response = admin(&proxy, Method::GET, "/admin/upstreams").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+
+        let mut upstreams = body_json(response).await["upstreams"]
+            .as_array()
+            .unwrap()
+            .clone();
+        for upstream in &mut upstreams {
+            assert!(!upstream["id"].as_str().unwrap().is_empty());
+            upstream.as_object_mut().unwrap().remove("id");
+        }
+        let expected = |url: &str, route: Value| {
+            json!({
+                "url": url,
+                "route": route,
+                "healthy": true,
+                "draining": false,
+                "circuit": null,
+            })
+        };
+        assert_eq!(upstreams.len(), 2);
+        assert!(upstreams.contains(&expected(&base, Value::Null)));
+        assert!(upstreams.contains(&expected(&api, json!("/api"))));
+    }
+
+    #[tokio::test]
+    async fn toggles_maintenance_mode() {
+        let (proxy, _, _) = proxy().await;
+        let response = admin(&proxy, Method::POST, "/admin/maintenance?enabled=true").await;
+        assert_eq!(body_json(response).await, json!({ "maintenance": true }));
+        assert_eq!(
+            proxy.get("/path").await.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // Without a query it flips.
+        let response = admin(&proxy, Method::POST, "/admin/maintenance").await;
+        assert_eq!(body_json(response).await, json!({ "maintenance": false }));
+        assert_eq!(proxy.get("/path").await.status(), StatusCode::OK);
+
+        let response = admin(&proxy, Method::POST, "/admin/maintenance?enabled=yes").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(proxy.get("/path").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn dumps_the_config_without_secrets() {
+        let (proxy, _, _) = proxy().await;
+        let config = body_json(admin(&proxy, Method::GET, "/admin/config").await).await;
+        assert_eq!(
+            config["admin"]["basic_auth"]["users"][0],
+            json!({ "username": "ops", "password_hash": "<redacted>" })
+        );
+    }
+
+    #[tokio::test]
+    async fn needs_credentials() {
+        let (proxy, _, _) = proxy().await;
+        let uri = format!("http://{}/admin/upstreams", proxy.admin_addr.unwrap());
+        let response = Client::new().get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn drained_upstreams_get_no_new_requests() {
+        let mut addrs = Vec::new();
+        for name in ["a", "b"] {
+            let addr =
+                test_support::upstream(move |_| async move { Response::new(Body::from(name)) })
+                    .await;
+            addrs.push(addr);
+        }
+        let proxy = Proxy::start(&format!(
+            r#"
+            upstreams = ["http://{}", "http://{}"]
+            [admin]
+            listen_addr = "127.0.0.1:0"
+            [[admin.basic_auth.users]]
+            username = "ops"
+            password_hash = "{}"
+            "#,
+            addrs[0],
+            addrs[1],
+            password_hash("secret")
+        ))
+        .await;
+        let served = || async {
+            let mut served = Vec::new();
+            for _ in 0..6 {
+                served.push(body_string(proxy.get("/path").await).await);
+            }
+            served.sort();
+            served.dedup();
+            served
+        };
+        let state = || async {
+            let listed = body_json(admin(&proxy, Method::GET, "/admin/upstreams").await).await;
+            let url = format!("http://{}/", addrs[0]);
+            listed["upstreams"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|upstream| upstream["url"] == url.as_str())
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(served().await, ["a", "b"]);
+
+        let id = state().await["id"].as_str().unwrap().to_string();
+        let response = admin(
+            &proxy,
+            Method::POST,
+            &format!("/admin/upstreams/{}/drain", id),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await["draining"], true);
+        assert_eq!(state().await["draining"], true);
+        assert_eq!(served().await, ["b"]);
+
+        let path = format!("/admin/upstreams/{}/undrain", id);
+        let response = admin(&proxy, Method::POST, &path).await;
+        assert_eq!(body_json(response).await["draining"], false);
+        assert_eq!(served().await, ["a", "b"]);
+
+        let response = admin(&proxy, Method::POST, "/admin/upstreams/unknown/drain").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,730 @@
+use crate::middleware::{reject, EarlyResponse};
+use anyhow::*;
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Request, StatusCode};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::debug;
+use ring::{constant_time, pbkdf2};
+use routerify::prelude::*;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuthConfig {
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    pub users: Vec<BasicAuthUser>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuthUser {
+    pub username: String,
+    /// `pbkdf2-sha256$<iterations>$<base64 salt>$<base64 hash>`; see the README for how
+    /// to make one.
+    pub password_hash: String,
+}
+
+fn default_realm() -> String {
+    "Vostok".to_string()
+}
+
+struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    fn parse(value: &str) -> Result<PasswordHash> {
+        let parts = value.split('$').collect::<Vec<_>>();
+        let (iterations, salt, hash) = match parts.as_slice() {
+            [HASH_SCHEME, iterations, salt, hash] => (iterations, salt, hash),
+            _ => bail!(
+                "Password hash must look like {}$<iterations>$<salt>$<hash>",
+                HASH_SCHEME
+            ),
+        };
+        let hash = PasswordHash {
+            iterations: iterations
+                .parse()
+                .context("Password hash iterations must be a positive integer")?,
+            salt: base64::decode(salt).context("Decoding password hash salt")?,
+            hash: base64::decode(hash).context("Decoding password hash")?,
+        };
+        ensure!(!hash.hash.is_empty(), "Password hash is empty");
+        Ok(hash)
+    }
+
+    /// The comparison inside `pbkdf2::verify` is constant-time.
+    fn matches(&self, password: &str) -> bool {
+        pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            self.iterations,
+            &self.salt,
+            password.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+/// HTTP Basic credentials checked against PBKDF2 password hashes.
+pub struct BasicAuth {
+    challenge: HeaderValue,
+    users: Vec<(String, PasswordHash)>,
+}
+
+impl BasicAuth {
+    pub fn new(config: &BasicAuthConfig) -> Result<BasicAuth> {
+        ensure!(
+            !config.users.is_empty(),
+            "basic_auth needs at least one user"
+        );
+        let challenge = HeaderValue::from_str(&format!(
+            "Basic realm=\"{}\", charset=\"UTF-8\"",
+            config.realm
+        ))
+        .context("basic_auth.realm isn't a valid header value")?;
+        let users = config
+            .users
+            .iter()
+            .map(|user| {
+                PasswordHash::parse(&user.password_hash)
+                    .with_context(|| format!("basic_auth user {:?}", user.username))
+                    .map(|hash| (user.username.clone(), hash))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(BasicAuth { challenge, users })
+    }
+
+    /// Unknown usernames still pay for a hash so response times don't reveal which
+    /// usernames exist.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        let user = self.users.iter().find(|(name, _)| {
+            constant_time::verify_slices_are_equal(name.as_bytes(), username.as_bytes()).is_ok()
+        });
+        match user {
+            Some((_, hash)) => hash.matches(password),
+            None => {
+                self.users[0].1.matches(password);
+                false
+            }
+        }
+    }
+
+    /// The username, if `value` carries valid credentials.
+    fn verify_header(&self, value: &HeaderValue) -> Option<String> {
+        let (username, password) = parse_basic_credentials(value)?;
+        if self.verify(&username, &password) {
+            Some(username)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decodes `Basic <base64(username:password)>`.
+fn parse_basic_credentials(value: &HeaderValue) -> Option<(String, String)> {
+    let value = value.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Requires the credentials of the request's site, if it has any.
+pub async fn auth(req: Request<Body>) -> Result<Request<Body>> {
+    let routing = req.data::<Arc<crate::Env>>().unwrap().routing.current();
+    let basic_auth = routing.site(req.uri(), req.headers()).basic_auth.clone();
+    match basic_auth {
+        Some(basic_auth) => require_basic_auth(req, basic_auth).await,
+        None => Ok(req),
+    }
+}
+
+/// The Basic auth user a request was let in as, kept in its extensions since the
+/// credentials themselves are gone by the time it's handled.
+#[derive(Clone)]
+pub struct AuthenticatedUser(pub String);
+
+/// Passes `req` on if it carries valid credentials, and rejects it with `401` otherwise.
+/// The credentials are Vostok's own, so they're removed before the request goes on.
+pub async fn require_basic_auth(
+    mut req: Request<Body>,
+    basic_auth: Arc<BasicAuth>,
+) -> Result<Request<Body>> {
+    // Hashing is deliberately slow, so keep it off the async workers.
+    let user = match req.headers().get(AUTHORIZATION).cloned() {
+        None => None,
+        Some(value) => {
+            let verifier = Arc::clone(&basic_auth);
+            tokio::task::spawn_blocking(move || verifier.verify_header(&value))
+                .await
+                .context("Verifying credentials")?
+        }
+    };
+    if let Some(user) = user {
+        req.headers_mut().remove(AUTHORIZATION);
+        req.extensions_mut().insert(AuthenticatedUser(user));
+        return Ok(req);
+    }
+
+    debug!(
+        "Rejecting unauthenticated request from {}",
+        crate::client_ip::client_ip(&req)
+    );
+    Err(reject(
+        &req,
+        EarlyResponse::new(StatusCode::UNAUTHORIZED, "Unauthorized")
+            .header(WWW_AUTHENTICATE, basic_auth.challenge.clone()),
+    ))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    /// Shared secret for HS256 tokens.
+    #[serde(default)]
+    pub hs256_secret: Option<String>,
+    /// PEM public key (`PUBLIC KEY` or `RSA PUBLIC KEY`) for RS256 tokens.
+    #[serde(default)]
+    pub rs256_public_key_path: Option<PathBuf>,
+    /// How far `exp` and `nbf` may be off, for clocks that aren't quite in sync.
+    #[serde(default, with = "humantime_serde")]
+    pub leeway: Duration,
+    /// Claims to forward to the upstream, as claim name to header name.
+    #[serde(default)]
+    pub forward_claims: BTreeMap<String, String>,
+}
+
+/// Validates bearer JWTs: the signature must match the configured key and algorithm,
+/// `exp` must be present and in the future, and `nbf` (if present) in the past.
+pub struct JwtValidator {
+    key: DecodingKey,
+    validation: Validation,
+    forward_claims: Vec<(String, HeaderName)>,
+}
+
+impl JwtValidator {
+    pub fn new(config: &JwtConfig) -> Result<JwtValidator> {
+        let (key, algorithm) = match (&config.hs256_secret, &config.rs256_public_key_path) {
+            (Some(secret), None) => {
+                ensure!(!secret.is_empty(), "jwt.hs256_secret is empty");
+                (
+                    DecodingKey::from_secret(secret.as_bytes()),
+                    Algorithm::HS256,
+                )
+            }
+            (None, Some(path)) => (
+                load_rsa_public_key(path)
+                    .with_context(|| format!("Loading JWT public key {}", path.display()))?,
+                Algorithm::RS256,
+            ),
+            _ => bail!("jwt needs exactly one of hs256_secret and rs256_public_key_path"),
+        };
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = config.leeway.as_secs();
+        validation.validate_nbf = true;
+        let forward_claims = config
+            .forward_claims
+            .iter()
+            .map(|(claim, header)| {
+                HeaderName::from_bytes(header.as_bytes())
+                    .with_context(|| {
+                        format!("Invalid header name {:?} for claim {}", header, claim)
+                    })
+                    .map(|header| (claim.clone(), header))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(JwtValidator {
+            key,
+            validation,
+            forward_claims,
+        })
+    }
+
+    /// Returns the token's claims if it's valid now.
+    pub fn validate(&self, token: &str) -> Result<Map<String, Value>> {
+        let data = jsonwebtoken::decode::<Map<String, Value>>(token, &self.key, &self.validation)
+            .map_err(|err| match err.kind() {
+            ErrorKind::InvalidAlgorithm => {
+                anyhow!(
+                    "Token isn't signed with {:?}",
+                    self.validation.algorithms[0]
+                )
+            }
+            ErrorKind::InvalidSignature => anyhow!("Invalid signature"),
+            ErrorKind::ExpiredSignature => anyhow!("Token expired"),
+            ErrorKind::ImmatureSignature => anyhow!("Token isn't valid yet"),
+            ErrorKind::MissingRequiredClaim(claim) => anyhow!("Token has no {} claim", claim),
+            _ => anyhow!("Malformed token: {}", err),
+        })?;
+        Ok(data.claims)
+    }
+}
+
+/// Reads an RSA public key from a `PUBLIC KEY` or `RSA PUBLIC KEY` PEM.
+fn load_rsa_public_key(path: &Path) -> Result<DecodingKey> {
+    let pem = std::fs::read_to_string(path)?;
+    // `jsonwebtoken` takes the first RSA key it finds in any PEM, including private keys
+    // and certificates, so check what this one is first.
+    let label = pem
+        .lines()
+        .find_map(|line| line.strip_prefix("-----BEGIN "))
+        .and_then(|line| line.strip_suffix("-----"))
+        .ok_or_else(|| anyhow!("No PEM block found"))?;
+    ensure!(
+        label == "PUBLIC KEY" || label == "RSA PUBLIC KEY",
+        "Expected a PUBLIC KEY or RSA PUBLIC KEY PEM block, found {}",
+        label
+    );
+    DecodingKey::from_rsa_pem(pem.as_bytes()).context("Not an RSA public key")
+}
+
+pub async fn jwt(mut req: Request<Body>) -> Result<Request<Body>> {
+    let env = req.data::<Arc<crate::Env>>().unwrap();
+    let validator = match &env.jwt {
+        Some(validator) => validator.clone(),
+        None => return Ok(req),
+    };
+
+    let claims = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .ok_or_else(|| anyhow!("Missing bearer token"))
+        .and_then(|(_, token)| validator.validate(token.trim()));
+    let claims = match claims {
+        Err(err) => {
+            debug!(
+                "Rejecting JWT from {}: {:#}",
+                crate::client_ip::client_ip(&req),
+                err
+            );
+            return Err(reject(
+                &req,
+                EarlyResponse::new(StatusCode::UNAUTHORIZED, "Unauthorized")
+                    .header(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer")),
+            ));
+        }
+        Result::Ok(claims) => claims,
+    };
+
+    // Clients must not be able to set these headers themselves.
+    let headers = req.headers_mut();
+    for (claim, header) in &validator.forward_claims {
+        headers.remove(header);
+        let value = match claims.get(claim) {
+            None => continue,
+            Some(Value::String(value)) => HeaderValue::from_str(value).ok(),
+            Some(value) => HeaderValue::from_str(&value.to_string()).ok(),
+        };
+        if let Some(value) = value {
+            headers.insert(header.clone(), value);
+        }
+    }
+    Ok(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_json, echo_upstream, password_hash, Proxy};
+    use ring::rand::SystemRandom;
+    use ring::{hmac, signature};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn basic_auth(users: &[(&str, &str)]) -> Result<BasicAuth> {
+        BasicAuth::new(&BasicAuthConfig {
+            realm: default_realm(),
+            users: users
+                .iter()
+                .map(|(username, password_hash)| BasicAuthUser {
+                    username: username.to_string(),
+                    password_hash: password_hash.to_string(),
+                })
+                .collect(),
+        })
+    }
+
+    fn credentials(username: &str, password: &str) -> HeaderValue {
+        let encoded = base64::encode(format!("{}:{}", username, password));
+        HeaderValue::from_str(&format!("Basic {}", encoded)).unwrap()
+    }
+
+    #[test]
+    fn verifies_passwords_against_their_hashes() {
+        let auth = basic_auth(&[
+            ("admin", &password_hash("secret")),
+            ("ops", &password_hash("pa:ss")),
+        ])
+        .unwrap();
+        assert!(auth.verify("admin", "secret"));
+        assert!(auth.verify("ops", "pa:ss"));
+        assert!(!auth.verify("admin", "pa:ss"));
+        assert!(!auth.verify("admin", ""));
+        assert!(!auth.verify("nobody", "secret"));
+
+        let user = |value: &HeaderValue| auth.verify_header(value);
+        assert_eq!(
+            user(&credentials("admin", "secret")).as_deref(),
+            Some("admin")
+        );
+        // Only the first colon separates the username from the password.
+        assert_eq!(user(&credentials("ops", "pa:ss")).as_deref(), Some("ops"));
+        assert_eq!(
+            user(
+                &HeaderValue::from_str(&format!("basic {}", base64::encode("admin:secret")))
+                    .unwrap()
+            )
+            .as_deref(),
+            Some("admin")
+        );
+        assert!(user(&credentials("admin", "wrong")).is_none());
+        assert!(user(&HeaderValue::from_static("Bearer abc")).is_none());
+        assert!(user(&HeaderValue::from_static("Basic not-base64!")).is_none());
+        assert!(user(
+            &HeaderValue::from_str(&format!("Basic {}", base64::encode("no colon"))).unwrap()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        let valid = password_hash("secret");
+        let salt_and_hash = valid.trim_start_matches("pbkdf2-sha256$1000$");
+        for hash in [
+            "plain text".to_string(),
+            format!("bcrypt$1000${}", salt_and_hash),
+            format!("pbkdf2-sha256$0${}", salt_and_hash),
+            format!("pbkdf2-sha256$many${}", salt_and_hash),
+            "pbkdf2-sha256$1000$not base64$AAAA".to_string(),
+            "pbkdf2-sha256$1000$AAAA$".to_string(),
+        ] {
+            assert!(basic_auth(&[("admin", &hash)]).is_err(), "{}", hash);
+        }
+        assert!(basic_auth(&[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn requests_need_valid_credentials() {
+        let upstream = echo_upstream().await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[basic_auth]\nusers = [{{ username = \"admin\", password_hash = \"{}\" }}]",
+            upstream,
+            password_hash("secret")
+        ))
+        .await;
+        let get = |authorization: Option<HeaderValue>| {
+            let mut req = Request::get("/path");
+            if let Some(authorization) = authorization {
+                req = req.header(AUTHORIZATION, authorization);
+            }
+            proxy.send(req.body(Body::empty()).unwrap())
+        };
+
+        let response = get(Some(credentials("admin", "secret"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // The upstream never sees the client's credentials.
+        let echo = body_json(response).await;
+        assert!(echo["headers"].get("authorization").is_none(), "{}", echo);
+
+        for authorization in [None, Some(credentials("admin", "wrong"))] {
+            let response = get(authorization).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                response.headers()[WWW_AUTHENTICATE],
+                "Basic realm=\"Vostok\", charset=\"UTF-8\""
+            );
+        }
+    }
+
+    const SECRET: &str = "change-me";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    fn encode(value: &Value) -> String {
+        base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+    }
+
+    /// A token with the given header and claims, signed by `sign`.
+    fn token(header: Value, claims: Value, sign: impl FnOnce(&[u8]) -> Vec<u8>) -> String {
+        let signed = format!("{}.{}", encode(&header), encode(&claims));
+        let signature = sign(signed.as_bytes());
+        format!(
+            "{}.{}",
+            signed,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    fn hs256(secret: &[u8]) -> impl FnOnce(&[u8]) -> Vec<u8> + '_ {
+        move |signed| {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+            hmac::sign(&key, signed).as_ref().to_vec()
+        }
+    }
+
+    /// Signs with the test key whose public halves are in `testdata`.
+    fn rs256(signed: &[u8]) -> Vec<u8> {
+        let pem = std::fs::read_to_string(testdata("localhost.key")).unwrap();
+        let der = pem
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect::<String>();
+        let key = signature::RsaKeyPair::from_pkcs8(&base64::decode(der).unwrap()).unwrap();
+        let mut signature = vec![0; key.public_modulus_len()];
+        key.sign(
+            &signature::RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            signed,
+            &mut signature,
+        )
+        .unwrap();
+        signature
+    }
+
+    fn claims() -> Value {
+        json!({ "sub": "alice", "exp": now() + 60 })
+    }
+
+    fn hs256_validator() -> JwtValidator {
+        JwtValidator::new(&JwtConfig {
+            hs256_secret: Some(SECRET.to_string()),
+            rs256_public_key_path: None,
+            leeway: Duration::ZERO,
+            forward_claims: BTreeMap::new(),
+        })
+        .unwrap()
+    }
+
+    fn rs256_validator(public_key: &str) -> Result<JwtValidator> {
+        JwtValidator::new(&JwtConfig {
+            hs256_secret: None,
+            rs256_public_key_path: Some(testdata(public_key)),
+            leeway: Duration::ZERO,
+            forward_claims: BTreeMap::new(),
+        })
+    }
+
+    fn error(validator: &JwtValidator, token: &str) -> String {
+        format!("{:#}", validator.validate(token).unwrap_err())
+    }
+
+    #[test]
+    fn accepts_valid_hs256_tokens() {
+        let validator = hs256_validator();
+        let valid = token(
+            json!({ "alg": "HS256", "typ": "JWT" }),
+            claims(),
+            hs256(SECRET.as_bytes()),
+        );
+        assert_eq!(validator.validate(&valid).unwrap()["sub"], "alice");
+
+        let not_before = token(
+            json!({ "alg": "HS256" }),
+            json!({ "exp": now() + 60, "nbf": now() }),
+            hs256(SECRET.as_bytes()),
+        );
+        assert!(validator.validate(&not_before).is_ok());
+    }
+
+    #[test]
+    fn rejects_expired_early_and_unexpiring_tokens() {
+        let validator = hs256_validator();
+        let header = || json!({ "alg": "HS256" });
+        let sign = || hs256(SECRET.as_bytes());
+
+        let expired = token(header(), json!({ "exp": now() - 1 }), sign());
+        assert!(error(&validator, &expired).contains("Token expired"));
+        let early = token(
+            header(),
+            json!({ "exp": now() + 60, "nbf": now() + 60 }),
+            sign(),
+        );
+        assert!(error(&validator, &early).contains("isn't valid yet"));
+        let unexpiring = token(header(), json!({ "sub": "alice" }), sign());
+        assert!(error(&validator, &unexpiring).contains("no exp claim"));
+    }
+
+    #[test]
+    fn leeway_tolerates_clock_skew() {
+        let validator = JwtValidator::new(&JwtConfig {
+            hs256_secret: Some(SECRET.to_string()),
+            rs256_public_key_path: None,
+            leeway: Duration::from_secs(120),
+            forward_claims: BTreeMap::new(),
+        })
+        .unwrap();
+        let header = || json!({ "alg": "HS256" });
+        let sign = || hs256(SECRET.as_bytes());
+
+        let just_expired = token(header(), json!({ "exp": now() - 60 }), sign());
+        assert!(validator.validate(&just_expired).is_ok());
+        let nearly_valid = token(
+            header(),
+            json!({ "exp": now() + 600, "nbf": now() + 60 }),
+            sign(),
+        );
+        assert!(validator.validate(&nearly_valid).is_ok());
+        let long_expired = token(header(), json!({ "exp": now() - 600 }), sign());
+        assert!(error(&validator, &long_expired).contains("Token expired"));
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let validator = hs256_validator();
+        let valid = token(
+            json!({ "alg": "HS256" }),
+            claims(),
+            hs256(SECRET.as_bytes()),
+        );
+        let parts = valid.split('.').collect::<Vec<_>>();
+
+        let escalated = encode(&json!({ "sub": "root", "exp": now() + 60 }));
+        let tampered = format!("{}.{}.{}", parts[0], escalated, parts[2]);
+        assert!(error(&validator, &tampered).contains("Invalid signature"));
+
+        let mut signature = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD).unwrap();
+        signature[0] ^= 1;
+        let tampered = format!(
+            "{}.{}.{}",
+            parts[0],
+            parts[1],
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        );
+        assert!(error(&validator, &tampered).contains("Invalid signature"));
+
+        let other_secret = token(json!({ "alg": "HS256" }), claims(), hs256(b"other"));
+        assert!(error(&validator, &other_secret).contains("Invalid signature"));
+
+        for malformed in ["", "a.b", "a.b.c.d", &format!("{}.{}", parts[0], parts[1])] {
+            assert!(validator.validate(malformed).is_err(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn rejects_unsigned_tokens() {
+        let unsigned = token(json!({ "alg": "none" }), claims(), |_| Vec::new());
+        assert!(error(&hs256_validator(), &unsigned).contains("Malformed token"));
+        let validator = rs256_validator("localhost.pub.pem").unwrap();
+        assert!(error(&validator, &unsigned).contains("Malformed token"));
+        let no_alg = token(json!({}), claims(), hs256(SECRET.as_bytes()));
+        assert!(error(&hs256_validator(), &no_alg).contains("Malformed token"));
+    }
+
+    #[test]
+    fn accepts_rs256_tokens_with_either_public_key_format() {
+        let valid = token(json!({ "alg": "RS256" }), claims(), rs256);
+        let forged = token(
+            json!({ "alg": "RS256" }),
+            claims(),
+            hs256(SECRET.as_bytes()),
+        );
+        for public_key in ["localhost.pub.pem", "localhost-rsa.pub.pem"] {
+            let validator = rs256_validator(public_key).unwrap();
+            assert_eq!(validator.validate(&valid).unwrap()["sub"], "alice");
+            assert!(error(&validator, &forged).contains("Invalid signature"));
+        }
+    }
+
+    /// The classic confusion attack: an HS256 token whose secret is the RS256 public key,
+    /// which the attacker knows.
+    #[test]
+    fn rejects_hs256_tokens_keyed_with_the_rs256_public_key() {
+        for public_key in ["localhost.pub.pem", "localhost-rsa.pub.pem"] {
+            let pem = std::fs::read(testdata(public_key)).unwrap();
+            let forged = token(json!({ "alg": "HS256" }), claims(), hs256(&pem));
+            let validator = rs256_validator(public_key).unwrap();
+            assert!(error(&validator, &forged).contains("isn't signed with RS256"));
+        }
+        // The other way around, an RS256 token doesn't pass for HS256.
+        let forged = token(json!({ "alg": "RS256" }), claims(), rs256);
+        assert!(error(&hs256_validator(), &forged).contains("isn't signed with HS256"));
+    }
+
+    #[test]
+    fn rejects_invalid_key_configs() {
+        for public_key in ["localhost.key", "localhost.crt", "missing.pem"] {
+            assert!(rs256_validator(public_key).is_err(), "{}", public_key);
+        }
+        let both = JwtConfig {
+            hs256_secret: Some(SECRET.to_string()),
+            rs256_public_key_path: Some(testdata("localhost.pub.pem")),
+            leeway: Duration::ZERO,
+            forward_claims: BTreeMap::new(),
+        };
+        assert!(JwtValidator::new(&both).is_err());
+        let neither = JwtConfig {
+            hs256_secret: None,
+            rs256_public_key_path: None,
+            leeway: Duration::ZERO,
+            forward_claims: BTreeMap::new(),
+        };
+        assert!(JwtValidator::new(&neither).is_err());
+    }
+
+    #[tokio::test]
+    async fn forwards_claims_from_valid_tokens_only() {
+        let upstream = echo_upstream().await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[jwt]\nhs256_secret = \"{}\"\nforward_claims = {{ sub = \"X-User-Id\" }}",
+            upstream, SECRET
+        ))
+        .await;
+        let now = now();
+        let valid = token(
+            json!({ "alg": "HS256" }),
+            json!({ "sub": "alice", "exp": now + 60 }),
+            hs256(SECRET.as_bytes()),
+        );
+        let get = |authorization: Option<String>| {
+            let mut req = Request::get("/path").header("x-user-id", "root");
+            if let Some(authorization) = authorization {
+                req = req.header(AUTHORIZATION, authorization);
+            }
+            proxy.send(req.body(Body::empty()).unwrap())
+        };
+
+        let echo = body_json(get(Some(format!("Bearer {}", valid))).await).await;
+        assert_eq!(echo["headers"]["x-user-id"], json!(["alice"]));
+
+        let expired = token(
+            json!({ "alg": "HS256" }),
+            json!({ "sub": "alice", "exp": now - 1 }),
+            hs256(SECRET.as_bytes()),
+        );
+        for authorization in [None, Some(format!("Bearer {}", expired)), Some(valid)] {
+            let response = get(authorization).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+        }
+    }
+}
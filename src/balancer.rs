@@ -0,0 +1,856 @@
+use anyhow::*;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, COOKIE, SET_COOKIE};
+use hyper::Uri;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub uri: Uri,
+    /// Share of traffic relative to the other upstreams' weights.
+    pub weight: u32,
+    /// Lower goes first: upstreams only get requests while every upstream with a lower
+    /// priority is unhealthy or draining.
+    pub priority: u32,
+    pub overrides: Overrides,
+    /// Sent as `Authorization` on every request to the upstream, replacing the client's.
+    pub authorization: Option<HeaderValue>,
+}
+
+/// Settings that replace the global ones for requests to a single upstream.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Overrides {
+    pub request_timeout: Option<Duration>,
+    pub retries: Option<u32>,
+    /// Speak HTTP/2 with prior knowledge over plain TCP, like `client.http2_only` does
+    /// for every upstream.
+    pub http2_only: bool,
+    /// Send the client's `Host` header instead of the upstream's authority.
+    pub preserve_host: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsistentHashConfig {
+    /// What requests are hashed by.
+    #[serde(default)]
+    pub key: HashKey,
+    /// The request header hashed with `key = "header"`.
+    pub header: Option<String>,
+    /// How many points each unit of an upstream's weight gets on the ring. More spread
+    /// the keys more evenly.
+    #[serde(default = "default_points_per_weight")]
+    pub points_per_weight: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    #[default]
+    ClientIp,
+    /// The path and query.
+    Uri,
+    Header,
+}
+
+fn default_points_per_weight() -> u32 {
+    100
+}
+
+/// Sends requests with the same key to the same upstream, so e.g. each upstream's cache
+/// only holds its share of the keys. When upstreams come and go, only the keys of the
+/// ones affected move.
+#[derive(Debug, Clone)]
+pub struct ConsistentHash {
+    key: Key,
+    points_per_weight: u32,
+}
+
+#[derive(Debug, Clone)]
+enum Key {
+    ClientIp,
+    Uri,
+    Header(HeaderName),
+}
+
+impl ConsistentHash {
+    pub fn new(config: &ConsistentHashConfig) -> Result<ConsistentHash> {
+        let key = match (config.key, &config.header) {
+            (HashKey::Header, Some(header)) => Key::Header(
+                HeaderName::from_bytes(header.as_bytes())
+                    .with_context(|| format!("Invalid consistent_hash.header {:?}", header))?,
+            ),
+            (HashKey::Header, None) => bail!("consistent_hash.key = \"header\" needs a header"),
+            (_, Some(_)) => bail!("consistent_hash.header only applies to key = \"header\""),
+            (HashKey::ClientIp, None) => Key::ClientIp,
+            (HashKey::Uri, None) => Key::Uri,
+        };
+        ensure!(
+            config.points_per_weight > 0,
+            "consistent_hash.points_per_weight must be at least 1"
+        );
+        Ok(ConsistentHash {
+            key,
+            points_per_weight: config.points_per_weight,
+        })
+    }
+
+    /// What a request is hashed by, or `None` if it lacks the header, leaving it to
+    /// normal balancing.
+    pub fn key(&self, uri: &Uri, headers: &HeaderMap, client_ip: IpAddr) -> Option<Vec<u8>> {
+        match &self.key {
+            Key::ClientIp => Some(client_ip.to_string().into_bytes()),
+            Key::Uri => Some(
+                uri.path_and_query()
+                    .map_or(uri.path(), |path_and_query| path_and_query.as_str())
+                    .as_bytes()
+                    .to_vec(),
+            ),
+            Key::Header(name) => Some(headers.get(name)?.as_bytes().to_vec()),
+        }
+    }
+}
+
+/// Points are placed by hashing the upstreams' sticky ids, so an upstream keeps its
+/// place whatever else is configured.
+struct Ring {
+    /// Sorted by point.
+    points: Vec<(u64, usize)>,
+    /// The upstreams with any points, which are those with a non-zero weight.
+    members: Vec<usize>,
+}
+
+/// The most points a ring may have, which keeps its memory and build time bounded
+/// whatever the weights; a million points take 16 MB.
+const MAX_RING_POINTS: u64 = 1_000_000;
+
+impl Ring {
+    fn new(ids: &[String], weights: &[u32], points_per_weight: u32) -> Result<Ring> {
+        let total = weights
+            .iter()
+            .map(|weight| u64::from(*weight) * u64::from(points_per_weight))
+            .sum::<u64>();
+        ensure!(
+            total <= MAX_RING_POINTS,
+            "consistent_hash would put {} points on the ring, over the limit of {}; lower points_per_weight or the upstream weights",
+            total,
+            MAX_RING_POINTS
+        );
+        let mut points = Vec::with_capacity(total as usize);
+        let mut members = Vec::new();
+        for (index, weight) in weights.iter().enumerate() {
+            if *weight > 0 {
+                members.push(index);
+            }
+            let count = u64::from(*weight) * u64::from(points_per_weight);
+            points.extend(
+                (0..count)
+                    .map(|point| (hash(format!("{}-{}", ids[index], point).as_bytes()), index)),
+            );
+        }
+        points.sort_unstable();
+        Ok(Ring { points, members })
+    }
+}
+
+enum Selection {
+    /// The cursor is a single atomic counter so concurrent requests each claim a
+    /// distinct slot.
+    RoundRobin(AtomicUsize),
+    Weighted {
+        weights: Vec<u64>,
+        rng: SplitMix64,
+    },
+}
+
+/// Name of the cookie that pins a client to an upstream when sticky sessions are on.
+pub const STICKY_COOKIE: &str = "vostok_upstream";
+
+/// Picks upstreams in round-robin order when they're all weighted the same, and at
+/// random in proportion to their weights otherwise. Upstreams marked unhealthy are
+/// skipped, unless none are healthy, in which case all of them are tried. Draining
+/// upstreams get no new requests unless every upstream is draining. Among the upstreams
+/// left, only those with the lowest priority number are picked from.
+pub struct Balancer {
+    upstreams: Vec<Uri>,
+    /// Sticky-session ids, derived from the URIs so they survive restarts and
+    /// reordering of the config. The admin API uses them to name upstreams too.
+    ids: Vec<String>,
+    healthy: Vec<AtomicBool>,
+    draining: Vec<AtomicBool>,
+    priorities: Vec<u32>,
+    overrides: Vec<Overrides>,
+    authorizations: Vec<Option<HeaderValue>>,
+    selection: Selection,
+    ring: Option<Ring>,
+}
+
+impl Balancer {
+    /// The same `seed` always produces the same sequence of weighted picks. With
+    /// `consistent_hash`, the ring [`Balancer::hashed`] picks from is built too.
+    pub fn new(
+        upstreams: Vec<Upstream>,
+        seed: u64,
+        consistent_hash: Option<&ConsistentHash>,
+    ) -> Result<Balancer> {
+        ensure!(!upstreams.is_empty(), "At least one upstream is required");
+        ensure!(
+            upstreams.iter().any(|upstream| upstream.weight > 0),
+            "At least one upstream needs a non-zero weight"
+        );
+
+        let first_weight = upstreams[0].weight;
+        let selection = if upstreams
+            .iter()
+            .all(|upstream| upstream.weight == first_weight)
+        {
+            Selection::RoundRobin(AtomicUsize::new(0))
+        } else {
+            Selection::Weighted {
+                weights: upstreams
+                    .iter()
+                    .map(|upstream| u64::from(upstream.weight))
+                    .collect(),
+                rng: SplitMix64::new(seed),
+            }
+        };
+        let ids = upstreams
+            .iter()
+            .map(|upstream| sticky_id(&upstream.uri))
+            .collect::<Vec<_>>();
+        let weights = upstreams
+            .iter()
+            .map(|upstream| upstream.weight)
+            .collect::<Vec<_>>();
+        let ring = consistent_hash
+            .map(|hash| Ring::new(&ids, &weights, hash.points_per_weight))
+            .transpose()?;
+        Ok(Balancer {
+            ids,
+            healthy: upstreams.iter().map(|_| AtomicBool::new(true)).collect(),
+            draining: upstreams.iter().map(|_| AtomicBool::new(false)).collect(),
+            priorities: upstreams.iter().map(|upstream| upstream.priority).collect(),
+            overrides: upstreams
+                .iter()
+                .map(|upstream| upstream.overrides)
+                .collect(),
+            authorizations: upstreams
+                .iter()
+                .map(|upstream| upstream.authorization.clone())
+                .collect(),
+            upstreams: upstreams.into_iter().map(|upstream| upstream.uri).collect(),
+            selection,
+            ring,
+        })
+    }
+
+    pub fn next(&self) -> &Uri {
+        let index = match &self.selection {
+            Selection::RoundRobin(cursor) => {
+                // Taking turns among the most preferred upstreams only, so they share
+                // the requests evenly whatever the others are doing.
+                let preferences = (0..self.upstreams.len())
+                    .map(|index| self.preference(index))
+                    .collect::<Vec<_>>();
+                let best = preferences.iter().min().unwrap();
+                let candidates = (0..self.upstreams.len())
+                    .filter(|index| preferences[*index] == *best)
+                    .collect::<Vec<_>>();
+                candidates[cursor.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            Selection::Weighted { weights, rng } => self.pick_weighted(weights, rng),
+        };
+        &self.upstreams[index]
+    }
+
+    /// The upstream at or after the key's point on the ring, going round until one is as
+    /// preferred as any available, so an unhealthy upstream's keys move to the next ones
+    /// along and come back once it recovers. Without a ring this is [`Balancer::next`].
+    pub fn hashed(&self, key: &[u8]) -> &Uri {
+        let ring = match &self.ring {
+            Some(ring) => ring,
+            None => return self.next(),
+        };
+        let best = ring
+            .members
+            .iter()
+            .map(|index| self.preference(*index))
+            .min()
+            .unwrap();
+        let point = hash(key);
+        let start = ring.points.partition_point(|(other, _)| *other < point);
+        let (_, index) = ring.points[start..]
+            .iter()
+            .chain(&ring.points[..start])
+            .find(|(_, index)| self.preference(*index) == best)
+            .unwrap();
+        &self.upstreams[*index]
+    }
+
+    /// Lower is better: healthy upstreams that aren't draining come first, then the
+    /// unhealthy ones that aren't draining, then the draining ones, each by priority.
+    fn preference(&self, index: usize) -> (u8, u32) {
+        let state = match (self.is_draining(index), self.is_healthy(index)) {
+            (false, true) => 0,
+            (false, false) => 1,
+            (true, _) => 2,
+        };
+        (state, self.priorities[index])
+    }
+
+    fn pick_weighted(&self, weights: &[u64], rng: &SplitMix64) -> usize {
+        // The most preferred upstreams with any weight between them; the total over
+        // every upstream is non-zero, so some level always has one.
+        let mut levels = (0..weights.len())
+            .map(|index| self.preference(index))
+            .collect::<Vec<_>>();
+        levels.sort_unstable();
+        levels.dedup();
+        let (level, total) = levels
+            .into_iter()
+            .map(|level| {
+                let total: u64 = weights
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| self.preference(*index) <= level)
+                    .map(|(_, weight)| weight)
+                    .sum();
+                (level, total)
+            })
+            .find(|(_, total)| *total > 0)
+            .unwrap();
+
+        let mut point = rng.next() % total;
+        for (index, weight) in weights.iter().enumerate() {
+            if self.preference(index) > level {
+                continue;
+            }
+            if point < *weight {
+                return index;
+            }
+            point -= weight;
+        }
+        unreachable!("point is below the total weight")
+    }
+
+    pub fn is_healthy(&self, index: usize) -> bool {
+        self.healthy[index].load(Ordering::Relaxed)
+    }
+
+    pub fn set_healthy(&self, index: usize, healthy: bool) {
+        self.healthy[index].store(healthy, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self, index: usize) -> bool {
+        self.draining[index].load(Ordering::Relaxed)
+    }
+
+    /// Stops (or resumes) sending new requests to the upstream; requests already sent
+    /// to it aren't affected.
+    pub fn set_draining(&self, index: usize, draining: bool) {
+        self.draining[index].store(draining, Ordering::Relaxed);
+    }
+
+    pub fn upstreams(&self) -> &[Uri] {
+        &self.upstreams
+    }
+
+    /// The stable id of each upstream, in the same order as [`Balancer::upstreams`].
+    pub fn ids(&self) -> &[String] {
+        &self.ids
+    }
+
+    pub fn overrides(&self, upstream: &Uri) -> Overrides {
+        self.upstreams
+            .iter()
+            .position(|candidate| candidate == upstream)
+            .map_or_else(Overrides::default, |index| self.overrides[index])
+    }
+
+    /// The `Authorization` header configured for `upstream`, if any.
+    pub fn authorization(&self, upstream: &Uri) -> Option<&HeaderValue> {
+        self.upstreams
+            .iter()
+            .position(|candidate| candidate == upstream)
+            .and_then(|index| self.authorizations[index].as_ref())
+    }
+
+    /// The upstream a request's sticky cookie points to, unless it's been removed, is
+    /// unhealthy or is draining.
+    pub fn pinned(&self, headers: &HeaderMap) -> Option<&Uri> {
+        let id = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .find_map(|cookie| match cookie.trim().split_once('=') {
+                Some((STICKY_COOKIE, id)) => Some(id),
+                _ => None,
+            })?;
+        let index = self.ids.iter().position(|known| known == id)?;
+        if !self.is_healthy(index) || self.is_draining(index) {
+            return None;
+        }
+        Some(&self.upstreams[index])
+    }
+
+    /// Adds a cookie pinning the client to `upstream`.
+    pub fn set_sticky_cookie(&self, headers: &mut HeaderMap, upstream: &Uri) {
+        let index = match self.upstreams.iter().position(|known| known == upstream) {
+            Some(index) => index,
+            None => return,
+        };
+        let cookie = format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax",
+            STICKY_COOKIE, self.ids[index]
+        );
+        if let Result::Ok(cookie) = HeaderValue::from_str(&cookie) {
+            headers.append(SET_COOKIE, cookie);
+        }
+    }
+}
+
+/// 64-bit FNV-1a of the upstream URI, in hex.
+fn sticky_id(uri: &Uri) -> String {
+    format!("{:016x}", fnv1a(uri.to_string().as_bytes()))
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// FNV-1a alone leaves similar inputs, like an id's ring points, bunched together.
+fn hash(bytes: &[u8]) -> u64 {
+    mix(fnv1a(bytes))
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A seed for [`Balancer::new`] that differs between runs.
+pub fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
+
+/// SplitMix64: each call advances the state by a fixed increment and scrambles it, so
+/// the state can be a single atomic shared by concurrent requests.
+struct SplitMix64 {
+    state: AtomicU64,
+}
+
+impl SplitMix64 {
+    const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+    fn new(seed: u64) -> SplitMix64 {
+        SplitMix64 {
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    fn next(&self) -> u64 {
+        mix(self
+            .state
+            .fetch_add(Self::GAMMA, Ordering::Relaxed)
+            .wrapping_add(Self::GAMMA))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_string, Proxy};
+    use hyper::{Body, Request, Response};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+
+    fn upstream(uri: &str) -> Upstream {
+        Upstream {
+            uri: uri.parse().unwrap(),
+            weight: 1,
+            priority: 0,
+            overrides: Overrides::default(),
+            authorization: None,
+        }
+    }
+
+    fn upstreams(count: usize) -> Vec<Upstream> {
+        (0..count)
+            .map(|index| upstream(&format!("http://10.0.0.{}:8080", index + 1)))
+            .collect()
+    }
+
+    fn counts<'a>(picks: impl IntoIterator<Item = &'a Uri>) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for uri in picks {
+            *counts.entry(uri.to_string()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let balancer = Balancer::new(upstreams(3), 0, None).unwrap();
+        let picks = (0..6)
+            .map(|_| balancer.next().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(picks[..3], picks[3..]);
+        let distinct = picks[..3].iter().collect::<HashSet<_>>();
+        assert_eq!(distinct.len(), 3);
+    }
+
+    #[test]
+    fn round_robin_spreads_100_requests_evenly() {
+        let balancer = Balancer::new(upstreams(4), 0, None).unwrap();
+        let counts = counts((0..100).map(|_| balancer.next()));
+        assert_eq!(counts.len(), 4);
+        assert!(counts.values().all(|count| *count == 25), "{:?}", counts);
+    }
+
+    #[test]
+    fn round_robin_spreads_concurrent_requests_evenly() {
+        let balancer = Arc::new(Balancer::new(upstreams(4), 0, None).unwrap());
+        let threads = (0..4)
+            .map(|_| {
+                let balancer = balancer.clone();
+                std::thread::spawn(move || {
+                    (0..25)
+                        .map(|_| balancer.next().to_string())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut counts = HashMap::new();
+        for thread in threads {
+            for uri in thread.join().unwrap() {
+                *counts.entry(uri).or_insert(0) += 1;
+            }
+        }
+        assert_eq!(counts.len(), 4);
+        assert!(counts.values().all(|count| *count == 25), "{:?}", counts);
+    }
+
+    #[test]
+    fn requires_an_upstream() {
+        assert!(Balancer::new(Vec::new(), 0, None).is_err());
+    }
+
+    fn weighted(weights: &[u32]) -> Vec<Upstream> {
+        upstreams(weights.len())
+            .into_iter()
+            .zip(weights)
+            .map(|(upstream, weight)| Upstream {
+                weight: *weight,
+                ..upstream
+            })
+            .collect()
+    }
+
+    #[test]
+    fn weighted_picks_follow_the_weights() {
+        let balancer = Balancer::new(weighted(&[1, 3, 6, 0]), 42, None).unwrap();
+        let counts = counts((0..10_000).map(|_| balancer.next()));
+        assert_eq!(counts.get("http://10.0.0.4:8080/"), None);
+        for (upstream, expected) in [("1", 1_000), ("2", 3_000), ("3", 6_000)].iter() {
+            let count = counts[&format!("http://10.0.0.{}:8080/", upstream)];
+            // Well over four standard deviations for any of these shares.
+            assert!(
+                (count as i64 - expected).abs() < 250,
+                "{} got {} of {:?}",
+                upstream,
+                count,
+                counts
+            );
+        }
+    }
+
+    #[test]
+    fn weighted_picks_are_determined_by_the_seed() {
+        let picks = |seed| {
+            let balancer = Balancer::new(weighted(&[1, 2, 3]), seed, None).unwrap();
+            (0..100)
+                .map(|_| balancer.next().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(picks(7), picks(7));
+        assert_ne!(picks(7), picks(8));
+    }
+
+    #[test]
+    fn some_upstream_needs_a_weight() {
+        assert!(Balancer::new(weighted(&[0, 0]), 0, None).is_err());
+    }
+
+    fn cookie_header(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn sticky_cookies_pin_clients_to_their_upstream() {
+        let balancer = Balancer::new(upstreams(3), 0, None).unwrap();
+        let upstream = &balancer.upstreams()[1];
+        let mut headers = HeaderMap::new();
+        balancer.set_sticky_cookie(&mut headers, upstream);
+        let cookie = headers[SET_COOKIE].to_str().unwrap();
+        assert_eq!(
+            cookie,
+            format!(
+                "vostok_upstream={}; Path=/; HttpOnly; SameSite=Lax",
+                balancer.ids()[1]
+            )
+        );
+
+        let pair = cookie.split(';').next().unwrap();
+        let request = cookie_header(&format!("theme=dark; {}; lang=en", pair));
+        assert_eq!(balancer.pinned(&request), Some(upstream));
+        assert_eq!(balancer.pinned(&cookie_header("theme=dark")), None);
+        assert_eq!(
+            balancer.pinned(&cookie_header("vostok_upstream=0123456789abcdef")),
+            None
+        );
+
+        // Unavailable upstreams leave the client to normal balancing.
+        balancer.set_healthy(1, false);
+        assert_eq!(balancer.pinned(&request), None);
+        balancer.set_healthy(1, true);
+        balancer.set_draining(1, true);
+        assert_eq!(balancer.pinned(&request), None);
+    }
+
+    #[test]
+    fn sticky_ids_depend_only_on_the_uri() {
+        let forwards = Balancer::new(upstreams(2), 0, None).unwrap();
+        let backwards = Balancer::new(upstreams(2).into_iter().rev().collect(), 0, None).unwrap();
+        assert_eq!(forwards.ids()[0], backwards.ids()[1]);
+        assert_ne!(forwards.ids()[0], forwards.ids()[1]);
+    }
+
+    #[tokio::test]
+    async fn follow_up_requests_stay_on_the_first_upstream() {
+        let mut addrs = Vec::new();
+        for name in ["a", "b", "c"].iter() {
+            addrs.push(
+                crate::test_support::upstream(
+                    move |_| async move { Response::new(Body::from(*name)) },
+                )
+                .await,
+            );
+        }
+        let upstreams = addrs
+            .iter()
+            .map(|addr| format!("\"http://{}\"", addr))
+            .collect::<Vec<_>>();
+        let proxy = Proxy::start(&format!(
+            "upstreams = [{}]\nsticky_sessions = true",
+            upstreams.join(", ")
+        ))
+        .await;
+
+        let first = proxy.get("/path").await;
+        let cookie = first.headers()[SET_COOKIE].to_str().unwrap().to_string();
+        let pinned = body_string(first).await;
+        let pair = cookie.split(';').next().unwrap().to_string();
+        for _ in 0..5 {
+            let response = proxy
+                .send(
+                    Request::get("/path")
+                        .header(COOKIE, pair.as_str())
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await;
+            // Already pinned, so there's no new cookie.
+            assert!(!response.headers().contains_key(SET_COOKIE));
+            assert_eq!(body_string(response).await, pinned);
+        }
+
+        // Without the cookie, requests are balanced and pinned anew.
+        let second = proxy.get("/path").await;
+        assert!(second.headers().contains_key(SET_COOKIE));
+        assert_ne!(body_string(second).await, pinned);
+    }
+
+    fn prioritized(priorities: &[u32], weights: &[u32]) -> Vec<Upstream> {
+        priorities
+            .iter()
+            .zip(weights)
+            .enumerate()
+            .map(|(index, (priority, weight))| Upstream {
+                priority: *priority,
+                weight: *weight,
+                ..upstream(&format!("http://10.0.0.{}:8080", index + 1))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lower_priorities_fail_over_to_higher_ones_and_back() {
+        for weights in &[[1, 1, 1], [1, 3, 2]] {
+            let balancer = Balancer::new(prioritized(&[0, 0, 1], weights), 7, None).unwrap();
+            let picks = |balancer: &Balancer| {
+                let counts = counts((0..40).map(|_| balancer.next()));
+                let mut upstreams = counts.into_keys().collect::<Vec<_>>();
+                upstreams.sort();
+                upstreams
+            };
+            let (first, second, backup) = (
+                "http://10.0.0.1:8080/",
+                "http://10.0.0.2:8080/",
+                "http://10.0.0.3:8080/",
+            );
+            assert_eq!(picks(&balancer), [first, second]);
+
+            balancer.set_healthy(0, false);
+            assert_eq!(picks(&balancer), [second]);
+            balancer.set_draining(1, true);
+            assert_eq!(picks(&balancer), [backup]);
+
+            // Recovering, the first priority gets the requests back.
+            balancer.set_healthy(0, true);
+            assert_eq!(picks(&balancer), [first]);
+            balancer.set_draining(1, false);
+            assert_eq!(picks(&balancer), [first, second]);
+        }
+    }
+
+    #[test]
+    fn priorities_only_matter_among_equally_healthy_upstreams() {
+        let balancer = Balancer::new(prioritized(&[0, 1], &[1, 1]), 0, None).unwrap();
+        balancer.set_healthy(0, false);
+        balancer.set_healthy(1, false);
+        assert_eq!(balancer.next(), "http://10.0.0.1:8080/");
+        balancer.set_draining(0, true);
+        assert_eq!(balancer.next(), "http://10.0.0.2:8080/");
+    }
+
+    fn consistent_hash(key: HashKey, header: Option<&str>) -> Result<ConsistentHash> {
+        ConsistentHash::new(&ConsistentHashConfig {
+            key,
+            header: header.map(str::to_string),
+            points_per_weight: default_points_per_weight(),
+        })
+    }
+
+    fn hashed_balancer(upstreams: Vec<Upstream>) -> Balancer {
+        let hash = consistent_hash(HashKey::Uri, None).unwrap();
+        Balancer::new(upstreams, 0, Some(&hash)).unwrap()
+    }
+
+    /// The upstream each of 1000 keys maps to.
+    fn mapping(balancer: &Balancer) -> Vec<String> {
+        (0..1000)
+            .map(|key| {
+                balancer
+                    .hashed(format!("/item/{}", key).as_bytes())
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hash_keys_come_from_the_request() {
+        let uri: Uri = "/items/7?page=2".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", HeaderValue::from_static("acme"));
+        let ip = "192.0.2.1".parse().unwrap();
+        let key = |key, header| {
+            consistent_hash(key, header)
+                .unwrap()
+                .key(&uri, &headers, ip)
+        };
+        assert_eq!(key(HashKey::ClientIp, None), Some(b"192.0.2.1".to_vec()));
+        assert_eq!(key(HashKey::Uri, None), Some(b"/items/7?page=2".to_vec()));
+        assert_eq!(
+            key(HashKey::Header, Some("X-Tenant")),
+            Some(b"acme".to_vec())
+        );
+        assert_eq!(key(HashKey::Header, Some("x-other")), None);
+    }
+
+    #[test]
+    fn invalid_consistent_hash_configs_are_rejected() {
+        assert!(consistent_hash(HashKey::Header, None).is_err());
+        assert!(consistent_hash(HashKey::Header, Some("bad header")).is_err());
+        assert!(consistent_hash(HashKey::Uri, Some("x-tenant")).is_err());
+        let config = ConsistentHashConfig {
+            key: HashKey::Uri,
+            header: None,
+            points_per_weight: 0,
+        };
+        assert!(ConsistentHash::new(&config).is_err());
+    }
+
+    #[test]
+    fn keys_keep_their_upstream_whatever_the_config_order() {
+        let balancer = hashed_balancer(upstreams(4));
+        let first = mapping(&balancer);
+        assert_eq!(mapping(&balancer), first);
+        let reordered = hashed_balancer(upstreams(4).into_iter().rev().collect());
+        assert_eq!(mapping(&reordered), first);
+
+        let uris = first
+            .iter()
+            .map(|uri| uri.parse().unwrap())
+            .collect::<Vec<Uri>>();
+        let counts = counts(&uris);
+        assert_eq!(counts.len(), 4);
+        assert!(counts.values().all(|count| *count > 150), "{:?}", counts);
+    }
+
+    #[test]
+    fn only_the_keys_of_changed_upstreams_move() {
+        let before = mapping(&hashed_balancer(upstreams(4)));
+        let added = mapping(&hashed_balancer(upstreams(5)));
+        let new = "http://10.0.0.5:8080/";
+        let moved = before
+            .iter()
+            .zip(&added)
+            .filter(|(before, after)| before != after)
+            .collect::<Vec<_>>();
+        assert!(moved.iter().all(|(_, after)| *after == new), "{:?}", moved);
+        assert!(moved.len() > 100 && moved.len() < 300, "{}", moved.len());
+
+        // Removing it again restores the old mapping exactly.
+        let removed = mapping(&hashed_balancer(upstreams(4)));
+        assert_eq!(removed, before);
+    }
+
+    #[test]
+    fn unavailable_upstreams_keys_move_and_come_back() {
+        let balancer = hashed_balancer(upstreams(3));
+        let before = mapping(&balancer);
+        balancer.set_healthy(0, false);
+        let during = mapping(&balancer);
+        let first = "http://10.0.0.1:8080/";
+        assert!(!during.iter().any(|uri| uri == first));
+        for (before, during) in before.iter().zip(&during) {
+            if before != first {
+                assert_eq!(before, during);
+            }
+        }
+        balancer.set_healthy(0, true);
+        assert_eq!(mapping(&balancer), before);
+    }
+
+    #[test]
+    fn the_ring_size_is_capped() {
+        let hash = consistent_hash(HashKey::Uri, None).unwrap();
+        let at_limit = weighted(&[5000, 5000]);
+        assert!(Balancer::new(at_limit, 0, Some(&hash)).is_ok());
+        let over = weighted(&[5000, 5001]);
+        let err = Balancer::new(over, 0, Some(&hash)).err().unwrap();
+        assert!(err.to_string().contains("1000100 points"), "{}", err);
+        // Without consistent hashing, weights are only ratios.
+        assert!(Balancer::new(weighted(&[u32::MAX, 1]), 0, None).is_ok());
+    }
+}
@@ -0,0 +1,198 @@
+use futures_util::StreamExt;
+use hyper::header::{HeaderMap, CONTENT_LENGTH};
+use hyper::Body;
+use std::fmt;
+
+#[derive(Debug)]
+pub struct BodyTooLarge {
+    pub limit: u64,
+}
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request body exceeds the {} byte limit", self.limit)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+pub fn declared_length_exceeds(headers: &HeaderMap, limit: u64) -> bool {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .is_some_and(|length| length > limit)
+}
+
+/// Wraps a body of unknown length so the stream errors with [`BodyTooLarge`] as soon as
+/// more than `limit` bytes have been read.
+pub fn limit_body(body: Body, limit: u64) -> Body {
+    let mut seen: u64 = 0;
+    Body::wrap_stream(body.map(move |chunk| {
+        let chunk =
+            chunk.map_err(|err| Box::new(err) as Box<dyn std::error::Error + Send + Sync>)?;
+        seen += chunk.len() as u64;
+        if seen > limit {
+            return Err(
+                Box::new(BodyTooLarge { limit }) as Box<dyn std::error::Error + Send + Sync>
+            );
+        }
+        Ok(chunk)
+    }))
+}
+
+/// Whether an upstream request failed because [`limit_body`] cut the body off.
+pub fn is_body_too_large(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<BodyTooLarge>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, Proxy};
+    use hyper::body::Bytes;
+    use hyper::{Request, Response, StatusCode};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn compares_the_declared_length() {
+        let mut headers = HeaderMap::new();
+        assert!(!declared_length_exceeds(&headers, 10));
+        headers.insert(CONTENT_LENGTH, "10".parse().unwrap());
+        assert!(!declared_length_exceeds(&headers, 10));
+        headers.insert(CONTENT_LENGTH, "11".parse().unwrap());
+        assert!(declared_length_exceeds(&headers, 10));
+    }
+
+    #[tokio::test]
+    async fn passes_bodies_within_the_limit() {
+        let body = limit_body(Body::from("0123456789"), 10);
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "0123456789");
+    }
+
+    #[tokio::test]
+    async fn cuts_off_bodies_over_the_limit() {
+        let body = limit_body(Body::from("0123456789"), 9);
+        let err = anyhow::Error::from(hyper::body::to_bytes(body).await.unwrap_err());
+        assert!(is_body_too_large(&err));
+    }
+
+    async fn proxy(max_body_bytes: u64) -> Proxy {
+        let upstream = test_support::upstream(|req: Request<Body>| async move {
+            match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => Response::new(Body::from(body)),
+                Err(_) => Response::new(Body::empty()),
+            }
+        })
+        .await;
+        Proxy::start(&format!(
+            "upstreams = \"http://{}\"\nmax_body_bytes = {}",
+            upstream, max_body_bytes
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_declared_bodies() {
+        let proxy = proxy(1000).await;
+        let response = proxy
+            .send(
+                Request::post("/upload")
+                    .body(Body::from(vec![b'x'; 1001]))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response.headers()["connection"], "close");
+
+        let response = proxy
+            .send(
+                Request::post("/upload")
+                    .body(Body::from(vec![b'x'; 1000]))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_chunked_bodies() {
+        let proxy = proxy(1000).await;
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                if sender
+                    .send_data(Bytes::from(vec![b'x'; 600]))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+        let response = proxy
+            .send(Request::post("/upload").body(body).unwrap())
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_past_the_limit_abort_the_upstream_request_and_close() {
+        let aborted = Arc::new(AtomicBool::new(false));
+        let upstream = test_support::upstream({
+            let aborted = aborted.clone();
+            move |req: Request<Body>| {
+                let aborted = aborted.clone();
+                async move {
+                    if hyper::body::to_bytes(req.into_body()).await.is_err() {
+                        aborted.store(true, Ordering::SeqCst);
+                    }
+                    Response::new(Body::empty())
+                }
+            }
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\nmax_body_bytes = 1000",
+            upstream
+        ))
+        .await;
+
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let chunk = format!("258\r\n{}\r\n", "x".repeat(600));
+            stream.write_all(chunk.as_bytes()).await.unwrap();
+        }
+        // The client never finishes the body; the proxy answers and hangs up anyway.
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(3), stream.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = String::from_utf8_lossy(&response).to_ascii_lowercase();
+        assert!(response.starts_with("http/1.1 413 "), "{}", response);
+        assert!(
+            response.contains("\r\nconnection: close\r\n"),
+            "{}",
+            response
+        );
+
+        tokio::time::timeout(Duration::from_secs(3), async {
+            while !aborted.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
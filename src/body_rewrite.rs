@@ -0,0 +1,308 @@
+use crate::compression;
+use anyhow::*;
+use futures_util::StreamExt;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Method, Response, StatusCode};
+use log::debug;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodyRewriteConfig {
+    /// Applied in order, each to the result of the previous one.
+    pub rules: Vec<ReplaceRule>,
+    /// Only responses of these types are rewritten; they must be text types.
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<String>,
+    /// Larger bodies are passed through unchanged rather than buffered.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplaceRule {
+    pub search: String,
+    pub replace: String,
+}
+
+fn default_content_types() -> Vec<String> {
+    vec!["text/html".to_string(), "application/json".to_string()]
+}
+
+fn default_max_body_bytes() -> u64 {
+    1024 * 1024
+}
+
+/// Replaces strings in upstream response bodies, e.g. internal hostnames in absolute
+/// URLs. The body has to be buffered whole to do that, so it's only done for text
+/// responses up to `max_body_bytes`.
+pub struct BodyRewriter {
+    rules: Vec<(Vec<u8>, Vec<u8>)>,
+    content_types: Vec<String>,
+    max_body_bytes: u64,
+}
+
+impl BodyRewriter {
+    pub fn new(config: &BodyRewriteConfig) -> Result<BodyRewriter> {
+        ensure!(
+            !config.rules.is_empty(),
+            "body_rewrite needs at least one rule"
+        );
+        if let Some(rule) = config.rules.iter().find(|rule| rule.search.is_empty()) {
+            bail!(
+                "body_rewrite rule replacing with {:?} has an empty search string",
+                rule.replace
+            );
+        }
+        let content_types = config
+            .content_types
+            .iter()
+            .map(|content_type| {
+                ensure!(
+                    compression::is_text_like(content_type),
+                    "body_rewrite content type {:?} isn't a text type",
+                    content_type
+                );
+                Ok(content_type.trim().to_ascii_lowercase())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(BodyRewriter {
+            rules: config
+                .rules
+                .iter()
+                .map(|rule| (rule.search.clone().into(), rule.replace.clone().into()))
+                .collect(),
+            content_types,
+            max_body_bytes: config.max_body_bytes,
+        })
+    }
+
+    fn applies_to(&self, method: &Method, response: &Response<Body>) -> bool {
+        if method == Method::HEAD
+            || matches!(
+                response.status(),
+                StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+            )
+            || response.status().is_informational()
+        {
+            return false;
+        }
+
+        let headers = response.headers();
+        if headers.contains_key(CONTENT_ENCODING) {
+            return false;
+        }
+        let mime = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if !self.content_types.contains(&mime) {
+            return false;
+        }
+
+        let length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        length.is_none_or(|length| length <= self.max_body_bytes)
+    }
+
+    /// A body without a `Content-Length` that turns out to be too large is streamed on
+    /// unchanged once the limit is reached.
+    pub async fn rewrite(
+        &self,
+        method: &Method,
+        response: Response<Body>,
+    ) -> Result<Response<Body>> {
+        if !self.applies_to(method, &response) {
+            return Ok(response);
+        }
+
+        let (mut parts, mut body) = response.into_parts();
+        let mut buffered = Vec::new();
+        while let Some(chunk) = body.data().await {
+            buffered.extend_from_slice(&chunk.context("Reading upstream response body")?);
+            if buffered.len() as u64 > self.max_body_bytes {
+                debug!(
+                    "Not rewriting a response body over {} bytes",
+                    self.max_body_bytes
+                );
+                let head = futures_util::stream::once(async move {
+                    std::result::Result::Ok::<_, hyper::Error>(Bytes::from(buffered))
+                });
+                let body = Body::wrap_stream(head.chain(body));
+                return Ok(Response::from_parts(parts, body));
+            }
+        }
+
+        let body = self.rules.iter().fold(buffered, |body, (search, replace)| {
+            replace_all(&body, search, replace)
+        });
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
+
+/// Replaces every non-overlapping occurrence of `search`, left to right.
+fn replace_all(haystack: &[u8], search: &[u8], replace: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(haystack.len());
+    let mut rest = haystack;
+    while let Some(index) = rest
+        .windows(search.len())
+        .position(|window| window == search)
+    {
+        result.extend_from_slice(&rest[..index]);
+        result.extend_from_slice(replace);
+        rest = &rest[index + search.len()..];
+    }
+    result.extend_from_slice(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_bytes, body_string, upstream, Proxy};
+
+    fn rewriter(config: &str) -> BodyRewriter {
+        BodyRewriter::new(&toml::from_str(config).unwrap()).unwrap()
+    }
+
+    fn typed(content_type: &str, body: impl Into<Body>) -> Response<Body> {
+        let mut response = Response::new(body.into());
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+        response
+    }
+
+    const RULES: &str = r#"
+        rules = [
+            { search = "http://internal-host", replace = "https://www.example.com" },
+            { search = "www.example.com/old", replace = "www.example.com/new" },
+        ]
+    "#;
+
+    #[test]
+    fn replaces_every_occurrence_left_to_right() {
+        assert_eq!(replace_all(b"aXbXc", b"X", b"YY"), b"aYYbYYc");
+        assert_eq!(replace_all(b"aaa", b"aa", b"b"), b"ba");
+        assert_eq!(replace_all(b"abc", b"x", b"y"), b"abc");
+    }
+
+    #[tokio::test]
+    async fn rewrites_urls_in_html_and_updates_the_length() {
+        let rewriter = rewriter(RULES);
+        let html = r#"<a href="http://internal-host/old/page">old</a> <img src="http://internal-host/logo.png">"#;
+        let mut upstream_response = typed("text/html; charset=utf-8", html);
+        upstream_response
+            .headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(html.len()));
+
+        let response = rewriter
+            .rewrite(&Method::GET, upstream_response)
+            .await
+            .unwrap();
+        let expected = r#"<a href="https://www.example.com/new/page">old</a> <img src="https://www.example.com/logo.png">"#;
+        assert_eq!(
+            response.headers()[CONTENT_LENGTH],
+            expected.len().to_string()
+        );
+        assert_eq!(body_string(response).await, expected);
+    }
+
+    #[tokio::test]
+    async fn leaves_other_bodies_untouched() {
+        let rewriter = rewriter(RULES);
+        let body = b"\x89PNG http://internal-host \xff\x00".to_vec();
+
+        let response = rewriter
+            .rewrite(&Method::GET, typed("image/png", body.clone()))
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(body_bytes(response).await, body);
+
+        let mut compressed = typed("text/html", "http://internal-host");
+        compressed
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let response = rewriter.rewrite(&Method::GET, compressed).await.unwrap();
+        assert_eq!(body_string(response).await, "http://internal-host");
+    }
+
+    #[tokio::test]
+    async fn passes_oversized_bodies_through() {
+        let rewriter = rewriter(&format!("max_body_bytes = 16\n{}", RULES));
+        let body = "http://internal-host/".repeat(4);
+
+        // Declared too large: not buffered at all.
+        let mut declared = typed("text/html", body.clone());
+        declared
+            .headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        let response = rewriter.rewrite(&Method::GET, declared).await.unwrap();
+        assert_eq!(body_string(response).await, body);
+
+        // Found too large while buffering: streamed on whole.
+        let chunks = body
+            .as_bytes()
+            .chunks(5)
+            .map(|chunk| std::result::Result::Ok::<_, std::io::Error>(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        let streamed = typed(
+            "text/html",
+            Body::wrap_stream(futures_util::stream::iter(chunks)),
+        );
+        let response = rewriter.rewrite(&Method::GET, streamed).await.unwrap();
+        assert!(!response.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(body_string(response).await, body);
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        for config in [
+            "rules = []",
+            r#"rules = [{ search = "", replace = "x" }]"#,
+            r#"rules = [{ search = "a", replace = "b" }]
+               content_types = ["image/png"]"#,
+        ] {
+            assert!(
+                BodyRewriter::new(&toml::from_str(config).unwrap()).is_err(),
+                "{}",
+                config
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rewrites_proxied_responses() {
+        let upstream = upstream(|_| async {
+            typed(
+                "application/json",
+                r#"{"next":"http://internal-host/old/2"}"#,
+            )
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[body_rewrite]\n{}",
+            upstream, RULES
+        ))
+        .await;
+
+        let response = proxy.get("/path").await;
+        let expected = r#"{"next":"https://www.example.com/new/2"}"#;
+        assert_eq!(
+            response.headers()[CONTENT_LENGTH],
+            expected.len().to_string()
+        );
+        assert_eq!(body_string(response).await, expected);
+    }
+}
@@ -0,0 +1,171 @@
+use futures_util::Stream;
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Response};
+use std::pin::Pin;
+use std::task::Poll;
+
+type Done = Box<dyn FnOnce(u64, bool) + Send>;
+
+/// Counts the bytes of `response`'s body as they're sent. `done` gets the total and
+/// whether the body was sent in full, once it ends, fails, or is dropped because the
+/// client went away part way through. The headers, `Content-Length` included, are left
+/// as they are.
+pub fn count(
+    response: Response<Body>,
+    done: impl FnOnce(u64, bool) + Send + 'static,
+) -> Response<Body> {
+    let (parts, body) = response.into_parts();
+    let body = Body::wrap_stream(Counted {
+        length: HttpBody::size_hint(&body).exact(),
+        body,
+        bytes: 0,
+        done: Some(Box::new(done)),
+    });
+    Response::from_parts(parts, body)
+}
+
+struct Counted {
+    body: Body,
+    /// hyper stops reading a body of known length after its last byte, without waiting
+    /// for the end, so reaching the length counts as complete too.
+    length: Option<u64>,
+    bytes: u64,
+    /// Taken when the total is reported, so it's only reported once.
+    done: Option<Done>,
+}
+
+impl Counted {
+    fn finish(&mut self, complete: bool) {
+        if let Some(done) = self.done.take() {
+            done(self.bytes, complete);
+        }
+    }
+}
+
+impl Stream for Counted {
+    type Item = std::result::Result<Bytes, hyper::Error>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let chunk = futures_util::ready!(Pin::new(&mut this.body).poll_data(cx));
+        match &chunk {
+            Some(Ok(data)) => this.bytes += data.len() as u64,
+            Some(Err(_)) => this.finish(false),
+            None => this.finish(true),
+        }
+        Poll::Ready(chunk)
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.finish(self.length == Some(self.bytes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_string, upstream};
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    type Reported = Arc<Mutex<Option<(u64, bool)>>>;
+
+    fn counted(body: Body) -> (Response<Body>, Reported) {
+        let reported = Reported::default();
+        let report = reported.clone();
+        let response = count(Response::new(body), move |bytes, complete| {
+            assert!(report.lock().unwrap().replace((bytes, complete)).is_none());
+        });
+        (response, reported)
+    }
+
+    fn streamed(chunks: &[&'static str]) -> Body {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Result::<_, Infallible>::Ok(*chunk))
+            .collect::<Vec<_>>();
+        Body::wrap_stream(futures_util::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn reports_bodies_sent_in_full() {
+        for body in [
+            Body::from("hello world"),
+            streamed(&["hello", " ", "world"]),
+        ] {
+            let (response, reported) = counted(body);
+            assert_eq!(body_string(response).await, "hello world");
+            assert_eq!(*reported.lock().unwrap(), Some((11, true)));
+        }
+        let (response, reported) = counted(Body::empty());
+        assert_eq!(body_string(response).await, "");
+        assert_eq!(*reported.lock().unwrap(), Some((0, true)));
+    }
+
+    #[tokio::test]
+    async fn reports_bodies_dropped_part_way_as_incomplete() {
+        let (response, reported) = counted(streamed(&["hello", " world"]));
+        let mut body = response.into_body();
+        assert_eq!(body.data().await.unwrap().unwrap(), "hello");
+        drop(body);
+        assert_eq!(*reported.lock().unwrap(), Some((5, false)));
+
+        // A known length counts as complete once it's all been read.
+        let (response, reported) = counted(Body::from("hello"));
+        let mut body = response.into_body();
+        assert_eq!(body.data().await.unwrap().unwrap(), "hello");
+        drop(body);
+        assert_eq!(*reported.lock().unwrap(), Some((5, true)));
+    }
+
+    #[tokio::test]
+    async fn reports_clients_that_disconnect_as_incomplete() {
+        let reported = Reported::default();
+        let report = reported.clone();
+        let addr = upstream(move |_| {
+            let report = report.clone();
+            async move {
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    while sender
+                        .send_data(Bytes::from(vec![b'x'; 1024]))
+                        .await
+                        .is_ok()
+                    {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                });
+                count(Response::new(body), move |bytes, complete| {
+                    *report.lock().unwrap() = Some((bytes, complete));
+                })
+            }
+        })
+        .await;
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut start = [0; 2048];
+        stream.read_exact(&mut start).await.unwrap();
+        drop(stream);
+
+        for _ in 0..100 {
+            if reported.lock().unwrap().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let (bytes, complete) = reported.lock().unwrap().unwrap();
+        assert!(!complete);
+        assert!(bytes >= 1024, "{}", bytes);
+    }
+}
@@ -0,0 +1,396 @@
+use crate::metrics::GaugeGuard;
+use anyhow::*;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode, Uri};
+use prometheus::IntGauge;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BulkheadConfig {
+    /// Requests in flight to a single upstream at once.
+    pub max_concurrent: usize,
+    /// How long a request waits for a free slot before getting a `503`. Zero rejects
+    /// it right away.
+    #[serde(default, with = "humantime_serde")]
+    pub queue_timeout: Duration,
+    /// Requests that may wait for a slot on a single upstream at once; the rest get a
+    /// `503` right away. Unset means no limit.
+    #[serde(default)]
+    pub max_queued: Option<usize>,
+    /// Describe the overload in a JSON body on the `503`, instead of the usual page.
+    #[serde(default)]
+    pub json_body: bool,
+}
+
+/// Requests still waiting for a slot on the upstream when one is turned away.
+pub const X_VOSTOK_QUEUE_DEPTH: HeaderName = HeaderName::from_static("x-vostok-queue-depth");
+
+/// Held while a request is in flight to its upstream.
+pub struct Permit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Why a request didn't get a slot, for the `503` it gets instead.
+#[derive(Debug, Clone, Copy)]
+pub struct Overloaded {
+    reason: &'static str,
+    /// Read once the request has left the queue, so it doesn't count itself.
+    queue_depth: usize,
+    max_concurrent: usize,
+    max_queued: Option<usize>,
+    json_body: bool,
+}
+
+impl Overloaded {
+    /// The usual `503` from `page`, or with `json_body`, a JSON description of the
+    /// overload, with the queue depth added either way.
+    pub fn response(&self, page: impl FnOnce() -> Response<Body>) -> Response<Body> {
+        let mut response = if self.json_body {
+            let body = serde_json::json!({
+                "error": "upstream overloaded",
+                "reason": self.reason,
+                "queue_depth": self.queue_depth,
+                "max_concurrent": self.max_concurrent,
+                "max_queued": self.max_queued,
+            });
+            let mut response = Response::new(Body::from(body.to_string()));
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response
+        } else {
+            page()
+        };
+        response
+            .headers_mut()
+            .insert(X_VOSTOK_QUEUE_DEPTH, HeaderValue::from(self.queue_depth));
+        response
+    }
+}
+
+/// Caps the requests in flight to each upstream, so one slow upstream can't tie up
+/// every connection and task. A request holds its slot until the upstream's response
+/// headers arrive, across any retries. Waiting requests get slots in the order they
+/// arrived.
+pub struct Bulkheads {
+    max_concurrent: usize,
+    queue_timeout: Duration,
+    max_queued: Option<usize>,
+    json_body: bool,
+    bulkheads: HashMap<Uri, Bulkhead>,
+}
+
+struct Bulkhead {
+    semaphore: Arc<Semaphore>,
+    queued: AtomicUsize,
+}
+
+/// A place in an upstream's queue, given up when dropped.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl<'a> Queued<'a> {
+    /// `None` if the queue already holds `max_queued` requests.
+    fn join(queued: &'a AtomicUsize, max_queued: Option<usize>) -> Option<Queued<'a>> {
+        let ahead = queued.fetch_add(1, Ordering::Relaxed);
+        let place = Queued(queued);
+        if max_queued.is_some_and(|max_queued| ahead >= max_queued) {
+            return None;
+        }
+        Some(place)
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Bulkheads {
+    pub fn new(config: &BulkheadConfig, upstreams: &[Uri]) -> Result<Bulkheads> {
+        ensure!(
+            config.max_concurrent > 0,
+            "bulkhead.max_concurrent must be at least 1"
+        );
+        Ok(Bulkheads {
+            max_concurrent: config.max_concurrent,
+            queue_timeout: config.queue_timeout,
+            max_queued: config.max_queued,
+            json_body: config.json_body,
+            bulkheads: upstreams
+                .iter()
+                .map(|upstream| {
+                    let bulkhead = Bulkhead {
+                        semaphore: Arc::new(Semaphore::new(config.max_concurrent)),
+                        queued: AtomicUsize::new(0),
+                    };
+                    (upstream.clone(), bulkhead)
+                })
+                .collect(),
+        })
+    }
+
+    /// Fails if `upstream` was full and its queue was too, or if it stayed full for the
+    /// whole queue timeout. `queue_depth` counts the request while it waits.
+    pub async fn acquire(
+        &self,
+        upstream: &Uri,
+        queue_depth: &IntGauge,
+    ) -> std::result::Result<Permit, Overloaded> {
+        let bulkhead = match self.bulkheads.get(upstream) {
+            Some(bulkhead) => bulkhead,
+            None => return Result::Ok(Permit { _permit: None }),
+        };
+        let semaphore = bulkhead.semaphore.clone();
+        if let Result::Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Result::Ok(Permit {
+                _permit: Some(permit),
+            });
+        }
+        let overloaded = |reason| Overloaded {
+            reason,
+            queue_depth: bulkhead.queued.load(Ordering::Relaxed),
+            max_concurrent: self.max_concurrent,
+            max_queued: self.max_queued,
+            json_body: self.json_body,
+        };
+        if self.queue_timeout.is_zero() {
+            return Err(overloaded("full"));
+        }
+        let queued = match Queued::join(&bulkhead.queued, self.max_queued) {
+            Some(queued) => queued,
+            None => return Err(overloaded("queue_full")),
+        };
+        let depth = GaugeGuard::new(queue_depth);
+        let acquired = tokio::time::timeout(self.queue_timeout, semaphore.acquire_owned()).await;
+        drop((queued, depth));
+        match acquired {
+            Result::Ok(Result::Ok(permit)) => Result::Ok(Permit {
+                _permit: Some(permit),
+            }),
+            _ => Err(overloaded("queue_timeout")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_json, body_string, upstream, Proxy};
+    use futures_util::future::join_all;
+
+    fn bulkheads(config: &str) -> (Bulkheads, Uri) {
+        let upstream: Uri = "http://10.0.0.1:8080".parse().unwrap();
+        let config = toml::from_str(config).unwrap();
+        (
+            Bulkheads::new(&config, std::slice::from_ref(&upstream)).unwrap(),
+            upstream,
+        )
+    }
+
+    fn gauge() -> IntGauge {
+        IntGauge::new("queued", "queued").unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_over_the_limit_without_a_queue() {
+        let (bulkheads, upstream) = bulkheads("max_concurrent = 2");
+        let gauge = gauge();
+        let first = bulkheads.acquire(&upstream, &gauge).await.unwrap();
+        let _second = bulkheads.acquire(&upstream, &gauge).await.unwrap();
+
+        let overloaded = bulkheads.acquire(&upstream, &gauge).await.err().unwrap();
+        assert_eq!(overloaded.reason, "full");
+        assert_eq!(overloaded.queue_depth, 0);
+
+        // A slot comes free once a permit is dropped.
+        drop(first);
+        let _third = bulkheads.acquire(&upstream, &gauge).await.unwrap();
+        assert!(bulkheads.acquire(&upstream, &gauge).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_over_max_queued() {
+        let (bulkheads, upstream) = bulkheads(
+            r#"
+            max_concurrent = 1
+            queue_timeout = "5s"
+            max_queued = 1
+            "#,
+        );
+        let gauge = gauge();
+        let permit = bulkheads.acquire(&upstream, &gauge).await.unwrap();
+
+        let queued = bulkheads.acquire(&upstream, &gauge);
+        let overflow = async {
+            tokio::task::yield_now().await;
+            let overloaded = bulkheads.acquire(&upstream, &gauge).await.err().unwrap();
+            assert_eq!(overloaded.reason, "queue_full");
+            assert_eq!(overloaded.queue_depth, 1);
+            assert_eq!(gauge.get(), 1);
+            drop(permit);
+        };
+        let (queued, ()) = futures_util::join!(queued, overflow);
+        assert!(queued.is_ok());
+        assert_eq!(gauge.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_requests_get_a_freed_slot() {
+        let (bulkheads, upstream) = bulkheads("max_concurrent = 1\nqueue_timeout = \"5s\"");
+        let gauge = gauge();
+        let permit = bulkheads.acquire(&upstream, &gauge).await.unwrap();
+
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(gauge.get(), 1);
+            drop(permit);
+        };
+        let (queued, ()) = futures_util::join!(bulkheads.acquire(&upstream, &gauge), release);
+        assert!(queued.is_ok());
+        assert_eq!(gauge.get(), 0);
+        assert_eq!(
+            bulkheads.bulkheads[&upstream]
+                .queued
+                .load(Ordering::Relaxed),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn queued_requests_give_up_after_the_queue_timeout() {
+        let (bulkheads, upstream) = bulkheads("max_concurrent = 1\nqueue_timeout = \"100ms\"");
+        let gauge = gauge();
+        let _permit = bulkheads.acquire(&upstream, &gauge).await.unwrap();
+
+        let started = std::time::Instant::now();
+        let overloaded = bulkheads.acquire(&upstream, &gauge).await.err().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(overloaded.reason, "queue_timeout");
+        // It no longer counts itself once it's left the queue.
+        assert_eq!(overloaded.queue_depth, 0);
+        assert_eq!(gauge.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn leaves_other_upstreams_alone() {
+        let (bulkheads, _) = bulkheads("max_concurrent = 1");
+        let other: Uri = "http://10.0.0.2:8080".parse().unwrap();
+        let gauge = gauge();
+        let permits = join_all((0..10).map(|_| bulkheads.acquire(&other, &gauge))).await;
+        assert!(permits.iter().all(|permit| permit.is_ok()));
+    }
+
+    #[test]
+    fn max_concurrent_must_be_at_least_one() {
+        let config = toml::from_str("max_concurrent = 0").unwrap();
+        assert!(Bulkheads::new(&config, &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_saturated_upstream_gets_503s() {
+        let slow = upstream(|_| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Response::new(Body::from("slow"))
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[bulkhead]\nmax_concurrent = 2",
+            slow
+        ))
+        .await;
+
+        let responses = join_all((0..3).map(|_| proxy.get("/path"))).await;
+        let mut statuses = responses
+            .iter()
+            .map(|response| response.status())
+            .collect::<Vec<_>>();
+        statuses.sort();
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::SERVICE_UNAVAILABLE
+            ]
+        );
+    }
+
+    fn overloaded(json_body: bool) -> Overloaded {
+        Overloaded {
+            reason: "queue_full",
+            queue_depth: 3,
+            max_concurrent: 2,
+            max_queued: Some(3),
+            json_body,
+        }
+    }
+
+    fn page() -> Response<Body> {
+        let mut response = Response::new(Body::from("page"));
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        response
+    }
+
+    #[tokio::test]
+    async fn overloaded_responses_report_the_queue_depth() {
+        let response = overloaded(false).response(page);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[X_VOSTOK_QUEUE_DEPTH], "3");
+        assert_eq!(body_string(response).await, "page");
+
+        let response = overloaded(true).response(|| panic!("no page for JSON bodies"));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[X_VOSTOK_QUEUE_DEPTH], "3");
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(
+            body_json(response).await,
+            serde_json::json!({
+                "error": "upstream overloaded",
+                "reason": "queue_full",
+                "queue_depth": 3,
+                "max_concurrent": 2,
+                "max_queued": 3,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_over_a_full_queue_get_its_depth() {
+        let slow = upstream(|_| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Response::new(Body::from("slow"))
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n\
+             [bulkhead]\nmax_concurrent = 1\nmax_queued = 1\nqueue_timeout = \"5s\"\njson_body = true",
+            slow
+        ))
+        .await;
+
+        // One request is served and one queues behind it before the last arrives.
+        let after = |delay| {
+            let proxy = &proxy;
+            async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                proxy.get("/path").await
+            }
+        };
+        let (served, queued, rejected) = futures_util::join!(after(0), after(50), after(100));
+        assert_eq!(served.status(), StatusCode::OK);
+        assert_eq!(queued.status(), StatusCode::OK);
+        assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rejected.headers()[X_VOSTOK_QUEUE_DEPTH], "1");
+        let body = body_json(rejected).await;
+        assert_eq!(body["reason"], "queue_full");
+        assert_eq!(body["queue_depth"], 1);
+    }
+}
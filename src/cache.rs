@@ -0,0 +1,1066 @@
+use anyhow::*;
+use futures_util::future::{FutureExt, Shared};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CACHE_CONTROL, HOST,
+    SET_COOKIE, VARY,
+};
+use hyper::{Body, Method, Response, StatusCode, Uri};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+pub const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// How long responses are kept when the upstream doesn't send a `max-age`.
+    #[serde(default = "default_ttl", with = "humantime_serde")]
+    pub ttl: Duration,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    /// Larger responses, and ones without a known length, aren't cached.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: u64,
+    /// Path prefixes to cache, each with its own TTL. Unset caches every path.
+    #[serde(default)]
+    pub rules: Vec<CacheRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheRule {
+    pub path_prefix: String,
+    /// Replaces the cache's `ttl` under this prefix.
+    #[serde(default, with = "humantime_serde")]
+    pub ttl: Option<Duration>,
+    /// Off keeps the paths under this prefix out of the cache, e.g. to carve a prefix
+    /// out of a broader rule.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_max_entries() -> usize {
+    1000
+}
+
+fn default_max_body_bytes() -> u64 {
+    1024 * 1024
+}
+
+/// A fully buffered response, as cached or handed to coalesced requests.
+#[derive(Clone)]
+struct Buffered {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Buffered {
+    fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+struct Entry {
+    response: Buffered,
+    expires: Instant,
+    last_used: u64,
+    /// The method and URI part of the entry's key.
+    primary: String,
+}
+
+impl Entry {
+    fn to_response(&self) -> Response<Body> {
+        let mut response = self.response.to_response();
+        response
+            .headers_mut()
+            .insert(X_CACHE, HeaderValue::from_static("HIT"));
+        response
+    }
+}
+
+/// Identifies a request to the cache: its method and URI, plus the request headers that
+/// responses to it may vary on.
+pub struct Key {
+    primary: String,
+    request: HeaderMap,
+    /// How long a response is kept unless it says otherwise, from the request's rule.
+    ttl: Duration,
+    /// Whether the request carries credentials, so its response is only stored, and
+    /// only shared with identical requests, if the response says it may be.
+    authorized: bool,
+}
+
+/// The response a flight got, with the headers of the request that led it.
+#[derive(Clone)]
+struct Landed {
+    response: Buffered,
+    request: HeaderMap,
+}
+
+impl Landed {
+    /// Whether `request` would have gotten the same response, going by its `Vary`.
+    fn matches(&self, request: &HeaderMap) -> bool {
+        vary_names(&self.response.headers).is_some_and(|names| {
+            names
+                .iter()
+                .all(|name| header_values(&self.request, name) == header_values(request, name))
+        })
+    }
+}
+
+/// What a flight ended with: the response, or `None` if it couldn't be buffered to
+/// share. An error sent by the sender being dropped means the request that led the
+/// flight gave up before getting a response.
+type Landing = Shared<oneshot::Receiver<Option<Landed>>>;
+
+type Flights = Arc<Mutex<HashMap<String, Landing>>>;
+
+/// Held by the one request that goes upstream for a missing key while identical
+/// requests wait for its response. Dropping it unregisters the flight, so whatever
+/// happens to that request, the next one for the key starts afresh.
+pub struct Flight {
+    key: String,
+    flights: Flights,
+    landed: Option<oneshot::Sender<Option<Landed>>>,
+}
+
+impl Flight {
+    fn land(mut self, response: Option<Landed>) {
+        if let Some(landed) = self.landed.take() {
+            let _ = landed.send(response);
+        }
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        self.flights.lock().unwrap().remove(&self.key);
+    }
+}
+
+pub enum Lookup {
+    /// A cached response, or the shared response of an identical request.
+    Found(Response<Body>),
+    /// The request should go upstream and hand its response to [`ResponseCache::store`],
+    /// along with the flight when there is one.
+    Miss(Option<Flight>),
+}
+
+/// Entries plus their recency: `order` maps each entry's last-use tick back to its key,
+/// so the least recently used entry is the first one in `order`.
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    /// For each method and URI with cached entries, the request headers its latest
+    /// response varied on.
+    variants: HashMap<String, Variants>,
+}
+
+struct Variants {
+    vary: Vec<HeaderName>,
+    entries: usize,
+}
+
+impl Lru {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// The entry key for `key`: its method and URI, followed by the values of the
+    /// request headers responses to it vary on.
+    fn entry_key(&self, key: &Key) -> String {
+        match self.variants.get(&key.primary) {
+            None => key.primary.clone(),
+            Some(variants) => variant_key(&key.primary, &variants.vary, &key.request),
+        }
+    }
+
+    fn insert(&mut self, key: String, entry: Entry, vary: Vec<HeaderName>) {
+        let variants = self
+            .variants
+            .entry(entry.primary.clone())
+            .or_insert(Variants { vary, entries: 0 });
+        variants.entries += 1;
+        self.order.insert(entry.last_used, key.clone());
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_used);
+            if let Some(variants) = self.variants.get_mut(&entry.primary) {
+                variants.entries -= 1;
+                if variants.entries == 0 {
+                    self.variants.remove(&entry.primary);
+                }
+            }
+        }
+    }
+}
+
+/// In-memory LRU cache of upstream responses to `GET` requests, keyed on method and URI
+/// plus the request headers named in the responses' `Vary`.
+pub struct ResponseCache {
+    ttl: Duration,
+    /// Longest prefix first; empty caches every path.
+    rules: Vec<CacheRule>,
+    max_entries: usize,
+    max_body_bytes: u64,
+    lru: Mutex<Lru>,
+    flights: Flights,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Result<ResponseCache> {
+        ensure!(
+            config.max_entries > 0,
+            "cache.max_entries must be at least 1"
+        );
+        let mut rules = config.rules.clone();
+        for (index, rule) in rules.iter().enumerate() {
+            ensure!(
+                rule.path_prefix.starts_with('/'),
+                "Cache rule prefix {:?} must start with '/'",
+                rule.path_prefix
+            );
+            ensure!(
+                rules[index + 1..]
+                    .iter()
+                    .all(|other| other.path_prefix != rule.path_prefix),
+                "Duplicate cache rule for {:?}",
+                rule.path_prefix
+            );
+        }
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.path_prefix.len()));
+        Ok(ResponseCache {
+            ttl: config.ttl,
+            rules,
+            max_entries: config.max_entries,
+            max_body_bytes: config.max_body_bytes,
+            lru: Mutex::new(Lru::default()),
+            flights: Flights::default(),
+        })
+    }
+
+    /// The key for a `GET` request, or `None` if its path isn't cached or the request
+    /// asks for `Cache-Control: no-store` or `no-cache`. HTTP/1 request URIs are only a
+    /// path, so the `Host` goes in front of it, which keeps the entries of different
+    /// virtual hosts apart.
+    pub fn key(&self, method: &Method, uri: &Uri, request: &HeaderMap) -> Option<Key> {
+        let ttl = match self.rule_for(uri.path()) {
+            None if self.rules.is_empty() => self.ttl,
+            Some(rule) if rule.enabled => rule.ttl.unwrap_or(self.ttl),
+            _ => return None,
+        };
+        let bypass = list_tokens(request, &CACHE_CONTROL).any(|directive| {
+            let name = directive.split('=').next().unwrap_or_default().trim();
+            name.eq_ignore_ascii_case("no-store") || name.eq_ignore_ascii_case("no-cache")
+        });
+        if bypass {
+            return None;
+        }
+        let primary = match (uri.authority(), request.get(HOST)) {
+            (None, Some(host)) => format!(
+                "{} {}{}",
+                method,
+                String::from_utf8_lossy(host.as_bytes()).to_ascii_lowercase(),
+                uri
+            ),
+            _ => format!("{} {}", method, uri),
+        };
+        Some(Key {
+            primary,
+            request: request.clone(),
+            ttl,
+            authorized: request.contains_key(AUTHORIZATION),
+        })
+    }
+
+    /// The rule with the longest prefix matching `path` in whole segments, so `/api`
+    /// covers `/api/users` but not `/apix`.
+    fn rule_for(&self, path: &str) -> Option<&CacheRule> {
+        self.rules.iter().find(|rule| {
+            path.strip_prefix(rule.path_prefix.as_str())
+                .is_some_and(|rest| {
+                    rule.path_prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+                })
+        })
+    }
+
+    /// Looks `key` up, coalescing concurrent misses: the first request for a missing key
+    /// leads a flight upstream, and identical requests arriving meanwhile wait for it and
+    /// get a copy of its response, errors included. They go upstream on their own if
+    /// that response couldn't be buffered or varies on a header they sent differently,
+    /// and one of them leads a new flight if the leader gave up. Requests with
+    /// credentials never take part in flights, since their responses may be theirs alone.
+    pub async fn lookup(&self, key: &Key) -> Lookup {
+        loop {
+            let entry_key = self.lru.lock().unwrap().entry_key(key);
+            if let Some(response) = self.get(&entry_key, Instant::now()) {
+                return Lookup::Found(response);
+            }
+            if key.authorized {
+                return Lookup::Miss(None);
+            }
+
+            let landing = {
+                let mut flights = self.flights.lock().unwrap();
+                match flights.get(&entry_key) {
+                    Some(landing) => landing.clone(),
+                    None => {
+                        let (landed, landing) = oneshot::channel();
+                        flights.insert(entry_key.clone(), landing.shared());
+                        return Lookup::Miss(Some(Flight {
+                            key: entry_key,
+                            flights: self.flights.clone(),
+                            landed: Some(landed),
+                        }));
+                    }
+                }
+            };
+            match landing.await {
+                Result::Ok(Some(landed)) if landed.matches(&key.request) => {
+                    return Lookup::Found(landed.response.to_response())
+                }
+                Result::Ok(_) => return Lookup::Miss(None),
+                Err(_) => continue,
+            }
+        }
+    }
+
+    fn get(&self, key: &str, now: Instant) -> Option<Response<Body>> {
+        let mut lru = self.lru.lock().unwrap();
+        let tick = lru.next_tick();
+        let lru = &mut *lru;
+        let entry = lru.entries.get_mut(key)?;
+        if entry.expires <= now {
+            lru.remove(key);
+            return None;
+        }
+
+        lru.order.remove(&entry.last_used);
+        entry.last_used = tick;
+        lru.order.insert(tick, key.to_string());
+        Some(entry.to_response())
+    }
+
+    /// Buffers and caches `response` if it's cacheable, and marks it as a miss either way.
+    /// Requests waiting on `flight` get a copy, which non-cacheable responses are only
+    /// buffered for if they have a known length up to `max_body_bytes`.
+    pub async fn store(
+        &self,
+        key: Key,
+        response: Response<Body>,
+        now: Instant,
+        flight: Option<Flight>,
+    ) -> Result<Response<Body>> {
+        let response = mark_miss(response);
+        let ttl = self.ttl_for(&response, key.ttl, key.authorized);
+        let flight = match (ttl, flight) {
+            (None, None) => return Ok(response),
+            (None, Some(flight)) if !self.buffers(&response) => {
+                flight.land(None);
+                return Ok(response);
+            }
+            (_, flight) => flight,
+        };
+
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .context("Buffering upstream response for the cache")?;
+        let buffered = Buffered {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        };
+        let ttl = match ttl {
+            Some(ttl) => ttl,
+            None => {
+                if let Some(flight) = flight {
+                    flight.land(Some(Landed {
+                        response: buffered,
+                        request: key.request,
+                    }));
+                }
+                return Ok(Response::from_parts(parts, Body::from(body)));
+            }
+        };
+        // Cacheable responses never vary on everything.
+        let vary = vary_names(&parts.headers).unwrap_or_default();
+        let entry_key = variant_key(&key.primary, &vary, &key.request);
+        let primary = key.primary;
+        if let Some(flight) = flight {
+            flight.land(Some(Landed {
+                response: buffered.clone(),
+                request: key.request,
+            }));
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        let tick = lru.next_tick();
+        // A changed Vary leaves entries from before unreachable, so they go now.
+        let stale = lru
+            .variants
+            .get(&primary)
+            .is_some_and(|variants| variants.vary != vary);
+        if stale {
+            let keys = lru
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.primary == primary)
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            for key in keys {
+                lru.remove(&key);
+            }
+        }
+        lru.remove(&entry_key);
+        while lru.entries.len() >= self.max_entries {
+            let oldest = match lru.order.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            if let Some(oldest) = lru.order.get(&oldest).cloned() {
+                lru.remove(&oldest);
+            }
+        }
+        lru.insert(
+            entry_key,
+            Entry {
+                response: buffered,
+                expires: now + ttl,
+                last_used: tick,
+                primary,
+            },
+            vary,
+        );
+        drop(lru);
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    fn buffers(&self, response: &Response<Body>) -> bool {
+        response
+            .body()
+            .size_hint()
+            .exact()
+            .is_some_and(|size| size <= self.max_body_bytes)
+    }
+
+    /// How long `response` may be cached, or `None` if it mustn't be. Only complete `200`
+    /// responses of a known, small enough size that don't set cookies or have `Vary: *`
+    /// are cached; `Cache-Control: s-maxage`, or else `max-age`, overrides the default
+    /// `ttl`, wherever they appear in the header. Responses to
+    /// `authorized` requests also need `public`, `s-maxage` or `must-revalidate`.
+    fn ttl_for(
+        &self,
+        response: &Response<Body>,
+        ttl: Duration,
+        authorized: bool,
+    ) -> Option<Duration> {
+        if response.status() != StatusCode::OK {
+            return None;
+        }
+        let size = response.body().size_hint().exact()?;
+        if size > self.max_body_bytes {
+            return None;
+        }
+        let headers = response.headers();
+        if headers.contains_key(SET_COOKIE) || vary_names(headers).is_none() {
+            return None;
+        }
+
+        let mut max_age = None;
+        let mut s_maxage = None;
+        let mut shared = false;
+        for directive in list_tokens(headers, &CACHE_CONTROL) {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("no-store", _) | ("no-cache", _) | ("private", _) => return None,
+                ("max-age", Some(seconds)) => max_age = Some(seconds.parse().ok()?),
+                ("s-maxage", Some(seconds)) => {
+                    s_maxage = Some(seconds.parse().ok()?);
+                    shared = true;
+                }
+                ("public", _) | ("must-revalidate", _) => shared = true,
+                _ => {}
+            }
+        }
+        let ttl = s_maxage.or(max_age).map_or(ttl, Duration::from_secs);
+        if ttl == Duration::from_secs(0) || (authorized && !shared) {
+            return None;
+        }
+        Some(ttl)
+    }
+}
+
+fn mark_miss(mut response: Response<Body>) -> Response<Body> {
+    response
+        .headers_mut()
+        .insert(X_CACHE, HeaderValue::from_static("MISS"));
+    response
+}
+
+fn list_tokens<'a>(headers: &'a HeaderMap, name: &HeaderName) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// The request headers named in a response's `Vary`, sorted, or `None` for `Vary: *`.
+/// Accept-Encoding is never forwarded, so varying on it doesn't split the cache.
+fn vary_names(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for token in list_tokens(headers, &VARY) {
+        if token == "*" {
+            return None;
+        }
+        match HeaderName::from_bytes(token.as_bytes()) {
+            Result::Ok(name) if name != ACCEPT_ENCODING => names.push(name),
+            _ => {}
+        }
+    }
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+    Some(names)
+}
+
+/// All of a request header's values, or `None` if the request doesn't have it, which
+/// keeps a missing header apart from an empty one.
+fn header_values(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let values = headers
+        .get_all(name)
+        .iter()
+        .map(|value| String::from_utf8_lossy(value.as_bytes()))
+        .collect::<Vec<_>>();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+fn variant_key(primary: &str, vary: &[HeaderName], request: &HeaderMap) -> String {
+    let mut key = primary.to_string();
+    for name in vary {
+        // Header values can't contain newlines, so entries can't run together.
+        match header_values(request, name) {
+            Some(values) => key.push_str(&format!("\n{}: {}", name, values)),
+            None => key.push_str(&format!("\n{}", name)),
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_string, upstream, Proxy};
+    use hyper::header::{ACCEPT_LANGUAGE, USER_AGENT};
+    use hyper::Request;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn cache(ttl: Duration) -> ResponseCache {
+        ResponseCache::new(&CacheConfig {
+            ttl,
+            max_entries: default_max_entries(),
+            max_body_bytes: default_max_body_bytes(),
+            rules: Vec::new(),
+        })
+        .unwrap()
+    }
+
+    fn key(cache: &ResponseCache, uri: &str, request: &[(HeaderName, &'static str)]) -> Key {
+        let request = request
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_static(value)))
+            .collect::<HeaderMap>();
+        cache
+            .key(&Method::GET, &uri.parse().unwrap(), &request)
+            .unwrap()
+    }
+
+    fn response(body: impl Into<Body>, headers: &[(HeaderName, &'static str)]) -> Response<Body> {
+        let mut response = Response::new(body.into());
+        for (name, value) in headers {
+            response
+                .headers_mut()
+                .append(name.clone(), HeaderValue::from_static(value));
+        }
+        response
+    }
+
+    /// Looks `uri` up, storing `response` as of `now` on a miss. Returns the body and
+    /// `X-Cache` of what the client would get.
+    async fn fetch(
+        cache: &ResponseCache,
+        uri: &str,
+        response: Response<Body>,
+        now: Instant,
+    ) -> (String, String) {
+        fetch_as(cache, uri, &[], response, now).await
+    }
+
+    /// [`fetch`] for a request with the `request` headers.
+    async fn fetch_as(
+        cache: &ResponseCache,
+        uri: &str,
+        request: &[(HeaderName, &'static str)],
+        response: Response<Body>,
+        now: Instant,
+    ) -> (String, String) {
+        let response = match cache.lookup(&key(cache, uri, request)).await {
+            Lookup::Found(response) => response,
+            Lookup::Miss(flight) => cache
+                .store(key(cache, uri, request), response, now, flight)
+                .await
+                .unwrap(),
+        };
+        let x_cache = response.headers()[X_CACHE].to_str().unwrap().to_string();
+        (body_string(response).await, x_cache)
+    }
+
+    #[tokio::test]
+    async fn serves_stored_responses_until_they_expire() {
+        let cache = cache(Duration::from_secs(60));
+        let now = Instant::now();
+        let hit = |body| fetch(&cache, "/a", response(body, &[]), now);
+        assert_eq!(hit("first").await, ("first".into(), "MISS".into()));
+        assert_eq!(hit("second").await, ("first".into(), "HIT".into()));
+        // Other URIs have their own entries.
+        let other = fetch(&cache, "/a?page=2", response("other", &[]), now).await;
+        assert_eq!(other, ("other".into(), "MISS".into()));
+
+        // Stored long enough ago that it has expired by now.
+        let expired = Instant::now() - Duration::from_secs(61);
+        fetch(&cache, "/b", response("old", &[]), expired).await;
+        let refetched = fetch(&cache, "/b", response("new", &[]), now).await;
+        assert_eq!(refetched, ("new".into(), "MISS".into()));
+    }
+
+    #[tokio::test]
+    async fn max_age_overrides_the_ttl() {
+        let cache = cache(Duration::from_secs(60));
+        let stored = Instant::now() - Duration::from_secs(30);
+        let short = response("short", &[(CACHE_CONTROL, "public, max-age=10")]);
+        fetch(&cache, "/short", short, stored).await;
+        let (body, _) = fetch(&cache, "/short", response("new", &[]), stored).await;
+        assert_eq!(body, "new");
+
+        let long = response("long", &[(CACHE_CONTROL, "s-maxage=\"3600\"")]);
+        fetch(&cache, "/long", long, stored).await;
+        let (body, _) = fetch(&cache, "/long", response("new", &[]), stored).await;
+        assert_eq!(body, "long");
+    }
+
+    #[tokio::test]
+    async fn s_maxage_takes_precedence_over_max_age() {
+        let cache = cache(Duration::from_secs(60));
+        let stored = Instant::now() - Duration::from_secs(30);
+        for (index, cache_control) in ["max-age=10, s-maxage=3600", "s-maxage=3600, max-age=10"]
+            .iter()
+            .enumerate()
+        {
+            let uri = format!("/{}", index);
+            let shared = response("shared", &[(CACHE_CONTROL, cache_control)]);
+            fetch(&cache, &uri, shared, stored).await;
+            let (body, _) = fetch(&cache, &uri, response("new", &[]), stored).await;
+            assert_eq!(body, "shared", "{}", cache_control);
+        }
+
+        let expired = response("expired", &[(CACHE_CONTROL, "max-age=3600, s-maxage=10")]);
+        fetch(&cache, "/expired", expired, stored).await;
+        let (body, _) = fetch(&cache, "/expired", response("new", &[]), stored).await;
+        assert_eq!(body, "new");
+    }
+
+    #[tokio::test]
+    async fn never_stores_uncacheable_responses() {
+        let cache = cache(Duration::from_secs(60));
+        let now = Instant::now();
+        let uncacheable = vec![
+            response("", &[(CACHE_CONTROL, "no-store")]),
+            response("", &[(CACHE_CONTROL, "No-Cache")]),
+            response("", &[(CACHE_CONTROL, "private")]),
+            response("", &[(CACHE_CONTROL, "max-age=0")]),
+            response("", &[(SET_COOKIE, "session=1")]),
+            response("", &[(VARY, "*")]),
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap(),
+            Response::new(Body::wrap_stream(futures_util::stream::iter(vec![
+                Result::<_, std::io::Error>::Ok("streamed"),
+            ]))),
+            response(vec![b'x'; 1024 * 1024 + 1], &[]),
+        ];
+        for (index, uncached) in uncacheable.into_iter().enumerate() {
+            let uri = format!("/{}", index);
+            let (_, x_cache) = fetch(&cache, &uri, uncached, now).await;
+            assert_eq!(x_cache, "MISS");
+            let (body, x_cache) = fetch(&cache, &uri, response("fresh", &[]), now).await;
+            assert_eq!(
+                (body.as_str(), x_cache.as_str()),
+                ("fresh", "MISS"),
+                "{}",
+                index
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_to_authorized_requests_are_stored_only_if_shared() {
+        let cache = cache(Duration::from_secs(60));
+        let now = Instant::now();
+        let alice = &[(AUTHORIZATION, "Bearer alice")][..];
+        let bob = &[(AUTHORIZATION, "Bearer bob")][..];
+
+        fetch_as(&cache, "/private", alice, response("alice's", &[]), now).await;
+        let fetched = fetch_as(&cache, "/private", bob, response("bob's", &[]), now).await;
+        assert_eq!(fetched, ("bob's".into(), "MISS".into()));
+
+        for (index, directive) in ["public", "s-maxage=60", "must-revalidate"]
+            .iter()
+            .enumerate()
+        {
+            let uri = format!("/shared/{}", index);
+            let shared = response("shared", &[(CACHE_CONTROL, directive)]);
+            fetch_as(&cache, &uri, alice, shared, now).await;
+            let fetched = fetch_as(&cache, &uri, bob, response("bob's", &[]), now).await;
+            assert_eq!(fetched, ("shared".into(), "HIT".into()), "{}", directive);
+        }
+    }
+
+    #[test]
+    fn requests_can_opt_out_of_the_cache() {
+        let cache = cache(Duration::from_secs(60));
+        for directive in ["no-store", "No-Cache", "max-age=0, no-cache"] {
+            let request = std::iter::once((CACHE_CONTROL, HeaderValue::from_static(directive)))
+                .collect::<HeaderMap>();
+            let uri = "/a".parse().unwrap();
+            assert!(
+                cache.key(&Method::GET, &uri, &request).is_none(),
+                "{}",
+                directive
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_a_variant_per_value_of_the_vary_headers() {
+        let cache = cache(Duration::from_secs(60));
+        let now = Instant::now();
+        let varying = |body| response(body, &[(VARY, "Accept-Language")]);
+        let en = &[(ACCEPT_LANGUAGE, "en")][..];
+        let fr = &[(ACCEPT_LANGUAGE, "fr")][..];
+
+        let fetched = fetch_as(&cache, "/a", en, varying("hello"), now).await;
+        assert_eq!(fetched, ("hello".into(), "MISS".into()));
+        let fetched = fetch_as(&cache, "/a", fr, varying("bonjour"), now).await;
+        assert_eq!(fetched, ("bonjour".into(), "MISS".into()));
+        let fetched = fetch_as(&cache, "/a", &[], varying("default"), now).await;
+        assert_eq!(fetched, ("default".into(), "MISS".into()));
+
+        let fetched = fetch_as(&cache, "/a", en, varying("new"), now).await;
+        assert_eq!(fetched, ("hello".into(), "HIT".into()));
+        let fetched = fetch_as(&cache, "/a", fr, varying("new"), now).await;
+        assert_eq!(fetched, ("bonjour".into(), "HIT".into()));
+        let fetched = fetch_as(&cache, "/a", &[], varying("new"), now).await;
+        assert_eq!(fetched, ("default".into(), "HIT".into()));
+        // Headers the response doesn't vary on don't matter.
+        let other = &[(ACCEPT_LANGUAGE, "en"), (USER_AGENT, "curl")][..];
+        let fetched = fetch_as(&cache, "/a", other, varying("new"), now).await;
+        assert_eq!(fetched, ("hello".into(), "HIT".into()));
+    }
+
+    #[tokio::test]
+    async fn accept_encoding_does_not_split_the_cache() {
+        let cache = cache(Duration::from_secs(60));
+        let now = Instant::now();
+        let varying = |body| response(body, &[(VARY, "Accept-Encoding")]);
+        let gzip = &[(ACCEPT_ENCODING, "gzip")][..];
+        fetch_as(&cache, "/a", gzip, varying("first"), now).await;
+        let fetched = fetch_as(&cache, "/a", &[], varying("second"), now).await;
+        assert_eq!(fetched, ("first".into(), "HIT".into()));
+    }
+
+    #[tokio::test]
+    async fn variants_reach_the_upstream_once_each() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let upstream = upstream(move |req: Request<Body>| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let language = req.headers()[ACCEPT_LANGUAGE].to_str().unwrap().to_string();
+            async move {
+                let mut response = Response::new(Body::from(language));
+                response
+                    .headers_mut()
+                    .insert(VARY, HeaderValue::from_static("Accept-Language"));
+                response
+            }
+        })
+        .await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"\n[cache]", upstream)).await;
+        for language in ["en", "fr", "en", "fr"] {
+            let req = Request::get("/path")
+                .header(ACCEPT_LANGUAGE, language)
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(body_string(proxy.send(req).await).await, language);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_entry() {
+        let cache = ResponseCache::new(&CacheConfig {
+            ttl: Duration::from_secs(60),
+            max_entries: 2,
+            max_body_bytes: default_max_body_bytes(),
+            rules: Vec::new(),
+        })
+        .unwrap();
+        let now = Instant::now();
+        fetch(&cache, "/a", response("a", &[]), now).await;
+        fetch(&cache, "/b", response("b", &[]), now).await;
+        // Using /a makes /b the one to go.
+        fetch(&cache, "/a", response("", &[]), now).await;
+        fetch(&cache, "/c", response("c", &[]), now).await;
+        assert_eq!(fetch(&cache, "/a", response("", &[]), now).await.1, "HIT");
+        assert_eq!(fetch(&cache, "/b", response("", &[]), now).await.1, "MISS");
+    }
+
+    #[tokio::test]
+    async fn cached_responses_skip_the_upstream() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let upstream = upstream(move |_| {
+            let call = counted.fetch_add(1, Ordering::SeqCst);
+            async move { Response::new(Body::from(format!("call {}", call))) }
+        })
+        .await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"\n[cache]", upstream)).await;
+
+        for _ in 0..3 {
+            let response = proxy.get("/path").await;
+            assert!(response.headers().contains_key(X_CACHE));
+            assert_eq!(body_string(response).await, "call 0");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    /// An upstream that answers with `status` and a count of its calls, slowly enough
+    /// for concurrent requests to pile up.
+    async fn slow_counting_upstream(status: StatusCode) -> (SocketAddr, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let addr = upstream(move |_| {
+            let call = counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let mut response = Response::new(Body::from(format!("call {}", call)));
+                *response.status_mut() = status;
+                response
+            }
+        })
+        .await;
+        (addr, calls)
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_share_one_upstream_request() {
+        let (upstream, calls) = slow_counting_upstream(StatusCode::OK).await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"\n[cache]", upstream)).await;
+
+        let responses = futures_util::future::join_all((0..50).map(|_| proxy.get("/path"))).await;
+        for response in responses {
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_string(response).await, "call 0");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn coalesced_requests_share_errors_without_storing_them() {
+        let (upstream, calls) = slow_counting_upstream(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"\n[cache]", upstream)).await;
+
+        let responses = futures_util::future::join_all((0..50).map(|_| proxy.get("/path"))).await;
+        for response in responses {
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(response.headers()[X_CACHE], "MISS");
+            assert_eq!(body_string(response).await, "call 0");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let response = proxy.get("/path").await;
+        assert_eq!(body_string(response).await, "call 1");
+    }
+
+    #[tokio::test]
+    async fn users_with_different_tokens_each_reach_the_upstream() {
+        let (upstream, calls) = slow_counting_upstream(StatusCode::OK).await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"\n[cache]", upstream)).await;
+        let get = |token: &str| {
+            let req = Request::get("/path")
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            proxy.send(req)
+        };
+
+        // Concurrently, so they'd share a flight if they could.
+        let (alice, bob) = futures_util::join!(get("alice"), get("bob"));
+        let mut bodies = vec![body_string(alice).await, body_string(bob).await];
+        bodies.sort();
+        assert_eq!(bodies, ["call 0", "call 1"]);
+        assert_eq!(body_string(get("alice").await).await, "call 2");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn a_waiting_request_takes_over_an_abandoned_flight() {
+        let cache = Arc::new(cache(Duration::from_secs(60)));
+        let leader = match cache.lookup(&key(&cache, "/a", &[])).await {
+            Lookup::Miss(Some(flight)) => flight,
+            _ => panic!("expected to lead a flight"),
+        };
+        let waiting = tokio::spawn({
+            let cache = cache.clone();
+            async move {
+                let lookup = cache.lookup(&key(&cache, "/a", &[])).await;
+                matches!(lookup, Lookup::Miss(Some(_)))
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        drop(leader);
+        assert!(waiting.await.unwrap());
+    }
+
+    fn rule(path_prefix: &str, ttl: Option<u64>, enabled: bool) -> CacheRule {
+        CacheRule {
+            path_prefix: path_prefix.to_string(),
+            ttl: ttl.map(Duration::from_secs),
+            enabled,
+        }
+    }
+
+    fn cache_with_rules(rules: Vec<CacheRule>) -> Result<ResponseCache> {
+        ResponseCache::new(&CacheConfig {
+            ttl: Duration::from_secs(60),
+            max_entries: default_max_entries(),
+            max_body_bytes: default_max_body_bytes(),
+            rules,
+        })
+    }
+
+    #[test]
+    fn the_longest_whole_segment_prefix_picks_the_ttl() {
+        let cache = cache_with_rules(vec![
+            rule("/api", None, true),
+            rule("/static", Some(3600), true),
+            rule("/api/live", None, false),
+            rule("/docs/", Some(10), true),
+        ])
+        .unwrap();
+        let ttl = |uri: &str| {
+            cache
+                .key(&Method::GET, &uri.parse().unwrap(), &HeaderMap::new())
+                .map(|key| key.ttl.as_secs())
+        };
+        assert_eq!(ttl("/static"), Some(3600));
+        assert_eq!(ttl("/static/app.js?v=2"), Some(3600));
+        assert_eq!(ttl("/api/users"), Some(60));
+        assert_eq!(ttl("/docs/intro"), Some(10));
+        // Carved out of `/api`, and prefixes only match whole segments.
+        assert_eq!(ttl("/api/live"), None);
+        assert_eq!(ttl("/api/live/feed"), None);
+        assert_eq!(ttl("/api/lively"), Some(60));
+        assert_eq!(ttl("/apix"), None);
+        assert_eq!(ttl("/other"), None);
+    }
+
+    #[tokio::test]
+    async fn entries_expire_after_their_rules_ttl() {
+        let cache = cache_with_rules(vec![
+            rule("/short", Some(1), true),
+            rule("/long", Some(3600), true),
+        ])
+        .unwrap();
+        let now = Instant::now();
+        let earlier = now - Duration::from_secs(2);
+        fetch(&cache, "/short", response("old", &[]), earlier).await;
+        fetch(&cache, "/long", response("old", &[]), earlier).await;
+
+        let short = fetch(&cache, "/short", response("new", &[]), now).await;
+        assert_eq!(short, ("new".into(), "MISS".into()));
+        let long = fetch(&cache, "/long", response("new", &[]), now).await;
+        assert_eq!(long, ("old".into(), "HIT".into()));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(cache_with_rules(vec![rule("api", None, true)]).is_err());
+        assert!(
+            cache_with_rules(vec![rule("/api", None, true), rule("/api", None, false)]).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn paths_outside_the_rules_bypass_the_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let upstream = upstream(move |_| {
+            let call = counted.fetch_add(1, Ordering::SeqCst);
+            async move { Response::new(Body::from(format!("call {}", call))) }
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n\
+             [[cache.rules]]\npath_prefix = \"/static\"",
+            upstream
+        ))
+        .await;
+
+        for _ in 0..2 {
+            let response = proxy.get("/static/app.js").await;
+            assert_eq!(body_string(response).await, "call 0");
+        }
+        for expected in &["call 1", "call 2"] {
+            let response = proxy.get("/api").await;
+            assert!(!response.headers().contains_key(X_CACHE));
+            assert_eq!(body_string(response).await, *expected);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::*;
+use hyper::Uri;
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures that open the circuit.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// How long an open circuit rejects requests before letting a probe through.
+    #[serde(default = "default_cooldown", with = "humantime_serde")]
+    pub cooldown: Duration,
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_cooldown() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A single probe request is in flight; its outcome decides whether to close again.
+    HalfOpen {
+        probe_started: Instant,
+    },
+}
+
+/// One closed/open/half-open state machine per upstream. Connection errors and 5xx
+/// responses count as failures; any other response closes the circuit.
+pub struct CircuitBreakers {
+    failure_threshold: u32,
+    cooldown: Duration,
+    states: HashMap<Uri, Mutex<State>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: &CircuitBreakerConfig, upstreams: &[Uri]) -> Result<CircuitBreakers> {
+        ensure!(
+            config.failure_threshold > 0,
+            "circuit_breaker.failure_threshold must be at least 1"
+        );
+        Ok(CircuitBreakers {
+            failure_threshold: config.failure_threshold,
+            cooldown: config.cooldown,
+            states: upstreams
+                .iter()
+                .map(|upstream| (upstream.clone(), Mutex::new(State::Closed { failures: 0 })))
+                .collect(),
+        })
+    }
+
+    /// Whether a request may be sent to `upstream`. An open circuit whose cooldown has
+    /// passed lets exactly one probe through; if that probe never reports back, another
+    /// is allowed after a further cooldown.
+    pub fn allow(&self, upstream: &Uri, now: Instant) -> bool {
+        let mut state = match self.states.get(upstream) {
+            Some(state) => state.lock().unwrap(),
+            None => return true,
+        };
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now < until => false,
+            State::HalfOpen { probe_started } if now < probe_started + self.cooldown => false,
+            State::Open { .. } | State::HalfOpen { .. } => {
+                *state = State::HalfOpen { probe_started: now };
+                true
+            }
+        }
+    }
+
+    /// `closed`, `open` or `half_open`, or `None` for an unknown upstream.
+    pub fn state(&self, upstream: &Uri) -> Option<&'static str> {
+        let state = *self.states.get(upstream)?.lock().unwrap();
+        Some(match state {
+            State::Closed { .. } => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+        })
+    }
+
+    pub fn record(&self, upstream: &Uri, success: bool, now: Instant) {
+        let mut state = match self.states.get(upstream) {
+            Some(state) => state.lock().unwrap(),
+            None => return,
+        };
+        *state = match (*state, success) {
+            (State::Closed { .. }, true) => State::Closed { failures: 0 },
+            (State::HalfOpen { .. }, true) => {
+                warn!("Upstream {} recovered, closing circuit", upstream);
+                State::Closed { failures: 0 }
+            }
+            (State::Closed { failures }, false) if failures + 1 < self.failure_threshold => {
+                State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (State::Closed { .. }, false) | (State::HalfOpen { .. }, false) => {
+                warn!(
+                    "Opening circuit for upstream {} for {:?}",
+                    upstream, self.cooldown
+                );
+                State::Open {
+                    until: now + self.cooldown,
+                }
+            }
+            // A request that started before the circuit opened; its outcome is stale.
+            (open @ State::Open { .. }, _) => open,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(30);
+
+    fn breakers(failure_threshold: u32) -> (CircuitBreakers, Uri) {
+        let upstream: Uri = "http://10.0.0.1:8080".parse().unwrap();
+        let config = CircuitBreakerConfig {
+            failure_threshold,
+            cooldown: COOLDOWN,
+        };
+        (
+            CircuitBreakers::new(&config, std::slice::from_ref(&upstream)).unwrap(),
+            upstream,
+        )
+    }
+
+    #[test]
+    fn opens_after_the_threshold_of_consecutive_failures() {
+        let (breakers, upstream) = breakers(3);
+        let now = Instant::now();
+        breakers.record(&upstream, false, now);
+        breakers.record(&upstream, false, now);
+        // A success in between starts the count again.
+        breakers.record(&upstream, true, now);
+        breakers.record(&upstream, false, now);
+        breakers.record(&upstream, false, now);
+        assert_eq!(breakers.state(&upstream), Some("closed"));
+        assert!(breakers.allow(&upstream, now));
+
+        breakers.record(&upstream, false, now);
+        assert_eq!(breakers.state(&upstream), Some("open"));
+        assert!(!breakers.allow(&upstream, now));
+        assert!(!breakers.allow(&upstream, now + COOLDOWN - Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn a_successful_probe_closes_the_circuit() {
+        let (breakers, upstream) = breakers(1);
+        let now = Instant::now();
+        breakers.record(&upstream, false, now);
+
+        let later = now + COOLDOWN;
+        assert!(breakers.allow(&upstream, later));
+        assert_eq!(breakers.state(&upstream), Some("half_open"));
+        // Only the one probe goes through.
+        assert!(!breakers.allow(&upstream, later));
+
+        breakers.record(&upstream, true, later);
+        assert_eq!(breakers.state(&upstream), Some("closed"));
+        assert!(breakers.allow(&upstream, later));
+    }
+
+    #[test]
+    fn a_failed_or_lost_probe_keeps_the_circuit_open() {
+        let (breakers, upstream) = breakers(1);
+        let now = Instant::now();
+        breakers.record(&upstream, false, now);
+
+        let probe = now + COOLDOWN;
+        assert!(breakers.allow(&upstream, probe));
+        breakers.record(&upstream, false, probe);
+        assert_eq!(breakers.state(&upstream), Some("open"));
+        assert!(!breakers.allow(&upstream, probe + COOLDOWN / 2));
+
+        // A probe that never reports back is replaced after another cooldown.
+        let probe = probe + COOLDOWN;
+        assert!(breakers.allow(&upstream, probe));
+        assert!(!breakers.allow(&upstream, probe + COOLDOWN / 2));
+        assert!(breakers.allow(&upstream, probe + COOLDOWN));
+    }
+
+    #[test]
+    fn stale_outcomes_and_unknown_upstreams_are_ignored() {
+        let (breakers, upstream) = breakers(1);
+        let now = Instant::now();
+        breakers.record(&upstream, false, now);
+        breakers.record(&upstream, true, now);
+        assert_eq!(breakers.state(&upstream), Some("open"));
+
+        let unknown: Uri = "http://10.0.0.2:8080".parse().unwrap();
+        breakers.record(&unknown, false, now);
+        assert!(breakers.allow(&unknown, now));
+        assert_eq!(breakers.state(&unknown), None);
+    }
+
+    #[test]
+    fn the_threshold_must_be_at_least_one() {
+        let config = CircuitBreakerConfig {
+            failure_threshold: 0,
+            cooldown: COOLDOWN,
+        };
+        assert!(CircuitBreakers::new(&config, &[]).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::connector::{PriorKnowledge, UpstreamConnector, UpstreamTlsConfig};
+use crate::dns::{DnsCacheConfig, UpstreamResolver};
+use crate::HttpsClient;
+use anyhow::*;
+use hyper::client::{self, Client};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Connection pool and protocol settings for upstream connections. The defaults are
+/// hyper's own.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Idle connections kept open per upstream host; `0` disables keep-alive. Unset
+    /// means no limit.
+    #[serde(default)]
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long an idle pooled connection is kept before it's closed.
+    #[serde(default = "default_pool_idle_timeout", with = "humantime_serde")]
+    pub pool_idle_timeout: Duration,
+    /// Talk HTTP/2 to every upstream, including prior-knowledge HTTP/2 over plain TCP.
+    #[serde(default)]
+    pub http2_only: bool,
+    /// How often to ping HTTP/2 connections. Unset means never.
+    #[serde(default, with = "humantime_serde")]
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// How long to wait for a ping to be acknowledged before closing the connection.
+    #[serde(default = "default_http2_keep_alive_timeout", with = "humantime_serde")]
+    pub http2_keep_alive_timeout: Duration,
+    /// Also ping connections that have no requests in flight.
+    #[serde(default)]
+    pub http2_keep_alive_while_idle: bool,
+    /// Cache upstream DNS lookups instead of resolving on every new connection.
+    #[serde(default)]
+    pub dns_cache: Option<DnsCacheConfig>,
+    /// Certificate verification for `https` upstreams.
+    #[serde(default)]
+    pub tls: UpstreamTlsConfig,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: default_pool_idle_timeout(),
+            http2_only: false,
+            http2_keep_alive_interval: None,
+            http2_keep_alive_timeout: default_http2_keep_alive_timeout(),
+            http2_keep_alive_while_idle: false,
+            dns_cache: None,
+            tls: UpstreamTlsConfig::default(),
+        }
+    }
+}
+
+fn default_pool_idle_timeout() -> Duration {
+    Duration::from_secs(90)
+}
+
+fn default_http2_keep_alive_timeout() -> Duration {
+    Duration::from_secs(20)
+}
+
+/// What [`build`] sets on hyper's client builder, which can't be read back once set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BuilderSettings {
+    pool_idle_timeout: Duration,
+    /// `usize::MAX`, hyper's default, when unlimited.
+    pool_max_idle_per_host: usize,
+    http2_only: bool,
+    http2_keep_alive_interval: Option<Duration>,
+    http2_keep_alive_timeout: Duration,
+    http2_keep_alive_while_idle: bool,
+}
+
+impl BuilderSettings {
+    fn new(config: &ClientConfig) -> BuilderSettings {
+        BuilderSettings {
+            pool_idle_timeout: config.pool_idle_timeout,
+            pool_max_idle_per_host: config.pool_max_idle_per_host.unwrap_or(usize::MAX),
+            http2_only: config.http2_only,
+            http2_keep_alive_interval: config.http2_keep_alive_interval,
+            http2_keep_alive_timeout: config.http2_keep_alive_timeout,
+            http2_keep_alive_while_idle: config.http2_keep_alive_while_idle,
+        }
+    }
+
+    fn builder(&self) -> client::Builder {
+        let mut builder = Client::builder();
+        builder
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .http2_only(self.http2_only)
+            .http2_keep_alive_interval(self.http2_keep_alive_interval)
+            .http2_keep_alive_timeout(self.http2_keep_alive_timeout)
+            .http2_keep_alive_while_idle(self.http2_keep_alive_while_idle);
+        builder
+    }
+}
+
+pub fn build(config: &ClientConfig, prior_knowledge: PriorKnowledge) -> Result<HttpsClient> {
+    let resolver = UpstreamResolver::new(config.dns_cache.as_ref())?;
+    Ok(BuilderSettings::new(config)
+        .builder()
+        .build(UpstreamConnector::new(
+            resolver,
+            prior_knowledge,
+            &config.tls,
+        )?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::body_string;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+    use std::collections::HashSet;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn settings_follow_the_config() {
+        let defaults: ClientConfig = toml::from_str("").unwrap();
+        assert_eq!(
+            BuilderSettings::new(&defaults),
+            BuilderSettings {
+                pool_idle_timeout: Duration::from_secs(90),
+                pool_max_idle_per_host: usize::MAX,
+                http2_only: false,
+                http2_keep_alive_interval: None,
+                http2_keep_alive_timeout: Duration::from_secs(20),
+                http2_keep_alive_while_idle: false,
+            }
+        );
+
+        let config: ClientConfig = toml::from_str(
+            r#"
+            pool_max_idle_per_host = 0
+            pool_idle_timeout = "5s"
+            http2_only = true
+            http2_keep_alive_interval = "30s"
+            http2_keep_alive_timeout = "3s"
+            http2_keep_alive_while_idle = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            BuilderSettings::new(&config),
+            BuilderSettings {
+                pool_idle_timeout: Duration::from_secs(5),
+                pool_max_idle_per_host: 0,
+                http2_only: true,
+                http2_keep_alive_interval: Some(Duration::from_secs(30)),
+                http2_keep_alive_timeout: Duration::from_secs(3),
+                http2_keep_alive_while_idle: true,
+            }
+        );
+    }
+
+    /// An upstream that answers every request with the client port of its connection.
+    async fn port_upstream() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let make_service = make_service_fn(|conn: &hyper::server::conn::AddrStream| {
+            let port = conn.remote_addr().port();
+            async move {
+                std::result::Result::Ok::<_, Infallible>(service_fn(move |_| async move {
+                    std::result::Result::Ok::<_, Infallible>(Response::new(Body::from(
+                        port.to_string(),
+                    )))
+                }))
+            }
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_service));
+        addr
+    }
+
+    /// How many connections it took `config`'s client to send 3 requests in a row.
+    async fn connections(config: &str) -> usize {
+        let upstream = port_upstream().await;
+        let client = build(&toml::from_str(config).unwrap(), PriorKnowledge::default()).unwrap();
+        let mut ports = HashSet::new();
+        for _ in 0..3 {
+            let uri = format!("http://{}/", upstream).parse().unwrap();
+            ports.insert(body_string(client.get(uri).await.unwrap()).await);
+        }
+        ports.len()
+    }
+
+    #[tokio::test]
+    async fn pools_connections_unless_disabled() {
+        assert_eq!(connections("").await, 1);
+        assert_eq!(connections("pool_max_idle_per_host = 0").await, 3);
+    }
+}
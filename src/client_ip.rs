@@ -0,0 +1,153 @@
+use hyper::header::HeaderMap;
+use hyper::{Body, Request};
+use ipnet::IpNet;
+use routerify::prelude::*;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The proxies in front of Vostok, whose `X-Forwarded-For` entries are believed.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    pub fn new(nets: Vec<IpNet>) -> TrustedProxies {
+        TrustedProxies(nets)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The address the request came from: `peer`, unless it's a trusted proxy, in which
+    /// case `X-Forwarded-For` is walked from the right past every trusted hop to the
+    /// first one that isn't. Entries to the left of that are whatever the client sent,
+    /// so they're never looked at. An entry that isn't an address ends the walk at the
+    /// trusted hop that added it.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let values = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>();
+        let mut client = peer;
+        for entry in values.iter().rev().flat_map(|value| value.rsplit(',')) {
+            client = match parse_entry(entry.trim()) {
+                Some(ip) => ip,
+                None => break,
+            };
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// An address as proxies write them, with or without a port.
+fn parse_entry(entry: &str) -> Option<IpAddr> {
+    entry
+        .parse()
+        .ok()
+        .or_else(|| entry.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// The client's IP for access control, rate limiting and logging. Requests to routers
+/// without trusted proxies (like the admin API's) just get the socket's peer.
+pub fn client_ip(req: &Request<Body>) -> IpAddr {
+    let peer = req.remote_addr().ip();
+    match req.data::<Arc<crate::Env>>() {
+        Some(env) => env.trusted_proxies.client_ip(peer, req.headers()),
+        None => peer,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_json, unused_addr, Proxy};
+    use hyper::header::HeaderValue;
+
+    fn proxies(nets: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(nets.iter().map(|net| net.parse().unwrap()).collect())
+    }
+
+    fn client_ip(proxies: &TrustedProxies, peer: &str, forwarded_for: &[&str]) -> IpAddr {
+        let mut headers = HeaderMap::new();
+        for value in forwarded_for {
+            headers.append(X_FORWARDED_FOR, HeaderValue::from_str(value).unwrap());
+        }
+        proxies.client_ip(peer.parse().unwrap(), &headers)
+    }
+
+    #[test]
+    fn walks_the_chain_past_trusted_proxies() {
+        let proxies = proxies(&["10.0.0.0/8", "2001:db8::/32"]);
+        let ip =
+            |peer, forwarded_for: &[&str]| client_ip(&proxies, peer, forwarded_for).to_string();
+        assert_eq!(ip("10.0.0.1", &["203.0.113.7"]), "203.0.113.7");
+        // Hops the client added to the left of the first untrusted one are ignored.
+        assert_eq!(
+            ip("10.0.0.1", &["198.51.100.1, 203.0.113.7, 10.0.0.2"]),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            ip(
+                "2001:db8::1",
+                &["198.51.100.1", "203.0.113.7:4711, [2001:db8::2]:80"]
+            ),
+            "203.0.113.7"
+        );
+        assert_eq!(ip("::ffff:10.0.0.1", &["203.0.113.7"]), "203.0.113.7");
+        // Only trusted proxies all the way, or no header, leave the last trusted one.
+        assert_eq!(ip("10.0.0.1", &["10.0.0.3, 10.0.0.2"]), "10.0.0.3");
+        assert_eq!(ip("10.0.0.1", &[]), "10.0.0.1");
+        // A garbled entry stops at the hop that added it.
+        assert_eq!(
+            ip("10.0.0.1", &["203.0.113.7, unknown, 10.0.0.2"]),
+            "10.0.0.2"
+        );
+    }
+
+    #[test]
+    fn ignores_the_header_from_untrusted_peers() {
+        let spoofed = ["10.0.0.2, 203.0.113.7"];
+        let trusting = proxies(&["10.0.0.0/8"]);
+        assert_eq!(
+            client_ip(&trusting, "192.0.2.1", &spoofed).to_string(),
+            "192.0.2.1"
+        );
+        let trusting_none = TrustedProxies::default();
+        assert_eq!(
+            client_ip(&trusting_none, "10.0.0.1", &spoofed).to_string(),
+            "10.0.0.1"
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_get_the_forwarded_client_ip_behind_trusted_proxies() {
+        let config = |trusted: &str| {
+            format!(
+                "upstreams = \"http://{}\"\ndebug_echo = true\ntrusted_proxies = [{}]",
+                unused_addr(),
+                trusted
+            )
+        };
+        let echoed_ip = |proxy: Proxy| async move {
+            let req = Request::get("/debug/echo")
+                .header(X_FORWARDED_FOR, "198.51.100.1, 203.0.113.7")
+                .body(Body::empty())
+                .unwrap();
+            body_json(proxy.send(req).await).await["client_ip"].clone()
+        };
+        let trusting = Proxy::start(&config("\"127.0.0.0/8\"")).await;
+        assert_eq!(echoed_ip(trusting).await, "203.0.113.7");
+        let untrusting = Proxy::start(&config("")).await;
+        assert_eq!(echoed_ip(untrusting).await, "127.0.0.1");
+    }
+}
@@ -0,0 +1,293 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
+use futures_util::TryStreamExt;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY,
+};
+use hyper::{Body, Response, StatusCode};
+use serde::Deserialize;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Responses with a declared length below this are sent uncompressed.
+    #[serde(default = "default_min_size")]
+    pub min_size: u64,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig {
+            enabled: false,
+            min_size: default_min_size(),
+        }
+    }
+}
+
+fn default_min_size() -> u64 {
+    1024
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Picks the encoding to use from the client's `Accept-Encoding`, preferring brotli.
+/// Encodings listed with `q=0` are treated as refused.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut accepts_brotli = false;
+    let mut accepts_gzip = false;
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        let value = match value.to_str() {
+            Result::Ok(value) => value,
+            Err(_) => continue,
+        };
+        for item in value.split(',') {
+            let mut params = item.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default().to_ascii_lowercase();
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            if refused {
+                continue;
+            }
+            match name.as_str() {
+                "br" => accepts_brotli = true,
+                "gzip" | "*" => accepts_gzip = true,
+                _ => {}
+            }
+        }
+    }
+
+    if accepts_brotli {
+        Some(Encoding::Brotli)
+    } else if accepts_gzip {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Only uncompressed, text-like bodies that are big enough (or of unknown length) are
+/// worth compressing.
+pub fn is_compressible(response: &Response<Body>, min_size: u64) -> bool {
+    if matches!(
+        response.status(),
+        StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+    ) || response.status().is_informational()
+    {
+        return false;
+    }
+
+    let headers = response.headers();
+    if headers.contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if !is_text_like(content_type) {
+        return false;
+    }
+
+    let length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    length.is_none_or(|length| length >= min_size)
+}
+
+pub fn is_text_like(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/x-ndjson"
+                | "image/svg+xml"
+        )
+}
+
+/// Compresses the body as a stream, so it's never buffered whole. The compressed length
+/// isn't known up front so `Content-Length` is dropped.
+pub fn compress(response: Response<Body>, encoding: Encoding) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+
+    let reader = StreamReader::new(body.map_err(std::io::Error::other));
+    let body = match encoding {
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::new(reader))),
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+    };
+    Response::from_parts(parts, body)
+}
+
+pub fn add_vary_accept_encoding(headers: &mut HeaderMap) {
+    let already_varies = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !already_varies {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, Proxy};
+    use async_compression::tokio::bufread::GzipDecoder;
+    use hyper::Request;
+    use tokio::io::AsyncReadExt;
+
+    fn accepting(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn response(content_type: &str, body: &str) -> Response<Body> {
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn gunzip(body: Body) -> String {
+        let reader = StreamReader::new(body.map_err(std::io::Error::other));
+        let mut decoded = String::new();
+        GzipDecoder::new(reader)
+            .read_to_string(&mut decoded)
+            .await
+            .unwrap();
+        decoded
+    }
+
+    #[test]
+    fn negotiates_the_preferred_encoding() {
+        assert_eq!(negotiate(&accepting("gzip, br")), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&accepting("gzip, br;q=0")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accepting("*")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accepting("identity")), None);
+        assert_eq!(negotiate(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn only_compresses_big_uncompressed_text() {
+        let text = &"x".repeat(2048);
+        assert!(is_compressible(
+            &response("text/html; charset=utf-8", text),
+            1024
+        ));
+        assert!(is_compressible(&response("application/json", text), 1024));
+        assert!(!is_compressible(&response("image/png", text), 1024));
+        assert!(!is_compressible(&response("text/plain", "short"), 1024));
+
+        let mut encoded = response("text/plain", text);
+        encoded
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        assert!(!is_compressible(&encoded, 1024));
+
+        let mut empty = response("text/plain", text);
+        *empty.status_mut() = StatusCode::NO_CONTENT;
+        assert!(!is_compressible(&empty, 1024));
+    }
+
+    #[tokio::test]
+    async fn gzips_the_body() {
+        let body = "hello world ".repeat(200);
+        let response = compress(response("text/plain", &body), Encoding::Gzip);
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert!(!response.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(gunzip(response.into_body()).await, body);
+    }
+
+    #[test]
+    fn adds_vary_once() {
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, HeaderValue::from_static("Origin"));
+        add_vary_accept_encoding(&mut headers);
+        add_vary_accept_encoding(&mut headers);
+        assert_eq!(
+            headers.get_all(VARY).iter().collect::<Vec<_>>(),
+            ["Origin", "Accept-Encoding"]
+        );
+    }
+
+    #[tokio::test]
+    async fn proxy_compresses_text_and_skips_encoded_bodies() {
+        let upstream = test_support::upstream(|req: Request<Body>| async move {
+            let body = "compressible ".repeat(500);
+            let mut response = Response::builder().header(CONTENT_TYPE, "text/plain");
+            if req.uri().path() == "/encoded" {
+                response = response.header(CONTENT_ENCODING, "br");
+            }
+            response.body(Body::from(body)).unwrap()
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[compression]\nenabled = true",
+            upstream
+        ))
+        .await;
+        let get = |path: &str| {
+            Request::get(path)
+                .header(ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = proxy.send(get("/text")).await;
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "Accept-Encoding");
+        assert_eq!(
+            gunzip(response.into_body()).await,
+            "compressible ".repeat(500)
+        );
+
+        let response = proxy.send(get("/encoded")).await;
+        assert_eq!(response.headers()[CONTENT_ENCODING], "br");
+        assert_eq!(
+            test_support::body_string(response).await,
+            "compressible ".repeat(500)
+        );
+    }
+}
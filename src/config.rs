@@ -0,0 +1,855 @@
+use crate::access_log::LogFormat;
+use crate::admin::AdminConfig;
+use crate::auth::{BasicAuthConfig, JwtConfig};
+use crate::balancer::{ConsistentHashConfig, Overrides, Upstream};
+use crate::body_rewrite::BodyRewriteConfig;
+use crate::bulkhead::BulkheadConfig;
+use crate::cache::CacheConfig;
+use crate::circuit_breaker::CircuitBreakerConfig;
+use crate::client::ClientConfig;
+use crate::compression::CompressionConfig;
+use crate::connector::unix_socket_uri;
+use crate::cors::CorsConfig;
+use crate::error_pages::ErrorPagesConfig;
+use crate::expect_continue::ExpectContinueConfig;
+use crate::health::{HealthCheckConfig, WarmupConfig};
+use crate::idempotency::IdempotencyConfig;
+use crate::log_file::LogFileConfig;
+use crate::maintenance::MaintenanceConfig;
+use crate::middleware::AccessControl;
+use crate::mirror::MirrorConfig;
+use crate::observability::TracingConfig;
+use crate::path_normalization::PathNormalizationConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::redirect::RedirectConfig;
+use crate::request_body_log::RequestBodyLogConfig;
+use crate::request_filter::RequestFilterConfig;
+use crate::request_headers::RequestHeadersConfig;
+use crate::response_headers::ResponseHeadersConfig;
+use crate::rewrite::RewriteRule;
+use crate::server::ListenerConfig;
+use crate::server_timing::ServerTimingConfig;
+use crate::static_files::StaticFilesConfig;
+use crate::timeout_header::TimeoutHeaderConfig;
+use crate::tls::TlsConfig;
+use crate::trailing_slash::TrailingSlashConfig;
+use crate::transform::TransformConfig;
+use crate::upgrade::UpgradeConfig;
+use anyhow::*;
+use hyper::header::HeaderValue;
+use hyper::Uri;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const CONFIG_ENV_VAR: &str = "VOSTOK_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "vostok.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(
+        alias = "listen_addr",
+        default = "default_listen_addrs",
+        deserialize_with = "deserialize_listen_addrs"
+    )]
+    pub listen_addrs: Vec<SocketAddr>,
+    /// Socket options for all listeners.
+    #[serde(default)]
+    pub listener: ListenerConfig,
+    #[serde(alias = "proxy_url", deserialize_with = "deserialize_upstreams")]
+    pub upstreams: Vec<Upstream>,
+    /// Path prefixes served by their own upstreams instead of `upstreams`.
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Hosts with their own upstreams, routes, rewrites and credentials. Requests for any
+    /// other host use the top-level ones.
+    #[serde(default)]
+    pub vhosts: Vec<VirtualHostConfig>,
+    /// Connection pool and protocol settings for talking to upstreams.
+    #[serde(default)]
+    pub client: ClientConfig,
+    /// Pin each client to one upstream with a `vostok_upstream` cookie.
+    #[serde(default)]
+    pub sticky_sessions: bool,
+    /// Pick upstreams by hashing each request's client IP, URI or a header.
+    #[serde(default)]
+    pub consistent_hash: Option<ConsistentHashConfig>,
+    /// Serve `/debug/echo`, which describes the request instead of proxying it.
+    #[serde(default)]
+    pub debug_echo: bool,
+    #[serde(default = "default_log_level")]
+    pub log_level: log::LevelFilter,
+    /// Write the log to a rotating file, instead of or as well as stdout.
+    #[serde(default)]
+    pub log_file: Option<LogFileConfig>,
+    /// `json` logs one JSON object per proxied request; `text` keeps the plain debug log.
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Share of successful requests that get a JSON access log line, e.g. `0.01`.
+    #[serde(default = "default_access_log_sample_rate")]
+    pub access_log_sample_rate: f64,
+    /// Add each response's body size to its access log line, which is then written once
+    /// the body has been sent.
+    #[serde(default)]
+    pub access_log_body_bytes: bool,
+    /// Maximum time to wait for the upstream to respond, e.g. `"30s"`. Unset means no timeout.
+    #[serde(default, with = "humantime_serde")]
+    pub request_timeout: Option<Duration>,
+    /// Maximum time from receiving a request to the upstream's response, retries and
+    /// their backoff included. Unset means no limit beyond `request_timeout` per attempt.
+    #[serde(default, with = "humantime_serde")]
+    pub total_request_timeout: Option<Duration>,
+    /// Report the upstream timeout to clients and let them ask for their own.
+    #[serde(default)]
+    pub timeout_header: Option<TimeoutHeaderConfig>,
+    /// Tell clients how long their request spent upstream, in `Server-Timing`.
+    #[serde(default)]
+    pub server_timing: Option<ServerTimingConfig>,
+    /// How long a client may take to send its request headers (and finish the TLS
+    /// handshake) before the connection is closed. Unset means no limit.
+    #[serde(default, with = "humantime_serde")]
+    pub header_read_timeout: Option<Duration>,
+    /// How long a client connection may sit with no request being handled and nothing
+    /// sent either way before it's closed. `"0s"` keeps idle connections open.
+    #[serde(default = "default_idle_timeout", with = "humantime_serde")]
+    pub idle_timeout: Duration,
+    /// How long a client may go without sending more of its request body before it gets
+    /// `408 Request Timeout`. Unset means no limit.
+    #[serde(default, with = "humantime_serde")]
+    pub body_read_timeout: Option<Duration>,
+    /// How many times to retry idempotent requests after a connection error or 502/503.
+    #[serde(default)]
+    pub retries: u32,
+    /// Delay before the first retry, doubled on each subsequent attempt.
+    #[serde(default = "default_retry_backoff", with = "humantime_serde")]
+    pub retry_backoff: Duration,
+    /// Request bodies up to this size are buffered so they can be retried and mirrored;
+    /// larger ones are streamed and sent only once.
+    #[serde(default = "default_max_buffer_bytes", alias = "retry_max_body_bytes")]
+    pub max_buffer_bytes: u64,
+    /// Longest upstream `Retry-After` to wait for before retrying a `503`.
+    #[serde(default = "default_max_retry_after", with = "humantime_serde")]
+    pub max_retry_after: Duration,
+    /// Requests with a larger body are rejected with `413 Payload Too Large`.
+    #[serde(default)]
+    pub max_body_bytes: Option<u64>,
+    /// How `Expect: 100-continue` requests are handled.
+    #[serde(default)]
+    pub expect_continue: ExpectContinueConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// Probe upstreams in the background and stop routing to failing ones.
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    /// How long `/readyz` waits for an upstream, independent of `request_timeout`.
+    #[serde(default = "default_readiness_timeout", with = "humantime_serde")]
+    pub readiness_timeout: Duration,
+    /// Keep `/readyz` failing after startup until an upstream has answered.
+    #[serde(default)]
+    pub warmup: Option<WarmupConfig>,
+    /// How long to wait for in-flight requests on SIGTERM/ctrl-c before exiting anyway.
+    #[serde(default = "default_drain_timeout", with = "humantime_serde")]
+    pub drain_timeout: Duration,
+    /// Hand the listeners over to a freshly started copy of the binary on SIGUSR2.
+    #[serde(default)]
+    pub upgrade: Option<UpgradeConfig>,
+    /// Path prefix rewrites applied before forwarding; the first matching rule wins.
+    #[serde(default)]
+    pub rewrite_rules: Vec<RewriteRule>,
+    #[serde(default)]
+    pub access_control: AccessControl,
+    /// Proxies whose `X-Forwarded-For` entries are believed when working out client IPs.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Canonicalize request paths before they're checked, routed or forwarded.
+    #[serde(default)]
+    pub path_normalization: Option<PathNormalizationConfig>,
+    /// Refuse requests by method or path before they're proxied.
+    #[serde(default)]
+    pub request_filter: Option<RequestFilterConfig>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuthConfig>,
+    #[serde(default)]
+    pub cors: Option<CorsConfig>,
+    /// Cache `GET` responses in memory.
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    /// Replay the response to a `POST` for retries carrying the same `Idempotency-Key`.
+    #[serde(default)]
+    pub idempotency: Option<IdempotencyConfig>,
+    /// Require a valid bearer JWT on every request.
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>,
+    /// Stop sending requests to an upstream that keeps failing, for a cooldown.
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Limit the requests in flight to each upstream.
+    #[serde(default)]
+    pub bulkhead: Option<BulkheadConfig>,
+    #[serde(default)]
+    pub https_redirect: RedirectConfig,
+    /// Serve the main listeners over HTTPS with this certificate and key.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Path prefixes served from local directories instead of being proxied.
+    #[serde(default)]
+    pub static_files: Vec<StaticFilesConfig>,
+    /// Add or remove trailing slashes on request paths before forwarding them.
+    #[serde(default)]
+    pub trailing_slash: Option<TrailingSlashConfig>,
+    /// Which client headers are forwarded upstream.
+    #[serde(default)]
+    pub request_headers: RequestHeadersConfig,
+    /// Headers to set on and remove from every proxied response.
+    #[serde(default)]
+    pub response_headers: ResponseHeadersConfig,
+    /// Headers to set on and remove from the responses of Vostok's own endpoints.
+    #[serde(default)]
+    pub internal_headers: ResponseHeadersConfig,
+    /// Upstream statuses sent to clients as other ones, e.g. `418 = 503`.
+    #[serde(default)]
+    pub status_map: BTreeMap<String, u16>,
+    /// Search/replace rules applied to text response bodies.
+    #[serde(default)]
+    pub body_rewrite: Option<BodyRewriteConfig>,
+    /// Field edits applied to each line of newline-delimited JSON response bodies.
+    #[serde(default)]
+    pub transform: Option<TransformConfig>,
+    /// HTML pages served instead of Vostok's own plain text error responses.
+    #[serde(default)]
+    pub error_pages: ErrorPagesConfig,
+    /// Export an OpenTelemetry span for each proxied request and its upstream call.
+    #[serde(default)]
+    pub tracing: Option<TracingConfig>,
+    /// Send a copy of every proxied request to a shadow upstream.
+    #[serde(default)]
+    pub mirror: Option<MirrorConfig>,
+    /// Log each request's headers and body at debug level before forwarding it.
+    #[serde(default)]
+    pub request_body_log: Option<RequestBodyLogConfig>,
+    /// Middleware to leave out even when its feature is configured, by name.
+    #[serde(default)]
+    pub disabled_middleware: Vec<String>,
+    /// Answer all proxied requests with `503` while `enabled`; toggled by a reload.
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    /// Serve the admin API on its own address.
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    /// The file as parsed, with secrets blanked out.
+    #[serde(skip)]
+    pub source: serde_json::Value,
+}
+
+impl Config {
+    /// The config path is taken from the first CLI argument, then `VOSTOK_CONFIG`,
+    /// falling back to `vostok.toml` in the working directory.
+    pub fn path() -> PathBuf {
+        config_path(std::env::args_os().nth(1), std::env::var_os(CONFIG_ENV_VAR))
+    }
+
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading config file {}", path.display()))?;
+        Config::parse(&contents).with_context(|| format!("Loading config file {}", path.display()))
+    }
+
+    /// Scheme clients use to reach the main listeners, as reported in `X-Forwarded-Proto`.
+    pub fn listener_proto(&self) -> &'static str {
+        if self.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    /// `${NAME}` in any string value is replaced by the environment variable `NAME`, so
+    /// secrets can stay out of the file; `$${` stands for a literal `${`. The admin API
+    /// shows the config as written, with the references rather than their values.
+    pub fn parse(contents: &str) -> Result<Config> {
+        let source: toml::Value = toml::from_str(contents).context("Parsing config")?;
+        let mut interpolated = source.clone();
+        interpolate(&mut interpolated, &mut String::new())?;
+        let mut config: Config = interpolated.try_into().context("Parsing config")?;
+        config.source = serde_json::to_value(source).context("Converting config to JSON")?;
+        redact(&mut config.source);
+        Ok(config)
+    }
+}
+
+fn config_path(arg: Option<OsString>, env_var: Option<OsString>) -> PathBuf {
+    arg.or(env_var)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+}
+
+/// Resolves `${NAME}` references in every string under `value`, which sits at `key` in
+/// the config, naming the key in any error.
+fn interpolate(value: &mut toml::Value, key: &mut String) -> Result<()> {
+    match value {
+        toml::Value::String(string) if string.contains('$') => {
+            *string = interpolate_str(string).with_context(|| format!("In {}", key))?;
+        }
+        toml::Value::Table(table) => {
+            for (name, value) in table.iter_mut() {
+                let len = key.len();
+                if !key.is_empty() {
+                    key.push('.');
+                }
+                key.push_str(name);
+                interpolate(value, key)?;
+                key.truncate(len);
+            }
+        }
+        toml::Value::Array(values) => {
+            for (index, value) in values.iter_mut().enumerate() {
+                let len = key.len();
+                key.push_str(&format!("[{}]", index));
+                interpolate(value, key)?;
+                key.truncate(len);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str(value: &str) -> Result<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = rest.strip_prefix("${") {
+            let end = reference
+                .find('}')
+                .with_context(|| format!("Unclosed ${{ in {:?}", value))?;
+            let name = &reference[..end];
+            ensure!(!name.is_empty(), "Empty ${{}} in {:?}", value);
+            let resolved = std::env::var(name).map_err(|err| match err {
+                std::env::VarError::NotPresent => {
+                    anyhow!("Environment variable {} is not set", name)
+                }
+                std::env::VarError::NotUnicode(_) => {
+                    anyhow!("Environment variable {} is not valid UTF-8", name)
+                }
+            })?;
+            result.push_str(&resolved);
+            rest = &reference[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Config keys whose values must never leave the process.
+const SECRET_KEYS: &[&str] = &["password_hash", "hs256_secret", "password"];
+
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    *value = serde_json::Value::from("<redacted>");
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    /// Query parameters the request must have, with exactly these values.
+    #[serde(default)]
+    pub query: BTreeMap<String, String>,
+    /// Headers the request must have, each with a value equal to or starting with the
+    /// given one.
+    #[serde(default)]
+    pub headers: BTreeMap<String, HeaderMatch>,
+    #[serde(alias = "upstream", deserialize_with = "deserialize_upstreams")]
+    pub upstreams: Vec<Upstream>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualHostConfig {
+    /// The `Host` this block serves, like `api.example.com`, or `*.example.com` for every
+    /// name under `example.com`.
+    pub host: String,
+    #[serde(alias = "upstream", deserialize_with = "deserialize_upstreams")]
+    pub upstreams: Vec<Upstream>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub rewrite_rules: Vec<RewriteRule>,
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuthConfig>,
+}
+
+/// A header value given either as a bare string to match exactly or as
+/// `{ prefix = "..." }`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum HeaderMatch {
+    Exact(String),
+    Prefix { prefix: String },
+}
+
+impl HeaderMatch {
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            HeaderMatch::Exact(expected) => value == expected,
+            HeaderMatch::Prefix { prefix } => value.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+fn default_listen_addrs() -> Vec<SocketAddr> {
+    vec![SocketAddr::from(([127, 0, 0, 1], 3000))]
+}
+
+fn default_retry_backoff() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_access_log_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_max_buffer_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_max_retry_after() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_readiness_timeout() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_log_level() -> log::LevelFilter {
+    log::LevelFilter::Debug
+}
+
+/// Parses an `http`/`https` URL, or `unix:/path/to.sock` for an upstream on a Unix socket.
+pub fn parse_upstream_uri(value: &str) -> Result<Uri> {
+    if let Some(path) = value.strip_prefix("unix:") {
+        ensure!(
+            path.starts_with('/'),
+            "Unix socket upstream {:?} must be an absolute path",
+            value
+        );
+        return unix_socket_uri(path)
+            .parse()
+            .with_context(|| format!("Invalid upstream URI {:?}", value));
+    }
+    let uri: Uri = value
+        .parse()
+        .with_context(|| format!("Invalid upstream URI {:?}", value))?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") => {}
+        _ => bail!("Upstream URI {:?} must use the http or https scheme", value),
+    }
+    ensure!(
+        uri.authority().is_some(),
+        "Upstream URI {:?} is missing a host",
+        value
+    );
+    Ok(uri)
+}
+
+/// A config value that may be given as a single value or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+fn deserialize_listen_addrs<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Vec<SocketAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = OneOrMany::<String>::deserialize(deserializer)?.into_vec();
+    if values.is_empty() {
+        return Err(serde::de::Error::custom(
+            "at least one listen address is required",
+        ));
+    }
+    values
+        .iter()
+        .map(|value| {
+            value.parse::<SocketAddr>().map_err(|err| {
+                serde::de::Error::custom(format!("Invalid listen address {:?}: {}", value, err))
+            })
+        })
+        .collect()
+}
+
+/// An upstream given either as a bare URL or as a table like
+/// `{ url = "...", weight = 5, priority = 1, request_timeout = "60s", retries = 0, http2_only = true,
+/// preserve_host = true, basic_auth = { username = "...", password = "..." } }`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UpstreamEntry {
+    Url(String),
+    Table(UpstreamTable),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamTable {
+    url: String,
+    #[serde(default = "default_weight")]
+    weight: u32,
+    #[serde(default)]
+    priority: u32,
+    #[serde(default, with = "humantime_serde")]
+    request_timeout: Option<Duration>,
+    #[serde(default)]
+    retries: Option<u32>,
+    #[serde(default)]
+    http2_only: bool,
+    #[serde(default)]
+    preserve_host: bool,
+    #[serde(default)]
+    basic_auth: Option<UpstreamBasicAuth>,
+}
+
+/// Credentials Vostok sends to an upstream that needs its own Basic auth.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamBasicAuth {
+    username: String,
+    password: String,
+}
+
+impl UpstreamBasicAuth {
+    /// Marked sensitive, so the value stays out of debug output and HPACK tables.
+    fn header_value(&self) -> Result<HeaderValue> {
+        ensure!(
+            !self.username.contains(':'),
+            "Upstream basic_auth username can't contain ':'"
+        );
+        let credentials = base64::encode(format!("{}:{}", self.username, self.password));
+        let mut value = HeaderValue::from_str(&format!("Basic {}", credentials))?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn deserialize_upstreams<'de, D>(deserializer: D) -> std::result::Result<Vec<Upstream>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = OneOrMany::<UpstreamEntry>::deserialize(deserializer)?.into_vec();
+    if values.is_empty() {
+        return Err(serde::de::Error::custom(
+            "at least one upstream is required",
+        ));
+    }
+    values
+        .into_iter()
+        .map(|value| {
+            let (url, weight, priority, overrides, basic_auth) = match value {
+                UpstreamEntry::Url(url) => (url, default_weight(), 0, Overrides::default(), None),
+                UpstreamEntry::Table(UpstreamTable {
+                    url,
+                    weight,
+                    priority,
+                    request_timeout,
+                    retries,
+                    http2_only,
+                    preserve_host,
+                    basic_auth,
+                }) => (
+                    url,
+                    weight,
+                    priority,
+                    Overrides {
+                        request_timeout,
+                        retries,
+                        http2_only,
+                        preserve_host,
+                    },
+                    basic_auth,
+                ),
+            };
+            let uri = parse_upstream_uri(&url)?;
+            ensure!(
+                !overrides.http2_only || uri.scheme_str() == Some("http"),
+                "Upstream {:?} can only use http2_only with the http scheme; https \
+                 negotiates HTTP/2 by itself",
+                url
+            );
+            Ok(Upstream {
+                uri,
+                weight,
+                priority,
+                overrides,
+                authorization: basic_auth
+                    .as_ref()
+                    .map(UpstreamBasicAuth::header_value)
+                    .transpose()?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+        .map_err(|err| serde::de::Error::custom(format!("{:#}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_json, echo_upstream, Proxy, TempDir, ENV};
+    use hyper::header::AUTHORIZATION;
+    use hyper::{Body, Request};
+
+    #[test]
+    fn loads_a_valid_file() {
+        let dir = TempDir::new();
+        let path = dir.write(
+            "vostok.toml",
+            "listen_addrs = [\"127.0.0.1:3001\", \"[::1]:3001\"]\n\
+             proxy_url = \"http://127.0.0.1:8080/base\"\n\
+             log_level = \"info\"\n",
+        );
+        let config = Config::load(&path).unwrap();
+        assert_eq!(
+            config.listen_addrs,
+            vec![
+                "127.0.0.1:3001".parse::<SocketAddr>().unwrap(),
+                "[::1]:3001".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.upstreams.len(), 1);
+        assert_eq!(config.upstreams[0].uri, "http://127.0.0.1:8080/base");
+        assert_eq!(config.log_level, log::LevelFilter::Info);
+    }
+
+    #[test]
+    fn defaults_everything_but_the_upstream() {
+        let config = Config::parse("proxy_url = \"http://127.0.0.1:8080\"").unwrap();
+        assert_eq!(config.listen_addrs, default_listen_addrs());
+        assert_eq!(config.log_level, log::LevelFilter::Debug);
+    }
+
+    #[test]
+    fn rejects_an_invalid_proxy_url() {
+        for proxy_url in [
+            "ftp://example.com",
+            "http://",
+            "not a url",
+            "unix:relative.sock",
+        ] {
+            let contents = format!("proxy_url = {:?}", proxy_url);
+            let err = Config::parse(&contents).unwrap_err();
+            assert!(
+                format!("{:#}", err).contains(proxy_url),
+                "{}: {:#}",
+                proxy_url,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn requires_an_upstream() {
+        assert!(Config::parse("").is_err());
+        assert!(Config::parse("upstreams = []").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let err = Config::parse("proxy_url = \"http://127.0.0.1\"\nproxy_ulr = \"x\"").unwrap_err();
+        assert!(format!("{:#}", err).contains("proxy_ulr"));
+    }
+
+    #[test]
+    fn rejects_unknown_upstream_keys() {
+        for upstreams in [
+            r#"upstreams = [{ url = "http://billing", wieght = 5 }]"#,
+            r#"upstreams = { url = "http://billing", retires = 1 }"#,
+        ] {
+            assert!(Config::parse(upstreams).is_err(), "{}", upstreams);
+        }
+        let config =
+            Config::parse(r#"upstreams = [{ url = "http://billing", weight = 5 }]"#).unwrap();
+        assert_eq!(config.upstreams[0].weight, 5);
+    }
+
+    #[test]
+    fn names_a_missing_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("missing.toml");
+        let err = Config::load(&path).unwrap_err();
+        assert!(format!("{:#}", err).contains("missing.toml"));
+    }
+
+    #[test]
+    fn prefers_the_cli_argument_then_the_env_var() {
+        assert_eq!(
+            config_path(Some("cli.toml".into()), Some("env.toml".into())),
+            PathBuf::from("cli.toml")
+        );
+        assert_eq!(
+            config_path(None, Some("env.toml".into())),
+            PathBuf::from("env.toml")
+        );
+        assert_eq!(config_path(None, None), PathBuf::from(DEFAULT_CONFIG_PATH));
+    }
+
+    #[test]
+    fn interpolates_environment_variables() {
+        let _env = ENV.lock().unwrap();
+        std::env::set_var("VOSTOK_TEST_INTERPOLATED_HOST", "10.0.0.7");
+        std::env::set_var("VOSTOK_TEST_INTERPOLATED_PORT", "8080");
+        assert_eq!(
+            interpolate_str(
+                "http://${VOSTOK_TEST_INTERPOLATED_HOST}:${VOSTOK_TEST_INTERPOLATED_PORT}/"
+            )
+            .unwrap(),
+            "http://10.0.0.7:8080/"
+        );
+        assert_eq!(
+            interpolate_str("$${VOSTOK_TEST_INTERPOLATED_HOST} costs $5").unwrap(),
+            "${VOSTOK_TEST_INTERPOLATED_HOST} costs $5"
+        );
+        assert_eq!(interpolate_str("plain").unwrap(), "plain");
+
+        let config =
+            Config::parse("[[upstreams]]\nurl = \"http://${VOSTOK_TEST_INTERPOLATED_HOST}\"\n")
+                .unwrap();
+        assert_eq!(config.upstreams[0].uri, "http://10.0.0.7");
+    }
+
+    #[test]
+    fn missing_or_malformed_references_are_errors() {
+        let _env = ENV.lock().unwrap();
+        std::env::remove_var("VOSTOK_TEST_INTERPOLATED_MISSING");
+        let err = interpolate_str("${VOSTOK_TEST_INTERPOLATED_MISSING}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Environment variable VOSTOK_TEST_INTERPOLATED_MISSING is not set"
+        );
+        assert!(interpolate_str("${UNCLOSED").is_err());
+        assert!(interpolate_str("${}").is_err());
+
+        let err =
+            Config::parse("[[upstreams]]\nurl = \"http://${VOSTOK_TEST_INTERPOLATED_MISSING}\"\n")
+                .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("In upstreams[0].url"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn upstream_basic_auth_becomes_a_sensitive_header() {
+        let config = Config::parse(
+            r#"upstreams = [{ url = "http://billing", basic_auth = { username = "vostok", password = "s3cret" } }]"#,
+        )
+        .unwrap();
+        let authorization = config.upstreams[0].authorization.as_ref().unwrap();
+        assert_eq!(
+            authorization,
+            &format!("Basic {}", base64::encode("vostok:s3cret"))
+        );
+        assert!(authorization.is_sensitive());
+        assert_eq!(
+            config.source["upstreams"][0]["basic_auth"]["password"],
+            "<redacted>"
+        );
+
+        let config = Config::parse("upstreams = \"http://billing\"").unwrap();
+        assert!(config.upstreams[0].authorization.is_none());
+        assert!(Config::parse(
+            r#"upstreams = [{ url = "http://billing", basic_auth = { username = "a:b", password = "c" } }]"#,
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn upstreams_get_their_configured_authorization() {
+        let (billing, other) = (echo_upstream().await, echo_upstream().await);
+        let proxy = Proxy::start(&format!(
+            r#"
+            upstreams = "http://{}"
+            [[routes]]
+            path_prefix = "/billing"
+            upstreams = [{{ url = "http://{}", basic_auth = {{ username = "vostok", password = "s3cret" }} }}]
+            "#,
+            other, billing
+        ))
+        .await;
+        let send = |path: &str| {
+            proxy.send(
+                Request::get(path)
+                    .header(AUTHORIZATION, "Bearer client-token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let echo = body_json(send("/billing/invoices").await).await;
+        let expected = format!("Basic {}", base64::encode("vostok:s3cret"));
+        assert_eq!(
+            echo["headers"]["authorization"],
+            serde_json::json!([expected])
+        );
+        // Upstreams without credentials get the client's.
+        let echo = body_json(send("/path").await).await;
+        assert_eq!(
+            echo["headers"]["authorization"],
+            serde_json::json!(["Bearer client-token"])
+        );
+    }
+}
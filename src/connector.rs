@@ -0,0 +1,518 @@
+use crate::dns::UpstreamResolver;
+use crate::tls;
+use anyhow::{ensure, Context as _};
+use futures_util::future::{BoxFuture, FutureExt};
+use hyper::client::connect::{Connected, Connection, HttpConnector};
+use hyper::service::Service;
+use hyper::Uri;
+use hyper_rustls::{HttpsConnector, MaybeHttpsStream};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Upstreams served on a Unix socket use this scheme, with the socket path hex-encoded
+/// as the host since a path can't appear in a URI authority.
+pub const UNIX_SCHEME: &str = "unix";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    /// PEM file with CA certificates trusted on top of the system's root certificates,
+    /// e.g. a private CA the upstreams' certificates are issued by.
+    #[serde(default)]
+    pub ca_path: Option<PathBuf>,
+    /// Check upstream certificates at all. Turning this off accepts any certificate for
+    /// any host, so it's only meant for testing.
+    #[serde(default = "default_verify")]
+    pub verify: bool,
+}
+
+impl Default for UpstreamTlsConfig {
+    fn default() -> UpstreamTlsConfig {
+        UpstreamTlsConfig {
+            ca_path: None,
+            verify: default_verify(),
+        }
+    }
+}
+
+fn default_verify() -> bool {
+    true
+}
+
+/// Accepts every certificate, for `verify = false`.
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Connects to upstreams over TCP (with TLS for `https`) or over a Unix socket, so the
+/// rest of the proxy doesn't care which transport an upstream uses.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    https: HttpsConnector<HttpConnector<UpstreamResolver>>,
+    prior_knowledge: PriorKnowledge,
+}
+
+/// Plain `http` upstreams that only speak HTTP/2 (h2c), so their connections skip
+/// HTTP/1.1 and start with the HTTP/2 preface. Kept in sync with the active routing.
+#[derive(Clone, Default)]
+pub struct PriorKnowledge(Arc<RwLock<HashSet<String>>>);
+
+impl PriorKnowledge {
+    /// Only affects new connections; pooled ones keep the protocol they started with.
+    pub fn set<'a>(&self, upstreams: impl Iterator<Item = &'a Uri>) {
+        *self.0.write().unwrap() = upstreams.map(origin).collect();
+    }
+
+    fn contains(&self, uri: &Uri) -> bool {
+        self.0.read().unwrap().contains(&origin(uri))
+    }
+}
+
+/// hyper pools connections by scheme and authority, so that's what identifies an
+/// upstream's connections.
+fn origin(uri: &Uri) -> String {
+    format!(
+        "{}://{}",
+        uri.scheme_str().unwrap_or_default(),
+        uri.authority().map_or("", |authority| authority.as_str())
+    )
+}
+
+impl UpstreamConnector {
+    /// Trusts the system's root certificates for `https` upstreams, plus the configured CA
+    /// certificates, unless verification is off.
+    pub fn new(
+        resolver: UpstreamResolver,
+        prior_knowledge: PriorKnowledge,
+        config: &UpstreamTlsConfig,
+    ) -> anyhow::Result<UpstreamConnector> {
+        let mut http = HttpConnector::new_with_resolver(resolver);
+        http.enforce_http(false);
+
+        let mut tls = ClientConfig::new();
+        if config.verify {
+            tls.root_store = root_store(config.ca_path.as_deref())?;
+        } else {
+            log::warn!(
+                "Upstream TLS certificate verification is disabled, any certificate is accepted. Don't use this in production!"
+            );
+            tls.dangerous()
+                .set_certificate_verifier(Arc::new(NoVerification));
+        }
+        tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        tls.ct_logs = Some(&ct_logs::LOGS);
+
+        Ok(UpstreamConnector {
+            https: (http, tls).into(),
+            prior_knowledge,
+        })
+    }
+}
+
+/// The native root certificates plus the ones in `ca_path`. Native roots that fail to
+/// load are only fatal when there's no CA file to fall back on.
+fn root_store(ca_path: Option<&Path>) -> anyhow::Result<RootCertStore> {
+    let mut store = match rustls_native_certs::load_native_certs() {
+        Ok(store) => store,
+        Err((Some(store), err)) => {
+            log::warn!("Could not load all native root certificates: {}", err);
+            store
+        }
+        Err((None, err)) if ca_path.is_some() => {
+            log::warn!("Could not load native root certificates: {}", err);
+            RootCertStore::empty()
+        }
+        Err((None, err)) => return Err(err).context("Loading native root certificates"),
+    };
+    if let Some(ca_path) = ca_path {
+        for cert in tls::load_certs(ca_path)? {
+            store
+                .add(&cert)
+                .with_context(|| format!("Invalid CA certificate in {}", ca_path.display()))?;
+        }
+    }
+    ensure!(!store.is_empty(), "No native root certificates found");
+    Ok(store)
+}
+
+pub struct UpstreamStream {
+    transport: Transport,
+    http2_prior_knowledge: bool,
+}
+
+enum Transport {
+    Tcp(Box<MaybeHttpsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<UpstreamStream, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.https.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let http2_prior_knowledge = self.prior_knowledge.contains(&uri);
+        match unix_socket_path(&uri) {
+            None => self
+                .https
+                .call(uri)
+                .map(move |tcp| {
+                    tcp.map(|tcp| UpstreamStream {
+                        transport: Transport::Tcp(Box::new(tcp)),
+                        http2_prior_knowledge,
+                    })
+                })
+                .boxed(),
+            #[cfg(unix)]
+            Some(path) => async move {
+                let stream = tokio::net::UnixStream::connect(&path)
+                    .await
+                    .map_err(|err| {
+                        io::Error::new(
+                            err.kind(),
+                            format!("Connecting to {}: {}", path.display(), err),
+                        )
+                    })?;
+                Ok(UpstreamStream {
+                    transport: Transport::Unix(stream),
+                    http2_prior_knowledge,
+                })
+            }
+            .boxed(),
+            #[cfg(not(unix))]
+            Some(path) => {
+                let err = format!("Can't connect to {}: not a Unix system", path.display());
+                futures_util::future::ready(Err(err.into())).boxed()
+            }
+        }
+    }
+}
+
+/// The socket path of a `unix://` upstream URI, or `None` for TCP upstreams.
+pub fn unix_socket_path(uri: &Uri) -> Option<PathBuf> {
+    if uri.scheme_str() != Some(UNIX_SCHEME) {
+        return None;
+    }
+    let host = uri.host()?.as_bytes();
+    let bytes = host
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(PathBuf::from(String::from_utf8(bytes).ok()?))
+}
+
+/// The `unix://` URI for a socket at `path`.
+pub fn unix_socket_uri(path: &str) -> String {
+    let host = path
+        .bytes()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!("{}://{}/", UNIX_SCHEME, host)
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        let connected = match &self.transport {
+            Transport::Tcp(stream) => stream.connected(),
+            #[cfg(unix)]
+            Transport::Unix(_) => Connected::new(),
+        };
+        // Tells hyper to start HTTP/2 as it would after negotiating it with ALPN.
+        if self.http2_prior_knowledge {
+            connected.negotiated_h2()
+        } else {
+            connected
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Transport::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Transport::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_json, Proxy, TempDir};
+    use hyper::service::service_fn;
+    use hyper::{Body, Request, Response, StatusCode};
+    use std::convert::Infallible;
+
+    /// Serves `stream` with a description of every request on it: its URI, Host header
+    /// and HTTP version.
+    fn serve_echo<S>(stream: S, http: &hyper::server::conn::Http)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let service = service_fn(|req: Request<Body>| async move {
+            let echo = serde_json::json!({
+                "uri": req.uri().to_string(),
+                "host": req.headers().get(hyper::header::HOST).map(|host| host.to_str().unwrap()),
+                "version": format!("{:?}", req.version()),
+            });
+            Result::<_, Infallible>::Ok(Response::new(Body::from(echo.to_string())))
+        });
+        tokio::spawn(http.serve_connection(stream, service));
+    }
+
+    /// Answers every request on a socket at `path`.
+    #[cfg(unix)]
+    fn unix_upstream(path: &Path) {
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                serve_echo(stream, &hyper::server::conn::Http::new());
+            }
+        });
+    }
+
+    /// An upstream on plain TCP that speaks both HTTP/1 and HTTP/2, or with
+    /// `http2_only`, just HTTP/2.
+    async fn tcp_upstream(http2_only: bool) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut http = hyper::server::conn::Http::new();
+            http.http2_only(http2_only);
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                serve_echo(stream, &http);
+            }
+        });
+        addr
+    }
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    /// An upstream on HTTPS with the test certificate for `localhost`, which isn't
+    /// trusted without the test CA.
+    async fn https_upstream() -> u16 {
+        let acceptor = tls::acceptor(&tls::TlsConfig {
+            cert_path: testdata("localhost.crt"),
+            key_path: testdata("localhost.key"),
+        })
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        serve_echo(stream, &hyper::server::conn::Http::new());
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn socket_paths_round_trip_through_uris() {
+        let uri: Uri = unix_socket_uri("/run/app/http.sock").parse().unwrap();
+        assert_eq!(uri.scheme_str(), Some(UNIX_SCHEME));
+        assert_eq!(
+            unix_socket_path(&uri),
+            Some(PathBuf::from("/run/app/http.sock"))
+        );
+        assert_eq!(
+            unix_socket_path(&"http://localhost:8080/".parse().unwrap()),
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn proxies_to_an_upstream_on_a_unix_socket() {
+        let dir = TempDir::new();
+        let socket = dir.path().join("upstream.sock");
+        unix_upstream(&socket);
+        let proxy = Proxy::start(&format!("upstreams = \"unix:{}\"", socket.display())).await;
+
+        let response = proxy.get("/path?query").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let echo = body_json(response).await;
+        assert_eq!(echo["uri"], "/path?query");
+        assert_eq!(echo["host"], "localhost");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_missing_socket_is_a_bad_gateway() {
+        let dir = TempDir::new();
+        let socket = dir.path().join("missing.sock");
+        let proxy = Proxy::start(&format!("upstreams = \"unix:{}\"", socket.display())).await;
+        assert_eq!(proxy.get("/path").await.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn speaks_http2_with_prior_knowledge_to_h2c_upstreams() {
+        let (h2c, http1) = (tcp_upstream(true).await, tcp_upstream(false).await);
+        let proxy = Proxy::start(&format!(
+            r#"
+            upstreams = "http://{}"
+            [[routes]]
+            path_prefix = "/h2c"
+            upstreams = [{{ url = "http://{}", http2_only = true }}]
+            "#,
+            http1, h2c
+        ))
+        .await;
+
+        for _ in 0..2 {
+            let response = proxy.get("/h2c/path").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let echo = body_json(response).await;
+            assert_eq!(echo["version"], "HTTP/2.0");
+            assert_eq!(echo["uri"], format!("http://{}/h2c/path", h2c));
+        }
+
+        // Other upstreams still get HTTP/1.1, even though they'd take HTTP/2.
+        let response = proxy.get("/path").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let echo = body_json(response).await;
+        assert_eq!(echo["version"], "HTTP/1.1");
+        assert_eq!(echo["uri"], "/path");
+        assert_eq!(echo["host"], http1.to_string());
+    }
+
+    #[test]
+    fn prior_knowledge_is_per_origin() {
+        let prior_knowledge = PriorKnowledge::default();
+        let h2c: Uri = "http://h2c:8080".parse().unwrap();
+        prior_knowledge.set(std::iter::once(&h2c));
+        assert!(prior_knowledge.contains(&"http://h2c:8080/any/path".parse().unwrap()));
+        assert!(!prior_knowledge.contains(&"http://h2c:8081/".parse().unwrap()));
+        assert!(!prior_knowledge.contains(&"https://h2c:8080/".parse().unwrap()));
+
+        prior_knowledge.set(std::iter::empty());
+        assert!(!prior_knowledge.contains(&h2c));
+    }
+
+    #[tokio::test]
+    async fn trusts_upstreams_certified_by_the_configured_ca() {
+        let port = https_upstream().await;
+        let upstreams = format!("upstreams = \"https://localhost:{}\"\n", port);
+
+        let proxy = Proxy::start(&format!(
+            "{}[client.tls]\nca_path = \"{}\"",
+            upstreams,
+            testdata("ca.crt").display()
+        ))
+        .await;
+        let response = proxy.get("/path").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Negotiated over ALPN, so it's HTTP/2 with the full URI.
+        let echo = body_json(response).await;
+        assert_eq!(echo["version"], "HTTP/2.0");
+        assert_eq!(echo["uri"], format!("https://localhost:{}/path", port));
+
+        // The system's roots alone don't cover the test CA.
+        let proxy = Proxy::start(&upstreams).await;
+        assert_eq!(proxy.get("/path").await.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn accepts_any_certificate_without_verification() {
+        let port = https_upstream().await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"https://localhost:{}\"\n[client.tls]\nverify = false",
+            port
+        ))
+        .await;
+        assert_eq!(proxy.get("/path").await.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn ca_files_must_hold_valid_certificates() {
+        let dir = TempDir::new();
+        let build = |ca_path: PathBuf| {
+            let config = crate::client::ClientConfig {
+                tls: UpstreamTlsConfig {
+                    ca_path: Some(ca_path),
+                    verify: true,
+                },
+                ..Default::default()
+            };
+            crate::client::build(&config, PriorKnowledge::default()).map(|_| ())
+        };
+        assert!(build(testdata("ca.crt")).is_ok());
+        assert!(build(dir.path().join("missing.pem")).is_err());
+        assert!(build(dir.write("empty.pem", "")).is_err());
+        // A key is no certificate.
+        assert!(build(testdata("ca.key")).is_err());
+    }
+}
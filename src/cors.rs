@@ -0,0 +1,304 @@
+use crate::middleware::{reject, EarlyResponse};
+use anyhow::*;
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::debug;
+use routerify::prelude::*;
+use routerify::RequestInfo;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to make cross-origin requests; `"*"` allows any.
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    /// Response headers browsers may expose to scripts.
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response.
+    #[serde(default, with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+}
+
+fn default_allowed_methods() -> Vec<String> {
+    vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()]
+}
+
+pub struct Cors {
+    any_origin: bool,
+    allowed_origins: Vec<HeaderValue>,
+    allow_credentials: bool,
+    allow_methods: HeaderValue,
+    allow_headers: Option<HeaderValue>,
+    expose_headers: Option<HeaderValue>,
+    max_age: Option<HeaderValue>,
+}
+
+impl Cors {
+    pub fn new(config: &CorsConfig) -> Result<Cors> {
+        ensure!(
+            !config.allowed_origins.is_empty(),
+            "cors.allowed_origins can't be empty"
+        );
+        let any_origin = config.allowed_origins.iter().any(|origin| origin == "*");
+        let allowed_origins = config
+            .allowed_origins
+            .iter()
+            .filter(|origin| *origin != "*")
+            .map(|origin| {
+                HeaderValue::from_str(origin.trim_end_matches('/'))
+                    .with_context(|| format!("Invalid CORS origin {:?}", origin))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Cors {
+            any_origin,
+            allowed_origins,
+            allow_credentials: config.allow_credentials,
+            allow_methods: join_header(&config.allowed_methods)
+                .context("Invalid cors.allowed_methods")?
+                .context("cors.allowed_methods can't be empty")?,
+            allow_headers: join_header(&config.allowed_headers)
+                .context("Invalid cors.allowed_headers")?,
+            expose_headers: join_header(&config.expose_headers)
+                .context("Invalid cors.expose_headers")?,
+            max_age: config
+                .max_age
+                .map(|max_age| HeaderValue::from(max_age.as_secs())),
+        })
+    }
+
+    /// The `Access-Control-Allow-Origin` value for `origin`, if it's allowed. A wildcard
+    /// can't be combined with credentials, so then the origin is echoed back instead.
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        if self.any_origin && !self.allow_credentials {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let allowed = self.any_origin
+            || self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.as_bytes().eq_ignore_ascii_case(origin.as_bytes()));
+        if allowed {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+}
+
+fn join_header(values: &[String]) -> Result<Option<HeaderValue>> {
+    if values.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(HeaderValue::from_str(&values.join(", "))?))
+}
+
+fn is_preflight<T>(req: &Request<T>) -> bool {
+    req.method() == Method::OPTIONS
+        && req.headers().contains_key(ORIGIN)
+        && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Answers preflight requests directly; browsers send them without credentials, so this
+/// runs before any auth middleware. Whether the actual request is allowed is left to the
+/// browser, based on the advertised methods and headers.
+pub async fn preflight(req: Request<Body>) -> Result<Request<Body>> {
+    if !is_preflight(&req) {
+        return Ok(req);
+    }
+    let env = req.data::<Arc<crate::Env>>().unwrap();
+    let cors = match &env.cors {
+        Some(cors) => cors,
+        None => return Ok(req),
+    };
+
+    let origin = req.headers().get(ORIGIN).unwrap();
+    if cors.allow_origin(origin).is_none() {
+        debug!("Rejecting CORS preflight from origin {:?}", origin);
+        return Err(reject(
+            &req,
+            EarlyResponse::new(StatusCode::FORBIDDEN, "Forbidden"),
+        ));
+    }
+
+    let mut response = EarlyResponse::new(StatusCode::NO_CONTENT, "")
+        .header(ACCESS_CONTROL_ALLOW_METHODS, cors.allow_methods.clone());
+    if let Some(allow_headers) = &cors.allow_headers {
+        response = response.header(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers.clone());
+    }
+    if let Some(max_age) = &cors.max_age {
+        response = response.header(ACCESS_CONTROL_MAX_AGE, max_age.clone());
+    }
+    Err(reject(&req, response))
+}
+
+/// Adds the origin and credentials headers to every response to an allowed origin,
+/// including preflight answers and error responses.
+pub async fn add_cors_headers(
+    mut res: Response<Body>,
+    req_info: RequestInfo,
+) -> Result<Response<Body>> {
+    let cors = match req_info
+        .data::<Arc<crate::Env>>()
+        .and_then(|env| env.cors.as_ref())
+    {
+        Some(cors) => cors,
+        None => return Ok(res),
+    };
+    let origin = match req_info.headers().get(ORIGIN) {
+        Some(origin) => origin,
+        None => return Ok(res),
+    };
+    let allow_origin = match cors.allow_origin(origin) {
+        Some(allow_origin) => allow_origin,
+        None => return Ok(res),
+    };
+
+    let headers = res.headers_mut();
+    if allow_origin != "*" {
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    if cors.allow_credentials {
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    if let Some(expose_headers) = &cors.expose_headers {
+        headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, expose_headers.clone());
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{echo_upstream, Proxy};
+    use hyper::header::ACCESS_CONTROL_REQUEST_HEADERS;
+
+    fn cors(origins: &[&str], allow_credentials: bool) -> Cors {
+        Cors::new(&CorsConfig {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            allowed_methods: default_allowed_methods(),
+            allowed_headers: Vec::new(),
+            expose_headers: Vec::new(),
+            allow_credentials,
+            max_age: None,
+        })
+        .unwrap()
+    }
+
+    fn allow_origin(cors: &Cors, origin: &'static str) -> Option<HeaderValue> {
+        cors.allow_origin(&HeaderValue::from_static(origin))
+    }
+
+    #[test]
+    fn allows_listed_origins() {
+        let cors = cors(&["https://app.example.com/"], false);
+        assert_eq!(
+            allow_origin(&cors, "https://APP.example.com").unwrap(),
+            "https://APP.example.com"
+        );
+        assert_eq!(allow_origin(&cors, "https://evil.example.com"), None);
+        assert_eq!(allow_origin(&cors, "http://app.example.com"), None);
+    }
+
+    #[test]
+    fn a_wildcard_is_echoed_back_with_credentials() {
+        let wildcard = cors(&["*"], false);
+        assert_eq!(allow_origin(&wildcard, "https://a.example").unwrap(), "*");
+        let with_credentials = cors(&["*"], true);
+        assert_eq!(
+            allow_origin(&with_credentials, "https://a.example").unwrap(),
+            "https://a.example"
+        );
+    }
+
+    async fn proxy() -> Proxy {
+        let upstream = echo_upstream().await;
+        Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[cors]\nallowed_origins = [\"https://app.example.com\"]\nallowed_methods = [\"GET\", \"PUT\"]\nallowed_headers = [\"Content-Type\"]\nexpose_headers = [\"X-Request-Id\"]\nallow_credentials = true\nmax_age = \"10m\"",
+            upstream
+        ))
+        .await
+    }
+
+    fn preflight_request(origin: &str) -> Request<Body> {
+        Request::options("/path")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_preflights_directly() {
+        let proxy = proxy().await;
+        let response = proxy
+            .send(preflight_request("https://app.example.com"))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, PUT");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "Content-Type");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(headers[VARY], "Origin");
+
+        let response = proxy.send(preflight_request("https://evil.example")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn adds_headers_to_simple_requests_from_allowed_origins() {
+        let proxy = proxy().await;
+        let get = |origin: &'static str| {
+            proxy.send(
+                Request::get("/path")
+                    .header(ORIGIN, origin)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get("https://app.example.com").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[ACCESS_CONTROL_EXPOSE_HEADERS], "X-Request-Id");
+        assert_eq!(headers[VARY], "Origin");
+        // Preflight-only headers stay off actual responses.
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_METHODS));
+
+        // Other origins are still proxied, just without permission to read the response.
+        let response = get("https://evil.example").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!response
+            .headers()
+            .contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+    }
+}
@@ -0,0 +1,283 @@
+use anyhow::*;
+use futures_util::future::{BoxFuture, FutureExt};
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::service::Service;
+use log::debug;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsCacheConfig {
+    /// How long a lookup is reused before it's refreshed.
+    #[serde(default = "default_ttl", with = "humantime_serde")]
+    pub ttl: Duration,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+fn default_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_max_entries() -> usize {
+    1024
+}
+
+struct Entry {
+    addrs: Vec<SocketAddr>,
+    expires: Instant,
+    refreshing: bool,
+}
+
+struct DnsCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl DnsCache {
+    fn insert(&self, host: String, addrs: Vec<SocketAddr>, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&host) && entries.len() >= self.max_entries {
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(host, _)| host.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(
+            host,
+            Entry {
+                addrs,
+                expires: now + self.ttl,
+                refreshing: false,
+            },
+        );
+    }
+}
+
+/// Resolves upstream hostnames with `getaddrinfo`, optionally remembering the answers
+/// for a TTL. An expired answer is still used once more while a fresh lookup runs in
+/// the background, so only a host's first lookup ever waits on DNS.
+#[derive(Clone)]
+pub struct UpstreamResolver {
+    lookup: Lookup,
+    cache: Option<Arc<DnsCache>>,
+}
+
+/// Looks a host up uncached; `getaddrinfo` outside of tests.
+type Lookup = Arc<dyn Fn(Name) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> + Send + Sync>;
+
+impl UpstreamResolver {
+    pub fn new(config: Option<&DnsCacheConfig>) -> Result<UpstreamResolver> {
+        let gai = GaiResolver::new();
+        UpstreamResolver::with_lookup(
+            config,
+            Arc::new(move |name| resolve(gai.clone(), name).boxed()),
+        )
+    }
+
+    fn with_lookup(config: Option<&DnsCacheConfig>, lookup: Lookup) -> Result<UpstreamResolver> {
+        let cache = match config {
+            None => None,
+            Some(config) => {
+                ensure!(
+                    config.max_entries > 0,
+                    "client.dns_cache.max_entries must be at least 1"
+                );
+                Some(Arc::new(DnsCache {
+                    ttl: config.ttl,
+                    max_entries: config.max_entries,
+                    entries: Mutex::new(HashMap::new()),
+                }))
+            }
+        };
+        Ok(UpstreamResolver { lookup, cache })
+    }
+}
+
+/// A failed lookup, kept recognizable through hyper's connect error.
+#[derive(Debug)]
+pub struct ResolveError {
+    host: String,
+    source: io::Error,
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Resolving {}: {}", self.host, self.source)
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+async fn resolve(mut gai: GaiResolver, name: Name) -> io::Result<Vec<SocketAddr>> {
+    let host = name.as_str().to_string();
+    match gai.call(name).await {
+        Result::Ok(addrs) => Result::Ok(addrs.collect()),
+        Err(source) => Err(io::Error::new(source.kind(), ResolveError { host, source })),
+    }
+}
+
+impl Service<Name> for UpstreamResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<Self::Response>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Result::Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let lookup = self.lookup.clone();
+        let cache = match &self.cache {
+            None => return lookup(name).map(|addrs| addrs.map(Vec::into_iter)).boxed(),
+            Some(cache) => cache.clone(),
+        };
+
+        let host = name.as_str().to_string();
+        let now = Instant::now();
+        let cached = {
+            let mut entries = cache.entries.lock().unwrap();
+            match entries.get_mut(&host) {
+                None => None,
+                Some(entry) => {
+                    let refresh = entry.expires <= now && !entry.refreshing;
+                    entry.refreshing |= refresh;
+                    Some((entry.addrs.clone(), refresh))
+                }
+            }
+        };
+
+        match cached {
+            Some((addrs, refresh)) => {
+                if refresh {
+                    tokio::spawn(async move {
+                        match lookup(name).await {
+                            Result::Ok(addrs) => cache.insert(host, addrs, Instant::now()),
+                            Err(err) => {
+                                // Resolve afresh next time rather than reuse a stale answer.
+                                debug!("Refreshing DNS for {} failed: {}", host, err);
+                                cache.entries.lock().unwrap().remove(&host);
+                            }
+                        }
+                    });
+                }
+                futures_util::future::ready(Result::Ok(addrs.into_iter())).boxed()
+            }
+            None => async move {
+                let addrs = lookup(name).await?;
+                cache.insert(host, addrs.clone(), Instant::now());
+                Result::Ok(addrs.into_iter())
+            }
+            .boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    const TTL: Duration = Duration::from_millis(200);
+
+    /// A resolver whose stubbed lookups answer with port 1 the first time, then 2 and so
+    /// on, and fail for hosts starting with `fail`.
+    fn resolver(max_entries: usize) -> (UpstreamResolver, Arc<AtomicU16>) {
+        let lookups = Arc::new(AtomicU16::new(0));
+        let count = lookups.clone();
+        let lookup: Lookup = Arc::new(move |name: Name| {
+            let port = count.fetch_add(1, Ordering::SeqCst) + 1;
+            let answer = if name.as_str().starts_with("fail") {
+                Err(io::Error::new(io::ErrorKind::NotFound, "no such host"))
+            } else {
+                Result::Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))])
+            };
+            futures_util::future::ready(answer).boxed()
+        });
+        let config = DnsCacheConfig {
+            ttl: TTL,
+            max_entries,
+        };
+        (
+            UpstreamResolver::with_lookup(Some(&config), lookup).unwrap(),
+            lookups,
+        )
+    }
+
+    async fn port(resolver: &mut UpstreamResolver, host: &str) -> io::Result<u16> {
+        let mut addrs = resolver.call(Name::from_str(host).unwrap()).await?;
+        Result::Ok(addrs.next().unwrap().port())
+    }
+
+    #[tokio::test]
+    async fn lookups_within_the_ttl_hit_the_cache() {
+        let (mut resolver, lookups) = resolver(16);
+        for _ in 0..5 {
+            assert_eq!(port(&mut resolver, "upstream.test").await.unwrap(), 1);
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        assert_eq!(port(&mut resolver, "other.test").await.unwrap(), 2);
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_refreshed_in_the_background() {
+        let (mut resolver, lookups) = resolver(16);
+        assert_eq!(port(&mut resolver, "upstream.test").await.unwrap(), 1);
+        tokio::time::sleep(TTL).await;
+
+        // The stale answer is used once more, and only one refresh is started.
+        assert_eq!(port(&mut resolver, "upstream.test").await.unwrap(), 1);
+        tokio::task::yield_now().await;
+        assert_eq!(port(&mut resolver, "upstream.test").await.unwrap(), 2);
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_are_not_cached() {
+        let (mut resolver, lookups) = resolver(16);
+        let err = port(&mut resolver, "fail.test").await.unwrap_err();
+        assert!(err.to_string().contains("no such host"), "{}", err);
+        assert!(port(&mut resolver, "fail.test").await.is_err());
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn the_soonest_to_expire_entry_is_evicted_when_full() {
+        let (mut resolver, lookups) = resolver(2);
+        port(&mut resolver, "a.test").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        port(&mut resolver, "b.test").await.unwrap();
+        port(&mut resolver, "c.test").await.unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+
+        port(&mut resolver, "b.test").await.unwrap();
+        port(&mut resolver, "c.test").await.unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+        assert_eq!(port(&mut resolver, "a.test").await.unwrap(), 4);
+    }
+
+    #[test]
+    fn max_entries_must_be_at_least_one() {
+        let config = DnsCacheConfig {
+            ttl: TTL,
+            max_entries: 0,
+        };
+        assert!(UpstreamResolver::new(Some(&config)).is_err());
+    }
+}
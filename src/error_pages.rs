@@ -0,0 +1,140 @@
+use anyhow::*;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// HTML pages keyed on status code, plus an optional `default` for every other status.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct ErrorPagesConfig(BTreeMap<String, PathBuf>);
+
+/// Marks a response Vostok made up itself, as opposed to one from an upstream.
+#[derive(Clone, Copy)]
+struct Generated;
+
+/// A plain text error response that [`ErrorPages::render`] may replace with a page.
+pub fn generated(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response.extensions_mut().insert(Generated);
+    response
+}
+
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    default: Option<Bytes>,
+    pages: HashMap<StatusCode, Bytes>,
+}
+
+impl ErrorPages {
+    /// Reads every page up front so a missing file fails startup rather than a request.
+    pub fn new(config: &ErrorPagesConfig) -> Result<ErrorPages> {
+        let mut error_pages = ErrorPages::default();
+        for (key, path) in &config.0 {
+            let page = std::fs::read(path)
+                .with_context(|| format!("Reading error page {}", path.display()))?;
+            if key == "default" {
+                error_pages.default = Some(page.into());
+                continue;
+            }
+            let status = key
+                .parse::<u16>()
+                .ok()
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .filter(|status| status.is_client_error() || status.is_server_error())
+                .with_context(|| {
+                    format!(
+                        "Invalid error_pages key {:?}, expected an error status or \"default\"",
+                        key
+                    )
+                })?;
+            error_pages.pages.insert(status, page.into());
+        }
+        Ok(error_pages)
+    }
+
+    /// Swaps the body of a [`generated`] response for its configured page. Upstream
+    /// responses are always passed through as they are.
+    pub fn render(&self, mut response: Response<Body>) -> Response<Body> {
+        if response.extensions().get::<Generated>().is_none() {
+            return response;
+        }
+        let page = match self.pages.get(&response.status()).or(self.default.as_ref()) {
+            Some(page) => page.clone(),
+            None => return response,
+        };
+        *response.body_mut() = Body::from(page);
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_string, TempDir};
+
+    fn pages(dir: &TempDir, pages: &[(&str, &str)]) -> Result<ErrorPages> {
+        let config = pages
+            .iter()
+            .map(|(key, page)| (key.to_string(), dir.write(&format!("{}.html", key), page)))
+            .collect();
+        ErrorPages::new(&ErrorPagesConfig(config))
+    }
+
+    async fn rendered(pages: &ErrorPages, response: Response<Body>) -> (StatusCode, String) {
+        let response = pages.render(response);
+        (response.status(), body_string(response).await)
+    }
+
+    #[tokio::test]
+    async fn replaces_generated_responses_only() {
+        let dir = TempDir::new();
+        let pages = pages(&dir, &[("502", "bad gateway page"), ("default", "oops")]).unwrap();
+        let generated = |status| generated(status, "plain");
+
+        assert_eq!(
+            rendered(&pages, generated(StatusCode::BAD_GATEWAY)).await,
+            (StatusCode::BAD_GATEWAY, "bad gateway page".to_string())
+        );
+        assert_eq!(
+            rendered(&pages, generated(StatusCode::TOO_MANY_REQUESTS)).await,
+            (StatusCode::TOO_MANY_REQUESTS, "oops".to_string())
+        );
+
+        let upstream = Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Body::from("from the upstream"))
+            .unwrap();
+        assert_eq!(
+            rendered(&pages, upstream).await,
+            (StatusCode::BAD_GATEWAY, "from the upstream".to_string())
+        );
+
+        let without_default = ErrorPages::default();
+        assert_eq!(
+            rendered(&without_default, generated(StatusCode::BAD_GATEWAY)).await,
+            (StatusCode::BAD_GATEWAY, "plain".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_keys_and_missing_files() {
+        let dir = TempDir::new();
+        for key in ["200", "302", "999", "oops"].iter() {
+            assert!(pages(&dir, &[(key, "page")]).is_err(), "{}", key);
+        }
+        let missing = ErrorPagesConfig(
+            vec![("404".to_string(), dir.path().join("missing.html"))]
+                .into_iter()
+                .collect(),
+        );
+        assert!(ErrorPages::new(&missing).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use hyper::header::{HeaderMap, EXPECT};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectContinueConfig {
+    /// Forward `Expect: 100-continue` to the upstream. Off removes it, for upstreams that
+    /// mishandle it; clients get their `100 Continue` either way.
+    #[serde(default = "default_relay")]
+    pub relay: bool,
+}
+
+impl Default for ExpectContinueConfig {
+    fn default() -> ExpectContinueConfig {
+        ExpectContinueConfig {
+            relay: default_relay(),
+        }
+    }
+}
+
+fn default_relay() -> bool {
+    true
+}
+
+/// What a request's `Expect` header asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expectation {
+    None,
+    /// The client waits for `100 Continue` before sending the body. hyper sends it when
+    /// the body is first read, so a request rejected before then never gets one.
+    Continue,
+    /// Anything else, which nothing can meet.
+    Unsupported,
+}
+
+pub fn expectation(headers: &HeaderMap) -> Expectation {
+    let mut values = headers.get_all(EXPECT).iter().peekable();
+    if values.peek().is_none() {
+        return Expectation::None;
+    }
+    if values.all(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue")) {
+        Expectation::Continue
+    } else {
+        Expectation::Unsupported
+    }
+}
+
+impl ExpectContinueConfig {
+    /// Removes the expectation from the upstream request unless it's relayed.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if !self.relay {
+            headers.remove(EXPECT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{echo_upstream, Proxy};
+    use hyper::header::HeaderValue;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    fn expecting(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(EXPECT, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn reads_the_expectation() {
+        assert_eq!(expectation(&expecting(&[])), Expectation::None);
+        assert_eq!(
+            expectation(&expecting(&["100-continue"])),
+            Expectation::Continue
+        );
+        assert_eq!(
+            expectation(&expecting(&["100-Continue"])),
+            Expectation::Continue
+        );
+        assert_eq!(
+            expectation(&expecting(&["100-continue", "x-other"])),
+            Expectation::Unsupported
+        );
+        assert_eq!(
+            expectation(&expecting(&["x-other"])),
+            Expectation::Unsupported
+        );
+    }
+
+    #[test]
+    fn apply_removes_the_header_unless_relayed() {
+        let mut headers = expecting(&["100-continue"]);
+        ExpectContinueConfig::default().apply(&mut headers);
+        assert_eq!(headers[EXPECT], "100-continue");
+        ExpectContinueConfig { relay: false }.apply(&mut headers);
+        assert!(headers.is_empty());
+    }
+
+    async fn proxy(relay: bool) -> Proxy {
+        let upstream = echo_upstream().await;
+        Proxy::start(&format!(
+            "upstreams = \"http://{}\"\nmax_body_bytes = 10\n[expect_continue]\nrelay = {}",
+            upstream, relay
+        ))
+        .await
+    }
+
+    /// Sends a POST's head with `headers`, and returns the stream and what came back
+    /// before any body was sent.
+    async fn send_head(proxy: &Proxy, headers: &str) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        let head = format!(
+            "POST /path HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+            headers
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let mut received = vec![0; 4096];
+        let len = tokio::time::timeout(Duration::from_secs(3), stream.read(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        (
+            stream,
+            String::from_utf8_lossy(&received[..len]).into_owned(),
+        )
+    }
+
+    #[tokio::test]
+    async fn continues_then_relays_the_expectation() {
+        for relay in [true, false] {
+            let proxy = proxy(relay).await;
+            let (mut stream, received) =
+                send_head(&proxy, "Content-Length: 5\r\nExpect: 100-continue\r\n").await;
+            assert_eq!(received, "HTTP/1.1 100 Continue\r\n\r\n");
+
+            stream.write_all(b"hello").await.unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            let response = String::from_utf8_lossy(&response);
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+            assert_eq!(
+                response.contains(r#""expect":["100-continue"]"#),
+                relay,
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_early_without_continuing() {
+        let proxy = proxy(true).await;
+        let (_, received) =
+            send_head(&proxy, "Content-Length: 11\r\nExpect: 100-continue\r\n").await;
+        assert!(
+            received.starts_with("HTTP/1.1 417 Expectation Failed\r\n"),
+            "{}",
+            received
+        );
+
+        let (_, received) = send_head(&proxy, "Content-Length: 5\r\nExpect: x-other\r\n").await;
+        assert!(
+            received.starts_with("HTTP/1.1 417 Expectation Failed\r\n"),
+            "{}",
+            received
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE, TE};
+
+/// Whether the request is a gRPC call, with a `Content-Type` of `application/grpc` or
+/// one of its `+proto`-style variants.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    let mime = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime == "application/grpc" || mime.starts_with("application/grpc+")
+}
+
+/// gRPC servers refuse calls that don't send `TE: trailers`, which is a hop-by-hop
+/// header and so gets stripped along with the others. Puts it back.
+pub fn restore_te(headers: &mut HeaderMap) {
+    headers.insert(TE, HeaderValue::from_static("trailers"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{upstream, Proxy};
+    use hyper::body::HttpBody;
+    use hyper::{Body, Client, Request, Response, Version};
+
+    fn content_type(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn recognizes_grpc_content_types() {
+        assert!(is_grpc(&content_type("application/grpc")));
+        assert!(is_grpc(&content_type(
+            "Application/gRPC+proto; charset=utf-8"
+        )));
+        assert!(!is_grpc(&content_type("application/grpc-web")));
+        assert!(!is_grpc(&content_type("application/json")));
+        assert!(!is_grpc(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn unary_calls_keep_their_trailers() {
+        let upstream = upstream(|req: Request<Body>| async move {
+            assert_eq!(req.version(), Version::HTTP_2);
+            assert_eq!(req.headers()[TE], "trailers");
+            let message = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                sender.send_data(message).await.unwrap();
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                trailers.insert("grpc-message", HeaderValue::from_static("done"));
+                sender.send_trailers(trailers).await.unwrap();
+            });
+            let mut response = Response::new(body);
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+            response
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = [{{ url = \"http://{}\", http2_only = true }}]",
+            upstream
+        ))
+        .await;
+
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+        let req = Request::post(proxy.url("/helloworld.Greeter/SayHello"))
+            .header(CONTENT_TYPE, "application/grpc")
+            .header(TE, "trailers")
+            .body(Body::from(&b"\0\0\0\0\x05hello"[..]))
+            .unwrap();
+        let mut response = client.request(req).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/grpc");
+        let mut message = Vec::new();
+        while let Some(chunk) = response.data().await {
+            message.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(message, b"\0\0\0\0\x05hello");
+        let trailers = response.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["grpc-message"], "done");
+    }
+}
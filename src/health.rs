@@ -0,0 +1,609 @@
+use crate::balancer::Balancer;
+use crate::routing::SharedRouting;
+use anyhow::*;
+use hyper::client::connect::Connect;
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri};
+use log::{debug, info, warn};
+use routerify::prelude::*;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+pub async fn healthz_handler(_req: Request<Body>) -> Result<Response<Body>> {
+    Ok(Response::new(Body::from("OK")))
+}
+
+/// Ready once at least one upstream answers a HEAD request within the readiness timeout,
+/// and, with a warmup configured, once the warmup has succeeded.
+pub async fn readyz_handler(req: Request<Body>) -> Result<Response<Body>> {
+    let env = req.data::<Arc<crate::Env>>().unwrap();
+
+    let (status, body) = match env.warmup.as_ref().map(|warmup| warmup.state()) {
+        Some(WarmupState::Warming) => (StatusCode::SERVICE_UNAVAILABLE, "Warming up"),
+        Some(WarmupState::GaveUp) => (StatusCode::SERVICE_UNAVAILABLE, "Warmup failed"),
+        Some(WarmupState::Warm) | None => {
+            let mut ready = false;
+            for upstream in env.routing.current().upstreams() {
+                if check_upstream(&env.client, upstream, env.readiness_timeout).await {
+                    ready = true;
+                    break;
+                }
+            }
+            if ready {
+                (StatusCode::OK, "OK")
+            } else {
+                (StatusCode::SERVICE_UNAVAILABLE, "Upstream unavailable")
+            }
+        }
+    };
+    Ok(Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap())
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WarmupConfig {
+    /// Give up if no upstream has answered this long after startup.
+    #[serde(default = "default_warmup_max_time", with = "humantime_serde")]
+    pub max_time: Duration,
+    /// Time between rounds of probes.
+    #[serde(default = "default_warmup_interval", with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+fn default_warmup_max_time() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_warmup_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarmupState {
+    Warming,
+    Warm,
+    /// No upstream answered within `max_time`; only a restart tries again.
+    GaveUp,
+}
+
+/// Keeps `/readyz` failing after startup until an upstream has answered a probe, so no
+/// traffic is sent to an instance that can't proxy it yet.
+pub struct Warmup {
+    state: AtomicU8,
+}
+
+impl Warmup {
+    /// Starts probing every upstream of the active routing, like `/readyz` does, each
+    /// round waiting up to `timeout` for an answer.
+    pub fn spawn<C>(
+        config: &WarmupConfig,
+        client: Client<C, Body>,
+        routing: Arc<SharedRouting>,
+        timeout: Duration,
+    ) -> Result<Arc<Warmup>>
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+    {
+        ensure!(!config.interval.is_zero(), "warmup.interval can't be zero");
+        let warmup = Arc::new(Warmup {
+            state: AtomicU8::new(WarmupState::Warming as u8),
+        });
+        tokio::spawn(warmup.clone().run(*config, client, routing, timeout));
+        Ok(warmup)
+    }
+
+    pub fn state(&self) -> WarmupState {
+        match self.state.load(Ordering::Relaxed) {
+            state if state == WarmupState::Warm as u8 => WarmupState::Warm,
+            state if state == WarmupState::GaveUp as u8 => WarmupState::GaveUp,
+            _ => WarmupState::Warming,
+        }
+    }
+
+    async fn run<C>(
+        self: Arc<Self>,
+        config: WarmupConfig,
+        client: Client<C, Body>,
+        routing: Arc<SharedRouting>,
+        timeout: Duration,
+    ) where
+        C: Connect + Clone + Send + Sync + 'static,
+    {
+        let started = Instant::now();
+        let mut ticker = tokio::time::interval(config.interval);
+        loop {
+            ticker.tick().await;
+            let upstreams = routing.current().upstreams().cloned().collect::<Vec<_>>();
+            let results = futures_util::future::join_all(
+                upstreams
+                    .iter()
+                    .map(|upstream| check_upstream(&client, upstream, timeout)),
+            )
+            .await;
+            if let Some(index) = results.iter().position(|reachable| *reachable) {
+                info!(
+                    "Warmed up after {:?}: {} answered",
+                    started.elapsed(),
+                    upstreams[index]
+                );
+                self.state.store(WarmupState::Warm as u8, Ordering::Relaxed);
+                return;
+            }
+            if started.elapsed() >= config.max_time {
+                warn!(
+                    "No upstream answered within the {:?} warmup, staying not ready",
+                    config.max_time
+                );
+                self.state
+                    .store(WarmupState::GaveUp as u8, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+}
+
+/// Any response counts as reachable; only connection failures and timeouts don't.
+pub async fn check_upstream<C>(client: &Client<C, Body>, uri: &Uri, timeout: Duration) -> bool
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    probe(client, Method::HEAD, uri, None, timeout)
+        .await
+        .is_some()
+}
+
+/// Sends a bodyless request, with the upstream's credentials if it has any, and returns
+/// the response status, or `None` if there was no response within `timeout`.
+async fn probe<C>(
+    client: &Client<C, Body>,
+    method: Method,
+    uri: &Uri,
+    authorization: Option<&HeaderValue>,
+    timeout: Duration,
+) -> Option<StatusCode>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let mut req = Request::builder().method(method).uri(uri.clone());
+    if let Some(authorization) = authorization {
+        req = req.header(AUTHORIZATION, authorization.clone());
+    }
+    let req = match req.body(Body::empty()) {
+        Result::Ok(req) => req,
+        Err(err) => {
+            debug!("Building probe for {}: {}", uri, err);
+            return None;
+        }
+    };
+
+    match tokio::time::timeout(timeout, client.request(req)).await {
+        Result::Ok(Result::Ok(response)) => Some(response.status()),
+        Result::Ok(Err(err)) => {
+            debug!("Probe to {} failed: {}", uri, err);
+            None
+        }
+        Err(_) => {
+            debug!("Probe to {} timed out after {:?}", uri, timeout);
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    /// Path requested on each upstream, below the upstream's own base path.
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Consecutive successful probes before an unhealthy upstream is used again.
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+    /// Consecutive failed probes before an upstream is taken out of rotation.
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_healthy_threshold() -> u32 {
+    2
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+/// Periodically sends `GET <path>` to every upstream and updates the balancer's health
+/// flags. A `2xx` or `3xx` answer is a success; anything else, including no answer, is
+/// a failure. The checker stops once the balancer is dropped.
+pub struct HealthChecker<C> {
+    client: Client<C, Body>,
+    balancer: Weak<Balancer>,
+    config: HealthCheckConfig,
+    probe_uris: Vec<Uri>,
+}
+
+impl<C> HealthChecker<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    pub fn new(
+        client: Client<C, Body>,
+        balancer: &Arc<Balancer>,
+        config: &HealthCheckConfig,
+    ) -> Result<HealthChecker<C>> {
+        ensure!(
+            !config.interval.is_zero(),
+            "health_check.interval can't be zero"
+        );
+        ensure!(
+            config.path.starts_with('/'),
+            "health_check.path must start with '/'"
+        );
+        ensure!(
+            config.healthy_threshold > 0 && config.unhealthy_threshold > 0,
+            "health_check thresholds must be at least 1"
+        );
+        let probe_uris = balancer
+            .upstreams()
+            .iter()
+            .map(|upstream| probe_uri(upstream, &config.path))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(HealthChecker {
+            client,
+            balancer: Arc::downgrade(balancer),
+            config: config.clone(),
+            probe_uris,
+        })
+    }
+
+    pub fn spawn(self) {
+        tokio::spawn(self.run());
+    }
+
+    async fn run(self) {
+        // Consecutive probe results that disagree with each upstream's current state.
+        let mut streaks = vec![0u32; self.probe_uris.len()];
+        let mut ticker = tokio::time::interval(self.config.interval);
+        loop {
+            ticker.tick().await;
+            let balancer = match self.balancer.upgrade() {
+                Some(balancer) => balancer,
+                None => return,
+            };
+            let results = futures_util::future::join_all(
+                self.probe_uris
+                    .iter()
+                    .zip(balancer.upstreams())
+                    .map(|(uri, upstream)| {
+                        let authorization = balancer.authorization(upstream);
+                        probe(
+                            &self.client,
+                            Method::GET,
+                            uri,
+                            authorization,
+                            self.config.timeout,
+                        )
+                    }),
+            )
+            .await;
+
+            for (index, status) in results.into_iter().enumerate() {
+                let success =
+                    status.is_some_and(|status| status.is_success() || status.is_redirection());
+                self.record(&balancer, index, success, &mut streaks[index]);
+            }
+        }
+    }
+
+    fn record(&self, balancer: &Balancer, index: usize, success: bool, streak: &mut u32) {
+        let healthy = balancer.is_healthy(index);
+        if success == healthy {
+            *streak = 0;
+            return;
+        }
+
+        *streak += 1;
+        let threshold = if healthy {
+            self.config.unhealthy_threshold
+        } else {
+            self.config.healthy_threshold
+        };
+        if *streak < threshold {
+            return;
+        }
+
+        *streak = 0;
+        balancer.set_healthy(index, success);
+        let upstream = &balancer.upstreams()[index];
+        if success {
+            info!("Upstream {} is healthy again", upstream);
+        } else {
+            warn!(
+                "Upstream {} is unhealthy, taking it out of rotation",
+                upstream
+            );
+        }
+    }
+}
+
+fn probe_uri(upstream: &Uri, path: &str) -> Result<Uri> {
+    let mut parts = upstream.clone().into_parts();
+    let path = format!("{}{}", upstream.path().trim_end_matches('/'), path);
+    parts.path_and_query = Some(
+        path.parse()
+            .with_context(|| format!("Invalid health check path {:?}", path))?,
+    );
+    Uri::from_parts(parts).with_context(|| format!("Building health check URI for {}", upstream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::{Overrides, Upstream};
+    use crate::connector::PriorKnowledge;
+    use crate::test_support::{self, Proxy};
+    use std::sync::atomic::AtomicBool;
+
+    fn uri(addr: std::net::SocketAddr) -> Uri {
+        format!("http://{}", addr).parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn upstreams_that_answer_are_reachable() {
+        let upstream = test_support::upstream(|_| async {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap()
+        })
+        .await;
+        let client = Client::new();
+        assert!(check_upstream(&client, &uri(upstream), Duration::from_secs(2)).await);
+    }
+
+    #[tokio::test]
+    async fn refused_and_slow_upstreams_are_unreachable() {
+        let client = Client::new();
+        let refused = uri(test_support::unused_addr());
+        assert!(!check_upstream(&client, &refused, Duration::from_secs(2)).await);
+
+        let slow = test_support::upstream(|_| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Response::new(Body::empty())
+        })
+        .await;
+        let started = Instant::now();
+        assert!(!check_upstream(&client, &uri(slow), Duration::from_millis(100)).await);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn readyz_reflects_the_upstreams() {
+        let upstream = test_support::upstream(|_| async { Response::new(Body::empty()) }).await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = [\"http://{}\", \"http://{}\"]",
+            test_support::unused_addr(),
+            upstream
+        ))
+        .await;
+        assert_eq!(proxy.get("/readyz").await.status(), StatusCode::OK);
+
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"",
+            test_support::unused_addr()
+        ))
+        .await;
+        assert_eq!(
+            proxy.get("/readyz").await.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(proxy.get("/healthz").await.status(), StatusCode::OK);
+    }
+
+    fn balancer(uris: &[Uri]) -> Arc<Balancer> {
+        let upstreams = uris
+            .iter()
+            .map(|uri| Upstream {
+                uri: uri.clone(),
+                weight: 1,
+                priority: 0,
+                overrides: Overrides::default(),
+                authorization: None,
+            })
+            .collect();
+        Arc::new(Balancer::new(upstreams, 0, None).unwrap())
+    }
+
+    fn config(interval: Duration) -> HealthCheckConfig {
+        HealthCheckConfig {
+            interval,
+            path: "/health".to_string(),
+            timeout: Duration::from_secs(1),
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+
+    #[test]
+    fn state_changes_need_a_streak_of_probes() {
+        let balancer = balancer(&["http://10.0.0.1:8080".parse().unwrap()]);
+        let checker =
+            HealthChecker::new(Client::new(), &balancer, &config(default_interval())).unwrap();
+        let mut streak = 0;
+        let mut record = |success| {
+            checker.record(&balancer, 0, success, &mut streak);
+            balancer.is_healthy(0)
+        };
+
+        // Flapping never gets a streak going.
+        for _ in 0..10 {
+            assert!(record(false));
+            assert!(record(false));
+            assert!(record(true));
+        }
+        assert!(record(false));
+        assert!(record(false));
+        assert!(!record(false));
+
+        assert!(!record(true));
+        assert!(!record(false));
+        assert!(!record(true));
+        assert!(record(true));
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let balancer = balancer(&["http://10.0.0.1:8080".parse().unwrap()]);
+        let new = |config| HealthChecker::new(Client::new(), &balancer, &config);
+        assert!(new(config(Duration::ZERO)).is_err());
+        assert!(new(HealthCheckConfig {
+            path: "health".to_string(),
+            ..config(default_interval())
+        })
+        .is_err());
+        assert!(new(HealthCheckConfig {
+            healthy_threshold: 0,
+            ..config(default_interval())
+        })
+        .is_err());
+        assert!(new(HealthCheckConfig {
+            unhealthy_threshold: 0,
+            ..config(default_interval())
+        })
+        .is_err());
+    }
+
+    async fn wait_until(mut condition: impl FnMut() -> bool) {
+        let started = Instant::now();
+        while !condition() {
+            assert!(started.elapsed() < Duration::from_secs(5), "Timed out");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_an_upstream_going_down_and_up() {
+        let up = Arc::new(AtomicBool::new(true));
+        let answer = up.clone();
+        let upstream = test_support::upstream(move |req| {
+            let status = if req.uri().path() == "/base/health" && answer.load(Ordering::SeqCst) {
+                StatusCode::NO_CONTENT
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            async move {
+                Response::builder()
+                    .status(status)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        })
+        .await;
+        let balancer = balancer(&[format!("http://{}/base/", upstream).parse().unwrap()]);
+        HealthChecker::new(Client::new(), &balancer, &config(Duration::from_millis(10)))
+            .unwrap()
+            .spawn();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(balancer.is_healthy(0));
+        up.store(false, Ordering::SeqCst);
+        wait_until(|| !balancer.is_healthy(0)).await;
+        up.store(true, Ordering::SeqCst);
+        wait_until(|| balancer.is_healthy(0)).await;
+    }
+
+    fn shared_routing(config: &str) -> Arc<SharedRouting> {
+        let config = crate::config::Config::parse(config).unwrap();
+        let client = crate::client::build(&config.client, PriorKnowledge::default()).unwrap();
+        let routing = crate::routing::Routing::new(&config, &client).unwrap();
+        Arc::new(SharedRouting::new(routing, PriorKnowledge::default()))
+    }
+
+    fn warmup_config(max_time: Duration) -> WarmupConfig {
+        WarmupConfig {
+            max_time,
+            interval: Duration::from_millis(20),
+        }
+    }
+
+    #[tokio::test]
+    async fn warms_up_once_an_upstream_answers() {
+        // Connections are accepted by the kernel, but nothing answers them yet.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let routing = shared_routing(&format!(
+            "upstreams = [\"http://{}\", \"http://{}\"]",
+            test_support::unused_addr(),
+            listener.local_addr().unwrap()
+        ));
+        let warmup = Warmup::spawn(
+            &warmup_config(Duration::from_secs(60)),
+            Client::new(),
+            routing,
+            Duration::from_millis(50),
+        )
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(warmup.state(), WarmupState::Warming);
+
+        let make_service = hyper::service::make_service_fn(|_| async {
+            std::result::Result::Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                |_| async {
+                    std::result::Result::Ok::<_, std::convert::Infallible>(Response::new(
+                        Body::empty(),
+                    ))
+                },
+            ))
+        });
+        listener.set_nonblocking(true).unwrap();
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(make_service),
+        );
+        wait_until(|| warmup.state() == WarmupState::Warm).await;
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_max_time() {
+        let routing = shared_routing(&format!(
+            "upstreams = \"http://{}\"",
+            test_support::unused_addr()
+        ));
+        let warmup = Warmup::spawn(
+            &warmup_config(Duration::from_millis(100)),
+            Client::new(),
+            routing.clone(),
+            Duration::from_millis(50),
+        )
+        .unwrap();
+        assert_eq!(warmup.state(), WarmupState::Warming);
+        wait_until(|| warmup.state() == WarmupState::GaveUp).await;
+
+        let config = WarmupConfig {
+            max_time: Duration::from_secs(1),
+            interval: Duration::ZERO,
+        };
+        let timeout = Duration::from_millis(50);
+        assert!(Warmup::spawn(&config, Client::new(), routing, timeout).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use hyper::header::{HeaderMap, HeaderName, CONNECTION};
+
+/// Headers that only apply to a single connection (RFC 7230 section 6.1, plus
+/// `Keep-Alive` and `Transfer-Encoding` from RFC 2616) and must not be forwarded.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Removes the standard hop-by-hop headers and any header named in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|token| HeaderName::from_bytes(token.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP.iter() {
+        headers.remove(*name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_json, echo_upstream, Proxy};
+    use hyper::header::HeaderValue;
+    use hyper::{Body, Request};
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn names(headers: &HeaderMap) -> Vec<&str> {
+        let mut names = headers.keys().map(HeaderName::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn strips_the_standard_headers_and_those_named_in_connection() {
+        let mut headers = headers(&[
+            ("connection", "keep-alive, X-Custom"),
+            ("connection", " x-other ,not a header"),
+            ("keep-alive", "timeout=5"),
+            ("te", "trailers"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "h2c"),
+            ("proxy-authorization", "Basic Zm9vOmJhcg=="),
+            ("x-custom", "1"),
+            ("x-other", "2"),
+            ("x-kept", "3"),
+            ("content-type", "text/plain"),
+        ]);
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(names(&headers), ["content-type", "x-kept"]);
+    }
+
+    #[tokio::test]
+    async fn the_upstream_never_sees_hop_by_hop_request_headers() {
+        let upstream = echo_upstream().await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+        let req = Request::get("/path")
+            .header("connection", "X-Custom")
+            .header("x-custom", "secret")
+            .header("proxy-authorization", "Basic Zm9vOmJhcg==")
+            .header("x-kept", "3")
+            .body(Body::empty())
+            .unwrap();
+        let echo = body_json(proxy.send(req).await).await;
+        let headers = echo["headers"].as_object().unwrap();
+        assert!(!headers.contains_key("x-custom"));
+        assert!(!headers.contains_key("proxy-authorization"));
+        assert_eq!(headers["x-kept"], serde_json::json!(["3"]));
+    }
+}
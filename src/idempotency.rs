@@ -0,0 +1,554 @@
+use anyhow::*;
+use futures_util::future::{FutureExt, Shared};
+use futures_util::StreamExt;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, HOST};
+use hyper::{Body, Method, Response, StatusCode, Uri};
+use log::debug;
+use ring::digest;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Marks a response as a replay of the one the key's first request got.
+pub const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    /// The request header carrying the client's key.
+    #[serde(default = "default_header")]
+    pub header: String,
+    /// How long a response is replayed for after the key's first request.
+    #[serde(default = "default_window", with = "humantime_serde")]
+    pub window: Duration,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    /// Larger responses aren't kept, so duplicates of their requests are forwarded.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: u64,
+}
+
+fn default_header() -> String {
+    "Idempotency-Key".to_string()
+}
+
+fn default_window() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_max_entries() -> usize {
+    10000
+}
+
+fn default_max_body_bytes() -> u64 {
+    1024 * 1024
+}
+
+struct Stored {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    expires: Instant,
+}
+
+enum Slot {
+    /// The key's first request is still waiting on the upstream. The receiver errors once
+    /// it's done, whether or not it stored a response.
+    InFlight(Shared<oneshot::Receiver<()>>),
+    Stored(Stored),
+}
+
+#[derive(Default)]
+struct Slots {
+    slots: HashMap<String, Slot>,
+    /// Stored keys by when they expire, which is also the order they were stored in.
+    expiry: VecDeque<(Instant, String)>,
+}
+
+impl Slots {
+    /// Drops expired responses, and the ones closest to expiring beyond `max_entries`.
+    fn prune(&mut self, now: Instant, max_entries: usize) {
+        while let Some((expires, key)) = self.expiry.front() {
+            if *expires > now && self.expiry.len() <= max_entries {
+                break;
+            }
+            // The key may have been claimed again since, once its response expired.
+            if matches!(self.slots.get(key), Some(Slot::Stored(stored)) if stored.expires == *expires)
+            {
+                self.slots.remove(key);
+            }
+            self.expiry.pop_front();
+        }
+    }
+}
+
+/// Held by the request that goes upstream for a key. Dropping it without storing a
+/// response frees the key, so one of the duplicates waiting on it goes upstream instead.
+pub struct Claim {
+    key: String,
+    slots: Arc<Mutex<Slots>>,
+    /// Dropped to wake the duplicates once the slot is updated.
+    _done: oneshot::Sender<()>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(Slot::InFlight(_)) = slots.slots.get(&self.key) {
+            slots.slots.remove(&self.key);
+        }
+    }
+}
+
+pub enum Lookup {
+    /// The response the key's first request got.
+    Replay(Response<Body>),
+    /// The request should go upstream and hand its response to [`Idempotency::store`].
+    Claimed(Claim),
+}
+
+/// Replays the response to a `POST` for later requests with the same idempotency key,
+/// host, path and credentials, so a client retrying after a lost response doesn't repeat
+/// the request's effect, and clients can't get each other's responses by reusing a key.
+/// Requests with a key that's already in flight wait for it.
+pub struct Idempotency {
+    header: HeaderName,
+    window: Duration,
+    max_entries: usize,
+    max_body_bytes: u64,
+    slots: Arc<Mutex<Slots>>,
+}
+
+impl Idempotency {
+    pub fn new(config: &IdempotencyConfig) -> Result<Idempotency> {
+        let header = HeaderName::from_bytes(config.header.as_bytes())
+            .with_context(|| format!("Invalid idempotency header {:?}", config.header))?;
+        ensure!(!config.window.is_zero(), "idempotency.window can't be zero");
+        ensure!(
+            config.max_entries > 0,
+            "idempotency.max_entries must be at least 1"
+        );
+        Ok(Idempotency {
+            header,
+            window: config.window,
+            max_entries: config.max_entries,
+            max_body_bytes: config.max_body_bytes,
+            slots: Arc::default(),
+        })
+    }
+
+    /// The store key for a request, or `None` if it isn't a `POST` with a key. `user` is
+    /// who Basic auth let the request in as, which stands in for the `Authorization`
+    /// header it removed. Credentials only go into the key hashed, so the store doesn't
+    /// keep them around for the whole window.
+    pub fn key(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        user: Option<&str>,
+    ) -> Option<String> {
+        if method != Method::POST {
+            return None;
+        }
+        let key = headers.get(&self.header)?;
+        if key.is_empty() {
+            return None;
+        }
+        let host = match (uri.authority(), headers.get(HOST)) {
+            (Some(authority), _) => authority.as_str().to_ascii_lowercase(),
+            (None, Some(host)) => String::from_utf8_lossy(host.as_bytes()).to_ascii_lowercase(),
+            (None, None) => String::new(),
+        };
+        let credentials = match (user, headers.get(AUTHORIZATION)) {
+            (Some(user), _) => format!("user {}", user).into_bytes(),
+            (None, Some(authorization)) => authorization.as_bytes().to_vec(),
+            (None, None) => Vec::new(),
+        };
+        let credentials = digest::digest(&digest::SHA256, &credentials);
+        Some(format!(
+            "{}\n{}\n{}\n{}",
+            host,
+            uri.path(),
+            base64::encode(credentials),
+            String::from_utf8_lossy(key.as_bytes())
+        ))
+    }
+
+    /// Claims `key` for this request, or waits for the request that holds it and replays
+    /// its response. If that request ends without one, the next waiter claims the key.
+    pub async fn lookup(&self, key: &str) -> Lookup {
+        loop {
+            let in_flight = {
+                let mut slots = self.slots.lock().unwrap();
+                slots.prune(Instant::now(), self.max_entries);
+                match slots.slots.get(key) {
+                    Some(Slot::Stored(stored)) => {
+                        let mut response = Response::new(Body::from(stored.body.clone()));
+                        *response.status_mut() = stored.status;
+                        *response.headers_mut() = stored.headers.clone();
+                        response
+                            .headers_mut()
+                            .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+                        return Lookup::Replay(response);
+                    }
+                    Some(Slot::InFlight(in_flight)) => in_flight.clone(),
+                    None => {
+                        let (done, in_flight) = oneshot::channel();
+                        slots
+                            .slots
+                            .insert(key.to_string(), Slot::InFlight(in_flight.shared()));
+                        return Lookup::Claimed(Claim {
+                            key: key.to_string(),
+                            slots: self.slots.clone(),
+                            _done: done,
+                        });
+                    }
+                }
+            };
+            let _ = in_flight.await;
+        }
+    }
+
+    /// Keeps `response` for replays unless it's a server error, which the client may
+    /// retry for real, or its body is over `max_body_bytes`.
+    pub async fn store(&self, claim: Claim, response: Response<Body>) -> Result<Response<Body>> {
+        if response.status().is_server_error() {
+            return Ok(response);
+        }
+        let (parts, mut body) = response.into_parts();
+        let mut buffered = Vec::new();
+        while let Some(chunk) = body.data().await {
+            buffered.extend_from_slice(&chunk.context("Reading upstream response body")?);
+            if buffered.len() as u64 > self.max_body_bytes {
+                debug!(
+                    "Not keeping a response over {} bytes for idempotent replays",
+                    self.max_body_bytes
+                );
+                let head = futures_util::stream::once(async move {
+                    std::result::Result::Ok::<_, hyper::Error>(Bytes::from(buffered))
+                });
+                let body = Body::wrap_stream(head.chain(body));
+                return Ok(Response::from_parts(parts, body));
+            }
+        }
+
+        let body = Bytes::from(buffered);
+        let now = Instant::now();
+        let expires = now + self.window;
+        let mut slots = self.slots.lock().unwrap();
+        slots.slots.insert(
+            claim.key.clone(),
+            Slot::Stored(Stored {
+                status: parts.status,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                expires,
+            }),
+        );
+        slots.expiry.push_back((expires, claim.key.clone()));
+        slots.prune(now, self.max_entries);
+        drop(slots);
+        drop(claim);
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_string, password_hash, upstream, Proxy};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn idempotency(window: Duration) -> Idempotency {
+        Idempotency::new(&IdempotencyConfig {
+            header: default_header(),
+            window,
+            max_entries: default_max_entries(),
+            max_body_bytes: default_max_body_bytes(),
+        })
+        .unwrap()
+    }
+
+    /// Looks `key` up, storing a `body` response on a claim. Returns the body the client
+    /// gets and whether it was a replay.
+    async fn post(idempotency: &Idempotency, key: &str, body: &'static str) -> (String, bool) {
+        match idempotency.lookup(key).await {
+            Lookup::Replay(response) => {
+                assert_eq!(response.headers()[IDEMPOTENT_REPLAYED], "true");
+                (body_string(response).await, true)
+            }
+            Lookup::Claimed(claim) => {
+                let response = Response::new(Body::from(body));
+                let response = idempotency.store(claim, response).await.unwrap();
+                (body_string(response).await, false)
+            }
+        }
+    }
+
+    #[test]
+    fn keys_are_scoped_to_posts_hosts_and_paths() {
+        let idempotency = idempotency(default_window());
+        let key = |method: Method, uri: &str, headers: &[(&str, &str)]| {
+            let headers = headers
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                        HeaderValue::from_str(value).unwrap(),
+                    )
+                })
+                .collect::<HeaderMap>();
+            idempotency.key(&method, &uri.parse().unwrap(), &headers, None)
+        };
+        let with_key = [("host", "Example.com"), ("idempotency-key", "abc")];
+        let first = key(Method::POST, "/orders", &with_key).unwrap();
+        assert_eq!(
+            key(
+                Method::POST,
+                "/orders?page=2",
+                &[("host", "example.com"), ("idempotency-key", "abc"),]
+            ),
+            Some(first.clone())
+        );
+        assert_ne!(
+            key(Method::POST, "/payments", &with_key),
+            Some(first.clone())
+        );
+        assert_ne!(
+            key(Method::POST, "http://other.com/orders", &with_key),
+            Some(first)
+        );
+        assert_eq!(key(Method::PUT, "/orders", &with_key), None);
+        assert_eq!(
+            key(Method::POST, "/orders", &[("idempotency-key", "")]),
+            None
+        );
+        assert_eq!(
+            key(Method::POST, "/orders", &[("host", "example.com")]),
+            None
+        );
+    }
+
+    #[test]
+    fn keys_are_scoped_to_credentials() {
+        let idempotency = idempotency(default_window());
+        let key = |authorization: Option<&'static str>, user: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert("idempotency-key", HeaderValue::from_static("abc"));
+            if let Some(authorization) = authorization {
+                headers.insert(AUTHORIZATION, HeaderValue::from_static(authorization));
+            }
+            let uri = "http://example.com/orders".parse().unwrap();
+            idempotency
+                .key(&Method::POST, &uri, &headers, user)
+                .unwrap()
+        };
+        let anonymous = key(None, None);
+        let alice = key(Some("Bearer alice"), None);
+        assert_eq!(alice, key(Some("Bearer alice"), None));
+        assert!(!alice.contains("Bearer alice"), "{}", alice);
+        let keys = [
+            anonymous,
+            alice,
+            key(Some("Bearer bob"), None),
+            key(None, Some("alice")),
+            key(None, Some("bob")),
+        ];
+        for (index, key) in keys.iter().enumerate() {
+            assert!(!keys[index + 1..].contains(key), "{}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_the_first_response_within_the_window() {
+        let idempotency = idempotency(Duration::from_millis(200));
+        assert_eq!(
+            post(&idempotency, "a", "first").await,
+            ("first".into(), false)
+        );
+        assert_eq!(
+            post(&idempotency, "a", "second").await,
+            ("first".into(), true)
+        );
+        assert_eq!(
+            post(&idempotency, "b", "other").await,
+            ("other".into(), false)
+        );
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(
+            post(&idempotency, "a", "third").await,
+            ("third".into(), false)
+        );
+        assert_eq!(
+            post(&idempotency, "a", "fourth").await,
+            ("third".into(), true)
+        );
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_abandoned_claims_free_the_key() {
+        let idempotency = idempotency(default_window());
+        let claim = match idempotency.lookup("a").await {
+            Lookup::Claimed(claim) => claim,
+            Lookup::Replay(_) => panic!("expected a claim"),
+        };
+        let mut error = Response::new(Body::from("error"));
+        *error.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        idempotency.store(claim, error).await.unwrap();
+        assert_eq!(
+            post(&idempotency, "a", "retried").await,
+            ("retried".into(), false)
+        );
+
+        match idempotency.lookup("b").await {
+            Lookup::Claimed(claim) => drop(claim),
+            Lookup::Replay(_) => panic!("expected a claim"),
+        }
+        assert_eq!(
+            post(&idempotency, "b", "retried").await,
+            ("retried".into(), false)
+        );
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let config = IdempotencyConfig {
+            header: "bad header".to_string(),
+            window: default_window(),
+            max_entries: default_max_entries(),
+            max_body_bytes: default_max_body_bytes(),
+        };
+        assert!(Idempotency::new(&config).is_err());
+        let config = IdempotencyConfig {
+            header: default_header(),
+            window: Duration::ZERO,
+            ..config
+        };
+        assert!(Idempotency::new(&config).is_err());
+        let config = IdempotencyConfig {
+            window: default_window(),
+            max_entries: 0,
+            ..config
+        };
+        assert!(Idempotency::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn retried_posts_reach_the_upstream_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let upstream = upstream(move |_| {
+            let call = counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                Response::new(Body::from(format!("call {}", call)))
+            }
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[idempotency]",
+            upstream
+        ))
+        .await;
+        let post = |key: &'static str| {
+            proxy.send(
+                hyper::Request::post("/orders")
+                    .header("idempotency-key", key)
+                    .body(Body::from("order"))
+                    .unwrap(),
+            )
+        };
+
+        // A duplicate sent while the first is in flight waits for its response.
+        let (first, duplicate) = futures_util::join!(post("a"), async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            post("a").await
+        });
+        assert!(!first.headers().contains_key(IDEMPOTENT_REPLAYED));
+        assert_eq!(body_string(first).await, "call 0");
+        assert_eq!(duplicate.headers()[IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(body_string(duplicate).await, "call 0");
+
+        let retried = post("a").await;
+        assert_eq!(body_string(retried).await, "call 0");
+        let other = post("b").await;
+        assert_eq!(body_string(other).await, "call 1");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn clients_with_other_credentials_get_their_own_responses() {
+        let upstream = upstream(|req: hyper::Request<Body>| async move {
+            let authorization = req.headers()[AUTHORIZATION].to_str().unwrap().to_string();
+            Response::new(Body::from(authorization))
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[idempotency]",
+            upstream
+        ))
+        .await;
+        let post = |authorization: &'static str| {
+            proxy.send(
+                hyper::Request::post("/orders")
+                    .header("idempotency-key", "a")
+                    .header(AUTHORIZATION, authorization)
+                    .body(Body::from("order"))
+                    .unwrap(),
+            )
+        };
+
+        for authorization in ["Bearer alice", "Bearer bob"] {
+            let response = post(authorization).await;
+            assert!(!response.headers().contains_key(IDEMPOTENT_REPLAYED));
+            assert_eq!(body_string(response).await, authorization);
+        }
+        let retried = post("Bearer alice").await;
+        assert_eq!(retried.headers()[IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(body_string(retried).await, "Bearer alice");
+    }
+
+    /// Basic auth removes the credentials before the request is keyed, so the user stands
+    /// in for them.
+    #[tokio::test]
+    async fn basic_auth_users_get_their_own_responses() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let upstream = upstream(move |_| {
+            let call = counted.fetch_add(1, Ordering::SeqCst);
+            async move { Response::new(Body::from(format!("call {}", call))) }
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[idempotency]\n[basic_auth]\nusers = [\
+             {{ username = \"alice\", password_hash = \"{}\" }}, \
+             {{ username = \"bob\", password_hash = \"{}\" }}]",
+            upstream,
+            password_hash("a"),
+            password_hash("b")
+        ))
+        .await;
+        let post = |credentials: &str| {
+            proxy.send(
+                hyper::Request::post("/orders")
+                    .header("idempotency-key", "a")
+                    .header(
+                        AUTHORIZATION,
+                        format!("Basic {}", base64::encode(credentials)),
+                    )
+                    .body(Body::from("order"))
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(body_string(post("alice:a").await).await, "call 0");
+        assert_eq!(body_string(post("bob:b").await).await, "call 1");
+        assert_eq!(body_string(post("alice:a").await).await, "call 0");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::response_headers::{ResponseHeaderRules, ResponseHeadersConfig};
+use anyhow::*;
+use futures_util::future::{BoxFuture, FutureExt};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CACHE_CONTROL, SERVER};
+use hyper::{Body, Request, Response};
+use std::future::Future;
+use std::sync::Arc;
+
+const X_CONTENT_TYPE_OPTIONS: HeaderName = HeaderName::from_static("x-content-type-options");
+
+/// The headers on responses from Vostok's own endpoints, like `/healthz`, `/metrics` and
+/// the admin API, kept apart from the rules for proxied responses. They're never cached
+/// or sniffed and don't name a `Server`, unless `internal_headers` says otherwise.
+pub struct InternalHeaders {
+    rules: ResponseHeaderRules,
+}
+
+impl InternalHeaders {
+    pub fn new(config: &ResponseHeadersConfig) -> Result<InternalHeaders> {
+        Ok(InternalHeaders {
+            rules: ResponseHeaderRules::new(config).context("internal_headers")?,
+        })
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        headers.remove(SERVER);
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        self.rules.apply(headers);
+    }
+
+    /// `handler` with these headers applied to every response it returns.
+    pub fn handler<H, R>(
+        self: &Arc<Self>,
+        handler: H,
+    ) -> impl Fn(Request<Body>) -> BoxFuture<'static, Result<Response<Body>>> + Send + Sync + 'static
+    where
+        H: Fn(Request<Body>) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Response<Body>>> + Send + 'static,
+    {
+        let headers = self.clone();
+        move |req| {
+            let headers = headers.clone();
+            let response = handler(req);
+            async move {
+                let mut response = response.await?;
+                headers.apply(response.headers_mut());
+                Ok(response)
+            }
+            .boxed()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{echo_upstream, Proxy};
+
+    #[test]
+    fn defaults_give_way_to_the_configured_rules() {
+        let mut headers = HeaderMap::new();
+        headers.insert(SERVER, HeaderValue::from_static("hyper"));
+        InternalHeaders::new(&ResponseHeadersConfig::default())
+            .unwrap()
+            .apply(&mut headers);
+        assert!(!headers.contains_key(SERVER));
+        assert_eq!(headers[CACHE_CONTROL], "no-store");
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+
+        let config = ResponseHeadersConfig {
+            set: vec![("Cache-Control".to_string(), "max-age=5".to_string())]
+                .into_iter()
+                .collect(),
+            remove: vec!["X-Content-Type-Options".to_string()],
+        };
+        let mut headers = HeaderMap::new();
+        InternalHeaders::new(&config).unwrap().apply(&mut headers);
+        assert_eq!(headers[CACHE_CONTROL], "max-age=5");
+        assert!(!headers.contains_key(X_CONTENT_TYPE_OPTIONS));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let config = ResponseHeadersConfig {
+            set: Default::default(),
+            remove: vec!["bad header".to_string()],
+        };
+        assert!(InternalHeaders::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn apply_to_built_in_endpoints_only() {
+        let upstream = echo_upstream().await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[internal_headers.set]\n\"X-Internal\" = \"yes\"",
+            upstream
+        ))
+        .await;
+        for path in &["/healthz", "/metrics"] {
+            let response = proxy.get(path).await;
+            let headers = response.headers();
+            assert_eq!(headers[CACHE_CONTROL], "no-store", "{}", path);
+            assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff", "{}", path);
+            assert_eq!(headers["x-internal"], "yes", "{}", path);
+            assert!(!headers.contains_key(SERVER), "{}", path);
+        }
+
+        let proxied = proxy.get("/path").await;
+        assert!(!proxied.headers().contains_key("x-internal"));
+        assert!(!proxied.headers().contains_key(X_CONTENT_TYPE_OPTIONS));
+    }
+}
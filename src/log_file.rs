@@ -0,0 +1,209 @@
+use anyhow::*;
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    /// Start a new file once the current one grows past this size.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Start a new file this long after the current one was opened, e.g. `"1d"`.
+    #[serde(default, with = "humantime_serde")]
+    pub rotate_every: Option<Duration>,
+    /// Rotated files to keep as `<path>.1` (newest) to `<path>.<keep>`.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    /// Keep logging to stdout as well.
+    #[serde(default = "default_stdout")]
+    pub stdout: bool,
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+fn default_stdout() -> bool {
+    true
+}
+
+/// A log file that moves itself aside when it gets too big or too old. fern flushes
+/// after every record, so rotating on flush never splits a line across two files.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    rotate_every: Option<Duration>,
+    keep: usize,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl RotatingFile {
+    /// Appends to an existing file at the path; its age counts from now.
+    pub fn open(config: &LogFileConfig) -> Result<RotatingFile> {
+        ensure!(
+            config.max_bytes != Some(0),
+            "log_file.max_bytes must be at least 1"
+        );
+        ensure!(
+            config.rotate_every != Some(Duration::ZERO),
+            "log_file.rotate_every can't be zero"
+        );
+        let file = open_append(&config.path)
+            .with_context(|| format!("Opening log file {}", config.path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: config.path.clone(),
+            max_bytes: config.max_bytes,
+            rotate_every: config.rotate_every,
+            keep: config.keep,
+            file,
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    fn rotation_due(&self) -> bool {
+        self.max_bytes
+            .is_some_and(|max_bytes| self.size >= max_bytes)
+            || self
+                .rotate_every
+                .is_some_and(|rotate_every| self.opened.elapsed() >= rotate_every)
+    }
+
+    /// Shifts `<path>.N` to `<path>.N+1`, dropping the oldest, and starts a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.keep).rev() {
+            match fs::rename(self.numbered(index), self.numbered(index + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.numbered(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        std::result::Result::Ok(())
+    }
+
+    fn numbered(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+}
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        std::result::Result::Ok(written)
+    }
+
+    /// A failed rotation is reported to fern, which prints it to stderr, and logging
+    /// carries on in the current file until the next rotation is due.
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if !self.rotation_due() {
+            return std::result::Result::Ok(());
+        }
+        let rotated = self.rotate();
+        if rotated.is_err() {
+            self.size = 0;
+            self.opened = Instant::now();
+        }
+        rotated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn config(dir: &TempDir, max_bytes: Option<u64>, keep: usize) -> LogFileConfig {
+        LogFileConfig {
+            path: dir.path().join("vostok.log"),
+            max_bytes,
+            rotate_every: None,
+            keep,
+            stdout: false,
+        }
+    }
+
+    fn log(file: &mut RotatingFile, line: &str) {
+        writeln!(file, "{}", line).unwrap();
+        file.flush().unwrap();
+    }
+
+    fn read(dir: &TempDir, name: &str) -> Option<String> {
+        fs::read_to_string(dir.path().join(name)).ok()
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest_files() {
+        let dir = TempDir::new();
+        dir.write("vostok.log", "old\n");
+        let mut file = RotatingFile::open(&config(&dir, Some(8), 2)).unwrap();
+        // Appended to what was there, which takes it past max_bytes.
+        log(&mut file, "one");
+        assert_eq!(read(&dir, "vostok.log").as_deref(), Some(""));
+        assert_eq!(read(&dir, "vostok.log.1").as_deref(), Some("old\none\n"));
+
+        for line in ["two", "three", "four", "five", "six"] {
+            log(&mut file, line);
+        }
+        assert_eq!(read(&dir, "vostok.log").as_deref(), Some("six\n"));
+        assert_eq!(read(&dir, "vostok.log.1").as_deref(), Some("four\nfive\n"));
+        assert_eq!(read(&dir, "vostok.log.2").as_deref(), Some("two\nthree\n"));
+        assert_eq!(read(&dir, "vostok.log.3"), None);
+    }
+
+    #[test]
+    fn keeping_no_files_starts_over() {
+        let dir = TempDir::new();
+        let mut file = RotatingFile::open(&config(&dir, Some(4), 0)).unwrap();
+        log(&mut file, "one");
+        log(&mut file, "two");
+        assert_eq!(read(&dir, "vostok.log").as_deref(), Some(""));
+        assert_eq!(read(&dir, "vostok.log.1"), None);
+    }
+
+    #[test]
+    fn rotates_by_age() {
+        let dir = TempDir::new();
+        let mut config = config(&dir, None, 1);
+        config.rotate_every = Some(Duration::from_millis(50));
+        let mut file = RotatingFile::open(&config).unwrap();
+        log(&mut file, "one");
+        assert_eq!(read(&dir, "vostok.log").as_deref(), Some("one\n"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        log(&mut file, "two");
+        log(&mut file, "three");
+        assert_eq!(read(&dir, "vostok.log").as_deref(), Some("three\n"));
+        assert_eq!(read(&dir, "vostok.log.1").as_deref(), Some("one\ntwo\n"));
+    }
+
+    #[test]
+    fn zero_limits_are_rejected() {
+        let dir = TempDir::new();
+        assert!(RotatingFile::open(&config(&dir, Some(0), 1)).is_err());
+        let mut config = config(&dir, None, 1);
+        config.rotate_every = Some(Duration::ZERO);
+        assert!(RotatingFile::open(&config).is_err());
+    }
+}
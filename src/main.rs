@@ -1,25 +1,159 @@
+mod access_log;
+mod admin;
+mod auth;
+mod balancer;
+mod body_limit;
+mod body_rewrite;
+mod body_size;
+mod bulkhead;
+mod cache;
+mod circuit_breaker;
+mod client;
+mod client_ip;
+mod compression;
+mod config;
+mod connector;
+mod cors;
+mod dns;
+mod error_pages;
+mod expect_continue;
+mod grpc;
+mod health;
+mod hop_by_hop;
+mod idempotency;
+mod internal_headers;
+mod log_file;
+mod maintenance;
+mod metrics;
+mod middleware;
+mod mirror;
+mod observability;
+mod path_normalization;
+mod proxy_protocol;
+mod ratelimit;
+mod read_timeout;
+mod redirect;
+mod request_body_log;
+mod request_buffer;
+mod request_filter;
+mod request_headers;
+mod request_id;
+mod response_headers;
+mod retry;
+mod rewrite;
+mod routing;
+mod server;
+mod server_timing;
+mod shutdown;
+mod socket_activation;
+mod static_files;
+mod status_map;
+#[cfg(test)]
+mod test_support;
+mod timeout_header;
+mod tls;
+mod trailing_slash;
+mod transform;
+mod upgrade;
+mod upstream_error;
+mod ws;
+
+use access_log::{AccessLogEntry, LogFormat, Sampler};
 use anyhow::*;
-use hyper::{
-    client::{connect::dns::GaiResolver, HttpConnector},
-    Body, Client, Request, Response, Server, StatusCode,
-};
-use log::{debug, info};
+use auth::JwtValidator;
+use body_rewrite::BodyRewriter;
+use cache::{Lookup, ResponseCache};
+use client_ip::TrustedProxies;
+use compression::CompressionConfig;
+use config::Config;
+use cors::Cors;
+use error_pages::ErrorPages;
+use expect_continue::{ExpectContinueConfig, Expectation};
+use futures_util::TryFutureExt;
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri, Version};
+use idempotency::Idempotency;
+use internal_headers::InternalHeaders;
+use log::{debug, error, info, warn};
+use log_file::{LogFileConfig, RotatingFile};
+use metrics::Metrics;
+use middleware::{AccessControl, EarlyResponse, MiddlewareChain};
+use mirror::Mirror;
+use observability::{SpanKind, Tracer};
+use path_normalization::PathNormalizationConfig;
+use ratelimit::RateLimiter;
+use request_body_log::RequestBodyLogger;
+use request_buffer::Buffered;
+use request_filter::RequestFilter;
+use request_headers::RequestHeaderRules;
+use request_id::RequestId;
+use response_headers::ResponseHeaderRules;
+use retry::RetryPolicy;
+use rewrite::RewriteRule;
 use routerify::prelude::*;
-use routerify::{Middleware, RequestInfo, Router, RouterService};
+use routerify::{Middleware, RequestInfo, Router};
+use routing::{Routing, SharedRouting};
+use server_timing::ServerTiming;
+use shutdown::InFlight;
+use static_files::StaticFiles;
+use status_map::StatusMap;
+use std::borrow::Cow;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use timeout_header::TimeoutHeaderConfig;
+use tokio_rustls::TlsAcceptor;
+use trailing_slash::{TrailingSlashConfig, TrailingSlashMode};
+use transform::LineTransform;
 
-const LOG_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
-const PROXY_URL: &str = "https://httpbin.org";
+type HttpsClient = Client<connector::UpstreamConnector, hyper::Body>;
 
 struct Env {
-    client: Arc<Client<hyper_rustls::HttpsConnector<HttpConnector<GaiResolver>>, hyper::Body>>,
+    client: Arc<HttpsClient>,
+    routing: Arc<SharedRouting>,
+    sticky_sessions: bool,
+    request_timeout: Option<Duration>,
+    total_request_timeout: Option<Duration>,
+    body_read_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    max_buffer_bytes: u64,
+    max_body_bytes: Option<u64>,
+    expect_continue: ExpectContinueConfig,
+    compression: CompressionConfig,
+    readiness_timeout: Duration,
+    warmup: Option<Arc<health::Warmup>>,
+    listener_proto: &'static str,
+    log_format: LogFormat,
+    access_log_sampler: Arc<Sampler>,
+    access_log_body_bytes: bool,
+    path_normalization: Option<PathNormalizationConfig>,
+    access_control: AccessControl,
+    trusted_proxies: TrustedProxies,
+    request_filter: Option<Arc<RequestFilter>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    jwt: Option<Arc<JwtValidator>>,
+    cors: Option<Arc<Cors>>,
+    cache: Option<Arc<ResponseCache>>,
+    idempotency: Option<Arc<Idempotency>>,
+    timeout_header: Option<TimeoutHeaderConfig>,
+    server_timing: Option<Arc<ServerTiming>>,
+    trailing_slash: Option<TrailingSlashConfig>,
+    request_headers: Arc<RequestHeaderRules>,
+    response_headers: Arc<ResponseHeaderRules>,
+    status_map: Arc<StatusMap>,
+    body_rewrite: Option<Arc<BodyRewriter>>,
+    transform: Option<Arc<LineTransform>>,
+    error_pages: Arc<ErrorPages>,
+    tracer: Option<Arc<Tracer>>,
+    mirror: Option<Arc<Mirror>>,
+    request_body_log: Option<Arc<RequestBodyLogger>>,
+    metrics: Arc<Metrics>,
+    in_flight: Arc<InFlight>,
     state: State,
 }
 struct State(u64);
 
 async fn user_handler_2(req: Request<Body>) -> Result<Response<Body>> {
-    let env = req.data::<Env>().unwrap();
+    let env = req.data::<Arc<Env>>().unwrap();
     debug!("State value: {}", env.state.0);
 
     Ok(Response::new(Body::from("User 2 page!")))
@@ -36,9 +170,12 @@ async fn user_handler(req: Request<Body>) -> Result<Response<Body>> {
     Ok(Response::new(Body::from(format!("Hello {}", user_id))))
 }
 
-fn setup_logging_service() -> Result<()> {
-    fern::Dispatch::new()
+fn setup_logging_service(level: log::LevelFilter, log_file: Option<&LogFileConfig>) -> Result<()> {
+    let mut dispatch = fern::Dispatch::new()
         .format(|out, message, record| {
+            if record.target() == access_log::TARGET {
+                return out.finish(format_args!("{}", message));
+            }
             out.finish(format_args!(
                 "{}[{}] {}",
                 chrono::Utc::now().format("[%Y-%m-%d][%H:%M:%S]"),
@@ -46,107 +183,1986 @@ fn setup_logging_service() -> Result<()> {
                 message
             ))
         })
-        .level(LOG_LEVEL)
-        .chain(std::io::stdout())
-        .apply()
-        .context("Setting up logging service")?;
+        .level(level);
+    if log_file.is_none_or(|log_file| log_file.stdout) {
+        dispatch = dispatch.chain(std::io::stdout());
+    }
+    if let Some(log_file) = log_file {
+        let file: Box<dyn std::io::Write + Send> = Box::new(RotatingFile::open(log_file)?);
+        dispatch = dispatch.chain(file);
+    }
+    dispatch.apply().context("Setting up logging service")?;
     Ok(())
 }
 
 async fn logger(req: Request<Body>) -> Result<Request<Body>> {
+    let request_id = req.context::<RequestId>();
     debug!(
-        "{} {} {}",
-        req.remote_addr(),
+        "{} {} {} {}",
+        request_id.as_ref().map_or("-", RequestId::as_str),
+        client_ip::client_ip(&req),
         req.method(),
         req.uri().path()
     );
     Ok(req)
 }
 
-async fn error_handler(err: routerify::Error, _: RequestInfo) -> Response<Body> {
-    eprintln!("{}", err);
-    Response::builder()
-        .status(StatusCode::INTERNAL_SERVER_ERROR)
-        .body(Body::from(format!("Something went wrong: {}", err)))
-        .unwrap()
+async fn error_handler(err: routerify::RouteError, req_info: RequestInfo) -> Response<Body> {
+    if let Some(response) = req_info.context::<EarlyResponse>() {
+        return response.into_response();
+    }
+
+    // The details stay in the log; clients only get a generic message.
+    error!("Request to {} failed: {:#}", req_info.uri(), err);
+    let response =
+        error_pages::generated(StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong");
+    match req_info.data::<Arc<Env>>() {
+        Some(env) => env.error_pages.render(response),
+        None => response,
+    }
 }
 
-fn router() -> Router<Body, anyhow::Error> {
-    let https = hyper_rustls::HttpsConnector::with_native_roots();
-    let client: Client<hyper_rustls::HttpsConnector<HttpConnector<GaiResolver>>, hyper::Body> =
-        Client::builder().build(https);
-    let client = Arc::new(client);
+fn router(
+    config: &Config,
+    client: Arc<HttpsClient>,
+    routing: Arc<SharedRouting>,
+    in_flight: Arc<InFlight>,
+    metrics: Arc<Metrics>,
+    internal: Arc<InternalHeaders>,
+) -> Result<Router<Body, anyhow::Error>> {
+    let rate_limiter = match &config.rate_limit {
+        None => None,
+        Some(rate_limit) => {
+            let limiter = Arc::new(RateLimiter::new(rate_limit)?);
+            ratelimit::spawn_cleanup(limiter.clone(), rate_limit.cleanup_interval);
+            Some(limiter)
+        }
+    };
+
+    let jwt = match &config.jwt {
+        None => None,
+        Some(jwt) => Some(Arc::new(JwtValidator::new(jwt)?)),
+    };
 
-    let mut r = Router::builder().data(Env {
+    let cors = match &config.cors {
+        None => None,
+        Some(cors) => Some(Arc::new(Cors::new(cors)?)),
+    };
+
+    let cache = match &config.cache {
+        None => None,
+        Some(cache) => Some(Arc::new(ResponseCache::new(cache)?)),
+    };
+
+    let tracer = match &config.tracing {
+        None => None,
+        Some(tracing) => Some(Arc::new(Tracer::new(tracing, client.clone())?)),
+    };
+
+    let warmup = match &config.warmup {
+        None => None,
+        Some(warmup) => Some(health::Warmup::spawn(
+            warmup,
+            (*client).clone(),
+            routing.clone(),
+            config.readiness_timeout,
+        )?),
+    };
+
+    let mut r = Router::builder().data(Arc::new(Env {
         client,
+        routing,
+        sticky_sessions: config.sticky_sessions,
+        request_timeout: config.request_timeout,
+        total_request_timeout: config.total_request_timeout,
+        body_read_timeout: config.body_read_timeout,
+        retry_policy: RetryPolicy {
+            retries: config.retries,
+            backoff: config.retry_backoff,
+            max_body_bytes: config.max_buffer_bytes,
+            max_retry_after: config.max_retry_after,
+        },
+        max_buffer_bytes: config.max_buffer_bytes,
+        max_body_bytes: config.max_body_bytes,
+        expect_continue: config.expect_continue,
+        compression: config.compression.clone(),
+        readiness_timeout: config.readiness_timeout,
+        warmup,
+        listener_proto: config.listener_proto(),
+        log_format: config.log_format,
+        access_log_sampler: Arc::new(Sampler::new(config.access_log_sample_rate)?),
+        access_log_body_bytes: config.access_log_body_bytes,
+        path_normalization: config.path_normalization,
+        access_control: config.access_control.clone(),
+        trusted_proxies: TrustedProxies::new(config.trusted_proxies.clone()),
+        request_filter: match &config.request_filter {
+            None => None,
+            Some(request_filter) => Some(Arc::new(RequestFilter::new(request_filter)?)),
+        },
+        rate_limiter,
+        jwt,
+        cors,
+        cache,
+        idempotency: match &config.idempotency {
+            None => None,
+            Some(idempotency) => Some(Arc::new(Idempotency::new(idempotency)?)),
+        },
+        timeout_header: config.timeout_header,
+        server_timing: match &config.server_timing {
+            None => None,
+            Some(server_timing) => Some(Arc::new(ServerTiming::new(server_timing)?)),
+        },
+        trailing_slash: config.trailing_slash,
+        request_headers: Arc::new(RequestHeaderRules::new(&config.request_headers)?),
+        response_headers: Arc::new(ResponseHeaderRules::new(&config.response_headers)?),
+        status_map: Arc::new(StatusMap::new(&config.status_map)?),
+        body_rewrite: match &config.body_rewrite {
+            None => None,
+            Some(body_rewrite) => Some(Arc::new(BodyRewriter::new(body_rewrite)?)),
+        },
+        transform: match &config.transform {
+            None => None,
+            Some(transform) => Some(Arc::new(LineTransform::new(transform)?)),
+        },
+        error_pages: Arc::new(ErrorPages::new(&config.error_pages)?),
+        tracer,
+        mirror: match &config.mirror {
+            None => None,
+            Some(mirror) => Some(Arc::new(Mirror::new(mirror)?)),
+        },
+        request_body_log: match &config.request_body_log {
+            None => None,
+            Some(request_body_log) => Some(Arc::new(RequestBodyLogger::new(request_body_log)?)),
+        },
+        metrics,
+        in_flight,
         state: State(100),
-    });
+    }));
 
-    if LOG_LEVEL == log::LevelFilter::Debug {
-        r = r.middleware(Middleware::pre(logger));
-    }
-    r.get("/", home_handler)
+    let mut chain = MiddlewareChain::default();
+    chain
+        .add("request_id", true, Middleware::pre(request_id::request_id))
+        .add(
+            "request_id",
+            true,
+            Middleware::post_with_info(request_id::echo_request_id),
+        )
+        .add(
+            "logger",
+            config.log_level >= log::LevelFilter::Debug,
+            Middleware::pre(logger),
+        )
+        .add(
+            "path_normalization",
+            config.path_normalization.is_some(),
+            Middleware::pre(path_normalization::normalize_path),
+        )
+        .add(
+            "access_control",
+            config.access_control.is_enabled(),
+            Middleware::pre(middleware::access_control),
+        )
+        .add(
+            "request_filter",
+            config.request_filter.is_some(),
+            Middleware::pre(request_filter::request_filter),
+        )
+        .add(
+            "cors",
+            config.cors.is_some(),
+            Middleware::pre(cors::preflight),
+        )
+        .add(
+            "cors",
+            config.cors.is_some(),
+            Middleware::post_with_info(cors::add_cors_headers),
+        )
+        .add(
+            "rate_limit",
+            config.rate_limit.is_some(),
+            Middleware::pre(ratelimit::rate_limit),
+        )
+        // Credentials are per site and reloadable, so this checks for them itself.
+        .add("basic_auth", true, Middleware::pre(auth::auth))
+        .add("jwt", config.jwt.is_some(), Middleware::pre(auth::jwt));
+    chain.disable(&config.disabled_middleware)?;
+    debug!("Middleware: {}", chain.enabled().join(", "));
+    r = chain.install(r);
+
+    r = r
+        .get("/", home_handler)
         .get("/users/:userId", user_handler)
         .get("/users/:userId/test", user_handler_2)
-        .get("/*", proxy::proxy_handler)
+        .get("/metrics", internal.handler(metrics::metrics_handler))
+        .get("/healthz", internal.handler(health::healthz_handler))
+        .get("/readyz", internal.handler(health::readyz_handler));
+    if config.debug_echo {
+        r = r.any_method("/debug/echo", internal.handler(proxy::echo_handler));
+    }
+    // Registered ahead of the proxy's catch-all, so it only sees the other paths.
+    for static_files in &config.static_files {
+        let static_files = Arc::new(StaticFiles::new(static_files)?);
+        for pattern in static_files.patterns() {
+            let static_files = static_files.clone();
+            r = r.any_method(pattern, move |req| {
+                let static_files = static_files.clone();
+                async move { static_files.serve(req).await }
+            });
+        }
+    }
+    r.any_method("/*", proxy::proxy_handler)
         .err_handler_with_info(error_handler)
         .build()
-        .unwrap()
+        .map_err(|err| anyhow!(err))
+        .context("Building router")
 }
 
 mod proxy {
     use super::*;
+    use hyper::body::HttpBody;
+    use hyper::header::{
+        HeaderMap, HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONNECTION, CONTENT_LENGTH,
+        CONTENT_TYPE, HOST, TRANSFER_ENCODING, UPGRADE,
+    };
+    use std::net::{IpAddr, SocketAddr};
+
+    const X_FORWARDED_FOR: &str = "x-forwarded-for";
+    const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+    const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+    /// Describes the request as received, and the headers it would be forwarded with
+    /// before the upstream's own `Host` and `Authorization` are set, as JSON.
+    pub async fn echo_handler(req: Request<Body>) -> Result<Response<Body>> {
+        let env = req.data::<Arc<Env>>().unwrap();
+        let client_ip = client_ip::client_ip(&req);
+        let mut forwarded = Request::new(Body::empty());
+        *forwarded.uri_mut() = req.uri().clone();
+        *forwarded.headers_mut() = req.headers().clone();
+        add_forwarding_headers(&mut forwarded, req.remote_addr(), env.listener_proto)?;
+        env.request_headers.apply(forwarded.headers_mut());
+        hop_by_hop::strip_hop_by_hop(forwarded.headers_mut());
+        if grpc::is_grpc(forwarded.headers()) {
+            grpc::restore_te(forwarded.headers_mut());
+        }
+        forwarded.headers_mut().remove(ACCEPT_ENCODING);
+
+        let echo = serde_json::json!({
+            "method": req.method().as_str(),
+            "path": req.uri().path(),
+            "query": req.uri().query(),
+            "version": format!("{:?}", req.version()),
+            "client_ip": client_ip.to_string(),
+            "headers": headers_json(req.headers()),
+            "forwarded_headers": headers_json(forwarded.headers()),
+        });
+        let mut response = Response::new(Body::from(echo.to_string()));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Ok(response)
+    }
+
+    /// Each header name with all its values, in order.
+    fn headers_json(headers: &HeaderMap) -> serde_json::Value {
+        let mut json = serde_json::Map::new();
+        for name in headers.keys() {
+            let values = headers
+                .get_all(name)
+                .iter()
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                .collect::<Vec<_>>();
+            json.insert(name.to_string(), values.into());
+        }
+        json.into()
+    }
+
+    /// Wraps every proxied request in a server span when tracing is enabled.
+    pub async fn proxy_handler(req: Request<Body>) -> Result<Response<Body>> {
+        let tracer = req.data::<Arc<Env>>().unwrap().tracer.clone();
+        let tracer = match tracer {
+            Some(tracer) => tracer,
+            None => return proxy(req, None).await,
+        };
+        let mut span = tracer.start_server_span(&req);
+        span.attribute("net.peer.ip", req.remote_addr().ip().to_string());
+        let response = proxy(req, Some(&span)).await;
+        tracer.finish(
+            span,
+            response.as_ref().ok().map(|response| response.status()),
+        );
+        response
+    }
+
+    /// Where a step of proxying leaves a request: on to the next step, or answered.
+    enum Step<T> {
+        Next(T),
+        Respond(Response<Body>),
+    }
+
+    /// A request ready to go upstream.
+    struct Outgoing {
+        req: Request<Body>,
+        retry_policy: RetryPolicy,
+        finishing: Finishing,
+    }
 
-    pub async fn proxy_handler(mut req: Request<Body>) -> Result<Response<Body>> {
-        let env = req.data::<Env>().unwrap();
-        let client = env.client.clone();
+    /// What's decided about a request before it goes upstream that its response needs
+    /// once it's back.
+    struct Finishing {
+        upstream: Uri,
+        balancer: Arc<balancer::Balancer>,
+        request_timeout: Option<Duration>,
+        /// Set a sticky cookie pinning the client to `upstream`.
+        sticky_sessions: bool,
+        encoding: Option<compression::Encoding>,
+        cache_key: Option<cache::Key>,
+        flight: Option<cache::Flight>,
+        cache_lookup: Option<Duration>,
+        claim: Option<idempotency::Claim>,
+        completion: Completion,
+    }
+
+    /// Proxies a request in three steps: what's decided and done before it goes
+    /// upstream, the exchange with the upstream, and what's done to the response after.
+    async fn proxy(
+        req: Request<Body>,
+        span: Option<&observability::Span>,
+    ) -> Result<Response<Body>> {
+        let received = Instant::now();
+        let env = req.data::<Arc<Env>>().unwrap().clone();
         debug!("State value: {}", env.state.0);
+        let routing = env.routing.current();
+        let _in_flight = env.in_flight.start();
 
-        rewrite_to_proxy(&mut req)?;
-        client
-            .request(req)
-            .await
-            .context("Making request to backend server")
-    }
-
-    fn rewrite_to_proxy(req: &mut Request<Body>) -> Result<()> {
-        let blacklisted_headers = [
-            "content-length",
-            "transfer-encoding",
-            "accept-encoding",
-            "content-encoding",
-        ];
-        blacklisted_headers.iter().for_each(|key| {
-            req.headers_mut().remove(*key);
+        let Outgoing {
+            req,
+            retry_policy,
+            finishing,
+        } = match prepare(&env, &routing, req, received).await? {
+            Step::Next(outgoing) => outgoing,
+            Step::Respond(response) => return Ok(response),
+        };
+        let (response, upstream_duration) =
+            match exchange(&env, &routing, req, &retry_policy, &finishing, span).await {
+                Step::Next(exchanged) => exchanged,
+                Step::Respond(response) => return Ok(response),
+            };
+        Ok(finish(&env, finishing, response, upstream_duration).await)
+    }
+
+    /// Everything up to sending the request: answering it from the cache or the
+    /// idempotency store, picking the upstream and getting the request (and any mirrored
+    /// copy) ready for it.
+    async fn prepare(
+        env: &Env,
+        routing: &Routing,
+        mut req: Request<Body>,
+        received: Instant,
+    ) -> Result<Step<Outgoing>> {
+        if routing.maintenance.is_enabled() {
+            return Ok(Step::Respond(
+                routing.maintenance.response(&env.error_pages),
+            ));
+        }
+        if let Some(trailing_slash) = env.trailing_slash.filter(|config| config.redirect) {
+            if let Some(response) = trailing_slash::redirect(trailing_slash.mode, req.uri()) {
+                return Ok(Step::Respond(response));
+            }
+        }
+        let trailing_slash_mode = env.trailing_slash.map(|config| config.mode);
+        let error_pages = &env.error_pages;
+
+        let client_ip = client_ip::client_ip(&req);
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        let completion = Completion {
+            log_format: env.log_format,
+            sampler: env.access_log_sampler.clone(),
+            log_body_bytes: env.access_log_body_bytes,
+            metrics: env.metrics.clone(),
+            method: method.clone(),
+            path: path.clone(),
+            client_ip,
+            received,
+        };
+        // gRPC bodies are streams of messages that can go quiet for as long as the call
+        // lasts, so they're never buffered or held to `body_read_timeout`.
+        let grpc = grpc::is_grpc(req.headers());
+
+        // Accept-Encoding isn't forwarded, so the upstream always answers uncompressed and
+        // compression (if enabled) is negotiated with the client here.
+        let encoding = if env.compression.enabled && method != Method::HEAD {
+            compression::negotiate(req.headers())
+        } else {
+            None
+        };
+
+        let cache_key = match &env.cache {
+            Some(cache) if method == Method::GET => cache.key(&method, req.uri(), req.headers()),
+            _ => None,
+        };
+        let mut flight = None;
+        let mut cache_lookup = None;
+        if let (Some(cache), Some(key)) = (&env.cache, &cache_key) {
+            let lookup_started = Instant::now();
+            let lookup = cache.lookup(key).await;
+            cache_lookup = Some(lookup_started.elapsed());
+            match lookup {
+                Lookup::Miss(miss) => flight = miss,
+                Lookup::Found(mut response) => {
+                    env.response_headers.apply(response.headers_mut());
+                    if let Some(server_timing) = &env.server_timing {
+                        server_timing.add(response.headers_mut(), None, cache_lookup);
+                    }
+                    let response = compress_response(response, &env.compression, encoding);
+                    return Ok(Step::Respond(completion.finish(response)));
+                }
+            }
+        }
+
+        let mut claim = None;
+        let idempotency_key = env.idempotency.as_ref().and_then(|idempotency| {
+            let user = req.extensions().get::<auth::AuthenticatedUser>();
+            let user = user.map(|user| user.0.as_str());
+            idempotency.key(&method, req.uri(), req.headers(), user)
         });
+        if let (Some(idempotency), Some(key)) = (&env.idempotency, &idempotency_key) {
+            match idempotency.lookup(key).await {
+                idempotency::Lookup::Claimed(claimed) => claim = Some(claimed),
+                idempotency::Lookup::Replay(mut response) => {
+                    env.response_headers.apply(response.headers_mut());
+                    let response = compress_response(response, &env.compression, encoding);
+                    return Ok(Step::Respond(completion.finish(response)));
+                }
+            }
+        }
+
+        // A pinned upstream whose circuit is open falls back to normal balancing.
+        let allowed = |upstream: &Uri| {
+            routing
+                .circuit_breakers
+                .as_ref()
+                .is_none_or(|breakers| breakers.allow(upstream, Instant::now()))
+        };
+        let site = routing.site(req.uri(), req.headers());
+        let balancer = site.balancer_for(&path, req.uri().query(), req.headers());
+        let pinned = if env.sticky_sessions {
+            balancer.pinned(req.headers())
+        } else {
+            None
+        };
+        let (upstream, is_pinned) = match pinned {
+            Some(pinned) if allowed(pinned) => (pinned.clone(), true),
+            _ => {
+                let key = routing
+                    .consistent_hash
+                    .as_ref()
+                    .and_then(|hash| hash.key(req.uri(), req.headers(), client_ip));
+                let upstream = match key {
+                    Some(key) => balancer.hashed(&key),
+                    None => balancer.next(),
+                }
+                .clone();
+                if !allowed(&upstream) {
+                    debug!("Circuit for upstream {} is open", upstream);
+                    return Ok(Step::Respond(error_pages.render(service_unavailable())));
+                }
+                (upstream, false)
+            }
+        };
+        let overrides = balancer.overrides(&upstream);
+        let mut request_timeout = overrides.request_timeout.or(env.request_timeout);
+        let retry_policy = RetryPolicy {
+            retries: overrides.retries.unwrap_or(env.retry_policy.retries),
+            ..env.retry_policy
+        };
+        if let Some(timeout_header) = &env.timeout_header {
+            if let Some(timeout) = timeout_header.take_client_timeout(req.headers_mut()) {
+                request_timeout = Some(timeout);
+            }
+        }
+
+        // Decided before anything reads the body, so a client waiting for `100 Continue`
+        // gets its answer without sending the body first.
+        let expectation = expect_continue::expectation(req.headers());
+        if expectation == Expectation::Unsupported {
+            return Ok(Step::Respond(error_pages.render(expectation_failed())));
+        }
+        env.expect_continue.apply(req.headers_mut());
+        if let Some(limit) = env.max_body_bytes {
+            if body_limit::declared_length_exceeds(req.headers(), limit) {
+                return Ok(Step::Respond(error_pages.render(match expectation {
+                    Expectation::Continue => expectation_failed(),
+                    _ => payload_too_large(),
+                })));
+            }
+            if req.body().size_hint().exact().is_none() {
+                let body = std::mem::replace(req.body_mut(), Body::empty());
+                *req.body_mut() = body_limit::limit_body(body, limit);
+            }
+        }
+
+        // Logged as the client sent it, before any headers are added or rewritten.
+        let mut body_buffered = false;
+        if let Some(request_body_log) = env.request_body_log.as_ref().filter(|_| !grpc) {
+            match request_body_log.log(&mut req, env.body_read_timeout).await {
+                Result::Ok(buffered) => body_buffered = buffered,
+                Err(err) => return Ok(Step::Respond(error_pages.render(unreadable_body(&err)))),
+            }
+        }
+
+        let request_id = req.context::<RequestId>();
+        let peer = req.remote_addr();
+        add_forwarding_headers(&mut req, peer, env.listener_proto)?;
+        // A WebSocket tunnel can't be shared, so handshakes aren't mirrored.
+        let mirror = env
+            .mirror
+            .as_ref()
+            .filter(|_| !grpc && !ws::is_upgrade_request(req.headers()));
+        let buffered = if mirror.is_some() || (!grpc && retry_policy.applies_to(req.method())) {
+            match request_buffer::buffer(&mut req, env.max_buffer_bytes, env.body_read_timeout)
+                .await
+            {
+                Result::Ok(buffered) => buffered,
+                Err(err) => return Ok(Step::Respond(error_pages.render(unreadable_body(&err)))),
+            }
+        } else {
+            Buffered::Skipped
+        };
+        body_buffered |= !matches!(buffered, Buffered::Skipped);
+        let mirrored = match (mirror, buffered) {
+            (Some(mirror), Buffered::Complete(body)) => mirror.copy(&req, body),
+            _ => None,
+        };
+        rewrite_to_proxy(
+            &mut req,
+            Target {
+                upstream: &upstream,
+                authorization: balancer.authorization(&upstream),
+                preserve_host: overrides.preserve_host,
+            },
+            &site.rewrite_rules,
+            trailing_slash_mode,
+            &env.request_headers,
+            request_id.as_ref(),
+        )?;
+        if let (Some(mirror), Some(mut mirrored)) = (mirror, mirrored) {
+            match rewrite_to_proxy(
+                &mut mirrored,
+                Target {
+                    upstream: mirror.upstream(),
+                    authorization: None,
+                    preserve_host: false,
+                },
+                &site.rewrite_rules,
+                trailing_slash_mode,
+                &env.request_headers,
+                request_id.as_ref(),
+            ) {
+                Result::Ok(()) => mirror.send(env.client.clone(), mirrored),
+                Err(err) => warn!("Not mirroring request: {:#}", err),
+            }
+        }
+        // Wrapped after the framing is set, so a known length still goes out as
+        // Content-Length.
+        if let Some(timeout) = env.body_read_timeout {
+            if !body_buffered && !grpc && req.body().size_hint().exact() != Some(0) {
+                let body = std::mem::replace(req.body_mut(), Body::empty());
+                *req.body_mut() = read_timeout::timeout_body(body, timeout);
+            }
+        }
+
+        Ok(Step::Next(Outgoing {
+            req,
+            retry_policy,
+            finishing: Finishing {
+                sticky_sessions: env.sticky_sessions && !is_pinned,
+                upstream,
+                balancer: balancer.clone(),
+                request_timeout,
+                encoding,
+                cache_key,
+                flight,
+                cache_lookup,
+                claim,
+                completion,
+            },
+        }))
+    }
+
+    /// The response for a request body that couldn't be read.
+    fn unreadable_body(err: &Error) -> Response<Body> {
+        if read_timeout::is_body_read_timeout(err) {
+            debug!("{:#}", err);
+            body_read_timed_out()
+        } else if body_limit::is_body_too_large(err) {
+            payload_too_large()
+        } else {
+            debug!("Reading request body failed: {:#}", err);
+            bad_request()
+        }
+    }
+
+    /// Sends the request upstream once there's a bulkhead slot for it, retrying as the
+    /// policy allows, and gives back the upstream's response, or one saying why there's
+    /// none, with how long it took. Tracing, circuit breakers and the upstream metrics
+    /// see the outcome here.
+    async fn exchange(
+        env: &Env,
+        routing: &Routing,
+        mut req: Request<Body>,
+        retry_policy: &RetryPolicy,
+        finishing: &Finishing,
+        span: Option<&observability::Span>,
+    ) -> Step<(Response<Body>, Duration)> {
+        let upstream = &finishing.upstream;
+        let Completion {
+            method,
+            path,
+            received,
+            ..
+        } = &finishing.completion;
+        let request_timeout = finishing.request_timeout;
+
+        let permit = match &routing.bulkheads {
+            None => None,
+            Some(bulkheads) => {
+                let queue_depth = env
+                    .metrics
+                    .bulkhead_queued
+                    .with_label_values(&[&upstream.to_string()]);
+                match bulkheads.acquire(upstream, &queue_depth).await {
+                    Result::Ok(permit) => Some(permit),
+                    Err(overloaded) => {
+                        debug!("Upstream {} is at its concurrency limit", upstream);
+                        return Step::Respond(
+                            overloaded.response(|| env.error_pages.render(service_unavailable())),
+                        );
+                    }
+                }
+            }
+        };
+
+        // One client span covers every retry of the upstream request.
+        let client_span = span.map(|span| {
+            let mut client_span = span.child(format!("HTTP {}", method), SpanKind::Client);
+            client_span.attribute("http.url", req.uri().to_string());
+            client_span.inject(req.headers_mut());
+            client_span
+        });
+
+        let started = Instant::now();
+        let client = &env.client;
+        let exchange = async {
+            if ws::is_upgrade_request(req.headers()) {
+                proxy_upgrade(client.clone(), req, request_timeout).await
+            } else {
+                retry::retry_request(retry_policy, req, |req| {
+                    send_upstream(client.clone(), req, request_timeout)
+                })
+                .await
+            }
+        };
+        // Counted from when the request arrived, so time spent buffering its body or
+        // queued for a bulkhead slot comes out of it too, whatever retries are left.
+        let response = match env.total_request_timeout {
+            None => exchange.await,
+            Some(total) => {
+                let deadline = tokio::time::Instant::from_std(*received + total);
+                match tokio::time::timeout_at(deadline, exchange).await {
+                    Result::Ok(response) => response,
+                    Err(_) => {
+                        warn!(
+                            "{} {} to {} gave up after total_request_timeout {:?}",
+                            method, path, upstream, total
+                        );
+                        Ok(gateway_timeout())
+                    }
+                }
+            }
+        };
+        drop(permit);
+
+        if let (Some(tracer), Some(client_span)) = (&env.tracer, client_span) {
+            let status = response.as_ref().ok().map(|response| response.status());
+            tracer.finish(client_span, status);
+        }
+
+        if let Some(breakers) = &routing.circuit_breakers {
+            let success = match &response {
+                // The client's fault, not the upstream's.
+                Err(err) => {
+                    body_limit::is_body_too_large(err) || read_timeout::is_body_read_timeout(err)
+                }
+                Result::Ok(response) => !response.status().is_server_error(),
+            };
+            breakers.record(upstream, success, Instant::now());
+        }
+
+        // Whatever the upstream answered is passed through untouched apart from its
+        // hop-by-hop headers and any status remapping; only failing to get an answer at
+        // all becomes a synthesized response.
+        let response = match response {
+            Err(err) if body_limit::is_body_too_large(&err) => payload_too_large(),
+            Err(err) if read_timeout::is_body_read_timeout(&err) => {
+                debug!("{:#}", err);
+                body_read_timed_out()
+            }
+            // The cause only goes to the log; the client learns nothing about the network
+            // behind the proxy.
+            Err(err) => {
+                let failure = upstream_error::classify(&err);
+                error!("Upstream {} {}: {:#}", upstream, failure.as_str(), err);
+                if failure.is_connect() {
+                    upstream_unreachable()
+                } else {
+                    bad_gateway()
+                }
+            }
+            Result::Ok(mut response) => {
+                // A 101 keeps Connection/Upgrade so the client knows the switch happened.
+                if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+                    hop_by_hop::strip_hop_by_hop(response.headers_mut());
+                }
+                env.status_map.apply(response.status_mut());
+                response
+            }
+        };
+
+        let upstream_duration = started.elapsed();
+        env.metrics
+            .observe(upstream, Some(response.status()), upstream_duration);
+        Step::Next((response, upstream_duration))
+    }
+
+    /// Everything done to the response after the exchange: rewriting and rendering it,
+    /// storing it for later requests, adding headers and compressing it.
+    async fn finish(
+        env: &Env,
+        finishing: Finishing,
+        response: Response<Body>,
+        upstream_duration: Duration,
+    ) -> Response<Body> {
+        let Finishing {
+            upstream,
+            balancer,
+            request_timeout,
+            sticky_sessions,
+            encoding,
+            cache_key,
+            flight,
+            cache_lookup,
+            claim,
+            completion,
+        } = finishing;
+        let failed = |err: Error| {
+            error!("Upstream {} failed: {:#}", upstream, err);
+            env.error_pages.render(bad_gateway())
+        };
+
+        // Rewritten before caching, so cache hits don't need rewriting again.
+        let response = match &env.body_rewrite {
+            Some(body_rewrite) => body_rewrite
+                .rewrite(&completion.method, response)
+                .await
+                .unwrap_or_else(failed),
+            None => response,
+        };
+        let response = match &env.transform {
+            Some(transform) => transform.apply(&completion.method, response),
+            None => response,
+        };
+
+        // Rendered before caching, so requests coalesced onto this one get the same page.
+        let response = env.error_pages.render(response);
+        let response = match (&env.cache, cache_key) {
+            (Some(cache), Some(key)) => cache
+                .store(key, response, Instant::now(), flight)
+                .await
+                .unwrap_or_else(failed),
+            _ => response,
+        };
+        let mut response = match (&env.idempotency, claim) {
+            (Some(idempotency), Some(claim)) => idempotency
+                .store(claim, response)
+                .await
+                .unwrap_or_else(failed),
+            _ => response,
+        };
+
+        env.response_headers.apply(response.headers_mut());
+        if let Some(timeout_header) = &env.timeout_header {
+            timeout_header.expose(response.headers_mut(), request_timeout);
+        }
+        if let Some(server_timing) = &env.server_timing {
+            server_timing.add(
+                response.headers_mut(),
+                Some(upstream_duration),
+                cache_lookup,
+            );
+        }
+        if sticky_sessions {
+            balancer.set_sticky_cookie(response.headers_mut(), &upstream);
+        }
+        let response = compress_response(response, &env.compression, encoding);
+        completion.finish(response)
+    }
+
+    /// What a request's access log line and the body size metric need. Both are
+    /// written once the response body has been sent, unless the line leaves the size out.
+    struct Completion {
+        log_format: LogFormat,
+        sampler: Arc<Sampler>,
+        log_body_bytes: bool,
+        metrics: Arc<Metrics>,
+        method: Method,
+        path: String,
+        client_ip: IpAddr,
+        received: Instant,
+    }
+
+    impl Completion {
+        fn finish(self, response: Response<Body>) -> Response<Body> {
+            let status = response.status();
+            // gRPC statuses arrive in trailers, which counting the body would lose, and
+            // an upgraded connection's traffic isn't a body.
+            if grpc::is_grpc(response.headers()) || status == StatusCode::SWITCHING_PROTOCOLS {
+                self.log(status, None);
+                return response;
+            }
+            if !self.log_body_bytes {
+                self.log(status, None);
+            }
+            body_size::count(response, move |bytes, complete| {
+                self.metrics.observe_body_bytes(bytes);
+                if self.log_body_bytes {
+                    self.log(status, Some((bytes, complete)));
+                }
+            })
+        }
+
+        fn log(&self, status: StatusCode, body: Option<(u64, bool)>) {
+            if self.log_format == LogFormat::Json && self.sampler.sample(Some(status)) {
+                let mut entry = AccessLogEntry::new(
+                    self.method.as_str(),
+                    &self.path,
+                    self.client_ip,
+                    Some(status.as_u16()),
+                    self.received.elapsed(),
+                );
+                if let Some((bytes, complete)) = body {
+                    entry = entry.with_body(bytes, complete);
+                }
+                entry.log();
+            }
+        }
+    }
+
+    fn compress_response(
+        mut response: Response<Body>,
+        compression: &CompressionConfig,
+        encoding: Option<compression::Encoding>,
+    ) -> Response<Body> {
+        if !compression.enabled || !compression::is_compressible(&response, compression.min_size) {
+            return response;
+        }
+        compression::add_vary_accept_encoding(response.headers_mut());
+        match encoding {
+            Some(encoding) => compression::compress(response, encoding),
+            None => response,
+        }
+    }
+
+    /// Forwards a WebSocket handshake. If the upstream accepts it, its `101` goes back to
+    /// the client and both upgraded connections are tunnelled in the background.
+    async fn proxy_upgrade(
+        client: Arc<HttpsClient>,
+        mut req: Request<Body>,
+        request_timeout: Option<Duration>,
+    ) -> Result<Response<Body>> {
+        let client_upgrade = hyper::upgrade::on(&mut req);
+        let mut response = send_upstream(client, req, request_timeout).await?;
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            let upstream_upgrade = hyper::upgrade::on(&mut response);
+            ws::spawn_tunnel(client_upgrade, upstream_upgrade);
+        }
+        Ok(response)
+    }
+
+    async fn send_upstream(
+        client: Arc<HttpsClient>,
+        req: Request<Body>,
+        request_timeout: Option<Duration>,
+    ) -> Result<Response<Body>> {
+        let uri = req.uri().clone();
+        let response = client.request(req);
+        match request_timeout {
+            None => response.await.context("Making request to backend server"),
+            Some(timeout) => match tokio::time::timeout(timeout, response).await.ok() {
+                Some(response) => response.context("Making request to backend server"),
+                None => {
+                    warn!("Upstream {} timed out after {:?}", uri, timeout);
+                    Ok(gateway_timeout())
+                }
+            },
+        }
+    }
+
+    fn gateway_timeout() -> Response<Body> {
+        error_pages::generated(StatusCode::GATEWAY_TIMEOUT, "Upstream request timed out")
+    }
+
+    fn bad_gateway() -> Response<Body> {
+        error_pages::generated(StatusCode::BAD_GATEWAY, "Bad Gateway")
+    }
+
+    fn upstream_unreachable() -> Response<Body> {
+        error_pages::generated(StatusCode::BAD_GATEWAY, "Upstream unreachable")
+    }
+
+    fn service_unavailable() -> Response<Body> {
+        error_pages::generated(StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable")
+    }
+
+    fn body_read_timed_out() -> Response<Body> {
+        let mut response =
+            error_pages::generated(StatusCode::REQUEST_TIMEOUT, "Request body timed out");
+        // The rest of the body may never arrive, so the connection can't be reused.
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+        response
+    }
+
+    fn bad_request() -> Response<Body> {
+        error_pages::generated(StatusCode::BAD_REQUEST, "Bad Request")
+    }
+
+    fn payload_too_large() -> Response<Body> {
+        let mut response =
+            error_pages::generated(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large");
+        // The rest of the body is never read, so the connection can't be reused.
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+        response
+    }
+
+    fn expectation_failed() -> Response<Body> {
+        error_pages::generated(StatusCode::EXPECTATION_FAILED, "Expectation failed")
+    }
+
+    /// Appends the peer's address to `X-Forwarded-For` (keeping any existing chain) and
+    /// records the original protocol and host for the upstream.
+    fn add_forwarding_headers(
+        req: &mut Request<Body>,
+        peer: SocketAddr,
+        proto: &'static str,
+    ) -> Result<()> {
+        // HTTP/2 clients send the host as the `:authority` pseudo-header rather than `Host`.
+        let host = match req.headers().get(HOST) {
+            Some(host) => Some(host.clone()),
+            None => req
+                .uri()
+                .authority()
+                .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok()),
+        };
+        let headers = req.headers_mut();
+
+        let mut forwarded_for = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if !forwarded_for.is_empty() {
+            forwarded_for.push_str(", ");
+        }
+        forwarded_for.push_str(&peer.ip().to_string());
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_str(&forwarded_for).context("Building X-Forwarded-For header")?,
+        );
+
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+
+        if let Some(host) = host {
+            headers.insert(X_FORWARDED_HOST, host);
+        }
+        Ok(())
+    }
+
+    /// Derives the framing headers from the body hyper will actually send, rather than
+    /// trusting the client's: a known length becomes `Content-Length`, anything else is
+    /// re-chunked.
+    fn set_body_framing(req: &mut Request<Body>) {
+        let length = req.body().size_hint().exact();
+        let headers = req.headers_mut();
+        headers.remove(TRANSFER_ENCODING);
+        match length {
+            Some(0) if !headers.contains_key(CONTENT_LENGTH) => {}
+            Some(length) => {
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+            }
+            None => {
+                headers.remove(CONTENT_LENGTH);
+                headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+            }
+        }
+    }
+
+    /// The upstream a request is rewritten for, and what it needs sent its way.
+    struct Target<'a> {
+        upstream: &'a Uri,
+        authorization: Option<&'a HeaderValue>,
+        preserve_host: bool,
+    }
+
+    fn rewrite_to_proxy(
+        req: &mut Request<Body>,
+        target: Target<'_>,
+        rewrite_rules: &[RewriteRule],
+        trailing_slash: Option<TrailingSlashMode>,
+        request_headers: &RequestHeaderRules,
+        request_id: Option<&RequestId>,
+    ) -> Result<()> {
+        let Target {
+            upstream,
+            authorization,
+            preserve_host,
+        } = target;
+        let original_host = match req.headers().get(HOST) {
+            Some(host) => Some(host.clone()),
+            None => req
+                .uri()
+                .authority()
+                .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok()),
+        };
+        // A WebSocket handshake still needs Connection/Upgrade to reach the upstream.
+        let upgrade = if ws::is_upgrade_request(req.headers()) {
+            req.headers().get(UPGRADE).cloned()
+        } else {
+            None
+        };
+        request_headers.apply(req.headers_mut());
+        hop_by_hop::strip_hop_by_hop(req.headers_mut());
+        if grpc::is_grpc(req.headers()) {
+            grpc::restore_te(req.headers_mut());
+        }
+        if let Some(upgrade) = upgrade {
+            req.headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("upgrade"));
+            req.headers_mut().insert(UPGRADE, upgrade);
+        }
+        req.headers_mut().remove(ACCEPT_ENCODING);
+        if let Some(authorization) = authorization {
+            req.headers_mut()
+                .insert(AUTHORIZATION, authorization.clone());
+        }
+        set_body_framing(req);
+        // The client's version says nothing about the upstream's, so reset it and let
+        // hyper pick the protocol per connection: HTTP/2 when ALPN or `http2_only` says
+        // so, HTTP/1.1 otherwise.
+        *req.version_mut() = Version::HTTP_11;
 
         let uri = req.uri();
-        let uri_string = match uri.query() {
-            None => format!("{}{}", PROXY_URL, uri.path()),
-            Some(query) => format!("{}{}?{}", PROXY_URL, uri.path(), query),
+        let base_path = upstream.path().trim_end_matches('/');
+        let path = match trailing_slash {
+            None => Cow::Borrowed(uri.path()),
+            Some(mode) => trailing_slash::normalize(mode, uri.path()),
+        };
+        let path = rewrite::rewrite_path(rewrite_rules, &path);
+        let path_and_query = match uri.query() {
+            None => format!("{}{}", base_path, path),
+            Some(query) => format!("{}{}?{}", base_path, path, query),
         };
-        *req.uri_mut() = uri_string
-            .parse()
-            .context("Parsing URI in rewrite_to_proxy")?;
+        let mut parts = upstream.clone().into_parts();
+        parts.path_and_query = Some(
+            path_and_query
+                .parse()
+                .context("Parsing path in rewrite_to_proxy")?,
+        );
+        *req.uri_mut() = Uri::from_parts(parts).context("Building URI in rewrite_to_proxy")?;
+
+        // The original host has already been copied to X-Forwarded-Host. A Unix socket
+        // has no host name of its own to send.
+        if let (true, Some(host)) = (preserve_host, original_host) {
+            req.headers_mut().insert(HOST, host);
+        } else if connector::unix_socket_path(upstream).is_some() {
+            req.headers_mut()
+                .insert(HOST, HeaderValue::from_static("localhost"));
+        } else if let Some(authority) = upstream.authority() {
+            req.headers_mut().insert(
+                HOST,
+                HeaderValue::from_str(authority.as_str())
+                    .context("Building Host header in rewrite_to_proxy")?,
+            );
+        }
+
+        if let Some(RequestId(id)) = request_id {
+            req.headers_mut()
+                .insert(request_id::X_REQUEST_ID, id.clone());
+        }
         Ok(())
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::test_support::{self, body_json, body_string, Proxy};
+        use hyper::header::HeaderName;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        async fn slow_upstream(delay: Duration) -> SocketAddr {
+            test_support::upstream(move |_| async move {
+                tokio::time::sleep(delay).await;
+                Response::new(Body::from("slow"))
+            })
+            .await
+        }
+
+        #[tokio::test]
+        async fn times_out_slow_upstreams() {
+            let upstream = slow_upstream(Duration::from_secs(5)).await;
+            let proxy = Proxy::start(&format!(
+                "upstreams = \"http://{}\"\nrequest_timeout = \"200ms\"",
+                upstream
+            ))
+            .await;
+            let started = Instant::now();
+            let response = proxy.get("/slow").await;
+            assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+            assert!(started.elapsed() < Duration::from_secs(2));
+        }
+
+        #[tokio::test]
+        async fn waits_for_upstreams_within_the_timeout() {
+            let upstream = slow_upstream(Duration::from_millis(50)).await;
+            let proxy = Proxy::start(&format!(
+                "upstreams = \"http://{}\"\nrequest_timeout = \"2s\"",
+                upstream
+            ))
+            .await;
+            let response = proxy.get("/slow").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_string(response).await, "slow");
+        }
+
+        #[tokio::test]
+        async fn per_upstream_timeouts_override_the_default() {
+            let slow = slow_upstream(Duration::from_millis(500)).await;
+            let also_slow = slow_upstream(Duration::from_millis(500)).await;
+            let proxy = Proxy::start(&format!(
+                r#"
+                upstreams = "http://{}"
+                request_timeout = "200ms"
+                [[routes]]
+                path_prefix = "/reports"
+                upstreams = [{{ url = "http://{}", request_timeout = "5s" }}]
+                "#,
+                slow, also_slow
+            ))
+            .await;
+
+            let response = proxy.get("/reports/daily").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_string(response).await, "slow");
+            assert_eq!(
+                proxy.get("/path").await.status(),
+                StatusCode::GATEWAY_TIMEOUT
+            );
+        }
+
+        #[tokio::test]
+        async fn per_upstream_retries_override_the_default() {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let counted = calls.clone();
+            let failing = test_support::upstream(move |_| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                async {
+                    let mut response = Response::new(Body::from("unavailable"));
+                    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    response
+                }
+            })
+            .await;
+            let proxy = Proxy::start(&format!(
+                r#"
+                upstreams = "http://{0}"
+                retries = 2
+                retry_backoff = "1ms"
+                [[routes]]
+                path_prefix = "/once"
+                upstreams = [{{ url = "http://{0}", retries = 0 }}]
+                "#,
+                failing
+            ))
+            .await;
+
+            proxy.get("/once").await;
+            assert_eq!(calls.swap(0, Ordering::SeqCst), 1);
+            proxy.get("/path").await;
+            assert_eq!(calls.load(Ordering::SeqCst), 3);
+        }
+
+        #[tokio::test]
+        async fn total_request_timeout_cuts_retries_short() {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let counted = calls.clone();
+            let failing = test_support::upstream(move |_| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                async {
+                    tokio::time::sleep(Duration::from_millis(150)).await;
+                    let mut response = Response::new(Body::from("unavailable"));
+                    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    response
+                }
+            })
+            .await;
+            let proxy = Proxy::start(&format!(
+                r#"
+                upstreams = "http://{}"
+                retries = 5
+                retry_backoff = "1ms"
+                total_request_timeout = "400ms"
+                "#,
+                failing
+            ))
+            .await;
+
+            let started = Instant::now();
+            let response = proxy.get("/path").await;
+            let elapsed = started.elapsed();
+            assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+            assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+            assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+            // The third attempt was under way when time ran out.
+            assert_eq!(calls.load(Ordering::SeqCst), 3);
+        }
+
+        fn forwarded(headers: &[(&str, &str)], peer: &str) -> HeaderMap {
+            let mut builder = Request::builder().uri("/path");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            let mut req = builder.body(Body::empty()).unwrap();
+            add_forwarding_headers(&mut req, peer.parse().unwrap(), "https").unwrap();
+            req.headers().clone()
+        }
+
+        #[test]
+        fn starts_x_forwarded_for_with_the_peer() {
+            let headers = forwarded(&[("host", "example.com")], "192.0.2.1:50000");
+            assert_eq!(headers[X_FORWARDED_FOR], "192.0.2.1");
+            assert_eq!(headers[X_FORWARDED_PROTO], "https");
+            assert_eq!(headers[X_FORWARDED_HOST], "example.com");
+        }
+
+        #[test]
+        fn chains_x_forwarded_for() {
+            let headers = forwarded(
+                &[
+                    ("x-forwarded-for", "203.0.113.7, 198.51.100.2"),
+                    ("x-forwarded-for", "198.51.100.3"),
+                ],
+                "[2001:db8::1]:50000",
+            );
+            assert_eq!(
+                headers.get_all(X_FORWARDED_FOR).iter().collect::<Vec<_>>(),
+                ["203.0.113.7, 198.51.100.2, 198.51.100.3, 2001:db8::1"]
+            );
+        }
+
+        fn rewritten(uri: &str, headers: &[(&str, &str)], target: Target<'_>) -> Request<Body> {
+            let mut builder = Request::builder().uri(uri);
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            let mut req = builder.body(Body::empty()).unwrap();
+            rewrite_to_proxy(
+                &mut req,
+                target,
+                &[],
+                None,
+                &RequestHeaderRules::default(),
+                None,
+            )
+            .unwrap();
+            req
+        }
+
+        fn target(upstream: &Uri) -> Target<'_> {
+            Target {
+                upstream,
+                authorization: None,
+                preserve_host: false,
+            }
+        }
+
+        #[test]
+        fn sets_host_to_the_upstream_authority() {
+            let upstream = "http://backend.internal:8080/base".parse().unwrap();
+            let req = rewritten(
+                "/users?page=2",
+                &[("host", "example.com")],
+                target(&upstream),
+            );
+            assert_eq!(req.headers()[HOST], "backend.internal:8080");
+            assert_eq!(req.uri(), "http://backend.internal:8080/base/users?page=2");
+        }
+
+        #[test]
+        fn sets_host_for_http2_requests_without_one() {
+            let upstream = "https://backend.internal".parse().unwrap();
+            let req = rewritten("https://example.com/users", &[], target(&upstream));
+            assert_eq!(req.headers()[HOST], "backend.internal");
+            assert_eq!(req.version(), Version::HTTP_11);
+        }
+
+        #[tokio::test]
+        async fn upstreams_get_their_own_host() {
+            let upstream = test_support::echo_upstream().await;
+            let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+            let echo = body_json(proxy.get("/path").await).await;
+            assert_eq!(
+                echo["headers"]["host"],
+                serde_json::json!([upstream.to_string()])
+            );
+        }
+
+        #[test]
+        fn preserve_host_keeps_the_clients_host() {
+            let upstream = "http://backend.internal:8080".parse().unwrap();
+            let preserving = Target {
+                preserve_host: true,
+                ..target(&upstream)
+            };
+            let req = rewritten("/users", &[("host", "example.com")], preserving);
+            assert_eq!(req.headers()[HOST], "example.com");
+            assert_eq!(req.uri(), "http://backend.internal:8080/users");
+
+            // HTTP/2 clients send the `:authority` instead.
+            let preserving = Target {
+                preserve_host: true,
+                ..target(&upstream)
+            };
+            let req = rewritten("https://example.com:8443/users", &[], preserving);
+            assert_eq!(req.headers()[HOST], "example.com:8443");
+        }
+
+        #[tokio::test]
+        async fn preserve_host_upstreams_get_the_clients_host() {
+            let upstream = test_support::echo_upstream().await;
+            let proxy = Proxy::start(&format!(
+                "upstreams = [{{ url = \"http://{}\", preserve_host = true }}]",
+                upstream
+            ))
+            .await;
+            let req = Request::get("/path")
+                .header(HOST, "legacy.example.com")
+                .body(Body::empty())
+                .unwrap();
+            let echo = body_json(proxy.send(req).await).await;
+            assert_eq!(
+                echo["headers"]["host"],
+                serde_json::json!(["legacy.example.com"])
+            );
+        }
+
+        fn framed(body: Body, headers: &[(HeaderName, &str)]) -> HeaderMap {
+            let mut req = Request::post("/upload").body(body).unwrap();
+            for (name, value) in headers {
+                req.headers_mut()
+                    .insert(name, HeaderValue::from_str(value).unwrap());
+            }
+            set_body_framing(&mut req);
+            req.headers().clone()
+        }
+
+        #[test]
+        fn frames_known_lengths_with_content_length() {
+            let headers = framed(Body::from("hello"), &[(TRANSFER_ENCODING, "chunked")]);
+            assert_eq!(headers[CONTENT_LENGTH], "5");
+            assert!(!headers.contains_key(TRANSFER_ENCODING));
+
+            assert!(framed(Body::empty(), &[]).is_empty());
+            assert_eq!(
+                framed(Body::empty(), &[(CONTENT_LENGTH, "0")])[CONTENT_LENGTH],
+                "0"
+            );
+        }
+
+        #[test]
+        fn rechunks_streamed_bodies() {
+            let (_sender, body) = Body::channel();
+            let headers = framed(body, &[(CONTENT_LENGTH, "100")]);
+            assert!(!headers.contains_key(CONTENT_LENGTH));
+            assert_eq!(headers[TRANSFER_ENCODING], "chunked");
+        }
+
+        /// Echoes the request body, with the framing it arrived with in `x-framing`.
+        async fn body_echo_upstream() -> SocketAddr {
+            test_support::upstream(|req: Request<Body>| async move {
+                let framing = match req.headers().get(CONTENT_LENGTH) {
+                    Some(length) => format!("length {}", length.to_str().unwrap()),
+                    None => "chunked".to_string(),
+                };
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                Response::builder()
+                    .header("x-framing", framing)
+                    .body(Body::from(body))
+                    .unwrap()
+            })
+            .await
+        }
+
+        #[tokio::test]
+        async fn forwards_bodies_byte_for_byte() {
+            let upstream = body_echo_upstream().await;
+            let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+            let body = (0..=255u8).cycle().take(100_000).collect::<Vec<_>>();
+
+            let response = proxy
+                .send(
+                    Request::post("/upload")
+                        .body(Body::from(body.clone()))
+                        .unwrap(),
+                )
+                .await;
+            assert_eq!(response.headers()["x-framing"], "length 100000");
+            assert_eq!(test_support::body_bytes(response).await, body);
+
+            let (mut sender, streamed) = Body::channel();
+            let chunks = body.clone();
+            tokio::spawn(async move {
+                for chunk in chunks.chunks(7_000) {
+                    sender
+                        .send_data(hyper::body::Bytes::copy_from_slice(chunk))
+                        .await
+                        .unwrap();
+                }
+            });
+            let response = proxy
+                .send(Request::post("/upload").body(streamed).unwrap())
+                .await;
+            assert_eq!(response.headers()["x-framing"], "chunked");
+            assert_eq!(test_support::body_bytes(response).await, body);
+        }
+
+        #[tokio::test]
+        async fn passes_upstream_errors_through_untouched() {
+            let upstream = test_support::upstream(|_| async {
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .header("set-cookie", "a=1; Path=/")
+                    .header("set-cookie", "b=2; HttpOnly")
+                    .header("x-upstream", "yes")
+                    .header("connection", "x-upstream")
+                    .body(Body::from("no such thing"))
+                    .unwrap()
+            })
+            .await;
+            let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+            let response = proxy.get("/missing").await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert_eq!(
+                response
+                    .headers()
+                    .get_all("set-cookie")
+                    .iter()
+                    .collect::<Vec<_>>(),
+                ["a=1; Path=/", "b=2; HttpOnly"]
+            );
+            // Named in Connection, so it's hop-by-hop.
+            assert!(!response.headers().contains_key("x-upstream"));
+            assert_eq!(body_string(response).await, "no such thing");
+        }
+
+        #[tokio::test]
+        async fn forwards_the_client_address_upstream() {
+            let upstream = test_support::echo_upstream().await;
+            let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+            let req = Request::get("/path")
+                .header("x-forwarded-for", "203.0.113.7")
+                .body(Body::empty())
+                .unwrap();
+            let echo = body_json(proxy.send(req).await).await;
+            assert_eq!(
+                echo["headers"]["x-forwarded-for"],
+                serde_json::json!(["203.0.113.7, 127.0.0.1"])
+            );
+            assert_eq!(
+                echo["headers"]["x-forwarded-proto"],
+                serde_json::json!(["http"])
+            );
+            assert_eq!(
+                echo["headers"]["x-forwarded-host"],
+                serde_json::json!([proxy.addr.to_string()])
+            );
+        }
+
+        #[tokio::test]
+        async fn debug_echo_describes_the_request() {
+            let upstream = test_support::unused_addr();
+            let proxy = Proxy::start(&format!(
+                "upstreams = \"http://{}\"\ndebug_echo = true",
+                upstream
+            ))
+            .await;
+            let req = Request::post("/debug/echo?a=1")
+                .header("x-custom", "one")
+                .header("x-custom", "two")
+                .header("accept-encoding", "gzip")
+                .header("connection", "x-hop")
+                .header("x-hop", "dropped")
+                .body(Body::from("ignored"))
+                .unwrap();
+            let response = proxy.send(req).await;
+            assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+            let echo = body_json(response).await;
+            assert_eq!(echo["method"], "POST");
+            assert_eq!(echo["path"], "/debug/echo");
+            assert_eq!(echo["query"], "a=1");
+            assert_eq!(echo["version"], "HTTP/1.1");
+            assert_eq!(echo["client_ip"], "127.0.0.1");
+            assert_eq!(
+                echo["headers"]["x-custom"],
+                serde_json::json!(["one", "two"])
+            );
+            assert_eq!(echo["headers"]["x-hop"], serde_json::json!(["dropped"]));
+
+            let forwarded = &echo["forwarded_headers"];
+            assert_eq!(forwarded["x-custom"], serde_json::json!(["one", "two"]));
+            assert_eq!(
+                forwarded["x-forwarded-for"],
+                serde_json::json!(["127.0.0.1"])
+            );
+            for removed in &["accept-encoding", "connection", "x-hop"] {
+                assert!(forwarded.get(removed).is_none(), "{}", removed);
+            }
+        }
+
+        #[tokio::test]
+        async fn debug_echo_is_proxied_unless_enabled() {
+            let upstream = test_support::echo_upstream().await;
+            let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+            let echo = body_json(proxy.get("/debug/echo").await).await;
+            assert_eq!(echo["uri"], "/debug/echo");
+        }
+
+        const MIB: usize = 1024 * 1024;
+        const CHUNK: usize = 64 * 1024;
+
+        #[tokio::test]
+        async fn streams_large_responses_as_they_arrive() {
+            // The upstream holds back everything after its first chunk until the client
+            // has that chunk, which it never would if the response were buffered.
+            let (first_seen, seen) = tokio::sync::oneshot::channel::<()>();
+            let seen = Arc::new(std::sync::Mutex::new(Some(seen)));
+            let upstream = test_support::upstream(move |_| {
+                let seen = seen.lock().unwrap().take().unwrap();
+                async move {
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        sender.send_data(vec![0; CHUNK].into()).await.unwrap();
+                        seen.await.unwrap();
+                        for _ in 1..8 * MIB / CHUNK {
+                            sender.send_data(vec![1; CHUNK].into()).await.unwrap();
+                        }
+                    });
+                    Response::new(body)
+                }
+            })
+            .await;
+            let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+
+            let response = proxy.get("/download").await;
+            assert!(!response.headers().contains_key(CONTENT_LENGTH));
+            let mut body = response.into_body();
+            let mut received = 0;
+            let mut first_seen = Some(first_seen);
+            let read = async {
+                while let Some(chunk) = body.data().await {
+                    received += chunk.unwrap().len();
+                    if let Some(first_seen) = first_seen.take() {
+                        first_seen.send(()).unwrap();
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(10), read)
+                .await
+                .expect("The response was held back");
+            assert_eq!(received, 8 * MIB);
+        }
+
+        #[tokio::test]
+        async fn streams_large_chunked_request_bodies() {
+            let upstream = test_support::upstream(|req: Request<Body>| async move {
+                let mut body = req.into_body();
+                let (mut bytes, mut sum) = (0, 0u64);
+                while let Some(chunk) = body.data().await {
+                    let chunk = chunk.unwrap();
+                    bytes += chunk.len();
+                    sum += chunk.iter().map(|byte| u64::from(*byte)).sum::<u64>();
+                }
+                Response::new(Body::from(format!("{} {}", bytes, sum)))
+            })
+            .await;
+            let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+
+            let chunks = (0..8 * MIB / CHUNK)
+                .map(|index| Result::<_, std::io::Error>::Ok(vec![(index % 256) as u8; CHUNK]));
+            let body = Body::wrap_stream(futures_util::stream::iter(chunks));
+            let response = proxy
+                .send(Request::put("/upload").body(body).unwrap())
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let sum = (0..8 * MIB / CHUNK)
+                .map(|index| (index % 256) as u64 * CHUNK as u64)
+                .sum::<u64>();
+            assert_eq!(body_string(response).await, format!("{} {}", 8 * MIB, sum));
+        }
+    }
+}
+
+/// A server to start: what it listens on and serves, with TLS for the main listeners
+/// when it's configured, and what it's called in the log.
+struct Listener {
+    addr: SocketAddr,
+    builder: server::ServiceBuilder,
+    tls: Option<TlsAcceptor>,
+    role: &'static str,
+}
+
+/// Every listener the config asks for. The HTTPS redirect either gets its own listeners
+/// next to the proxy, or takes over the main ones when it has none.
+fn listeners(
+    config: &Config,
+    proxy: &server::ServiceBuilder,
+    redirect: Option<&server::ServiceBuilder>,
+    admin: Option<&server::ServiceBuilder>,
+    tls: Option<&TlsAcceptor>,
+) -> Vec<Listener> {
+    let redirect_config = &config.https_redirect;
+    let (main, role) = match redirect {
+        Some(redirect) if redirect_config.listen_addrs.is_empty() => (redirect, "HTTPS redirect"),
+        _ => (proxy, "proxy"),
+    };
+    let mut listeners = config
+        .listen_addrs
+        .iter()
+        .map(|addr| Listener {
+            addr: *addr,
+            builder: main.clone(),
+            tls: tls.cloned(),
+            role,
+        })
+        .collect::<Vec<_>>();
+    if let Some(redirect) = redirect {
+        for addr in &redirect_config.listen_addrs {
+            listeners.push(Listener {
+                addr: *addr,
+                builder: redirect.clone(),
+                tls: None,
+                role: "HTTPS redirect",
+            });
+        }
+    }
+    if let (Some(admin_config), Some(admin)) = (&config.admin, admin) {
+        listeners.push(Listener {
+            addr: admin_config.listen_addr,
+            builder: admin.clone(),
+            tls: None,
+            role: "admin",
+        });
+    }
+    listeners
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    setup_logging_service()?;
+    let config_path = Config::path();
+    let config = Config::load(&config_path)?;
+    setup_logging_service(config.log_level, config.log_file.as_ref())?;
+
+    let prior_knowledge = connector::PriorKnowledge::default();
+    let client = Arc::new(client::build(&config.client, prior_knowledge.clone())?);
+    let routing = Arc::new(SharedRouting::new(
+        Routing::new(&config, &client)?,
+        prior_knowledge,
+    ));
+    tokio::spawn(routing::reload_on_sighup(
+        config_path,
+        routing.clone(),
+        client.clone(),
+    ));
+
+    let internal = Arc::new(InternalHeaders::new(&config.internal_headers)?);
+    let admin_builder = match &config.admin {
+        None => None,
+        Some(admin) => Some(server::service_builder(admin::router(
+            admin,
+            routing.clone(),
+            internal.clone(),
+        )?)?),
+    };
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let in_flight = Arc::new(InFlight::default());
+    let metrics = Arc::new(Metrics::new()?);
+    let router = router(
+        &config,
+        client,
+        routing,
+        in_flight.clone(),
+        metrics.clone(),
+        internal,
+    )?;
+    let builder = server::service_builder(router)?;
 
-    let router = router();
-    let service = RouterService::new(router).unwrap();
+    let tls = config.tls.as_ref().map(tls::acceptor).transpose()?;
 
-    let server = Server::bind(&addr).serve(service);
+    let redirect_builder = if config.https_redirect.enabled {
+        Some(server::service_builder(redirect::router(
+            &config.https_redirect,
+        )?)?)
+    } else {
+        None
+    };
+    let listeners = listeners(
+        &config,
+        &builder,
+        redirect_builder.as_ref(),
+        admin_builder.as_ref(),
+        tls.as_ref(),
+    );
 
-    info!("App is running on: {}", addr);
-    info!("Try calling http://localhost:3000/uuid to test the proxy.");
-    server
-        .await
-        .context("Fatal server error resulting in the hyper server stopping")?;
-    Ok::<(), anyhow::Error>(())
+    // Load balancers only sit in front of the main listeners, so only they expect a PROXY
+    // protocol header.
+    let side_listener = server::ListenerConfig {
+        proxy_protocol: false,
+        ..config.listener.clone()
+    };
+    let timeouts = server::ConnectionTimeouts {
+        header_read: config.header_read_timeout,
+        idle: Some(config.idle_timeout).filter(|timeout| !timeout.is_zero()),
+    };
+    // One limit for all the main listeners; the others aren't limited, so the admin API
+    // stays reachable through a flood.
+    let connections = server::Connections::new(
+        metrics.open_connections.clone(),
+        config.listener.max_connections,
+        config.listener.max_connections_per_ip,
+        metrics.rejected_connections.clone(),
+    );
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let mut inherited = upgrade::Inherited::from_env()?;
+    let mut handed_over = upgrade::Listeners::default();
+    let servers = listeners
+        .iter()
+        .map(
+            |Listener {
+                 addr, builder, tls, ..
+             }| {
+                let (listener_config, connections) = if config.listen_addrs.contains(addr) {
+                    (&config.listener, connections.clone())
+                } else {
+                    (&side_listener, connections.unlimited())
+                };
+                let listener = server::bind(*addr, listener_config, inherited.take(addr))
+                    .with_context(|| format!("Binding {}", addr))?;
+                handed_over.add(*addr, &listener);
+                server::serve(
+                    listener,
+                    builder.clone(),
+                    tls.clone(),
+                    listener_config,
+                    timeouts,
+                    connections,
+                    shutdown_rx.clone(),
+                )
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+    // Taking over from an upgraded process is just a drain from its side.
+    let upgrade = config.upgrade;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = shutdown::signal() => {}
+            _ = upgrade::upgraded(upgrade, handed_over) => {}
+        }
+        let _ = shutdown_tx.send(true);
+    });
+
+    for Listener {
+        addr, tls, role, ..
+    } in &listeners
+    {
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        info!("App is running on: {}://{} ({})", scheme, addr, role);
+    }
+    info!(
+        "Try calling {}://{}/uuid to test the proxy.",
+        config.listener_proto(),
+        config.listen_addrs[0]
+    );
+    inherited.ready();
+
+    shutdown::drain(
+        futures_util::future::try_join_all(servers).map_ok(|_| ()),
+        shutdown_rx,
+        &in_flight,
+        config.drain_timeout,
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, body_string};
+
+    fn builder(body: &'static str) -> server::ServiceBuilder {
+        let router = Router::builder()
+            .any(move |_| async move { Ok(Response::new(Body::from(body))) })
+            .build()
+            .unwrap();
+        server::service_builder(router).unwrap()
+    }
+
+    fn roles(listeners: &[Listener]) -> Vec<(SocketAddr, &'static str)> {
+        listeners
+            .iter()
+            .map(|listener| (listener.addr, listener.role))
+            .collect()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn builds_a_listener_per_listen_addr() {
+        let config = Config::parse(
+            "listen_addrs = [\"127.0.0.1:3000\", \"[::1]:3001\"]\n\
+             upstreams = \"http://127.0.0.1:8080\"",
+        )
+        .unwrap();
+        let proxy = builder("proxy");
+        let listeners = listeners(&config, &proxy, None, None, None);
+        assert_eq!(
+            roles(&listeners),
+            [
+                (addr("127.0.0.1:3000"), "proxy"),
+                (addr("[::1]:3001"), "proxy")
+            ]
+        );
+        assert!(listeners
+            .iter()
+            .all(|listener| Arc::ptr_eq(&listener.builder, &proxy)));
+    }
+
+    #[test]
+    fn adds_redirect_and_admin_listeners() {
+        let mut config = Config::parse(
+            "listen_addrs = \"127.0.0.1:3000\"\n\
+             upstreams = \"http://127.0.0.1:8080\"\n\
+             [https_redirect]\nenabled = true\n\
+             [admin]\nlisten_addr = \"127.0.0.1:9000\"\n\
+             basic_auth = { users = [] }",
+        )
+        .unwrap();
+        let (proxy, redirect, admin) = (builder("proxy"), builder("redirect"), builder("admin"));
+        let built = listeners(&config, &proxy, Some(&redirect), Some(&admin), None);
+        assert_eq!(
+            roles(&built),
+            [
+                (addr("127.0.0.1:3000"), "HTTPS redirect"),
+                (addr("127.0.0.1:9000"), "admin")
+            ]
+        );
+
+        config.https_redirect.listen_addrs = vec![addr("127.0.0.1:80")];
+        let built = listeners(&config, &proxy, Some(&redirect), None, None);
+        assert_eq!(
+            roles(&built),
+            [
+                (addr("127.0.0.1:3000"), "proxy"),
+                (addr("127.0.0.1:80"), "HTTPS redirect")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn internal_errors_stay_out_of_responses() {
+        let router = Router::builder()
+            .get("/fail", |_| async {
+                Err(anyhow!("Connecting to postgres://admin:hunter2@db failed"))
+            })
+            .err_handler_with_info(error_handler)
+            .build()
+            .unwrap();
+        let addr = test_support::serve(server::service_builder(router).unwrap()).await;
+        let uri = format!("http://{}/fail", addr).parse().unwrap();
+        let response = Client::new().get(uri).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body_string(response).await, "Something went wrong");
+    }
+
+    #[tokio::test]
+    async fn failed_upstream_requests_show_an_error_page() {
+        let dir = test_support::TempDir::new();
+        let page = dir.write("502.html", "<h1>We'll be right back</h1>");
+        let upstream = test_support::unused_addr();
+        let proxy = test_support::Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[error_pages]\n502 = {:?}",
+            upstream, page
+        ))
+        .await;
+        let response = proxy.get("/path").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let body = body_string(response).await;
+        assert_eq!(body, "<h1>We'll be right back</h1>");
+        assert!(!body.contains(&upstream.to_string()));
+    }
+
+    #[tokio::test]
+    async fn serves_every_listen_addr() {
+        let addrs = [test_support::unused_addr(), test_support::unused_addr()];
+        let config = Config::parse(&format!(
+            "listen_addrs = [\"{}\", \"{}\"]\nupstreams = \"http://127.0.0.1:8080\"",
+            addrs[0], addrs[1]
+        ))
+        .unwrap();
+        let listeners = listeners(&config, &builder("served"), None, None, None);
+        let (_shutdown, shutdown_rx) = tokio::sync::watch::channel(false);
+        for listener in &listeners {
+            let bound = server::bind(listener.addr, &config.listener, None).unwrap();
+            let served = server::serve(
+                bound,
+                listener.builder.clone(),
+                None,
+                &config.listener,
+                server::ConnectionTimeouts::default(),
+                server::Connections::new(
+                    prometheus::IntGauge::new("open", "open").unwrap(),
+                    None,
+                    None,
+                    prometheus::IntCounter::new("rejected", "rejected").unwrap(),
+                ),
+                shutdown_rx.clone(),
+            )
+            .unwrap();
+            tokio::spawn(served);
+        }
+        for addr in addrs {
+            let uri = format!("http://{}/", addr).parse().unwrap();
+            let response = Client::new().get(uri).await.unwrap();
+            assert_eq!(body_string(response).await, "served");
+        }
+    }
 }
@@ -0,0 +1,160 @@
+use crate::error_pages::{self, ErrorPages};
+use anyhow::*;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Response, StatusCode};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Sent as `Retry-After`, in seconds.
+    #[serde(default = "default_retry_after", with = "humantime_serde")]
+    pub retry_after: Duration,
+    /// HTML page served instead of the plain text message.
+    #[serde(default)]
+    pub page: Option<PathBuf>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> MaintenanceConfig {
+        MaintenanceConfig {
+            enabled: false,
+            retry_after: default_retry_after(),
+            page: None,
+        }
+    }
+}
+
+fn default_retry_after() -> Duration {
+    Duration::from_secs(120)
+}
+
+/// Answers every proxied request with a `503` while enabled. The admin API can flip it
+/// at runtime; a reload resets it to the config file's `enabled`.
+pub struct Maintenance {
+    enabled: AtomicBool,
+    retry_after: HeaderValue,
+    page: Option<Bytes>,
+}
+
+impl Maintenance {
+    pub fn new(config: &MaintenanceConfig) -> Result<Maintenance> {
+        let page = match &config.page {
+            None => None,
+            Some(path) => Some(
+                std::fs::read(path)
+                    .with_context(|| format!("Reading maintenance page {}", path.display()))?
+                    .into(),
+            ),
+        };
+        Ok(Maintenance {
+            enabled: AtomicBool::new(config.enabled),
+            retry_after: HeaderValue::from(config.retry_after.as_secs()),
+            page,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Without a page of its own, the configured `503` error page is used, if any.
+    pub fn response(&self, error_pages: &ErrorPages) -> Response<Body> {
+        let mut response = match &self.page {
+            Some(page) => {
+                let mut response = Response::new(Body::from(page.clone()));
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/html; charset=utf-8"),
+                );
+                response
+            }
+            None => error_pages.render(error_pages::generated(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Down for maintenance",
+            )),
+        };
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, self.retry_after.clone());
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_string, echo_upstream, Proxy, TempDir};
+
+    async fn proxy(maintenance: &str) -> Proxy {
+        Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[maintenance]\n{}",
+            echo_upstream().await,
+            maintenance
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    async fn proxied_routes_get_503_while_health_endpoints_answer() {
+        let proxy = proxy("enabled = true\nretry_after = \"30s\"").await;
+
+        let response = proxy.get("/path").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "30");
+        assert!(body_string(response).await.contains("Down for maintenance"));
+
+        for path in ["/healthz", "/metrics"] {
+            assert_eq!(proxy.get(path).await.status(), StatusCode::OK, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_the_configured_page() {
+        let dir = TempDir::new();
+        let page = dir.write("maintenance.html", "<h1>Back soon</h1>");
+        let proxy = proxy(&format!("enabled = true\npage = {:?}", page)).await;
+
+        let response = proxy.get("/path").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "120");
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(body_string(response).await, "<h1>Back soon</h1>");
+    }
+
+    #[tokio::test]
+    async fn proxies_normally_while_disabled() {
+        let proxy = proxy("enabled = false").await;
+        assert_eq!(proxy.get("/path").await.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn toggles_at_runtime() {
+        let maintenance = Maintenance::new(&MaintenanceConfig::default()).unwrap();
+        assert!(!maintenance.is_enabled());
+        maintenance.set_enabled(true);
+        assert!(maintenance.is_enabled());
+        maintenance.set_enabled(false);
+        assert!(!maintenance.is_enabled());
+    }
+
+    #[test]
+    fn a_missing_page_fails_to_load() {
+        let config = MaintenanceConfig {
+            page: Some("/nonexistent/maintenance.html".into()),
+            ..MaintenanceConfig::default()
+        };
+        assert!(Maintenance::new(&config).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+use crate::shutdown::InFlight;
+use anyhow::*;
+use hyper::{header::CONTENT_TYPE, Body, Request, Response, StatusCode, Uri};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use routerify::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub struct Metrics {
+    registry: Registry,
+    requests_total: IntCounter,
+    responses_total: IntCounterVec,
+    upstream_latency: Histogram,
+    /// Upstream latency by upstream and status class, which keeps the label sets few.
+    upstream_responses: HistogramVec,
+    response_body_bytes: Histogram,
+    /// Kept up to date by the servers through [`GaugeGuard`]s.
+    pub open_connections: IntGauge,
+    /// Connections closed on arrival because `max_connections`, or the client's
+    /// `max_connections_per_ip`, were already open.
+    pub rejected_connections: IntCounter,
+    /// Set from the in-flight request count whenever metrics are rendered.
+    in_flight_requests: IntGauge,
+    /// Requests waiting for a bulkhead slot, by upstream.
+    pub bulkhead_queued: IntGaugeVec,
+}
+
+/// Adds one to a gauge for as long as it lives, so the gauge also comes back down on
+/// early returns and panics.
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &IntGauge) -> GaugeGuard {
+        gauge.inc();
+        GaugeGuard(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Result<Metrics> {
+        let registry = Registry::new_custom(Some("vostok".into()), None)
+            .context("Creating metrics registry")?;
+
+        let requests_total = IntCounter::new("requests_total", "Total proxied requests")?;
+        let responses_total = IntCounterVec::new(
+            Opts::new("responses_total", "Proxied responses by status code"),
+            &["status"],
+        )?;
+        let upstream_latency = Histogram::with_opts(HistogramOpts::new(
+            "upstream_latency_seconds",
+            "Time spent waiting for the upstream to respond",
+        ))?;
+        let upstream_responses = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_response_seconds",
+                "Time spent waiting for each upstream to respond, by status class",
+            ),
+            &["upstream", "status_class"],
+        )?;
+        let response_body_bytes = Histogram::with_opts(
+            HistogramOpts::new(
+                "response_body_bytes",
+                "Size of the response bodies sent to clients, as far as they got",
+            )
+            .buckets(prometheus::exponential_buckets(256.0, 4.0, 12)?),
+        )?;
+
+        let open_connections = IntGauge::new(
+            "open_connections",
+            "Client connections currently open, on all listeners",
+        )?;
+        let rejected_connections = IntCounter::new(
+            "rejected_connections_total",
+            "Client connections closed on arrival because the connection limit was reached",
+        )?;
+        let in_flight_requests = IntGauge::new(
+            "in_flight_requests",
+            "Proxied requests still waiting on an upstream response",
+        )?;
+        let bulkhead_queued = IntGaugeVec::new(
+            Opts::new(
+                "bulkhead_queued_requests",
+                "Requests waiting for a free bulkhead slot on their upstream",
+            ),
+            &["upstream"],
+        )?;
+
+        registry.register(Box::new(requests_total.clone()))?;
+        registry.register(Box::new(responses_total.clone()))?;
+        registry.register(Box::new(upstream_latency.clone()))?;
+        registry.register(Box::new(upstream_responses.clone()))?;
+        registry.register(Box::new(response_body_bytes.clone()))?;
+        registry.register(Box::new(open_connections.clone()))?;
+        registry.register(Box::new(rejected_connections.clone()))?;
+        registry.register(Box::new(in_flight_requests.clone()))?;
+        registry.register(Box::new(bulkhead_queued.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            requests_total,
+            responses_total,
+            upstream_latency,
+            upstream_responses,
+            response_body_bytes,
+            open_connections,
+            rejected_connections,
+            in_flight_requests,
+            bulkhead_queued,
+        })
+    }
+
+    /// Records a finished proxied request to `upstream`. `status` is `None` when no
+    /// upstream response was received at all.
+    pub fn observe(&self, upstream: &Uri, status: Option<StatusCode>, latency: Duration) {
+        self.requests_total.inc();
+        let (code, class) = match status {
+            Some(status) => (
+                status.as_u16().to_string(),
+                format!("{}xx", status.as_u16() / 100),
+            ),
+            None => ("error".to_string(), "error".to_string()),
+        };
+        self.responses_total.with_label_values(&[&code]).inc();
+        self.upstream_latency.observe(latency.as_secs_f64());
+        self.upstream_responses
+            .with_label_values(&[&upstream.to_string(), &class])
+            .observe(latency.as_secs_f64());
+    }
+
+    /// Records the bytes of a response body sent, once it's done or the client left.
+    pub fn observe_body_bytes(&self, bytes: u64) {
+        self.response_body_bytes.observe(bytes as f64);
+    }
+
+    pub fn render(&self, in_flight: &InFlight) -> Result<Vec<u8>> {
+        self.in_flight_requests.set(in_flight.count() as i64);
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .context("Encoding metrics")?;
+        Ok(buffer)
+    }
+}
+
+pub async fn metrics_handler(req: Request<Body>) -> Result<Response<Body>> {
+    let env = req.data::<Arc<crate::Env>>().unwrap();
+    let body = env.metrics.render(&env.in_flight)?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, TextEncoder::new().format_type())
+        .body(Body::from(body))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, body_string, Proxy};
+
+    #[tokio::test]
+    async fn scrape_counts_proxied_requests() {
+        let upstream = test_support::upstream(|_| async {
+            Response::builder()
+                .status(StatusCode::CREATED)
+                .body(Body::from("created"))
+                .unwrap()
+        })
+        .await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+        assert_eq!(proxy.get("/things").await.status(), StatusCode::CREATED);
+
+        let response = proxy.get("/metrics").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            TextEncoder::new().format_type()
+        );
+        let metrics = body_string(response).await;
+        assert!(metrics.contains("vostok_requests_total 1\n"), "{}", metrics);
+        assert!(
+            metrics.contains("vostok_responses_total{status=\"201\"} 1\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("vostok_upstream_latency_seconds_count 1\n"),
+            "{}",
+            metrics
+        );
+    }
+
+    #[test]
+    fn counts_requests_without_a_response_as_errors() {
+        let metrics = Metrics::new().unwrap();
+        let upstream = "http://upstream".parse().unwrap();
+        metrics.observe(&upstream, None, Duration::from_millis(5));
+        let rendered = String::from_utf8(metrics.render(&InFlight::default()).unwrap()).unwrap();
+        assert!(rendered.contains("vostok_responses_total{status=\"error\"} 1\n"));
+    }
+
+    #[test]
+    fn upstream_latency_is_labelled_by_upstream_and_status_class() {
+        let metrics = Metrics::new().unwrap();
+        let (first, second) = (
+            "http://first".parse().unwrap(),
+            "http://second".parse().unwrap(),
+        );
+        metrics.observe(&first, Some(StatusCode::OK), Duration::from_millis(5));
+        metrics.observe(&first, Some(StatusCode::CREATED), Duration::from_millis(5));
+        metrics.observe(
+            &first,
+            Some(StatusCode::NOT_FOUND),
+            Duration::from_millis(5),
+        );
+        metrics.observe(
+            &second,
+            Some(StatusCode::BAD_GATEWAY),
+            Duration::from_millis(5),
+        );
+        metrics.observe(&second, None, Duration::from_millis(5));
+        let rendered = String::from_utf8(metrics.render(&InFlight::default()).unwrap()).unwrap();
+        for (labels, count) in &[
+            ("status_class=\"2xx\",upstream=\"http://first/\"", 2),
+            ("status_class=\"4xx\",upstream=\"http://first/\"", 1),
+            ("status_class=\"5xx\",upstream=\"http://second/\"", 1),
+            ("status_class=\"error\",upstream=\"http://second/\"", 1),
+        ] {
+            let line = format!(
+                "vostok_upstream_response_seconds_count{{{}}} {}\n",
+                labels, count
+            );
+            assert!(rendered.contains(&line), "{}\n{}", line, rendered);
+        }
+        assert!(!rendered.contains("status_class=\"2xx\",upstream=\"http://second/\""));
+    }
+
+    #[tokio::test]
+    async fn scrape_labels_upstream_latency_with_the_upstream() {
+        let upstream = test_support::echo_upstream().await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+        proxy.get("/path").await;
+
+        let metrics = body_string(proxy.get("/metrics").await).await;
+        let line = format!(
+            "vostok_upstream_response_seconds_count{{status_class=\"2xx\",upstream=\"http://{}/\"}} 1\n",
+            upstream
+        );
+        assert!(metrics.contains(&line), "{}\n{}", line, metrics);
+    }
+
+    #[test]
+    fn gauge_guards_count_while_they_live() {
+        let metrics = Metrics::new().unwrap();
+        let first = GaugeGuard::new(&metrics.open_connections);
+        let second = GaugeGuard::new(&metrics.open_connections);
+        assert_eq!(metrics.open_connections.get(), 2);
+        drop(first);
+        assert_eq!(metrics.open_connections.get(), 1);
+        drop(second);
+        assert_eq!(metrics.open_connections.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn scrape_reports_requests_in_flight() {
+        let release = Arc::new(tokio::sync::Semaphore::new(0));
+        let upstream = test_support::upstream({
+            let release = release.clone();
+            move |_| {
+                let release = release.clone();
+                async move {
+                    release.acquire().await.unwrap().forget();
+                    Response::new(Body::from("done"))
+                }
+            }
+        })
+        .await;
+        let proxy = Arc::new(Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await);
+        let pending = tokio::spawn({
+            let proxy = proxy.clone();
+            async move { body_string(proxy.get("/path").await).await }
+        });
+        while proxy.in_flight.count() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let metrics = body_string(proxy.get("/metrics").await).await;
+        assert!(
+            metrics.contains("vostok_in_flight_requests 1\n"),
+            "{}",
+            metrics
+        );
+        // The waiting request's connection and the scrape's.
+        assert!(
+            metrics.contains("vostok_open_connections 2\n"),
+            "{}",
+            metrics
+        );
+
+        release.add_permits(1);
+        assert_eq!(pending.await.unwrap(), "done");
+        let metrics = body_string(proxy.get("/metrics").await).await;
+        assert!(
+            metrics.contains("vostok_in_flight_requests 0\n"),
+            "{}",
+            metrics
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use anyhow::*;
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use ipnet::IpNet;
+use log::debug;
+use routerify::prelude::*;
+use routerify::{Middleware, RouterBuilder};
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// A response a pre-middleware sends instead of passing the request on.
+///
+/// routerify pre-middleware can only return a request or an error, so [`reject`] stashes
+/// the response in the request context and fails the request; `error_handler` then sends
+/// the stashed response.
+#[derive(Clone, Debug)]
+pub struct EarlyResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl EarlyResponse {
+    pub fn new(status: StatusCode, body: impl Into<Bytes>) -> EarlyResponse {
+        EarlyResponse {
+            status,
+            headers: HeaderMap::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> EarlyResponse {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+/// The middleware a router runs, each registered under a name so it can be switched off
+/// from the config. routerify runs pre-middleware in the order they were added, then the
+/// handler, then post-middleware in the order they were added.
+#[derive(Default)]
+pub struct MiddlewareChain {
+    entries: Vec<(&'static str, bool, Middleware<Body, Error>)>,
+}
+
+impl MiddlewareChain {
+    /// Middleware that isn't `enabled` is skipped, but its name can still be disabled.
+    /// A pre/post pair can share one name.
+    pub fn add(
+        &mut self,
+        name: &'static str,
+        enabled: bool,
+        middleware: Middleware<Body, Error>,
+    ) -> &mut MiddlewareChain {
+        self.entries.push((name, enabled, middleware));
+        self
+    }
+
+    /// Turns off every middleware in `names`, which must all be in the chain.
+    pub fn disable(&mut self, names: &[String]) -> Result<()> {
+        for name in names {
+            let mut found = false;
+            for (entry, enabled, _) in &mut self.entries {
+                if entry == name {
+                    *enabled = false;
+                    found = true;
+                }
+            }
+            ensure!(
+                found,
+                "Unknown middleware {:?} in disabled_middleware",
+                name
+            );
+        }
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        for (name, enabled, _) in &self.entries {
+            if *enabled && !names.contains(name) {
+                names.push(*name);
+            }
+        }
+        names
+    }
+
+    pub fn install(self, mut router: RouterBuilder<Body, Error>) -> RouterBuilder<Body, Error> {
+        for (_, enabled, middleware) in self.entries {
+            if enabled {
+                router = router.middleware(middleware);
+            }
+        }
+        router
+    }
+}
+
+pub fn reject(req: &Request<Body>, response: EarlyResponse) -> Error {
+    let err = anyhow!("Request rejected with {}", response.status);
+    req.set_context(response);
+    err
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessControl {
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessControl {
+    pub fn is_enabled(&self) -> bool {
+        !self.allow.is_empty() || !self.deny.is_empty()
+    }
+
+    /// Deny wins over allow; an empty allowlist allows everyone not denied.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+pub async fn access_control(req: Request<Body>) -> Result<Request<Body>> {
+    let env = req.data::<Arc<crate::Env>>().unwrap();
+    let ip = crate::client_ip::client_ip(&req);
+    if env.access_control.is_allowed(ip) {
+        return Ok(req);
+    }
+
+    debug!("Denying request from {}", ip);
+    Err(reject(
+        &req,
+        EarlyResponse::new(StatusCode::FORBIDDEN, "Forbidden"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server;
+    use crate::test_support::{self, Proxy};
+    use routerify::Router;
+    use std::sync::Mutex;
+
+    fn access_control(allow: &[&str], deny: &[&str]) -> AccessControl {
+        AccessControl {
+            allow: allow.iter().map(|net| net.parse().unwrap()).collect(),
+            deny: deny.iter().map(|net| net.parse().unwrap()).collect(),
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn allowlists_ipv4_and_ipv6() {
+        let access_control = access_control(&["10.0.0.0/8", "2001:db8::/32"], &[]);
+        assert!(access_control.is_allowed(ip("10.1.2.3")));
+        assert!(access_control.is_allowed(ip("2001:db8::1")));
+        assert!(access_control.is_allowed(ip("::ffff:10.1.2.3")));
+        assert!(!access_control.is_allowed(ip("192.0.2.1")));
+        assert!(!access_control.is_allowed(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn denylists_ipv4_and_ipv6() {
+        let access_control = access_control(&[], &["192.0.2.0/24", "2001:db8::/32"]);
+        assert!(!access_control.is_allowed(ip("192.0.2.77")));
+        assert!(!access_control.is_allowed(ip("::ffff:192.0.2.77")));
+        assert!(!access_control.is_allowed(ip("2001:db8:1::1")));
+        assert!(access_control.is_allowed(ip("198.51.100.1")));
+        assert!(access_control.is_allowed(ip("::1")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let access_control = access_control(&["10.0.0.0/8"], &["10.0.0.5/32"]);
+        assert!(access_control.is_allowed(ip("10.0.0.4")));
+        assert!(!access_control.is_allowed(ip("10.0.0.5")));
+        assert!(!AccessControl::default().is_enabled());
+        assert!(access_control.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn rejects_denied_clients_with_403() {
+        let upstream = test_support::upstream(|_| async { Response::new(Body::empty()) }).await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[access_control]\ndeny = [\"127.0.0.0/8\"]",
+            upstream
+        ))
+        .await;
+        assert_eq!(proxy.get("/x").await.status(), StatusCode::FORBIDDEN);
+
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[access_control]\nallow = [\"127.0.0.1/32\"]",
+            upstream
+        ))
+        .await;
+        assert_eq!(proxy.get("/x").await.status(), StatusCode::OK);
+    }
+
+    type Calls = Arc<Mutex<Vec<&'static str>>>;
+
+    fn pre(calls: &Calls, name: &'static str) -> Middleware<Body, Error> {
+        let calls = calls.clone();
+        Middleware::pre(move |req| {
+            calls.lock().unwrap().push(name);
+            async move { Ok(req) }
+        })
+    }
+
+    fn post(calls: &Calls, name: &'static str) -> Middleware<Body, Error> {
+        let calls = calls.clone();
+        Middleware::post(move |res| {
+            calls.lock().unwrap().push(name);
+            async move { Ok(res) }
+        })
+    }
+
+    #[tokio::test]
+    async fn installs_enabled_middleware_in_order() {
+        let calls = Calls::default();
+        let mut chain = MiddlewareChain::default();
+        chain
+            .add("first", true, pre(&calls, "first"))
+            .add("off", false, pre(&calls, "off"))
+            .add("timing", true, pre(&calls, "timing pre"))
+            .add("disabled", true, pre(&calls, "disabled"))
+            .add("timing", true, post(&calls, "timing post"))
+            .add("last", true, post(&calls, "last"));
+        chain.disable(&["disabled".to_string()]).unwrap();
+        assert_eq!(chain.enabled(), ["first", "timing", "last"]);
+
+        let handled = calls.clone();
+        let router = chain
+            .install(Router::builder())
+            .get("/", move |_| {
+                handled.lock().unwrap().push("handler");
+                async { Ok(Response::new(Body::from("OK"))) }
+            })
+            .build()
+            .unwrap();
+        let addr = test_support::serve(server::service_builder(router).unwrap()).await;
+        let response = hyper::Client::new()
+            .get(format!("http://{}/", addr).parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["first", "timing pre", "handler", "timing post", "last"]
+        );
+    }
+
+    #[test]
+    fn only_known_middleware_can_be_disabled() {
+        let mut chain = MiddlewareChain::default();
+        chain.add("known", true, Middleware::pre(|req| async { Ok(req) }));
+        let err = chain.disable(&["unknown".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("unknown"), "{}", err);
+    }
+}
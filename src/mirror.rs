@@ -0,0 +1,192 @@
+use crate::config::parse_upstream_uri;
+use crate::HttpsClient;
+use anyhow::*;
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Request, Uri};
+use log::{debug, warn};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    /// Receives a copy of every proxied request; its responses are discarded.
+    pub upstream: String,
+    /// Requests with larger bodies aren't mirrored. Unset means `max_buffer_bytes`, and
+    /// it can't go above that, since larger bodies are never buffered.
+    #[serde(default)]
+    pub max_body_bytes: Option<u64>,
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// Shadows live traffic to a second upstream without involving the client.
+pub struct Mirror {
+    upstream: Uri,
+    max_body_bytes: Option<u64>,
+    timeout: Duration,
+}
+
+impl Mirror {
+    pub fn new(config: &MirrorConfig) -> Result<Mirror> {
+        Ok(Mirror {
+            upstream: parse_upstream_uri(&config.upstream).context("Invalid mirror.upstream")?,
+            max_body_bytes: config.max_body_bytes,
+            timeout: config.timeout,
+        })
+    }
+
+    pub fn upstream(&self) -> &Uri {
+        &self.upstream
+    }
+
+    /// A copy of `req` with `body`, its buffered body, or `None` if that's over
+    /// `max_body_bytes`.
+    pub fn copy(&self, req: &Request<Body>, body: Bytes) -> Option<Request<Body>> {
+        if let Some(limit) = self.max_body_bytes {
+            if body.len() as u64 > limit {
+                debug!(
+                    "Not mirroring {} {}: request body is over mirror.max_body_bytes",
+                    req.method(),
+                    req.uri()
+                );
+                return None;
+            }
+        }
+
+        let mut copy = Request::new(Body::from(body));
+        *copy.method_mut() = req.method().clone();
+        *copy.uri_mut() = req.uri().clone();
+        *copy.version_mut() = req.version();
+        *copy.headers_mut() = req.headers().clone();
+        Some(copy)
+    }
+
+    /// Sends `req` in the background and reads its response to the end. The outcome is
+    /// only logged.
+    pub fn send(&self, client: Arc<HttpsClient>, req: Request<Body>) {
+        let timeout = self.timeout;
+        let uri = req.uri().clone();
+        tokio::spawn(async move {
+            let exchange = async {
+                let response = client.request(req).await?;
+                let status = response.status();
+                let mut body = response.into_body();
+                while let Some(chunk) = body.data().await {
+                    chunk?;
+                }
+                std::result::Result::Ok::<_, hyper::Error>(status)
+            };
+            match tokio::time::timeout(timeout, exchange).await {
+                Err(_) => warn!("Mirror request to {} timed out after {:?}", uri, timeout),
+                Result::Ok(Err(err)) => warn!("Mirror request to {} failed: {}", uri, err),
+                Result::Ok(Result::Ok(status)) => debug!("Mirror {} answered {}", uri, status),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{self, body_bytes, body_string, Proxy};
+    use hyper::{Body, Request, Response, StatusCode};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    /// A mirror that passes on the method, path and body of each request it gets.
+    async fn recording_mirror() -> (std::net::SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let (sender, received) = mpsc::unbounded_channel();
+        let addr = test_support::upstream(move |req: Request<Body>| {
+            let sender = sender.clone();
+            async move {
+                let line = format!("{} {}", req.method(), req.uri());
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                sender
+                    .send(format!("{} {}", line, String::from_utf8_lossy(&body)))
+                    .unwrap();
+                Response::new(Body::from("ignored"))
+            }
+        })
+        .await;
+        (addr, received)
+    }
+
+    async fn primary() -> std::net::SocketAddr {
+        test_support::upstream(|_| async { Response::new(Body::from("primary")) }).await
+    }
+
+    async fn proxy(primary: std::net::SocketAddr, mirror: std::net::SocketAddr) -> Proxy {
+        Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[mirror]\nupstream = \"http://{}\"\nmax_body_bytes = 16\ntimeout = \"200ms\"",
+            primary, mirror
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    async fn mirrors_requests_with_their_bodies() {
+        let (mirror, mut received) = recording_mirror().await;
+        let proxy = proxy(primary().await, mirror).await;
+        let response = proxy
+            .send(
+                Request::post("/orders?id=7")
+                    .body(Body::from("small body"))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(body_string(response).await, "primary");
+        let mirrored = tokio::time::timeout(Duration::from_secs(2), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(mirrored, "POST /orders?id=7 small body");
+
+        // Bodies over mirror.max_body_bytes only go to the primary.
+        let response = proxy
+            .send(
+                Request::post("/orders")
+                    .body(Body::from("a body of over sixteen bytes"))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(body_string(response).await, "primary");
+        let response = proxy.get("/after").await;
+        assert_eq!(body_string(response).await, "primary");
+        let mirrored = tokio::time::timeout(Duration::from_secs(2), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(mirrored, "GET /after ");
+    }
+
+    #[tokio::test]
+    async fn mirror_failures_leave_the_primary_response_alone() {
+        let failing = test_support::upstream(|_| async {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("mirror broke"))
+                .unwrap()
+        })
+        .await;
+        let slow = test_support::upstream(|_| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Response::new(Body::empty())
+        })
+        .await;
+        let primary = primary().await;
+        for mirror in [failing, slow, test_support::unused_addr()] {
+            let proxy = proxy(primary, mirror).await;
+            let started = std::time::Instant::now();
+            let response = proxy.get("/path").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_bytes(response).await, "primary");
+            // Nothing waits for the mirror.
+            assert!(started.elapsed() < Duration::from_secs(1));
+        }
+    }
+}
@@ -0,0 +1,509 @@
+use crate::HttpsClient;
+use anyhow::*;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Method, Request, StatusCode, Uri};
+use log::{debug, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+pub const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+/// Spans are exported in batches of at most this many.
+const MAX_BATCH: usize = 512;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+    /// Base URL of an OTLP/HTTP collector; spans are posted to `/v1/traces` as JSON.
+    pub otlp_endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    #[serde(default = "default_export_interval", with = "humantime_serde")]
+    pub export_interval: Duration,
+    /// Finished spans waiting for export; more are dropped until the queue drains.
+    #[serde(default = "default_max_queue")]
+    pub max_queue: usize,
+}
+
+fn default_service_name() -> String {
+    "vostok".to_string()
+}
+
+fn default_export_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_max_queue() -> usize {
+    2048
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Server,
+    Client,
+}
+
+impl SpanKind {
+    /// The OTLP `SpanKind` enum value.
+    fn otlp(self) -> u8 {
+        match self {
+            SpanKind::Server => 2,
+            SpanKind::Client => 3,
+        }
+    }
+}
+
+/// A span that's still running. Finish it with [`Tracer::finish`].
+pub struct Span {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    sampled: bool,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+impl Span {
+    pub fn child(&self, name: impl Into<String>, kind: SpanKind) -> Span {
+        Span {
+            trace_id: self.trace_id,
+            span_id: random_id(),
+            parent_span_id: Some(self.span_id),
+            sampled: self.sampled,
+            name: name.into(),
+            kind,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn attribute(&mut self, key: &'static str, value: impl Into<String>) {
+        self.attributes
+            .push((key, json!({ "stringValue": value.into() })));
+    }
+
+    fn int_attribute(&mut self, key: &'static str, value: i64) {
+        self.attributes
+            .push((key, json!({ "intValue": value.to_string() })));
+    }
+
+    /// Makes this span the parent of whatever the request leads to upstream. Any
+    /// `tracestate` is left on the request as it is.
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        let traceparent = format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.sampled as u8
+        );
+        headers.insert(TRACEPARENT, HeaderValue::from_str(&traceparent).unwrap());
+    }
+
+    fn to_otlp(&self, end: SystemTime, error: bool) -> Value {
+        let mut span = json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&self.span_id),
+            "name": self.name,
+            "kind": self.kind.otlp(),
+            "startTimeUnixNano": unix_nanos(self.start).to_string(),
+            "endTimeUnixNano": unix_nanos(end).to_string(),
+            "attributes": self
+                .attributes
+                .iter()
+                .map(|(key, value)| json!({ "key": key, "value": value }))
+                .collect::<Vec<_>>(),
+            // STATUS_CODE_ERROR, or STATUS_CODE_UNSET
+            "status": { "code": if error { 2 } else { 0 } },
+        });
+        if let Some(parent_span_id) = &self.parent_span_id {
+            span["parentSpanId"] = Value::from(hex(parent_span_id));
+        }
+        span
+    }
+}
+
+/// Records spans for proxied requests and exports them to an OTLP collector in the
+/// background.
+///
+/// This is a small exporter of its own rather than `tracing-opentelemetry`: Vostok logs
+/// through `log`, not `tracing`, so that would mean moving every log line over, and
+/// `opentelemetry-otlp` brings its own HTTP or gRPC client on top of hyper. Two spans
+/// per request, posted as OTLP/HTTP JSON with the client Vostok already has, don't need
+/// either.
+pub struct Tracer {
+    spans: mpsc::Sender<Value>,
+}
+
+impl Tracer {
+    pub fn new(config: &TracingConfig, client: Arc<HttpsClient>) -> Result<Tracer> {
+        let endpoint: Uri = format!("{}/v1/traces", config.otlp_endpoint.trim_end_matches('/'))
+            .parse()
+            .with_context(|| format!("Invalid tracing.otlp_endpoint {:?}", config.otlp_endpoint))?;
+        ensure!(
+            matches!(endpoint.scheme_str(), Some("http") | Some("https")),
+            "tracing.otlp_endpoint must be an http or https URL"
+        );
+        ensure!(config.max_queue > 0, "tracing.max_queue must be at least 1");
+        ensure!(
+            !config.export_interval.is_zero(),
+            "tracing.export_interval can't be zero"
+        );
+
+        let (spans, rx) = mpsc::channel(config.max_queue);
+        tokio::spawn(export(
+            client,
+            endpoint,
+            config.service_name.clone(),
+            config.export_interval,
+            rx,
+        ));
+        Ok(Tracer { spans })
+    }
+
+    /// Starts the span for an incoming request, continuing the caller's trace if the
+    /// request has a valid `traceparent`.
+    pub fn start_server_span<T>(&self, req: &Request<T>) -> Span {
+        let parent = req
+            .headers()
+            .get(TRACEPARENT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_traceparent);
+        let (trace_id, parent_span_id, sampled) = match parent {
+            Some((trace_id, span_id, sampled)) => (trace_id, Some(span_id), sampled),
+            None => (random_id(), None, true),
+        };
+        let mut span = Span {
+            trace_id,
+            span_id: random_id(),
+            parent_span_id,
+            sampled,
+            name: format!("HTTP {}", req.method()),
+            kind: SpanKind::Server,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        };
+        span.attribute("http.method", req.method().as_str());
+        span.attribute("http.target", req.uri().path());
+        span
+    }
+
+    /// Ends `span` with the response status, or as failed if there was no response.
+    pub fn finish(&self, mut span: Span, status: Option<StatusCode>) {
+        if !span.sampled {
+            return;
+        }
+        if let Some(status) = status {
+            span.int_attribute("http.status_code", status.as_u16().into());
+        }
+        let error = status.is_none_or(|status| status.is_server_error());
+        if self
+            .spans
+            .try_send(span.to_otlp(SystemTime::now(), error))
+            .is_err()
+        {
+            debug!("Span queue is full, dropping span {}", span.name);
+        }
+    }
+}
+
+/// Parses a version 00 `traceparent` into its trace id, parent span id and sampled flag.
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], bool)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let span_id = parts.next()?;
+    let flags = parts.next()?;
+    if version != "00" || parts.next().is_some() || flags.len() != 2 {
+        return None;
+    }
+    let trace_id: [u8; 16] = unhex(trace_id)?;
+    let span_id: [u8; 8] = unhex(span_id)?;
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    if trace_id == [0; 16] || span_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, span_id, flags & 1 == 1))
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    let rng = SystemRandom::new();
+    let mut id = [0; N];
+    while id == [0; N] {
+        rng.fill(&mut id).expect("System random source failed");
+    }
+    id
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+fn unhex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2
+        || !value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+/// Sends finished spans to the collector every `interval`, or as soon as a full batch
+/// is waiting. Failed exports are logged and their spans dropped.
+async fn export(
+    client: Arc<HttpsClient>,
+    endpoint: Uri,
+    service_name: String,
+    interval: Duration,
+    mut spans: mpsc::Receiver<Value>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    let mut batch = Vec::new();
+    loop {
+        let (due, closed) = tokio::select! {
+            span = spans.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    (batch.len() >= MAX_BATCH, false)
+                }
+                None => (true, true),
+            },
+            _ = ticker.tick() => (true, false),
+        };
+        if due && !batch.is_empty() {
+            let body = json!({
+                "resourceSpans": [{
+                    "resource": {
+                        "attributes": [{
+                            "key": "service.name",
+                            "value": { "stringValue": service_name },
+                        }],
+                    },
+                    "scopeSpans": [{
+                        "scope": { "name": "vostok" },
+                        "spans": std::mem::take(&mut batch),
+                    }],
+                }],
+            });
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(endpoint.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            match client.request(req).await {
+                Result::Ok(response) if response.status().is_success() => {}
+                Result::Ok(response) => {
+                    warn!(
+                        "Exporting spans to {} failed: {}",
+                        endpoint,
+                        response.status()
+                    )
+                }
+                Err(err) => warn!("Exporting spans to {} failed: {}", endpoint, err),
+            }
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client;
+    use crate::connector::PriorKnowledge;
+    use crate::test_support::{self, body_json, Proxy};
+    use hyper::Response;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    /// A mock OTLP collector, and the spans it has been sent.
+    async fn collector() -> (std::net::SocketAddr, Arc<Mutex<Vec<Value>>>) {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let received = spans.clone();
+        let addr = test_support::upstream(move |req: Request<Body>| {
+            let received = received.clone();
+            async move {
+                assert_eq!(req.uri().path(), "/v1/traces");
+                let export: Value =
+                    serde_json::from_slice(&hyper::body::to_bytes(req).await.unwrap()).unwrap();
+                let batch = export["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                    .as_array()
+                    .unwrap()
+                    .clone();
+                received.lock().unwrap().extend(batch);
+                Response::new(Body::empty())
+            }
+        })
+        .await;
+        (addr, spans)
+    }
+
+    async fn wait_for_spans(spans: &Mutex<Vec<Value>>, count: usize) -> Vec<Value> {
+        let started = Instant::now();
+        while spans.lock().unwrap().len() < count {
+            assert!(started.elapsed() < Duration::from_secs(5), "Not exported");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        spans.lock().unwrap().clone()
+    }
+
+    fn config(otlp_endpoint: String) -> TracingConfig {
+        TracingConfig {
+            otlp_endpoint,
+            service_name: default_service_name(),
+            export_interval: Duration::from_millis(10),
+            max_queue: default_max_queue(),
+        }
+    }
+
+    fn tracer(config: &TracingConfig) -> Result<Tracer> {
+        let client = client::build(&Default::default(), PriorKnowledge::default()).unwrap();
+        Tracer::new(config, Arc::new(client))
+    }
+
+    #[test]
+    fn parses_traceparent() {
+        let (trace_id, span_id, sampled) =
+            parse_traceparent(&format!("00-{}-{}-01", TRACE_ID, PARENT_ID)).unwrap();
+        assert_eq!(
+            (hex(&trace_id), hex(&span_id), sampled),
+            (TRACE_ID.into(), PARENT_ID.into(), true)
+        );
+        let (_, _, sampled) =
+            parse_traceparent(&format!("00-{}-{}-00", TRACE_ID, PARENT_ID)).unwrap();
+        assert!(!sampled);
+
+        for invalid in [
+            format!("01-{}-{}-01", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-01-extra", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-1", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-01", TRACE_ID.to_uppercase(), PARENT_ID),
+            format!("00-{}-{}-01", &TRACE_ID[1..], PARENT_ID),
+            format!("00-{}-{}-01", "0".repeat(32), PARENT_ID),
+            format!("00-{}-{}-01", TRACE_ID, "0".repeat(16)),
+            "garbage".to_string(),
+        ]
+        .iter()
+        {
+            assert_eq!(parse_traceparent(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_configs() {
+        assert!(tracer(&config("http://127.0.0.1:4318".to_string())).is_ok());
+        assert!(tracer(&config("ftp://127.0.0.1:4318".to_string())).is_err());
+        assert!(tracer(&config("not a url".to_string())).is_err());
+        assert!(tracer(&TracingConfig {
+            export_interval: Duration::ZERO,
+            ..config("http://127.0.0.1:4318".to_string())
+        })
+        .is_err());
+        assert!(tracer(&TracingConfig {
+            max_queue: 0,
+            ..config("http://127.0.0.1:4318".to_string())
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn unsampled_spans_are_not_exported() {
+        let (collector, spans) = collector().await;
+        let tracer = tracer(&config(format!("http://{}", collector))).unwrap();
+        let req = Request::get("/")
+            .header(TRACEPARENT, format!("00-{}-{}-00", TRACE_ID, PARENT_ID))
+            .body(())
+            .unwrap();
+        tracer.finish(tracer.start_server_span(&req), Some(StatusCode::OK));
+        let sampled = Request::get("/").body(()).unwrap();
+        tracer.finish(tracer.start_server_span(&sampled), Some(StatusCode::OK));
+
+        let exported = wait_for_spans(&spans, 1).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(spans.lock().unwrap().len(), 1);
+        assert_ne!(exported[0]["traceId"], TRACE_ID);
+    }
+
+    fn attribute<'a>(span: &'a Value, key: &str) -> &'a Value {
+        let attribute = span["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|attribute| attribute["key"] == key)
+            .unwrap();
+        &attribute["value"]
+    }
+
+    #[tokio::test]
+    async fn records_a_server_span_per_request_and_forwards_the_trace() {
+        let (collector, spans) = collector().await;
+        let upstream = test_support::echo_upstream().await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[tracing]\notlp_endpoint = \"http://{}\"\nexport_interval = \"10ms\"",
+            upstream, collector
+        ))
+        .await;
+
+        let req = Request::get("/path?q=1")
+            .header(TRACEPARENT, format!("00-{}-{}-01", TRACE_ID, PARENT_ID))
+            .header("tracestate", "vendor=1")
+            .body(Body::empty())
+            .unwrap();
+        let echo = body_json(proxy.send(req).await).await;
+        let exported = wait_for_spans(&spans, 2).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(spans.lock().unwrap().len(), 2);
+
+        let server = exported.iter().find(|span| span["kind"] == 2).unwrap();
+        let client = exported.iter().find(|span| span["kind"] == 3).unwrap();
+        assert_eq!(server["traceId"], TRACE_ID);
+        assert_eq!(server["parentSpanId"], PARENT_ID);
+        assert_eq!(server["name"], "HTTP GET");
+        assert_eq!(attribute(server, "http.target")["stringValue"], "/path");
+        assert_eq!(attribute(server, "http.status_code")["intValue"], "200");
+        assert_eq!(server["status"]["code"], 0);
+        assert_eq!(client["traceId"], TRACE_ID);
+        assert_eq!(client["parentSpanId"], server["spanId"]);
+
+        // The upstream's parent is the client span, and tracestate passes through.
+        let traceparent = format!("00-{}-{}-01", TRACE_ID, client["spanId"].as_str().unwrap());
+        assert_eq!(echo["headers"]["traceparent"], json!([traceparent]));
+        assert_eq!(echo["headers"]["tracestate"], json!(["vendor=1"]));
+
+        // A request without a traceparent starts a trace of its own.
+        let echo = body_json(proxy.get("/other").await).await;
+        let exported = wait_for_spans(&spans, 4).await;
+        let traceparent = echo["headers"]["traceparent"][0].as_str().unwrap();
+        assert!(!traceparent.contains(TRACE_ID));
+        assert!(exported[2..]
+            .iter()
+            .all(|span| traceparent.contains(span["traceId"].as_str().unwrap())));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::middleware::{reject, EarlyResponse};
+use anyhow::*;
+use hyper::http::uri::PathAndQuery;
+use hyper::{Body, Request, StatusCode, Uri};
+use log::debug;
+use routerify::prelude::*;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathNormalizationConfig {
+    /// Collapse runs of slashes, so `/a//b` becomes `/a/b`.
+    #[serde(default = "default_true")]
+    pub merge_slashes: bool,
+    /// Decode percent-encoded letters, digits and `-._~`, which mean the same either way.
+    #[serde(default = "default_true")]
+    pub decode_unreserved: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl PathNormalizationConfig {
+    /// `path` in canonical form, or `None` if its `..` segments climb above the root.
+    /// Dot segments are always resolved, encoded ones like `%2e%2e` included, and
+    /// percent-encodings that stay are uppercased.
+    pub fn normalize(&self, path: &str) -> Option<String> {
+        // Like `*` for `OPTIONS *`, which has no segments to normalize.
+        if !path.starts_with('/') {
+            return Some(path.to_string());
+        }
+        let decoded = self.decode(path);
+        let mut segments: Vec<&str> = Vec::new();
+        let mut trailing_slash = false;
+        for segment in decoded.split('/').skip(1) {
+            trailing_slash = false;
+            match (segment, dots(segment)) {
+                ("", _) if self.merge_slashes => trailing_slash = true,
+                (_, Some(1)) => trailing_slash = true,
+                (_, Some(2)) => {
+                    segments.pop()?;
+                    trailing_slash = true;
+                }
+                (segment, _) => segments.push(segment),
+            }
+        }
+        // A path ending in a slash keeps it; one made only of slashes stays `/`.
+        let mut normalized = String::with_capacity(decoded.len());
+        for segment in &segments {
+            normalized.push('/');
+            normalized.push_str(segment);
+        }
+        if trailing_slash || normalized.is_empty() {
+            normalized.push('/');
+        }
+        Some(normalized)
+    }
+
+    fn decode(&self, path: &str) -> String {
+        let bytes = path.as_bytes();
+        let mut decoded = String::with_capacity(path.len());
+        let mut index = 0;
+        while index < bytes.len() {
+            let escaped = match bytes.get(index..index + 3) {
+                Some([b'%', high, low]) => hex_value(*high).zip(hex_value(*low)),
+                _ => None,
+            };
+            match escaped {
+                Some((high, low)) => {
+                    let byte = high << 4 | low;
+                    if self.decode_unreserved && is_unreserved(byte) {
+                        decoded.push(byte as char);
+                    } else {
+                        decoded.push_str(&format!("%{:02X}", byte));
+                    }
+                    index += 3;
+                }
+                None => {
+                    decoded.push(bytes[index] as char);
+                    index += 1;
+                }
+            }
+        }
+        decoded
+    }
+}
+
+/// How many dots a segment is made of, counting `%2E`s, or `None` if it has anything
+/// else in it.
+fn dots(segment: &str) -> Option<usize> {
+    let mut rest = segment;
+    let mut count = 0;
+    while !rest.is_empty() {
+        rest = rest
+            .strip_prefix('.')
+            .or_else(|| rest.strip_prefix("%2E"))?;
+        count += 1;
+    }
+    Some(count)
+}
+
+/// `uri` with its path replaced, keeping the query.
+fn with_path(uri: &Uri, path: &str) -> Result<Uri> {
+    let path_and_query = match uri.query() {
+        None => path.to_string(),
+        Some(query) => format!("{}?{}", path, query),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query)?);
+    Ok(Uri::from_parts(parts)?)
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+/// Rewrites the request path to its canonical form before anything else looks at it, so
+/// access rules, routes and the cache all see the same path for equivalent spellings.
+/// Paths that climb above the root get a `400`.
+pub async fn normalize_path(mut req: Request<Body>) -> Result<Request<Body>> {
+    let env = req.data::<Arc<crate::Env>>().unwrap();
+    let config = match env.path_normalization {
+        Some(config) => config,
+        None => return Ok(req),
+    };
+    let path = match config.normalize(req.uri().path()) {
+        Some(path) => path,
+        None => {
+            debug!("Refusing path {:?} that leaves the root", req.uri().path());
+            return Err(reject(
+                &req,
+                EarlyResponse::new(StatusCode::BAD_REQUEST, "Bad Request"),
+            ));
+        }
+    };
+    if path != req.uri().path() {
+        debug!("Normalized path {:?} to {:?}", req.uri().path(), path);
+        *req.uri_mut() = with_path(req.uri(), &path)?;
+    }
+    Ok(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_json, echo_upstream, Proxy};
+
+    const DEFAULT: PathNormalizationConfig = PathNormalizationConfig {
+        merge_slashes: true,
+        decode_unreserved: true,
+    };
+
+    #[test]
+    fn resolves_slashes_and_dot_segments() {
+        for (path, normalized) in &[
+            ("/a//b", "/a/b"),
+            ("//a///b//", "/a/b/"),
+            ("/a/../b", "/b"),
+            ("/a/./b/.", "/a/b/"),
+            ("/a/b/..", "/a/"),
+            ("/a/%2e%2E/b", "/b"),
+            ("/%7euser/%41-%2fx", "/~user/A-%2Fx"),
+            ("/a/...", "/a/..."),
+            ("///", "/"),
+            ("/..a/b", "/..a/b"),
+            ("*", "*"),
+        ] {
+            assert_eq!(
+                DEFAULT.normalize(path).as_deref(),
+                Some(*normalized),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_paths_above_the_root() {
+        for path in &["/..", "/../etc/passwd", "/a/../../b", "/%2e%2e/b"] {
+            assert_eq!(DEFAULT.normalize(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn merging_and_decoding_can_be_turned_off() {
+        let config = PathNormalizationConfig {
+            merge_slashes: false,
+            decode_unreserved: false,
+        };
+        assert_eq!(
+            config.normalize("/a//b/%7e/../c").as_deref(),
+            Some("/a//b/c")
+        );
+        assert_eq!(config.normalize("/%7e%2f").as_deref(), Some("/%7E%2F"));
+    }
+
+    #[tokio::test]
+    async fn upstreams_get_the_normalized_path() {
+        let upstream = echo_upstream().await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[path_normalization]",
+            upstream
+        ))
+        .await;
+        let echo = body_json(proxy.get("/a//b/../c?x=/..").await).await;
+        assert_eq!(echo["uri"], "/a/c?x=/..");
+
+        let response = proxy.get("/a/../../etc/passwd").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
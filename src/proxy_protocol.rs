@@ -0,0 +1,258 @@
+use anyhow::*;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Starts every version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest version 1 header allowed, line ending included.
+const V1_MAX_LEN: usize = 107;
+
+/// Reads the PROXY protocol header (version 1 or 2) a load balancer sends ahead of the
+/// client's data, and returns the client address it names. That's `None` for the load
+/// balancer's own connections, like health checks, and for clients it doesn't know the
+/// address of. Nothing past the header is read, so the stream is left at the client's
+/// first byte.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let mut start = [0u8; 6];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY " {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            ensure!(line.len() < V1_MAX_LEN, "PROXY protocol header is too long");
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(&line);
+    }
+
+    ensure!(
+        start == V2_SIGNATURE[..6],
+        "Connection didn't start with a PROXY protocol header"
+    );
+    let mut header = [0u8; 16];
+    header[..6].copy_from_slice(&start);
+    stream.read_exact(&mut header[6..]).await?;
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut addresses = vec![0u8; len];
+    stream.read_exact(&mut addresses).await?;
+    parse_v2(&header, &addresses)
+}
+
+/// `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n`, with
+/// `TCP6` for IPv6 or `UNKNOWN` and anything after it.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .context("Invalid PROXY protocol v1 header")?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.get(1).copied() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4") | Some("TCP6") if fields.len() == 6 => {}
+        _ => bail!("Invalid PROXY protocol v1 header {:?}", line),
+    }
+    let ip: IpAddr = fields[2]
+        .parse()
+        .with_context(|| format!("Invalid source address in {:?}", line))?;
+    ensure!(
+        ip.is_ipv4() == (fields[1] == "TCP4"),
+        "Source address doesn't match the protocol in {:?}",
+        line
+    );
+    let port: u16 = fields[4]
+        .parse()
+        .with_context(|| format!("Invalid source port in {:?}", line))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// `header` is the fixed 16 bytes; `addresses` is the rest, whose layout depends on the
+/// address family. Trailing TLVs are ignored.
+fn parse_v2(header: &[u8; 16], addresses: &[u8]) -> Result<Option<SocketAddr>> {
+    ensure!(
+        header[..12] == V2_SIGNATURE,
+        "Invalid PROXY protocol v2 signature"
+    );
+    ensure!(
+        header[12] >> 4 == 2,
+        "Unsupported PROXY protocol version {}",
+        header[12] >> 4
+    );
+    match header[12] & 0x0f {
+        // LOCAL: the load balancer's own connection.
+        0 => return Ok(None),
+        1 => {}
+        command => bail!("Unsupported PROXY protocol v2 command {}", command),
+    }
+
+    let source = match header[13] >> 4 {
+        1 => {
+            ensure!(
+                addresses.len() >= 12,
+                "Truncated PROXY protocol v2 addresses"
+            );
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            SocketAddr::new(ip.into(), port)
+        }
+        2 => {
+            ensure!(
+                addresses.len() >= 36,
+                "Truncated PROXY protocol v2 addresses"
+            );
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            SocketAddr::new(ip.into(), port)
+        }
+        // Unspecified or Unix socket addresses; there's no IP to report.
+        _ => return Ok(None),
+    };
+    Ok(Some(source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{echo_upstream, Proxy};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    /// Reads a header off `bytes` and returns what it named and what was left unread.
+    async fn read(bytes: &[u8]) -> Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut stream = bytes;
+        let source = read_header(&mut stream).await?;
+        Ok((source, stream.to_vec()))
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family << 4 | 1);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (source, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 443\r\nGET /")
+            .await
+            .unwrap();
+        assert_eq!(source, Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (source, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 51000 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(source, Some("[2001:db8::7]:51000".parse().unwrap()));
+
+        let (source, rest) = read(b"PROXY UNKNOWN whatever\r\nGET /").await.unwrap();
+        assert_eq!(source, None);
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_and_truncated_v1_headers() {
+        for header in [
+            &b"PROXY TCP4 203.0.113.7 10.0.0.1 51000\r\n"[..],
+            b"PROXY TCP6 203.0.113.7 10.0.0.1 51000 443\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 port 443\r\n",
+            b"PROXY UDP4 203.0.113.7 10.0.0.1 51000 443\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 443\n",
+            b"PROXY TCP4 203.0.113.7",
+            b"PROX",
+            b"GET / HTTP/1.1\r\n\r\n",
+        ] {
+            assert!(read(header).await.is_err(), "{:?}", header);
+        }
+        let too_long = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LEN));
+        assert!(read(too_long.as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let mut ipv4 = vec![203, 0, 113, 7, 10, 0, 0, 1];
+        ipv4.extend_from_slice(&51000u16.to_be_bytes());
+        ipv4.extend_from_slice(&443u16.to_be_bytes());
+        let mut bytes = v2(1, 1, &ipv4);
+        bytes.extend_from_slice(b"GET /");
+        let (source, rest) = read(&bytes).await.unwrap();
+        assert_eq!(source, Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let mut ipv6 = "2001:db8::7".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        ipv6.extend_from_slice(&[0; 16]);
+        ipv6.extend_from_slice(&51000u16.to_be_bytes());
+        ipv6.extend_from_slice(&443u16.to_be_bytes());
+        // A trailing TLV is skipped along with the addresses.
+        ipv6.extend_from_slice(&[0x04, 0, 1, 0]);
+        let (source, rest) = read(&v2(1, 2, &ipv6)).await.unwrap();
+        assert_eq!(source, Some("[2001:db8::7]:51000".parse().unwrap()));
+        assert!(rest.is_empty());
+
+        let (source, _) = read(&v2(0, 0, &[])).await.unwrap();
+        assert_eq!(source, None);
+        let (source, _) = read(&v2(1, 3, &[0; 216])).await.unwrap();
+        assert_eq!(source, None);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_and_truncated_v2_headers() {
+        let mut wrong_version = v2(1, 1, &[0; 12]);
+        wrong_version[12] = 0x11;
+        let complete = v2(1, 1, &[0; 12]);
+        for header in [
+            v2(1, 1, &[0; 8]),
+            v2(1, 2, &[0; 12]),
+            v2(2, 1, &[0; 12]),
+            wrong_version,
+            // Cut off in the fixed part and in the addresses.
+            complete[..14].to_vec(),
+            complete[..20].to_vec(),
+        ] {
+            assert!(read(&header).await.is_err(), "{:?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn listeners_serve_for_the_named_client() {
+        let upstream = echo_upstream().await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[listener]\nproxy_protocol = true",
+            upstream
+        ))
+        .await;
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        stream
+            .write_all(
+                b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 443\r\n\
+                  GET /path HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(
+            response.contains(r#""x-forwarded-for":["203.0.113.7"]"#),
+            "{}",
+            response
+        );
+
+        // Without a header the connection is closed before anything is served.
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        stream
+            .write_all(b"GET /path HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        // Closing with the request unread may reset the connection.
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(
+            response.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&response)
+        );
+    }
+}
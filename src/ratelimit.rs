@@ -0,0 +1,223 @@
+use crate::middleware::{reject, EarlyResponse};
+use anyhow::*;
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::{Body, Request, StatusCode};
+use log::debug;
+use routerify::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub requests_per_second: f64,
+    pub burst: u32,
+    /// How often idle buckets are purged.
+    #[serde(default = "default_cleanup_interval", with = "humantime_serde")]
+    pub cleanup_interval: Duration,
+}
+
+fn default_cleanup_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Per-client-IP token buckets. Each bucket holds up to `burst` tokens and refills at
+/// `requests_per_second`; a request takes one token.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Result<RateLimiter> {
+        ensure!(
+            config.requests_per_second > 0.0,
+            "rate_limit.requests_per_second must be positive"
+        );
+        ensure!(config.burst > 0, "rate_limit.burst must be at least 1");
+        ensure!(
+            !config.cleanup_interval.is_zero(),
+            "rate_limit.cleanup_interval can't be zero"
+        );
+        Ok(RateLimiter {
+            rate: config.requests_per_second,
+            burst: f64::from(config.burst),
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes a token for `ip`, or returns how long until one is available.
+    pub fn check(&self, ip: IpAddr, now: Instant) -> std::result::Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return std::result::Result::Ok(());
+        }
+        Err(seconds((1.0 - bucket.tokens) / self.rate))
+    }
+
+    /// Drops buckets that would have refilled completely by `now`; they're
+    /// indistinguishable from a fresh bucket.
+    pub fn purge_idle(&self, now: Instant) {
+        let refill_time = seconds(self.burst / self.rate);
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < refill_time);
+    }
+}
+
+/// A tiny rate can make a wait too long for a `Duration`, which is then as long as
+/// they get.
+fn seconds(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX)
+}
+
+pub fn spawn_cleanup(limiter: Arc<RateLimiter>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            limiter.purge_idle(Instant::now());
+        }
+    });
+}
+
+pub async fn rate_limit(req: Request<Body>) -> Result<Request<Body>> {
+    let env = req.data::<Arc<crate::Env>>().unwrap();
+    let limiter = match &env.rate_limiter {
+        Some(limiter) => limiter,
+        None => return Ok(req),
+    };
+
+    let ip = crate::client_ip::client_ip(&req);
+    let retry_after = match limiter.check(ip, Instant::now()) {
+        Result::Ok(()) => return Ok(req),
+        Err(retry_after) => retry_after,
+    };
+
+    debug!("Rate limiting {}", ip);
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    Err(reject(
+        &req,
+        EarlyResponse::new(StatusCode::TOO_MANY_REQUESTS, "Too Many Requests")
+            .header(RETRY_AFTER, HeaderValue::from(seconds)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, Proxy};
+    use hyper::Response;
+
+    fn limiter(requests_per_second: f64, burst: u32) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            requests_per_second,
+            burst,
+            cleanup_interval: default_cleanup_interval(),
+        })
+        .unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn refills_after_the_burst_is_used_up() {
+        let limiter = limiter(2.0, 3);
+        let client = ip("192.0.2.1");
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check(client, start).is_ok());
+        }
+        assert_eq!(
+            limiter.check(client, start),
+            Err(Duration::from_millis(500))
+        );
+        // Other clients have buckets of their own.
+        assert!(limiter.check(ip("192.0.2.2"), start).is_ok());
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check(client, later).is_ok());
+        assert!(limiter.check(client, later).is_err());
+
+        // Never refills past the burst.
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.check(client, much_later).is_ok());
+        }
+        assert!(limiter.check(client, much_later).is_err());
+    }
+
+    #[test]
+    fn purges_buckets_once_they_would_be_full() {
+        let limiter = limiter(1.0, 2);
+        let start = Instant::now();
+        limiter.check(ip("192.0.2.1"), start).unwrap();
+        limiter.purge_idle(start + Duration::from_secs(1));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+        limiter.purge_idle(start + Duration::from_secs(2));
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handles_tiny_rates() {
+        let limiter = limiter(1e-300, 1);
+        let client = ip("192.0.2.1");
+        let now = Instant::now();
+        limiter.check(client, now).unwrap();
+        assert_eq!(limiter.check(client, now), Err(Duration::MAX));
+        limiter.purge_idle(now + Duration::from_secs(3600));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let config = |requests_per_second, burst, cleanup_interval| RateLimitConfig {
+            requests_per_second,
+            burst,
+            cleanup_interval,
+        };
+        let interval = default_cleanup_interval();
+        assert!(RateLimiter::new(&config(0.0, 1, interval)).is_err());
+        assert!(RateLimiter::new(&config(f64::NAN, 1, interval)).is_err());
+        assert!(RateLimiter::new(&config(1.0, 0, interval)).is_err());
+        assert!(RateLimiter::new(&config(1.0, 1, Duration::ZERO)).is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_429_with_retry_after() {
+        let upstream = test_support::upstream(|_| async { Response::new(Body::empty()) }).await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n\
+             [rate_limit]\nrequests_per_second = 0.1\nburst = 2",
+            upstream
+        ))
+        .await;
+        assert_eq!(proxy.get("/x").await.status(), StatusCode::OK);
+        assert_eq!(proxy.get("/x").await.status(), StatusCode::OK);
+        let response = proxy.get("/x").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "10");
+    }
+}
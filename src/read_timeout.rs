@@ -0,0 +1,112 @@
+use futures_util::stream::{self, StreamExt};
+use hyper::Body;
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct BodyReadTimeout {
+    pub timeout: Duration,
+}
+
+impl fmt::Display for BodyReadTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Client sent no request body data for {:?}", self.timeout)
+    }
+}
+
+impl std::error::Error for BodyReadTimeout {}
+
+/// Wraps a request body so the stream errors with [`BodyReadTimeout`] when the client
+/// goes `timeout` without sending the next chunk.
+pub fn timeout_body(body: Body, timeout: Duration) -> Body {
+    Body::wrap_stream(stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match tokio::time::timeout(timeout, body.next()).await {
+            Err(_) => Some((
+                Err(Box::new(BodyReadTimeout { timeout })
+                    as Box<dyn std::error::Error + Send + Sync>),
+                None,
+            )),
+            Ok(None) => None,
+            Ok(Some(chunk)) => Some((
+                chunk.map_err(|err| Box::new(err) as Box<dyn std::error::Error + Send + Sync>),
+                Some(body),
+            )),
+        }
+    }))
+}
+
+/// Whether an upstream request failed because [`timeout_body`] gave up on the client.
+pub fn is_body_read_timeout(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<BodyReadTimeout>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{upstream, Proxy};
+    use hyper::body::Bytes;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn errors_once_the_body_stalls() {
+        let (mut sender, body) = Body::channel();
+        let mut body = timeout_body(body, Duration::from_millis(100));
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from("first")).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            sender.send_data(Bytes::from("second")).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        assert_eq!(body.next().await.unwrap().unwrap(), "first");
+        assert_eq!(body.next().await.unwrap().unwrap(), "second");
+        let started = Instant::now();
+        let err = body.next().await.unwrap().unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(is_body_read_timeout(&anyhow::Error::new(err)));
+        assert!(body.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn bodies_that_end_in_time_pass_through() {
+        let body = timeout_body(Body::from("whole"), Duration::from_millis(100));
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "whole");
+    }
+
+    #[tokio::test]
+    async fn stalled_request_bodies_get_408() {
+        // Reads the whole body before answering, as upstreams usually do.
+        let upstream = upstream(|req: hyper::Request<Body>| async move {
+            match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => hyper::Response::new(Body::from(body)),
+                Err(_) => hyper::Response::new(Body::empty()),
+            }
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\nbody_read_timeout = \"200ms\"",
+            upstream
+        ))
+        .await;
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        stream
+            .write_all(b"POST /path HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nabc")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(3), stream.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = String::from_utf8_lossy(&response).to_ascii_lowercase();
+        assert!(response.starts_with("http/1.1 408 "), "{}", response);
+        assert!(
+            response.contains("\r\nconnection: close\r\n"),
+            "{}",
+            response
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::*;
+use hyper::header::{HeaderValue, HOST, LOCATION};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use routerify::prelude::*;
+use routerify::Router;
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Addresses to serve redirects on alongside the proxy. When empty, the main
+    /// `listen_addrs` serve redirects instead of proxying.
+    #[serde(default)]
+    pub listen_addrs: Vec<SocketAddr>,
+    /// Port to put in the `Location` header; unset uses the default HTTPS port.
+    #[serde(default)]
+    pub https_port: Option<u16>,
+}
+
+struct RedirectEnv {
+    https_port: Option<u16>,
+}
+
+pub fn router(config: &RedirectConfig) -> Result<Router<Body, Error>> {
+    Router::builder()
+        .data(RedirectEnv {
+            https_port: config.https_port,
+        })
+        .any(redirect_handler)
+        .build()
+        .map_err(|err| anyhow!(err))
+        .context("Building redirect router")
+}
+
+async fn redirect_handler(req: Request<Body>) -> Result<Response<Body>> {
+    let env = req.data::<RedirectEnv>().unwrap();
+    let location = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| https_location(host, req.uri(), env.https_port));
+
+    let response = match location {
+        Some(location) => Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(LOCATION, location)
+            .body(Body::empty())
+            .unwrap(),
+        None => Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from("Missing or invalid Host header"))
+            .unwrap(),
+    };
+    Ok(response)
+}
+
+/// Builds the `https://` equivalent of `uri` on `host`, keeping the path and query.
+/// Any port on the incoming host is replaced by `https_port`.
+pub fn https_location(host: &str, uri: &Uri, https_port: Option<u16>) -> Option<HeaderValue> {
+    let authority: hyper::http::uri::Authority = host.parse().ok()?;
+    let host = authority.host();
+    let host = match https_port {
+        None | Some(443) => host.to_string(),
+        Some(port) => format!("{}:{}", host, port),
+    };
+    let path_and_query = uri
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    HeaderValue::from_str(&format!("https://{}{}", host, path_and_query)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server, test_support};
+    use hyper::Client;
+
+    fn location(host: &str, uri: &str, https_port: Option<u16>) -> Option<String> {
+        https_location(host, &uri.parse().unwrap(), https_port)
+            .map(|location| location.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn keeps_the_path_and_query() {
+        assert_eq!(
+            location("example.com", "/search?q=rust&page=2", None).unwrap(),
+            "https://example.com/search?q=rust&page=2"
+        );
+    }
+
+    #[test]
+    fn replaces_the_port() {
+        assert_eq!(
+            location("example.com:8080", "/a", None).unwrap(),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            location("example.com:8080", "/a", Some(443)).unwrap(),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            location("example.com", "/a", Some(8443)).unwrap(),
+            "https://example.com:8443/a"
+        );
+        assert_eq!(
+            location("[::1]:80", "/a", Some(8443)).unwrap(),
+            "https://[::1]:8443/a"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_hosts() {
+        assert_eq!(location("exa mple.com", "/", None), None);
+        assert_eq!(location("", "/", None), None);
+    }
+
+    #[tokio::test]
+    async fn redirects_with_301() {
+        let config = RedirectConfig {
+            enabled: true,
+            listen_addrs: Vec::new(),
+            https_port: Some(8443),
+        };
+        let builder = server::service_builder(router(&config).unwrap()).unwrap();
+        let addr = test_support::serve(builder).await;
+
+        let uri = format!("http://{}/login?next=%2F", addr).parse().unwrap();
+        let response = Client::new().get(uri).await.unwrap();
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers()[LOCATION],
+            "https://127.0.0.1:8443/login?next=%2F"
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use crate::read_timeout;
+use anyhow::*;
+use futures_util::StreamExt;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::HeaderName;
+use hyper::{Body, Request};
+use log::{debug, log_enabled, Level};
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestBodyLogConfig {
+    /// Only this much of each body makes it into the log.
+    #[serde(default = "default_max_logged_bytes")]
+    pub max_logged_bytes: usize,
+    /// Bodies of a larger declared length aren't touched; streamed bodies are only
+    /// buffered up to this much and the rest is passed on as it arrives.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: u64,
+    /// Logged as `[redacted]`.
+    #[serde(default = "default_redact_headers")]
+    pub redact_headers: Vec<String>,
+}
+
+fn default_max_logged_bytes() -> usize {
+    4096
+}
+
+fn default_max_body_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_redact_headers() -> Vec<String> {
+    vec![
+        "authorization".to_string(),
+        "proxy-authorization".to_string(),
+        "cookie".to_string(),
+    ]
+}
+
+/// Logs client requests with their bodies at debug level, for tracking down clients
+/// that send something unexpected. Bodies can only be read once, so each one is
+/// buffered and put back into the request before it's forwarded.
+pub struct RequestBodyLogger {
+    max_logged_bytes: usize,
+    max_body_bytes: u64,
+    redact_headers: Vec<HeaderName>,
+}
+
+impl RequestBodyLogger {
+    pub fn new(config: &RequestBodyLogConfig) -> Result<RequestBodyLogger> {
+        let redact_headers = config
+            .redact_headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("request_body_log.redact_headers: {:?}", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(RequestBodyLogger {
+            max_logged_bytes: config.max_logged_bytes,
+            max_body_bytes: config.max_body_bytes,
+            redact_headers,
+        })
+    }
+
+    /// Returns whether the body was read, in which case it's already been subject to
+    /// `body_read_timeout`. Does nothing unless debug logging is on. A failure here
+    /// means the client's body couldn't be read, so the request can't go anywhere.
+    pub async fn log(
+        &self,
+        req: &mut Request<Body>,
+        body_read_timeout: Option<Duration>,
+    ) -> Result<bool> {
+        if !log_enabled!(Level::Debug) {
+            return Ok(false);
+        }
+        let headers = self.headers(req);
+        if let Some(length) = req.body().size_hint().exact() {
+            if length > self.max_body_bytes {
+                debug!(
+                    "Request {} {} [{}] with a body of {} bytes, not logged",
+                    req.method(),
+                    req.uri(),
+                    headers,
+                    length
+                );
+                return Ok(false);
+            }
+        }
+
+        let body = std::mem::replace(req.body_mut(), Body::empty());
+        let mut body = match body_read_timeout {
+            Some(timeout) => read_timeout::timeout_body(body, timeout),
+            None => body,
+        };
+        let mut buffered = Vec::new();
+        let mut complete = true;
+        while let Some(chunk) = body.data().await {
+            buffered.extend_from_slice(&chunk.context("Buffering request body for the log")?);
+            if buffered.len() as u64 > self.max_body_bytes {
+                complete = false;
+                break;
+            }
+        }
+
+        let shown = buffered.len().min(self.max_logged_bytes);
+        let omitted = if complete && shown == buffered.len() {
+            String::new()
+        } else if complete {
+            format!(" ({} more bytes)", buffered.len() - shown)
+        } else {
+            " (truncated, body is still streaming)".to_string()
+        };
+        debug!(
+            "Request {} {} [{}] with body {:?}{}",
+            req.method(),
+            req.uri(),
+            headers,
+            String::from_utf8_lossy(&buffered[..shown]),
+            omitted
+        );
+
+        *req.body_mut() = if complete {
+            Body::from(buffered)
+        } else {
+            let head = futures_util::stream::once(async move {
+                std::result::Result::Ok::<_, hyper::Error>(Bytes::from(buffered))
+            });
+            Body::wrap_stream(head.chain(body))
+        };
+        Ok(true)
+    }
+
+    fn headers(&self, req: &Request<Body>) -> String {
+        req.headers()
+            .iter()
+            .map(|(name, value)| {
+                if self.redact_headers.contains(name) {
+                    format!("{}: [redacted]", name)
+                } else {
+                    format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes()))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, Once};
+
+    /// Keeps every debug message, so tests can look for the ones about their request.
+    struct Capture(Mutex<Vec<String>>);
+
+    impl log::Log for Capture {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= Level::Debug
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+    fn capture_logs() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CAPTURE).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+    }
+
+    /// The messages logged about requests to `path`.
+    fn logged(path: &str) -> Vec<String> {
+        let needle = format!(" {} [", path);
+        CAPTURE
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| message.contains(&needle))
+            .cloned()
+            .collect()
+    }
+
+    fn logger(max_logged_bytes: usize, max_body_bytes: u64) -> RequestBodyLogger {
+        capture_logs();
+        RequestBodyLogger::new(&RequestBodyLogConfig {
+            max_logged_bytes,
+            max_body_bytes,
+            redact_headers: default_redact_headers(),
+        })
+        .unwrap()
+    }
+
+    fn streamed(chunks: &[&'static str]) -> Body {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| std::result::Result::Ok::<_, std::io::Error>(*chunk))
+            .collect::<Vec<_>>();
+        Body::wrap_stream(futures_util::stream::iter(chunks))
+    }
+
+    async fn body(req: Request<Body>) -> Bytes {
+        hyper::body::to_bytes(req.into_body()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn logs_the_body_and_forwards_it_unchanged() {
+        let logger = logger(5, 1024);
+        let mut req = Request::post("/logged")
+            .header("authorization", "Bearer secret")
+            .header("cookie", "session=secret")
+            .header("x-request-id", "abc")
+            .body(Body::from("hello world"))
+            .unwrap();
+        assert!(logger.log(&mut req, None).await.unwrap());
+        assert_eq!(body(req).await, "hello world");
+
+        let logged = logged("/logged");
+        assert_eq!(logged.len(), 1, "{:?}", logged);
+        assert!(
+            logged[0].contains("authorization: [redacted]"),
+            "{}",
+            logged[0]
+        );
+        assert!(logged[0].contains("cookie: [redacted]"), "{}", logged[0]);
+        assert!(logged[0].contains("x-request-id: abc"), "{}", logged[0]);
+        assert!(!logged[0].contains("secret"), "{}", logged[0]);
+        assert!(
+            logged[0].ends_with("with body \"hello\" (6 more bytes)"),
+            "{}",
+            logged[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn large_bodies_are_passed_on_whole() {
+        let logger = logger(1024, 8);
+
+        let mut req = Request::post("/declared")
+            .body(Body::from("0123456789"))
+            .unwrap();
+        assert!(!logger.log(&mut req, None).await.unwrap());
+        assert_eq!(body(req).await, "0123456789");
+        assert_eq!(
+            logged("/declared"),
+            ["Request POST /declared [] with a body of 10 bytes, not logged"]
+        );
+
+        let mut req = Request::post("/streamed")
+            .body(streamed(&["0123", "4567", "89", "ab"]))
+            .unwrap();
+        assert!(logger.log(&mut req, None).await.unwrap());
+        assert_eq!(body(req).await, "0123456789ab");
+        assert_eq!(
+            logged("/streamed"),
+            ["Request POST /streamed [] with body \"0123456789\" (truncated, body is still streaming)"]
+        );
+    }
+
+    #[test]
+    fn redacted_headers_must_be_valid_names() {
+        let config = RequestBodyLogConfig {
+            max_logged_bytes: default_max_logged_bytes(),
+            max_body_bytes: default_max_body_bytes(),
+            redact_headers: vec!["not a header".to_string()],
+        };
+        assert!(RequestBodyLogger::new(&config).is_err());
+    }
+}
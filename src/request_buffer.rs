@@ -0,0 +1,164 @@
+use crate::read_timeout;
+use anyhow::*;
+use futures_util::StreamExt;
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Request};
+use log::debug;
+use std::time::Duration;
+
+pub enum Buffered {
+    /// The whole body, which the request now holds a copy of.
+    Complete(Bytes),
+    /// The body went over the limit part way through. What was read is put back in
+    /// front of the rest, which streams on, already subject to `body_read_timeout`.
+    Streaming,
+    /// The body was declared over the limit, so none of it was read.
+    Skipped,
+}
+
+/// Reads the body of `req` into memory so it can be sent more than once, for retries or
+/// the mirror, as long as it's no larger than `max_bytes`. Bodies of unknown length are
+/// read up to the limit before giving up on them. A failure here means the client's body
+/// couldn't be read, so the request can't go anywhere.
+pub async fn buffer(
+    req: &mut Request<Body>,
+    max_bytes: u64,
+    body_read_timeout: Option<Duration>,
+) -> Result<Buffered> {
+    match req.body().size_hint().exact() {
+        Some(0) => return Ok(Buffered::Complete(Bytes::new())),
+        Some(length) if length > max_bytes => {
+            debug!(
+                "Streaming the {} byte request body of {} {}: over max_buffer_bytes, so it isn't retried or mirrored",
+                length,
+                req.method(),
+                req.uri()
+            );
+            return Ok(Buffered::Skipped);
+        }
+        _ => {}
+    }
+
+    let body = std::mem::replace(req.body_mut(), Body::empty());
+    let mut body = match body_read_timeout {
+        Some(timeout) => read_timeout::timeout_body(body, timeout),
+        None => body,
+    };
+    let mut buffered = Vec::new();
+    while let Some(chunk) = body.data().await {
+        buffered.extend_from_slice(&chunk.context("Buffering request body")?);
+        if buffered.len() as u64 > max_bytes {
+            debug!(
+                "Streaming the request body of {} {}: over max_buffer_bytes, so it isn't retried or mirrored",
+                req.method(),
+                req.uri()
+            );
+            let head = futures_util::stream::once(async move {
+                std::result::Result::Ok::<_, hyper::Error>(Bytes::from(buffered))
+            });
+            *req.body_mut() = Body::wrap_stream(head.chain(body));
+            return Ok(Buffered::Streaming);
+        }
+    }
+
+    let body = Bytes::from(buffered);
+    *req.body_mut() = Body::from(body.clone());
+    Ok(Buffered::Complete(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_string, upstream, Proxy};
+    use hyper::{Response, StatusCode};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn streamed(chunks: &[&'static str]) -> Body {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Result::<_, Infallible>::Ok(*chunk))
+            .collect::<Vec<_>>();
+        Body::wrap_stream(futures_util::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn buffers_bodies_up_to_the_limit() {
+        for body in [Body::from("0123456789"), streamed(&["01234", "56789"])] {
+            let mut req = Request::new(body);
+            match buffer(&mut req, 10, None).await.unwrap() {
+                Buffered::Complete(body) => assert_eq!(body, "0123456789"),
+                _ => panic!("expected the whole body"),
+            }
+            assert_eq!(req.body().size_hint().exact(), Some(10));
+            assert_eq!(
+                hyper::body::to_bytes(req.into_body()).await.unwrap(),
+                "0123456789"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_larger_bodies_on_unchanged() {
+        let mut req = Request::new(Body::from("0123456789"));
+        assert!(matches!(
+            buffer(&mut req, 9, None).await.unwrap(),
+            Buffered::Skipped
+        ));
+        assert_eq!(
+            hyper::body::to_bytes(req.into_body()).await.unwrap(),
+            "0123456789"
+        );
+
+        let mut req = Request::new(streamed(&["0123", "4567", "89"]));
+        assert!(matches!(
+            buffer(&mut req, 6, None).await.unwrap(),
+            Buffered::Streaming
+        ));
+        assert_eq!(
+            hyper::body::to_bytes(req.into_body()).await.unwrap(),
+            "0123456789"
+        );
+    }
+
+    /// Fails each request's first attempt with a 503, then echoes the body it gets.
+    async fn flaky_upstream() -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let addr = upstream(move |req: Request<Body>| {
+            let call = counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let mut response = Response::new(Body::from(body));
+                if call.is_multiple_of(2) {
+                    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                }
+                response
+            }
+        })
+        .await;
+        (addr, calls)
+    }
+
+    #[tokio::test]
+    async fn only_buffered_bodies_are_retried() {
+        let (upstream, calls) = flaky_upstream().await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\nretries = 1\nretry_backoff = \"1ms\"\nmax_buffer_bytes = 16",
+            upstream
+        ))
+        .await;
+        let put = |body: Body| proxy.send(Request::put("/path").body(body).unwrap());
+
+        let response = put(streamed(&["small ", "body"])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "small body");
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 2);
+
+        let response = put(streamed(&["a body that is ", "over the limit"])).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_string(response).await, "a body that is over the limit");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
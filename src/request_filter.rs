@@ -0,0 +1,269 @@
+use crate::middleware::{reject, EarlyResponse};
+use anyhow::*;
+use hyper::header::{HeaderValue, ALLOW};
+use hyper::{Body, Method, Request, StatusCode};
+use log::debug;
+use routerify::prelude::*;
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestFilterConfig {
+    /// When non-empty, any other method is refused.
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+    #[serde(default)]
+    pub blocked_methods: Vec<String>,
+    /// Globs; when non-empty, paths matching none of them are refused.
+    #[serde(default)]
+    pub allowed_paths: Vec<String>,
+    #[serde(default)]
+    pub blocked_paths: Vec<String>,
+}
+
+/// Refuses requests by method (`405`) or path (`403`) before they reach any handler.
+/// Blocklists win over allowlists.
+pub struct RequestFilter {
+    allowed_methods: Vec<Method>,
+    blocked_methods: Vec<Method>,
+    allow_header: Option<HeaderValue>,
+    allowed_paths: Vec<String>,
+    blocked_paths: Vec<String>,
+}
+
+impl RequestFilter {
+    pub fn new(config: &RequestFilterConfig) -> Result<RequestFilter> {
+        let allowed_methods = parse_methods(&config.allowed_methods)?;
+        let allow_header = if allowed_methods.is_empty() {
+            None
+        } else {
+            Some(HeaderValue::from_str(&config.allowed_methods.join(", "))?)
+        };
+        for pattern in config.allowed_paths.iter().chain(&config.blocked_paths) {
+            ensure!(
+                pattern.starts_with('/') || pattern.starts_with('*'),
+                "Path pattern {:?} must start with '/' or '*'",
+                pattern
+            );
+        }
+        Ok(RequestFilter {
+            allowed_methods,
+            blocked_methods: parse_methods(&config.blocked_methods)?,
+            allow_header,
+            allowed_paths: config.allowed_paths.clone(),
+            blocked_paths: config.blocked_paths.clone(),
+        })
+    }
+
+    pub fn is_method_allowed(&self, method: &Method) -> bool {
+        !self.blocked_methods.contains(method)
+            && (self.allowed_methods.is_empty() || self.allowed_methods.contains(method))
+    }
+
+    pub fn is_path_allowed(&self, path: &str) -> bool {
+        !self
+            .blocked_paths
+            .iter()
+            .any(|pattern| glob_match(pattern, path))
+            && (self.allowed_paths.is_empty()
+                || self
+                    .allowed_paths
+                    .iter()
+                    .any(|pattern| glob_match(pattern, path)))
+    }
+}
+
+fn parse_methods(methods: &[String]) -> Result<Vec<Method>> {
+    methods
+        .iter()
+        .map(|method| {
+            Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .with_context(|| format!("Invalid HTTP method {:?}", method))
+        })
+        .collect()
+}
+
+enum Token {
+    Literal(u8),
+    /// `?`
+    Any,
+    /// `*`
+    Star,
+    /// `**`
+    DoubleStar,
+}
+
+/// Matches `path` against a glob where `?` is any one character other than `/`, `*` is
+/// any run of characters within a segment, and `**` is any run including `/`.
+fn glob_match(pattern: &str, path: &str) -> bool {
+    let mut tokens = Vec::new();
+    let mut pattern = pattern.as_bytes();
+    while let Some((&c, rest)) = pattern.split_first() {
+        pattern = rest;
+        tokens.push(match c {
+            b'*' if pattern.first() == Some(&b'*') => {
+                pattern = &pattern[1..];
+                Token::DoubleStar
+            }
+            b'*' => Token::Star,
+            b'?' => Token::Any,
+            c => Token::Literal(c),
+        });
+    }
+
+    // matched[j]: whether the tokens so far match the first j bytes of the path.
+    let path = path.as_bytes();
+    let mut matched = vec![false; path.len() + 1];
+    matched[0] = true;
+    for token in &tokens {
+        let mut next = vec![false; path.len() + 1];
+        for j in 0..=path.len() {
+            next[j] = match token {
+                Token::Literal(c) => j > 0 && matched[j - 1] && path[j - 1] == *c,
+                Token::Any => j > 0 && matched[j - 1] && path[j - 1] != b'/',
+                Token::Star => matched[j] || (j > 0 && next[j - 1] && path[j - 1] != b'/'),
+                Token::DoubleStar => matched[j] || (j > 0 && next[j - 1]),
+            };
+        }
+        matched = next;
+    }
+    matched[path.len()]
+}
+
+pub async fn request_filter(req: Request<Body>) -> Result<Request<Body>> {
+    let env = req.data::<Arc<crate::Env>>().unwrap();
+    let filter = match &env.request_filter {
+        Some(filter) => filter,
+        None => return Ok(req),
+    };
+
+    if !filter.is_method_allowed(req.method()) {
+        debug!(
+            "Refusing {} {}: method not allowed",
+            req.method(),
+            req.uri()
+        );
+        let mut response = EarlyResponse::new(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed");
+        if let Some(allow) = &filter.allow_header {
+            response = response.header(ALLOW, allow.clone());
+        }
+        return Err(reject(&req, response));
+    }
+    if !filter.is_path_allowed(req.uri().path()) {
+        debug!("Refusing {} {}: path not allowed", req.method(), req.uri());
+        return Err(reject(
+            &req,
+            EarlyResponse::new(StatusCode::FORBIDDEN, "Forbidden"),
+        ));
+    }
+    Ok(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{echo_upstream, Proxy};
+
+    fn filter(config: &str) -> RequestFilter {
+        RequestFilter::new(&toml::from_str(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn globs_match_within_and_across_segments() {
+        assert!(glob_match("/admin", "/admin"));
+        assert!(!glob_match("/admin", "/admin/users"));
+        assert!(glob_match("/admin/**", "/admin/users/1"));
+        assert!(!glob_match("/admin/**", "/admin"));
+        assert!(glob_match("/api/*/status", "/api/v1/status"));
+        assert!(!glob_match("/api/*/status", "/api/v1/x/status"));
+        assert!(glob_match("/**/*.php", "/wp/login.php"));
+        assert!(!glob_match("/**/*.php", "/wp/login.html"));
+        assert!(glob_match("/v?", "/v2"));
+        assert!(!glob_match("/v?", "/v/"));
+    }
+
+    #[test]
+    fn blocklists_win_over_allowlists() {
+        let filter = filter(
+            r#"
+            allowed_methods = ["get", "POST"]
+            blocked_methods = ["POST"]
+            allowed_paths = ["/api/**"]
+            blocked_paths = ["/api/internal/**"]
+            "#,
+        );
+        assert!(filter.is_method_allowed(&Method::GET));
+        assert!(!filter.is_method_allowed(&Method::POST));
+        assert!(!filter.is_method_allowed(&Method::DELETE));
+        assert!(filter.is_path_allowed("/api/users"));
+        assert!(!filter.is_path_allowed("/api/internal/keys"));
+        assert!(!filter.is_path_allowed("/static/app.js"));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        for config in [
+            r#"blocked_methods = ["NOT A METHOD"]"#,
+            r#"blocked_paths = ["admin"]"#,
+        ] {
+            assert!(
+                RequestFilter::new(&toml::from_str(config).unwrap()).is_err(),
+                "{}",
+                config
+            );
+        }
+    }
+
+    async fn proxy() -> Proxy {
+        Proxy::start(&format!(
+            r#"
+            upstreams = "http://{}"
+            [request_filter]
+            allowed_methods = ["GET", "POST"]
+            blocked_methods = ["TRACE"]
+            blocked_paths = ["/admin/**", "/**/*.php"]
+            "#,
+            echo_upstream().await
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    async fn blocked_methods_get_405() {
+        let proxy = proxy().await;
+        for method in [Method::TRACE, Method::DELETE] {
+            let response = proxy
+                .send(
+                    Request::builder()
+                        .method(method.clone())
+                        .uri("/path")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await;
+            assert_eq!(
+                response.status(),
+                StatusCode::METHOD_NOT_ALLOWED,
+                "{}",
+                method
+            );
+            assert_eq!(response.headers()[ALLOW], "GET, POST");
+        }
+        assert_eq!(proxy.get("/path").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn blocked_path_globs_get_403() {
+        let proxy = proxy().await;
+        for path in ["/admin/users", "/wp/login.php"] {
+            assert_eq!(
+                proxy.get(path).await.status(),
+                StatusCode::FORBIDDEN,
+                "{}",
+                path
+            );
+        }
+        assert_eq!(proxy.get("/admin").await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,188 @@
+use anyhow::*;
+use hyper::header::{HeaderMap, HeaderName};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestHeadersConfig {
+    /// When set, the only client headers forwarded upstream, besides the ones proxying
+    /// itself depends on.
+    #[serde(default)]
+    pub allow: Option<Vec<String>>,
+    /// Client headers never forwarded upstream.
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// Headers the upstream request can't do without: Vostok sets or rewrites them itself,
+/// or a WebSocket handshake needs them. They're never filtered.
+const REQUIRED: [&str; 13] = [
+    "host",
+    "content-length",
+    "transfer-encoding",
+    "connection",
+    "upgrade",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    "x-request-id",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-protocol",
+    "sec-websocket-extensions",
+];
+
+/// Decides which client headers reach the upstream. Without any config every header
+/// but the hop-by-hop ones is forwarded.
+#[derive(Debug, Default)]
+pub struct RequestHeaderRules {
+    allow: Option<Vec<HeaderName>>,
+    remove: Vec<HeaderName>,
+}
+
+impl RequestHeaderRules {
+    pub fn new(config: &RequestHeadersConfig) -> Result<RequestHeaderRules> {
+        let parse = |names: &[String]| {
+            names
+                .iter()
+                .map(|name| {
+                    HeaderName::from_bytes(name.as_bytes())
+                        .with_context(|| format!("Invalid request header name {:?}", name))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let remove = parse(&config.remove)?;
+        if let Some(name) = remove.iter().find(|name| REQUIRED.contains(&name.as_str())) {
+            bail!(
+                "request_headers.remove can't include {}, proxying needs it",
+                name
+            );
+        }
+        Ok(RequestHeaderRules {
+            allow: config.allow.as_deref().map(parse).transpose()?,
+            remove,
+        })
+    }
+
+    /// The allowlist is applied first, so a header that's both allowed and removed is
+    /// removed.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if let Some(allow) = &self.allow {
+            let dropped = headers
+                .keys()
+                .filter(|name| !allow.contains(name) && !REQUIRED.contains(&name.as_str()))
+                .cloned()
+                .collect::<Vec<_>>();
+            for name in dropped {
+                headers.remove(name);
+            }
+        }
+        for name in &self.remove {
+            headers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_json, echo_upstream, Proxy};
+    use hyper::{Body, Request};
+
+    fn parse_rules(allow: Option<&[&str]>, remove: &[&str]) -> Result<RequestHeaderRules> {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        RequestHeaderRules::new(&RequestHeadersConfig {
+            allow: allow.map(names),
+            remove: names(remove),
+        })
+    }
+
+    fn header_map(names: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for name in names {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                "1".parse().unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn names(headers: &HeaderMap) -> Vec<&str> {
+        let mut names = headers.keys().map(HeaderName::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn the_allowlist_keeps_listed_and_required_headers() {
+        let rules = parse_rules(Some(&["accept", "Cookie"]), &[]).unwrap();
+        let mut headers = header_map(&["accept", "cookie", "x-debug", "host", "x-request-id"]);
+        rules.apply(&mut headers);
+        assert_eq!(
+            names(&headers),
+            ["accept", "cookie", "host", "x-request-id"]
+        );
+    }
+
+    #[test]
+    fn removed_headers_are_dropped_even_when_allowed() {
+        let rules = parse_rules(Some(&["accept", "cookie"]), &["cookie"]).unwrap();
+        let mut headers = header_map(&["accept", "cookie", "host"]);
+        rules.apply(&mut headers);
+        assert_eq!(names(&headers), ["accept", "host"]);
+
+        let rules = parse_rules(None, &["x-debug"]);
+        let mut headers = header_map(&["accept", "x-debug", "x-other"]);
+        rules.unwrap().apply(&mut headers);
+        assert_eq!(names(&headers), ["accept", "x-other"]);
+    }
+
+    #[test]
+    fn no_config_keeps_every_header() {
+        let mut headers = header_map(&["accept", "x-debug"]);
+        RequestHeaderRules::default().apply(&mut headers);
+        assert_eq!(names(&headers), ["accept", "x-debug"]);
+    }
+
+    #[test]
+    fn required_headers_and_invalid_names_are_rejected() {
+        assert!(parse_rules(None, &["host"]).is_err());
+        assert!(parse_rules(None, &["X-Forwarded-For"]).is_err());
+        assert!(parse_rules(None, &["not a header"]).is_err());
+        assert!(parse_rules(Some(&["not a header"]), &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn only_allowed_headers_reach_the_upstream() {
+        let upstream = echo_upstream().await;
+        let proxy = Proxy::start(&format!(
+            r#"
+            upstreams = "http://{}"
+            [request_headers]
+            allow = ["accept", "x-keep"]
+            remove = ["x-keep-not"]
+            "#,
+            upstream
+        ))
+        .await;
+        let response = proxy
+            .send(
+                Request::get("/path")
+                    .header("accept", "text/plain")
+                    .header("x-keep", "1")
+                    .header("x-keep-not", "1")
+                    .header("x-dropped", "1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        let headers = &body_json(response).await["headers"];
+        assert_eq!(headers["accept"], serde_json::json!(["text/plain"]));
+        assert_eq!(headers["x-keep"], serde_json::json!(["1"]));
+        assert!(headers.get("x-keep-not").is_none(), "{}", headers);
+        assert!(headers.get("x-dropped").is_none(), "{}", headers);
+        assert!(headers.get("host").is_some(), "{}", headers);
+        assert!(headers.get("x-forwarded-for").is_some(), "{}", headers);
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::*;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, Response};
+use routerify::prelude::*;
+use routerify::RequestInfo;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Incoming IDs longer than this are replaced rather than propagated.
+const MAX_INCOMING_LEN: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct RequestId(pub HeaderValue);
+
+impl RequestId {
+    fn generate() -> RequestId {
+        let id = uuid::Uuid::new_v4().to_hyphenated().to_string();
+        RequestId(HeaderValue::from_str(&id).unwrap())
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().unwrap_or_default()
+    }
+}
+
+/// Reuses the client's `X-Request-Id` when it's usable, otherwise generates a UUID, and
+/// stores it in the request context for the logger, proxy and response.
+pub async fn request_id(req: Request<Body>) -> Result<Request<Body>> {
+    let id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .filter(|value| {
+            !value.is_empty() && value.len() <= MAX_INCOMING_LEN && value.to_str().is_ok()
+        })
+        .map(|value| RequestId(value.clone()))
+        .unwrap_or_else(RequestId::generate);
+    req.set_context(id);
+    Ok(req)
+}
+
+pub async fn echo_request_id(
+    mut res: Response<Body>,
+    req_info: RequestInfo,
+) -> Result<Response<Body>> {
+    if let Some(RequestId(id)) = req_info.context::<RequestId>() {
+        res.headers_mut().insert(X_REQUEST_ID, id);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, body_json, Proxy};
+
+    /// The ID the upstream got and the one the client got back.
+    async fn ids(proxy: &Proxy, incoming: Option<HeaderValue>) -> (String, String) {
+        let mut req = Request::get("/x").body(Body::empty()).unwrap();
+        if let Some(incoming) = incoming {
+            req.headers_mut().insert(X_REQUEST_ID, incoming);
+        }
+        let response = proxy.send(req).await;
+        let returned = response.headers()[X_REQUEST_ID]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let echo = body_json(response).await;
+        let forwarded = echo["headers"]["x-request-id"][0]
+            .as_str()
+            .unwrap()
+            .to_string();
+        (forwarded, returned)
+    }
+
+    fn is_uuid(id: &str) -> bool {
+        uuid::Uuid::parse_str(id).is_ok()
+    }
+
+    #[tokio::test]
+    async fn generates_and_reuses_ids() {
+        let upstream = test_support::echo_upstream().await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+
+        let (forwarded, returned) = ids(&proxy, None).await;
+        assert!(is_uuid(&forwarded), "{}", forwarded);
+        assert_eq!(forwarded, returned);
+        let (second, _) = ids(&proxy, None).await;
+        assert_ne!(second, forwarded);
+
+        let incoming = HeaderValue::from_static("client-chosen-id");
+        let (forwarded, returned) = ids(&proxy, Some(incoming)).await;
+        assert_eq!(forwarded, "client-chosen-id");
+        assert_eq!(returned, "client-chosen-id");
+    }
+
+    #[tokio::test]
+    async fn replaces_unusable_ids() {
+        let upstream = test_support::echo_upstream().await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+
+        let longest = "a".repeat(MAX_INCOMING_LEN);
+        let (forwarded, _) = ids(&proxy, Some(HeaderValue::from_str(&longest).unwrap())).await;
+        assert_eq!(forwarded, longest);
+
+        let too_long = HeaderValue::from_str(&"a".repeat(MAX_INCOMING_LEN + 1)).unwrap();
+        let (forwarded, returned) = ids(&proxy, Some(too_long)).await;
+        assert!(is_uuid(&forwarded));
+        assert_eq!(forwarded, returned);
+
+        let non_ascii = HeaderValue::from_bytes(b"caf\xe9").unwrap();
+        let (forwarded, _) = ids(&proxy, Some(non_ascii)).await;
+        assert!(is_uuid(&forwarded));
+    }
+}
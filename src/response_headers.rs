@@ -0,0 +1,142 @@
+use anyhow::*;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseHeadersConfig {
+    /// Headers added to every proxied response, replacing any upstream values.
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    /// Headers dropped from every proxied response, including all their values.
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ResponseHeaderRules {
+    set: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+impl ResponseHeaderRules {
+    pub fn new(config: &ResponseHeadersConfig) -> Result<ResponseHeaderRules> {
+        let set = config
+            .set
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("Invalid response header name {:?}", name))?;
+                let value = HeaderValue::from_str(value)
+                    .with_context(|| format!("Invalid value for response header {}", name))?;
+                Ok((name, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let remove = config
+            .remove
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("Invalid response header name {:?}", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ResponseHeaderRules { set, remove })
+    }
+
+    /// Removals run first, so a header that's both removed and set ends up set.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{upstream, Proxy};
+    use hyper::{Body, Response};
+
+    fn rules(set: &[(&str, &str)], remove: &[&str]) -> Result<ResponseHeaderRules> {
+        ResponseHeaderRules::new(&ResponseHeadersConfig {
+            set: set
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            remove: remove.iter().map(|name| name.to_string()).collect(),
+        })
+    }
+
+    fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sets_and_removes_every_value() {
+        let rules = rules(
+            &[("x-frame-options", "DENY"), ("Cache-Control", "no-store")],
+            &["Server", "cache-control", "x-missing"],
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("server", "nginx"),
+            ("server", "again"),
+            ("x-frame-options", "SAMEORIGIN"),
+            ("x-frame-options", "ALLOW-FROM a"),
+            ("cache-control", "public"),
+            ("set-cookie", "a=1"),
+            ("set-cookie", "b=2"),
+        ]
+        .iter()
+        {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        rules.apply(&mut headers);
+
+        assert!(!headers.contains_key("server"));
+        assert_eq!(values(&headers, "x-frame-options"), ["DENY"]);
+        // Removed and set ends up set.
+        assert_eq!(values(&headers, "cache-control"), ["no-store"]);
+        assert_eq!(values(&headers, "set-cookie"), ["a=1", "b=2"]);
+    }
+
+    #[test]
+    fn rejects_invalid_names_and_values() {
+        assert!(rules(&[("bad name", "1")], &[]).is_err());
+        assert!(rules(&[("x-ok", "line\nbreak")], &[]).is_err());
+        assert!(rules(&[], &["bad:name"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn applies_to_proxied_responses() {
+        let upstream = upstream(|_| async {
+            Response::builder()
+                .header("server", "backend/1.0")
+                .header("x-powered-by", "php")
+                .header("x-powered-by", "again")
+                .header("x-frame-options", "SAMEORIGIN")
+                .body(Body::empty())
+                .unwrap()
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[response_headers]\nremove = [\"Server\", \"X-Powered-By\"]\n[response_headers.set]\n\"X-Frame-Options\" = \"DENY\"",
+            upstream
+        ))
+        .await;
+        let response = proxy.get("/path").await;
+        let headers = response.headers();
+        assert!(!headers.contains_key("server"));
+        assert!(!headers.contains_key("x-powered-by"));
+        assert_eq!(values(headers, "x-frame-options"), ["DENY"]);
+    }
+}
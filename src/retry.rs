@@ -0,0 +1,364 @@
+use anyhow::*;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{CONTENT_LENGTH, RETRY_AFTER};
+use hyper::{http::request::Parts, Body, Method, Request, Response, StatusCode};
+use log::{debug, warn};
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration,
+    /// Request bodies are buffered so they can be replayed, but only up to this size,
+    /// `max_buffer_bytes`.
+    pub max_body_bytes: u64,
+    /// A `503` asking to retry later than this is returned to the client instead.
+    pub max_retry_after: Duration,
+}
+
+impl RetryPolicy {
+    /// Whether `method` is ever retried, so its body is worth buffering.
+    pub fn applies_to(&self, method: &Method) -> bool {
+        self.retries > 0 && is_idempotent(method)
+    }
+
+    /// Delay before retry number `attempt` (starting at 0): `backoff * 2^attempt`.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+/// Sends `req` through `send`, retrying idempotent requests on connection errors and
+/// 502/503 responses. A 503's `Retry-After` replaces the backoff for that retry.
+/// Requests whose body length isn't known up front (bodies still streaming) or is over
+/// `max_body_bytes` aren't buffered for a replay, so they're sent exactly once.
+pub async fn retry_request<F, Fut>(
+    policy: &RetryPolicy,
+    req: Request<Body>,
+    send: F,
+) -> Result<Response<Body>>
+where
+    F: Fn(Request<Body>) -> Fut,
+    Fut: Future<Output = Result<Response<Body>>>,
+{
+    if !policy.applies_to(req.method()) {
+        return send(req).await;
+    }
+    // A Content-Length also counts, for bodies wrapped after their framing was set.
+    let length = req.body().size_hint().exact().or_else(|| {
+        req.headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+    });
+    match length {
+        None => {
+            debug!(
+                "Not retrying {} {}: request body is streamed",
+                req.method(),
+                req.uri()
+            );
+            return send(req).await;
+        }
+        Some(length) if length > policy.max_body_bytes => {
+            debug!(
+                "Not retrying {} {}: request body is too large to buffer",
+                req.method(),
+                req.uri()
+            );
+            return send(req).await;
+        }
+        Some(_) => {}
+    }
+
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .context("Buffering request body for retries")?;
+
+    let mut attempt = 0;
+    loop {
+        let result = send(rebuild_request(&parts, body.clone())).await;
+        if attempt >= policy.retries || !should_retry(&result) {
+            return result;
+        }
+
+        let delay = match retry_after(&result, SystemTime::now()) {
+            Some(delay) if delay > policy.max_retry_after => {
+                debug!(
+                    "Not retrying {} {}: upstream asked to retry after {:?}",
+                    parts.method, parts.uri, delay
+                );
+                return result;
+            }
+            Some(delay) => delay,
+            None => policy.delay(attempt),
+        };
+        attempt += 1;
+        warn!(
+            "Retrying {} {} in {:?} (attempt {}/{})",
+            parts.method, parts.uri, delay, attempt, policy.retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+    )
+}
+
+fn should_retry(result: &Result<Response<Body>>) -> bool {
+    match result {
+        Err(_) => true,
+        Result::Ok(response) => matches!(
+            response.status(),
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE
+        ),
+    }
+}
+
+/// The delay a `503` asks for with `Retry-After`, given either in seconds or as an
+/// HTTP-date. A date in the past means no delay.
+fn retry_after(result: &Result<Response<Body>>, now: SystemTime) -> Option<Duration> {
+    let response = match result {
+        Result::Ok(response) if response.status() == StatusCode::SERVICE_UNAVAILABLE => response,
+        _ => return None,
+    };
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Result::Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+fn rebuild_request(parts: &Parts, body: Bytes) -> Request<Body> {
+    let mut req = Request::new(Body::from(body));
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    req
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    fn policy(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            backoff: Duration::from_millis(1),
+            max_body_bytes: 1024,
+            max_retry_after: Duration::from_secs(10),
+        }
+    }
+
+    fn request(method: Method) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("http://upstream/path")
+            .body(Body::from("body"))
+            .unwrap()
+    }
+
+    fn status(status: StatusCode) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// Sends requests to an upstream that fails the first `failures` times, first with
+    /// a connection error and then with a `503`, checking each attempt gets the body.
+    async fn send_failing(
+        policy: &RetryPolicy,
+        req: Request<Body>,
+        failures: u32,
+    ) -> (Result<Response<Body>>, u32) {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let result = retry_request(policy, req, |req| {
+            let attempts = attempts.clone();
+            async move {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                let body = hyper::body::to_bytes(req.into_body()).await?;
+                assert_eq!(body, "body");
+                match attempt {
+                    attempt if attempt >= failures => Ok(status(StatusCode::OK)),
+                    0 => bail!("connection refused"),
+                    _ => Ok(status(StatusCode::SERVICE_UNAVAILABLE)),
+                }
+            }
+        })
+        .await;
+        (result, attempts.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn retries_until_the_upstream_succeeds() {
+        let (result, attempts) = send_failing(&policy(3), request(Method::PUT), 2).await;
+        assert_eq!(result.unwrap().status(), StatusCode::OK);
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_retry() {
+        let (result, attempts) = send_failing(&policy(2), request(Method::GET), u32::MAX).await;
+        assert_eq!(result.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(attempts, 3);
+
+        let (result, attempts) = send_failing(&policy(0), request(Method::GET), u32::MAX).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn never_retries_post() {
+        let (result, attempts) = send_failing(&policy(3), request(Method::POST), 1).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn sends_unbuffered_bodies_once() {
+        let large = Request::put("http://upstream/path")
+            .body(Body::from(vec![b'x'; 1025]))
+            .unwrap();
+        let (sender, streamed) = Body::channel();
+        drop(sender);
+        let streamed = Request::put("http://upstream/path").body(streamed).unwrap();
+        for req in [large, streamed] {
+            let attempts = AtomicU32::new(0);
+            let result = retry_request(&policy(3), req, |_| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async { Ok(status(StatusCode::SERVICE_UNAVAILABLE)) }
+            })
+            .await;
+            assert_eq!(result.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    fn unavailable(retry_after: Option<&str>) -> Result<Response<Body>> {
+        let mut response = status(StatusCode::SERVICE_UNAVAILABLE);
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, retry_after.parse().unwrap());
+        }
+        Ok(response)
+    }
+
+    #[test]
+    fn parses_retry_after_in_seconds_and_as_a_date() {
+        let now = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(
+            retry_after(&unavailable(Some("120")), now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(&unavailable(Some("Sun, 06 Nov 1994 08:50:07 GMT")), now),
+            Some(Duration::from_secs(30))
+        );
+        // A date already past means retrying right away.
+        assert_eq!(
+            retry_after(&unavailable(Some("Sun, 06 Nov 1994 08:00:00 GMT")), now),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn ignores_missing_or_invalid_retry_after() {
+        let now = SystemTime::now();
+        assert_eq!(retry_after(&unavailable(None), now), None);
+        assert_eq!(retry_after(&unavailable(Some("soon")), now), None);
+        assert_eq!(retry_after(&unavailable(Some("-5")), now), None);
+        // Only a 503's counts.
+        let mut bad_gateway = status(StatusCode::BAD_GATEWAY);
+        bad_gateway
+            .headers_mut()
+            .insert(RETRY_AFTER, "5".parse().unwrap());
+        assert_eq!(retry_after(&Ok(bad_gateway), now), None);
+        assert_eq!(retry_after(&Err(anyhow!("connection refused")), now), None);
+    }
+
+    /// Sends a request to an upstream answering `first` and then `200`, returning the
+    /// final status, its `Retry-After`, the number of attempts and how long it took.
+    async fn send_after(
+        policy: &RetryPolicy,
+        first: Option<&'static str>,
+    ) -> (StatusCode, Option<String>, u32, Duration) {
+        let attempts = AtomicU32::new(0);
+        let started = std::time::Instant::now();
+        let response = retry_request(policy, request(Method::GET), |_| {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match attempt {
+                    0 => unavailable(first),
+                    _ => Ok(status(StatusCode::OK)),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .map(|value| value.to_str().unwrap().to_string());
+        (
+            response.status(),
+            retry_after,
+            attempts.load(Ordering::SeqCst),
+            started.elapsed(),
+        )
+    }
+
+    #[tokio::test]
+    async fn waits_out_retry_after_instead_of_the_backoff() {
+        let (status, _, attempts, elapsed) = send_after(&policy(1), Some("1")).await;
+        assert_eq!((status, attempts), (StatusCode::OK, 2));
+        assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+
+        let (status, _, attempts, elapsed) = send_after(&policy(1), None).await;
+        assert_eq!((status, attempts), (StatusCode::OK, 2));
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn passes_retry_after_on_when_not_retrying() {
+        // Later than max_retry_after.
+        let (status, retry_after, attempts, _) = send_after(&policy(3), Some("3600")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(retry_after.as_deref(), Some("3600"));
+        assert_eq!(attempts, 1);
+
+        // Out of retries.
+        let (status, retry_after, attempts, _) = send_after(&policy(0), Some("1")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(retry_after.as_deref(), Some("1"));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy {
+            backoff: Duration::from_millis(100),
+            ..policy(3)
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(400));
+        let policy = RetryPolicy {
+            backoff: Duration::MAX,
+            ..policy
+        };
+        assert_eq!(policy.delay(1), Duration::MAX);
+    }
+}
@@ -0,0 +1,181 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+
+/// A rule as written in the config, with exactly one of the two matchers.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RewriteRuleEntry {
+    #[serde(default)]
+    match_prefix: Option<String>,
+    #[serde(default)]
+    match_regex: Option<String>,
+    #[serde(default)]
+    replace_with: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RewriteRuleEntry")]
+pub struct RewriteRule {
+    matcher: Matcher,
+    /// With a regex, `$1` or `${name}` insert capture groups.
+    replace_with: String,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Prefix(String),
+    /// Compiled when the config is loaded, so an invalid one fails it.
+    Regex(Regex),
+}
+
+impl TryFrom<RewriteRuleEntry> for RewriteRule {
+    type Error = String;
+
+    fn try_from(entry: RewriteRuleEntry) -> Result<RewriteRule, String> {
+        let matcher = match (entry.match_prefix, entry.match_regex) {
+            (Some(prefix), None) => Matcher::Prefix(prefix),
+            (None, Some(regex)) => Matcher::Regex(
+                Regex::new(&regex)
+                    .map_err(|err| format!("Invalid match_regex {:?}: {}", regex, err))?,
+            ),
+            _ => return Err("a rewrite rule needs one of match_prefix or match_regex".to_string()),
+        };
+        Ok(RewriteRule {
+            matcher,
+            replace_with: entry.replace_with,
+        })
+    }
+}
+
+impl RewriteRule {
+    /// `path` rewritten, or `None` if the rule doesn't match it. A regex only replaces
+    /// the part of the path it matches, so anchor it to replace the whole path.
+    fn apply(&self, path: &str) -> Option<String> {
+        match &self.matcher {
+            Matcher::Prefix(prefix) => path
+                .strip_prefix(prefix.as_str())
+                .map(|rest| format!("{}{}", self.replace_with, rest)),
+            Matcher::Regex(regex) => {
+                let captures = regex.captures(path)?;
+                let matched = captures.get(0).unwrap();
+                let mut rewritten = path[..matched.start()].to_string();
+                captures.expand(&self.replace_with, &mut rewritten);
+                rewritten.push_str(&path[matched.end()..]);
+                Some(rewritten)
+            }
+        }
+    }
+}
+
+/// Applies the first rule that matches `path`; unmatched paths are returned as-is.
+pub fn rewrite_path<'a>(rules: &[RewriteRule], path: &'a str) -> Cow<'a, str> {
+    match rules.iter().find_map(|rule| rule.apply(path)) {
+        None => Cow::Borrowed(path),
+        Some(rewritten) if rewritten.starts_with('/') => Cow::Owned(rewritten),
+        Some(rewritten) => Cow::Owned(format!("/{}", rewritten)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[derive(Deserialize)]
+    struct Rules {
+        rules: Vec<RewriteRule>,
+    }
+
+    fn rules(config: &str) -> Vec<RewriteRule> {
+        toml::from_str::<Rules>(config).unwrap().rules
+    }
+
+    #[test]
+    fn strips_a_prefix() {
+        let rules = rules("[[rules]]\nmatch_prefix = \"/api\"");
+        assert_eq!(rewrite_path(&rules, "/api/users"), "/users");
+        assert_eq!(rewrite_path(&rules, "/api"), "/");
+    }
+
+    #[test]
+    fn replaces_a_prefix() {
+        let rules = rules("[[rules]]\nmatch_prefix = \"/old\"\nreplace_with = \"/new/v2\"");
+        assert_eq!(rewrite_path(&rules, "/old/users"), "/new/v2/users");
+    }
+
+    #[test]
+    fn leaves_unmatched_paths_alone() {
+        let rules = rules("[[rules]]\nmatch_prefix = \"/api\"");
+        assert!(matches!(
+            rewrite_path(&rules, "/static/app.js"),
+            Cow::Borrowed("/static/app.js")
+        ));
+        assert_eq!(rewrite_path(&[], "/api/users"), "/api/users");
+    }
+
+    #[test]
+    fn applies_the_first_matching_rule() {
+        let rules = rules(
+            "[[rules]]\nmatch_prefix = \"/api/v1\"\nreplace_with = \"/v1\"\n\
+             [[rules]]\nmatch_prefix = \"/api\"\nreplace_with = \"/latest\"",
+        );
+        assert_eq!(rewrite_path(&rules, "/api/v1/users"), "/v1/users");
+        assert_eq!(rewrite_path(&rules, "/api/v2/users"), "/latest/v2/users");
+    }
+
+    #[test]
+    fn needs_exactly_one_matcher() {
+        assert!(toml::from_str::<Rules>("[[rules]]\nreplace_with = \"/x\"").is_err());
+        assert!(
+            toml::from_str::<Rules>("[[rules]]\nmatch_prefix = \"/a\"\nmatch_regex = \"^/b\"")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn regexes_insert_their_capture_groups() {
+        let rules = rules(
+            r#"
+            [[rules]]
+            match_regex = "^/users/(?P<id>[0-9]+)/posts/([0-9]+)$"
+            replace_with = "/v2/posts/$2?author=${id}"
+            [[rules]]
+            match_regex = "/v1/"
+            replace_with = "/v2/"
+            "#,
+        );
+        assert_eq!(
+            rewrite_path(&rules, "/users/42/posts/7"),
+            "/v2/posts/7?author=42"
+        );
+        // Only the matched part is replaced.
+        assert_eq!(rewrite_path(&rules, "/api/v1/users"), "/api/v2/users");
+    }
+
+    #[test]
+    fn leaves_paths_a_regex_doesnt_match_alone() {
+        let rules =
+            rules("[[rules]]\nmatch_regex = \"^/users/([0-9]+)$\"\nreplace_with = \"/u/$1\"");
+        assert!(matches!(
+            rewrite_path(&rules, "/users/me"),
+            Cow::Borrowed("/users/me")
+        ));
+        assert_eq!(rewrite_path(&rules, "/users/42/posts"), "/users/42/posts");
+    }
+
+    #[test]
+    fn invalid_regexes_fail_the_config() {
+        let err = Config::parse(
+            "upstreams = \"http://127.0.0.1:8080\"\n\
+             [[rewrite_rules]]\nmatch_regex = \"^/users/(\"",
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Invalid match_regex"),
+            "{:#}",
+            err
+        );
+    }
+}
@@ -0,0 +1,900 @@
+use crate::auth::{BasicAuth, BasicAuthConfig};
+use crate::balancer::{self, Balancer, ConsistentHash, Upstream};
+use crate::bulkhead::Bulkheads;
+use crate::circuit_breaker::CircuitBreakers;
+use crate::config::{Config, HeaderMatch, RouteConfig};
+use crate::connector::PriorKnowledge;
+use crate::health::HealthChecker;
+use crate::maintenance::Maintenance;
+use crate::rewrite::RewriteRule;
+use crate::HttpsClient;
+use anyhow::*;
+use hyper::header::{HeaderMap, HeaderName, HOST};
+use hyper::Uri;
+use log::{error, info};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Requests whose path is under `prefix`, whose query has all of `query` and whose
+/// headers match all of `headers` go to this route's own upstreams.
+struct Route {
+    prefix: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(HeaderName, HeaderMatch)>,
+    /// The conditions as written in the config, for logs and the admin API.
+    label: String,
+    balancer: Arc<Balancer>,
+}
+
+impl Route {
+    /// Prefixes match whole path segments: `/auth` matches `/auth` and `/auth/login`,
+    /// but not `/authors`.
+    /// A header sent more than once matches if any of its values does.
+    fn matches(&self, path: &str, query: &[(String, String)], headers: &HeaderMap) -> bool {
+        let path_matches = match path.strip_prefix(self.prefix.as_str()) {
+            None => false,
+            Some(rest) => self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        };
+        path_matches
+            && self.query.iter().all(|param| query.contains(param))
+            && self.headers.iter().all(|(name, expected)| {
+                headers
+                    .get_all(name)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .any(|value| expected.matches(value))
+            })
+    }
+
+    /// Header routes come first and keep their config order, then query routes, then
+    /// plain path routes, longest prefix first.
+    fn precedence(&self) -> (bool, bool, std::cmp::Reverse<usize>) {
+        if !self.headers.is_empty() {
+            return (false, false, std::cmp::Reverse(0));
+        }
+        (
+            true,
+            self.query.is_empty(),
+            std::cmp::Reverse(self.prefix.len()),
+        )
+    }
+
+    fn same_conditions(&self, other: &Route) -> bool {
+        self.prefix == other.prefix && self.query == other.query && self.headers == other.headers
+    }
+}
+
+/// The upstreams, routes, path rewrites and credentials for one or more hosts.
+pub struct Site {
+    /// Used for requests that match no route.
+    pub balancer: Arc<Balancer>,
+    /// In the order they're tried, see `Route::precedence`.
+    routes: Vec<Route>,
+    pub rewrite_rules: Vec<RewriteRule>,
+    pub basic_auth: Option<Arc<BasicAuth>>,
+    /// The virtual host's `host`, or `None` for the top-level site.
+    host: Option<String>,
+}
+
+impl Site {
+    fn new(
+        host: Option<String>,
+        upstreams: &[Upstream],
+        routes: &[RouteConfig],
+        rewrite_rules: &[RewriteRule],
+        basic_auth: Option<&BasicAuthConfig>,
+        consistent_hash: Option<&ConsistentHash>,
+    ) -> Result<Site> {
+        let balancer = Arc::new(Balancer::new(
+            upstreams.to_vec(),
+            balancer::random_seed(),
+            consistent_hash,
+        )?);
+        let mut routes = routes
+            .iter()
+            .map(|route| new_route(route, consistent_hash))
+            .collect::<Result<Vec<_>>>()?;
+        routes.sort_by_key(Route::precedence);
+        for (index, route) in routes.iter().enumerate() {
+            if routes[index + 1..]
+                .iter()
+                .any(|other| other.same_conditions(route))
+            {
+                bail!("Duplicate route for {:?}", route.label);
+            }
+        }
+        let basic_auth = match basic_auth {
+            None => None,
+            Some(basic_auth) => Some(Arc::new(BasicAuth::new(basic_auth)?)),
+        };
+        Ok(Site {
+            balancer,
+            routes,
+            rewrite_rules: rewrite_rules.to_vec(),
+            basic_auth,
+            host,
+        })
+    }
+
+    /// The balancer of the first route matching the request, or the default one.
+    pub fn balancer_for(
+        &self,
+        path: &str,
+        query: Option<&str>,
+        headers: &HeaderMap,
+    ) -> &Arc<Balancer> {
+        let query = if self.routes.iter().any(|route| !route.query.is_empty()) {
+            parse_query(query.unwrap_or_default())
+        } else {
+            Vec::new()
+        };
+        self.routes
+            .iter()
+            .find(|route| route.matches(path, &query, headers))
+            .map_or(&self.balancer, |route| &route.balancer)
+    }
+
+    /// Each balancer with its label: the host for a virtual host's default upstreams,
+    /// and the route's conditions, after the host if there is one, for a route's.
+    fn routes(&self) -> impl Iterator<Item = (Option<String>, &Arc<Balancer>)> {
+        std::iter::once((self.host.clone(), &self.balancer)).chain(self.routes.iter().map(
+            move |route| {
+                let label = match &self.host {
+                    None => route.label.clone(),
+                    Some(host) => format!("{} {}", host, route.label),
+                };
+                (Some(label), &route.balancer)
+            },
+        ))
+    }
+}
+
+/// A virtual host's `host`: `Exact("api.example.com")`, or `Suffix(".example.com")` for
+/// `*.example.com`, which doesn't match `example.com` itself.
+#[derive(PartialEq)]
+enum HostPattern {
+    Exact(String),
+    Suffix(String),
+}
+
+impl HostPattern {
+    fn parse(host: &str) -> Result<HostPattern> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let pattern = match host.strip_prefix('*') {
+            Some(suffix) => HostPattern::Suffix(suffix.to_string()),
+            None => HostPattern::Exact(host.clone()),
+        };
+        let name = match &pattern {
+            HostPattern::Exact(name) => name.as_str(),
+            HostPattern::Suffix(suffix) => suffix.strip_prefix('.').unwrap_or_default(),
+        };
+        ensure!(
+            !name.is_empty() && !name.contains(['*', ':', '/']),
+            "Invalid virtual host {:?}",
+            host
+        );
+        Ok(pattern)
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => host == name,
+            HostPattern::Suffix(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+        }
+    }
+
+    /// Exact names come first, then wildcards, most specific first.
+    fn precedence(&self) -> (bool, std::cmp::Reverse<usize>) {
+        match self {
+            HostPattern::Exact(_) => (false, std::cmp::Reverse(0)),
+            HostPattern::Suffix(suffix) => (true, std::cmp::Reverse(suffix.len())),
+        }
+    }
+}
+
+/// The host a request is for, lowercased and without port or trailing dot. HTTP/2
+/// requests carry it in the URI, HTTP/1 ones in `Host`.
+fn request_host(uri: &Uri, headers: &HeaderMap) -> Option<String> {
+    let host = match uri.host() {
+        Some(host) => host,
+        None => {
+            let host = headers.get(HOST)?.to_str().ok()?;
+            match host.rfind(':') {
+                Some(colon) if !host[colon..].contains(']') => &host[..colon],
+                _ => host,
+            }
+        }
+    };
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// Everything about where requests go that can change on SIGHUP.
+pub struct Routing {
+    /// For requests no virtual host matches.
+    site: Site,
+    /// In the order they're tried, see `HostPattern::precedence`.
+    vhosts: Vec<(HostPattern, Site)>,
+    pub circuit_breakers: Option<CircuitBreakers>,
+    /// Requests still in flight keep the slots of the routing they started with, so
+    /// right after a reload an upstream can briefly see up to twice its limit.
+    pub bulkheads: Option<Bulkheads>,
+    pub maintenance: Maintenance,
+    /// Every balancer has a ring for it when set.
+    pub consistent_hash: Option<ConsistentHash>,
+    /// The config file this routing was loaded from, for `/admin/config`.
+    pub config: serde_json::Value,
+}
+
+impl Routing {
+    /// Also starts the health checkers for the new upstreams, which stop by themselves
+    /// once this routing is replaced and no request uses it anymore.
+    pub fn new(config: &Config, client: &HttpsClient) -> Result<Routing> {
+        let consistent_hash = match &config.consistent_hash {
+            Some(consistent_hash) => Some(ConsistentHash::new(consistent_hash)?),
+            None => None,
+        };
+        let site = Site::new(
+            None,
+            &config.upstreams,
+            &config.routes,
+            &config.rewrite_rules,
+            config.basic_auth.as_ref(),
+            consistent_hash.as_ref(),
+        )?;
+        let mut vhosts = Vec::new();
+        for vhost in &config.vhosts {
+            let pattern = HostPattern::parse(&vhost.host)?;
+            ensure!(
+                vhosts.iter().all(|(other, _)| *other != pattern),
+                "Duplicate virtual host {:?}",
+                vhost.host
+            );
+            let site = Site::new(
+                Some(vhost.host.clone()),
+                &vhost.upstreams,
+                &vhost.routes,
+                &vhost.rewrite_rules,
+                vhost.basic_auth.as_ref(),
+                consistent_hash.as_ref(),
+            )
+            .with_context(|| format!("Virtual host {:?}", vhost.host))?;
+            vhosts.push((pattern, site));
+        }
+        vhosts.sort_by_key(|(pattern, _)| pattern.precedence());
+        ensure!(
+            config.jwt.is_none()
+                || std::iter::once(&site)
+                    .chain(vhosts.iter().map(|(_, site)| site))
+                    .all(|site| site.basic_auth.is_none()),
+            "basic_auth and jwt can't both be enabled"
+        );
+
+        let mut routing = Routing {
+            site,
+            vhosts,
+            circuit_breakers: None,
+            bulkheads: None,
+            maintenance: Maintenance::new(&config.maintenance)?,
+            consistent_hash,
+            config: config.source.clone(),
+        };
+        let upstreams = routing.upstreams().cloned().collect::<Vec<_>>();
+        if let Some(circuit_breaker) = &config.circuit_breaker {
+            routing.circuit_breakers = Some(CircuitBreakers::new(circuit_breaker, &upstreams)?);
+        }
+        if let Some(bulkhead) = &config.bulkhead {
+            routing.bulkheads = Some(Bulkheads::new(bulkhead, &upstreams)?);
+        }
+        if let Some(health_check) = &config.health_check {
+            for balancer in routing.balancers() {
+                HealthChecker::new(client.clone(), balancer, health_check)?.spawn();
+            }
+        }
+        Ok(routing)
+    }
+
+    /// The site of the first virtual host matching the request's host, or the top-level
+    /// one.
+    pub fn site(&self, uri: &Uri, headers: &HeaderMap) -> &Site {
+        if self.vhosts.is_empty() {
+            return &self.site;
+        }
+        let host = request_host(uri, headers).unwrap_or_default();
+        self.vhosts
+            .iter()
+            .find(|(pattern, _)| pattern.matches(&host))
+            .map_or(&self.site, |(_, site)| site)
+    }
+
+    /// Every site's balancers, the top-level default one first.
+    pub fn balancers(&self) -> impl Iterator<Item = &Arc<Balancer>> {
+        self.routes().map(|(_, balancer)| balancer)
+    }
+
+    /// Each balancer with its label, or `None` for the top-level default one.
+    pub fn routes(&self) -> impl Iterator<Item = (Option<String>, &Arc<Balancer>)> {
+        self.site
+            .routes()
+            .chain(self.vhosts.iter().flat_map(|(_, site)| site.routes()))
+    }
+
+    pub fn upstreams(&self) -> impl Iterator<Item = &Uri> {
+        self.balancers().flat_map(|balancer| balancer.upstreams())
+    }
+
+    /// Carries over what `previous` knew about the upstreams still configured: which ones
+    /// are draining, and, if health checks are still on, which ones are unhealthy, so a
+    /// reload doesn't send traffic back to them before anything has changed.
+    fn inherit(&self, previous: &Routing, health_checked: bool) {
+        for balancer in self.balancers() {
+            for (index, upstream) in balancer.upstreams().iter().enumerate() {
+                let known = previous.balancers().find_map(|old| {
+                    let old_index = old.upstreams().iter().position(|uri| uri == upstream)?;
+                    Some((old, old_index))
+                });
+                if let Some((old, old_index)) = known {
+                    balancer.set_draining(index, old.is_draining(old_index));
+                    if health_checked {
+                        balancer.set_healthy(index, old.is_healthy(old_index));
+                    }
+                }
+            }
+        }
+    }
+
+    fn http2_only_upstreams(&self) -> impl Iterator<Item = &Uri> {
+        self.balancers().flat_map(|balancer| {
+            balancer
+                .upstreams()
+                .iter()
+                .filter(move |upstream| balancer.overrides(upstream).http2_only)
+        })
+    }
+}
+
+fn new_route(config: &RouteConfig, consistent_hash: Option<&ConsistentHash>) -> Result<Route> {
+    ensure!(
+        config.path_prefix.starts_with('/'),
+        "Route path prefix {:?} must start with '/'",
+        config.path_prefix
+    );
+    let mut label = config.path_prefix.clone();
+    for (i, (name, value)) in config.query.iter().enumerate() {
+        label.push(if i == 0 { '?' } else { '&' });
+        label.push_str(&format!("{}={}", name, value));
+    }
+    let headers = config
+        .headers
+        .iter()
+        .map(|(name, expected)| {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("Route header name {:?}", name))?;
+            Ok((name, expected.clone()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (name, expected) in &headers {
+        label.push_str(&match expected {
+            HeaderMatch::Exact(value) => format!(" [{}: {}]", name, value),
+            HeaderMatch::Prefix { prefix } => format!(" [{}: {}*]", name, prefix),
+        });
+    }
+    let balancer = Balancer::new(
+        config.upstreams.clone(),
+        balancer::random_seed(),
+        consistent_hash,
+    )
+    .with_context(|| format!("Route for {:?}", label))?;
+    Ok(Route {
+        prefix: config.path_prefix.clone(),
+        query: config
+            .query
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+        headers,
+        label,
+        balancer: Arc::new(balancer),
+    })
+}
+
+/// Decodes `a=1&b=two+words` form-style, so `%20` and `+` both become spaces. A
+/// parameter without `=` has an empty value.
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    let decode = |value: &str| {
+        percent_encoding::percent_decode_str(&value.replace('+', " "))
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            (decode(name), decode(value))
+        })
+        .collect()
+}
+
+/// The active [`Routing`]. Each request takes a snapshot when it starts, so a reload
+/// never changes the upstream of a request that's already in flight.
+pub struct SharedRouting {
+    routing: RwLock<Arc<Routing>>,
+    /// The `http2_only` upstreams of the active routing, as the connector sees them.
+    prior_knowledge: PriorKnowledge,
+}
+
+impl SharedRouting {
+    pub fn new(routing: Routing, prior_knowledge: PriorKnowledge) -> SharedRouting {
+        prior_knowledge.set(routing.http2_only_upstreams());
+        SharedRouting {
+            routing: RwLock::new(Arc::new(routing)),
+            prior_knowledge,
+        }
+    }
+
+    pub fn current(&self) -> Arc<Routing> {
+        self.routing.read().unwrap().clone()
+    }
+
+    fn replace(&self, routing: Routing) {
+        self.prior_knowledge.set(routing.http2_only_upstreams());
+        *self.routing.write().unwrap() = Arc::new(routing);
+    }
+}
+
+/// Re-reads the config file on every SIGHUP and swaps in its routing. A config that
+/// fails to load is logged and the current routing stays active.
+pub async fn reload_on_sighup(
+    path: PathBuf,
+    routing: Arc<SharedRouting>,
+    client: Arc<HttpsClient>,
+) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Result::Ok(sighup) => sighup,
+            Err(err) => {
+                log::warn!(
+                    "Unable to listen for SIGHUP, config reloads are disabled: {}",
+                    err
+                );
+                return;
+            }
+        };
+        while sighup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading {}", path.display());
+            if let Err(err) = reload(&path, &routing, &client) {
+                error!("Keeping the current config: {:#}", err);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (path, routing, client);
+}
+
+/// Swaps in the routing of the config file at `path`, unless it fails to load.
+fn reload(path: &Path, routing: &SharedRouting, client: &HttpsClient) -> Result<()> {
+    let config = Config::load(path)?;
+    let reloaded = Routing::new(&config, client)?;
+    reloaded.inherit(&routing.current(), config.health_check.is_some());
+    info!(
+        "Reloaded config, upstreams: {:?}, maintenance mode {}",
+        reloaded.upstreams().collect::<Vec<_>>(),
+        if reloaded.maintenance.is_enabled() {
+            "on"
+        } else {
+            "off"
+        }
+    );
+    routing.replace(reloaded);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client;
+    use crate::test_support::{body_json, echo_upstream, Proxy, TempDir};
+    use std::time::{Duration, Instant};
+
+    fn load(path: &Path) -> (Arc<SharedRouting>, Arc<HttpsClient>) {
+        let config = Config::load(path).unwrap();
+        let prior_knowledge = PriorKnowledge::default();
+        let client = Arc::new(client::build(&config.client, prior_knowledge.clone()).unwrap());
+        let routing = Routing::new(&config, &client).unwrap();
+        (
+            Arc::new(SharedRouting::new(routing, prior_knowledge)),
+            client,
+        )
+    }
+
+    fn routing(config: &str) -> Result<Routing> {
+        let config = Config::parse(config)?;
+        let client = client::build(&config.client, PriorKnowledge::default())?;
+        Routing::new(&config, &client)
+    }
+
+    /// The first upstream of the balancer a request for `path_and_query` gets, with
+    /// `headers`.
+    fn route(routing: &Routing, path_and_query: &str, headers: &[(&str, &str)]) -> String {
+        let uri: Uri = path_and_query.parse().unwrap();
+        let headers = headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    value.parse().unwrap(),
+                )
+            })
+            .collect::<HeaderMap>();
+        let balancer = routing
+            .site(&uri, &headers)
+            .balancer_for(uri.path(), uri.query(), &headers);
+        balancer.upstreams()[0].authority().unwrap().to_string()
+    }
+
+    #[test]
+    fn the_longest_matching_prefix_wins() {
+        let routing = routing(
+            r#"
+            upstreams = "http://default"
+            [[routes]]
+            path_prefix = "/api"
+            upstreams = "http://api"
+            [[routes]]
+            path_prefix = "/api/v2"
+            upstreams = "http://v2"
+            [[routes]]
+            path_prefix = "/static/"
+            upstreams = "http://static"
+            "#,
+        )
+        .unwrap();
+        for (path, expected) in [
+            ("/", "default"),
+            ("/other", "default"),
+            ("/api", "api"),
+            ("/api/", "api"),
+            ("/api/users?page=2", "api"),
+            ("/api/v2", "v2"),
+            ("/api/v2/users", "v2"),
+            ("/api/v20", "api"),
+            // Prefixes match whole segments only.
+            ("/apis", "default"),
+            ("/static/app.js", "static"),
+            ("/static", "default"),
+        ]
+        .iter()
+        {
+            assert_eq!(route(&routing, path, &[]), *expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn routes_need_distinct_conditions() {
+        let duplicate = routing(
+            r#"
+            upstreams = "http://default"
+            [[routes]]
+            path_prefix = "/api"
+            upstreams = "http://a"
+            [[routes]]
+            path_prefix = "/api"
+            upstreams = "http://b"
+            "#,
+        );
+        assert!(duplicate.is_err());
+    }
+
+    #[test]
+    fn query_routes_need_every_parameter_with_its_value() {
+        let routing = routing(
+            r#"
+            upstreams = "http://default"
+            [[routes]]
+            path_prefix = "/api"
+            upstreams = "http://api"
+            [[routes]]
+            path_prefix = "/api"
+            query = { version = "beta", region = "eu west" }
+            upstreams = "http://beta"
+            [[routes]]
+            query = { debug = "1" }
+            upstreams = "http://debug"
+            "#,
+        )
+        .unwrap();
+        for (path_and_query, expected) in [
+            ("/api/users?version=beta&region=eu+west", "beta"),
+            ("/api/users?region=eu%20west&page=2&version=beta", "beta"),
+            // A value that's not the same, or a parameter that's missing, doesn't match.
+            ("/api/users?version=stable&region=eu+west", "api"),
+            ("/api/users?version=beta", "api"),
+            ("/api/users?version=beta=&region=eu+west", "api"),
+            ("/api/users", "api"),
+            // Query routes come before path routes, even with a shorter prefix.
+            ("/api/users?debug=1", "debug"),
+            ("/other?debug=1", "debug"),
+            ("/other?debug", "default"),
+        ] {
+            assert_eq!(
+                route(&routing, path_and_query, &[]),
+                expected,
+                "{}",
+                path_and_query
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn query_routes_forward_the_query_unchanged() {
+        let (default, beta) = (echo_upstream().await, echo_upstream().await);
+        let proxy = Proxy::start(&format!(
+            r#"
+            upstreams = "http://{}"
+            [[routes]]
+            query = {{ version = "beta" }}
+            upstreams = "http://{}"
+            "#,
+            default, beta
+        ))
+        .await;
+        let echo = body_json(proxy.get("/path?version=beta&q=a+b%21").await).await;
+        assert_eq!(echo["uri"], "/path?version=beta&q=a+b%21");
+        assert_eq!(echo["headers"]["host"][0], beta.to_string());
+    }
+
+    #[test]
+    fn header_routes_match_exactly_or_by_prefix_in_config_order() {
+        let routing = routing(
+            r#"
+            upstreams = "http://default"
+            [[routes]]
+            path_prefix = "/api"
+            upstreams = "http://api"
+            [[routes]]
+            headers = { "X-Tenant" = "acme" }
+            upstreams = "http://acme"
+            [[routes]]
+            headers = { "User-Agent" = { prefix = "MobileApp/" } }
+            upstreams = "http://mobile"
+            [[routes]]
+            headers = { "X-Tenant" = { prefix = "acme" } }
+            upstreams = "http://acme-family"
+            "#,
+        )
+        .unwrap();
+        for (headers, expected) in [
+            (&[("x-tenant", "acme")][..], "acme"),
+            (&[("X-TENANT", "acme")], "acme"),
+            // Values are case-sensitive.
+            (&[("x-tenant", "ACME")], "default"),
+            (&[("user-agent", "MobileApp/2.1 (iOS)")], "mobile"),
+            (&[("user-agent", "Mozilla/5.0 MobileApp/2.1")], "default"),
+            // The first route that matches wins.
+            (
+                &[("x-tenant", "acme"), ("user-agent", "MobileApp/2.1")],
+                "acme",
+            ),
+            (
+                &[("x-tenant", "acme-labs"), ("user-agent", "MobileApp/2.1")],
+                "mobile",
+            ),
+            (&[("x-tenant", "acme-labs")], "acme-family"),
+            // Any of a repeated header's values can match.
+            (&[("x-tenant", "other"), ("x-tenant", "acme")], "acme"),
+        ] {
+            assert_eq!(route(&routing, "/", headers), expected, "{:?}", headers);
+        }
+
+        // Without the header, path routes and then the default upstreams apply.
+        assert_eq!(route(&routing, "/api/users", &[]), "api");
+        assert_eq!(route(&routing, "/", &[]), "default");
+        assert_eq!(route(&routing, "/", &[("x-other", "acme")]), "default");
+    }
+
+    #[test]
+    fn virtual_hosts_pick_the_site_by_host() {
+        let routing = routing(
+            r#"
+            upstreams = "http://default"
+            [[vhosts]]
+            host = "api.example.com"
+            upstreams = "http://api"
+            [[vhosts]]
+            host = "shop.example.org"
+            upstreams = "http://shop"
+            [[vhosts.routes]]
+            path_prefix = "/admin"
+            upstreams = "http://backoffice"
+            "#,
+        )
+        .unwrap();
+        for (host, path, expected) in [
+            ("api.example.com", "/", "api"),
+            ("API.example.com:8443", "/", "api"),
+            ("shop.example.org", "/cart", "shop"),
+            ("shop.example.org", "/admin/users", "backoffice"),
+            // Routes belong to their host.
+            ("api.example.com", "/admin/users", "api"),
+            ("other.example.com", "/", "default"),
+        ] {
+            assert_eq!(
+                route(&routing, path, &[("host", host)]),
+                expected,
+                "{}",
+                host
+            );
+        }
+        assert_eq!(route(&routing, "/", &[]), "default");
+        assert_eq!(route(&routing, "http://api.example.com/", &[]), "api");
+    }
+
+    #[test]
+    fn exact_hosts_beat_wildcards_and_longer_wildcards_beat_shorter_ones() {
+        let routing = routing(
+            r#"
+            upstreams = "http://default"
+            [[vhosts]]
+            host = "*.example.com"
+            upstreams = "http://wildcard"
+            [[vhosts]]
+            host = "*.eu.example.com"
+            upstreams = "http://eu"
+            [[vhosts]]
+            host = "api.eu.example.com"
+            upstreams = "http://api"
+            "#,
+        )
+        .unwrap();
+        for (host, expected) in [
+            ("www.example.com", "wildcard"),
+            ("a.b.example.com", "wildcard"),
+            ("shop.eu.example.com", "eu"),
+            ("api.eu.example.com", "api"),
+            // A wildcard doesn't cover the name itself.
+            ("example.com", "default"),
+            ("eu.example.com", "wildcard"),
+            ("notexample.com", "default"),
+        ] {
+            assert_eq!(
+                route(&routing, "/", &[("host", host)]),
+                expected,
+                "{}",
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn virtual_hosts_must_be_distinct() {
+        assert!(routing(
+            r#"
+            upstreams = "http://default"
+            [[vhosts]]
+            host = "api.example.com"
+            upstreams = "http://a"
+            [[vhosts]]
+            host = "API.example.com"
+            upstreams = "http://b"
+            "#,
+        )
+        .is_err());
+    }
+
+    fn upstreams(routing: &SharedRouting) -> Vec<String> {
+        routing
+            .current()
+            .upstreams()
+            .map(|upstream| upstream.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn reloads_swap_in_the_new_upstreams() {
+        let dir = TempDir::new();
+        let path = dir.write("vostok.toml", "upstreams = \"http://10.0.0.1:8080\"");
+        let (routing, client) = load(&path);
+        let before = routing.current();
+
+        dir.write("vostok.toml", "upstreams = [\"http://10.0.0.2:8080\"]");
+        reload(&path, &routing, &client).unwrap();
+        assert_eq!(upstreams(&routing), ["http://10.0.0.2:8080/"]);
+        // Requests that took a snapshot before keep their upstream.
+        assert_eq!(
+            before.upstreams().next().unwrap().to_string(),
+            "http://10.0.0.1:8080/"
+        );
+    }
+
+    #[test]
+    fn drained_upstreams_stay_drained_across_reloads() {
+        let dir = TempDir::new();
+        let path = dir.write(
+            "vostok.toml",
+            "upstreams = [\"http://10.0.0.1:8080\", \"http://10.0.0.2:8080\"]",
+        );
+        let (routing, client) = load(&path);
+        routing
+            .current()
+            .balancers()
+            .next()
+            .unwrap()
+            .set_draining(1, true);
+
+        dir.write(
+            "vostok.toml",
+            "upstreams = [\"http://10.0.0.2:8080\", \"http://10.0.0.1:8080\", \"http://10.0.0.3:8080\"]",
+        );
+        reload(&path, &routing, &client).unwrap();
+        let current = routing.current();
+        let balancer = current.balancers().next().unwrap();
+        assert!(balancer.is_draining(0));
+        assert!(!balancer.is_draining(1) && !balancer.is_draining(2));
+        for _ in 0..10 {
+            assert_ne!(balancer.next(), "http://10.0.0.2:8080/");
+        }
+    }
+
+    #[test]
+    fn reloads_keep_health_only_while_health_checks_are_on() {
+        let config = "upstreams = [\"http://10.0.0.1:8080\", \"http://10.0.0.2:8080\"]";
+        let previous = routing(config).unwrap();
+        previous.balancers().next().unwrap().set_healthy(0, false);
+
+        let reloaded = routing(config).unwrap();
+        reloaded.inherit(&previous, true);
+        let balancer = reloaded.balancers().next().unwrap();
+        assert!(!balancer.is_healthy(0) && balancer.is_healthy(1));
+
+        let reloaded = routing(config).unwrap();
+        reloaded.inherit(&previous, false);
+        assert!(reloaded.balancers().next().unwrap().is_healthy(0));
+    }
+
+    #[test]
+    fn configs_that_fail_to_load_are_ignored() {
+        let dir = TempDir::new();
+        let path = dir.write("vostok.toml", "upstreams = \"http://10.0.0.1:8080\"");
+        let (routing, client) = load(&path);
+
+        for invalid in [
+            "upstreams = [",
+            "upstreams = \"ftp://10.0.0.2\"",
+            "upstreams = \"http://10.0.0.2:8080\"\nunknown = 1",
+        ]
+        .iter()
+        {
+            dir.write("vostok.toml", invalid);
+            assert!(reload(&path, &routing, &client).is_err(), "{}", invalid);
+            assert_eq!(upstreams(&routing), ["http://10.0.0.1:8080/"]);
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(reload(&path, &routing, &client).is_err());
+        assert_eq!(upstreams(&routing), ["http://10.0.0.1:8080/"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sighup_reloads_the_config_file() {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        // Listening here too keeps a SIGHUP sent before the reloader listens from
+        // killing the test binary.
+        let _sighup = signal(SignalKind::hangup()).unwrap();
+        let dir = TempDir::new();
+        let path = dir.write("vostok.toml", "upstreams = \"http://10.0.0.1:8080\"");
+        let (routing, client) = load(&path);
+        tokio::spawn(reload_on_sighup(path.clone(), routing.clone(), client));
+
+        dir.write("vostok.toml", "upstreams = \"http://10.0.0.3:8080\"");
+        let started = Instant::now();
+        while upstreams(&routing) != ["http://10.0.0.3:8080/"] {
+            assert!(started.elapsed() < Duration::from_secs(5), "Not reloaded");
+            unsafe { libc::kill(libc::getpid(), libc::SIGHUP) };
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+}
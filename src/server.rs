@@ -0,0 +1,1004 @@
+use crate::metrics::GaugeGuard;
+use crate::proxy_protocol;
+use anyhow::*;
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::stream;
+use futures_util::task::AtomicWaker;
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use hyper::service::{make_service_fn, Service};
+use hyper::{Body, Request, Server};
+use log::{debug, warn};
+use prometheus::{IntCounter, IntGauge};
+use routerify::{RequestServiceBuilder, Router};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{Instant, Sleep};
+use tokio_rustls::TlsAcceptor;
+
+/// Connections that finished their TLS handshake but haven't been picked up by hyper yet.
+const HANDSHAKE_BACKLOG: usize = 128;
+
+pub type ServiceBuilder = Arc<RequestServiceBuilder<Body, Error>>;
+
+/// Socket options for every listener. The defaults leave the OS's own behavior alone.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Pending connections the kernel queues before refusing new ones. Unset uses the
+    /// standard library's default.
+    #[serde(default)]
+    pub backlog: Option<u32>,
+    /// Set `TCP_NODELAY` on accepted connections.
+    #[serde(default)]
+    pub tcp_nodelay: bool,
+    /// Send TCP keepalive probes once an accepted connection has been idle this long.
+    /// Unset means no keepalive.
+    #[serde(default, with = "humantime_serde")]
+    pub tcp_keepalive: Option<Duration>,
+    /// Expect a PROXY protocol (v1 or v2) header on every connection, and serve it for the
+    /// client address the header names. Only applies to `listen_addrs`.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Connections the main listeners keep open at once, between them. Ones over the
+    /// limit are closed as soon as they're accepted. Unset means no limit.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Connections a single client IP keeps open to the main listeners at once. Counted
+    /// by the socket's peer, before any PROXY protocol header. Unset means no limit.
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+}
+
+/// How long a client connection may take, or stay unused, before it's closed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionTimeouts {
+    /// For its request headers (or TLS handshake) to arrive; hyper can't answer a
+    /// connection that runs out of it with a `408`.
+    pub header_read: Option<Duration>,
+    /// Without a request being handled or anything read or written.
+    pub idle: Option<Duration>,
+}
+
+/// Keeps count of a server's client connections, and turns away the ones over `max`.
+/// Clones share the count, so one limit can cover several listeners.
+#[derive(Clone)]
+pub struct Connections {
+    /// Connections being served, which doesn't include ones still in their handshake.
+    open: IntGauge,
+    /// Connections accepted and not closed yet, handshakes included.
+    accepted: Arc<AtomicUsize>,
+    max: Option<usize>,
+    per_ip: Option<Arc<PerIp>>,
+    rejected: IntCounter,
+}
+
+/// Connections accepted and not closed yet by client IP. IPs without any are removed,
+/// so the map only holds the clients currently connected.
+struct PerIp {
+    max: usize,
+    accepted: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl PerIp {
+    fn admit(&self, ip: IpAddr) -> bool {
+        let mut accepted = self.accepted.lock().unwrap();
+        let count = accepted.entry(ip).or_insert(0);
+        if *count >= self.max {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut accepted = self.accepted.lock().unwrap();
+        if let Some(count) = accepted.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                accepted.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Holds an accepted connection's place in the counts.
+struct Slot {
+    accepted: Arc<AtomicUsize>,
+    per_ip: Option<(Arc<PerIp>, IpAddr)>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.accepted.fetch_sub(1, Ordering::SeqCst);
+        if let Some((per_ip, ip)) = &self.per_ip {
+            per_ip.release(*ip);
+        }
+    }
+}
+
+impl Connections {
+    pub fn new(
+        open: IntGauge,
+        max: Option<usize>,
+        max_per_ip: Option<usize>,
+        rejected: IntCounter,
+    ) -> Connections {
+        Connections {
+            open,
+            accepted: Arc::default(),
+            max,
+            per_ip: max_per_ip.map(|max| {
+                Arc::new(PerIp {
+                    max,
+                    accepted: Mutex::default(),
+                })
+            }),
+            rejected,
+        }
+    }
+
+    /// The same gauge, with a count of their own that's never limited.
+    pub fn unlimited(&self) -> Connections {
+        Connections::new(self.open.clone(), None, None, self.rejected.clone())
+    }
+
+    /// A slot for a connection from `ip`, or the name of the limit it's over.
+    fn admit(&self, ip: IpAddr) -> std::result::Result<Slot, &'static str> {
+        self.accepted
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |accepted| {
+                match self.max {
+                    Some(max) if accepted >= max => None,
+                    _ => Some(accepted + 1),
+                }
+            })
+            .map_err(|_| "max_connections")?;
+        // Dropping it from here on gives the connection's place in the total back.
+        let mut slot = Slot {
+            accepted: self.accepted.clone(),
+            per_ip: None,
+        };
+        if let Some(per_ip) = &self.per_ip {
+            let ip = ip.to_canonical();
+            if !per_ip.admit(ip) {
+                return Err("max_connections_per_ip");
+            }
+            slot.per_ip = Some((per_ip.clone(), ip));
+        }
+        std::result::Result::Ok(slot)
+    }
+
+    /// Accepts the next connection there's room for. Any over the limit are closed
+    /// straight away, rather than left waiting in the kernel's queue.
+    fn poll_accept(
+        &self,
+        incoming: &mut AddrIncoming,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<std::io::Result<(AddrStream, Slot)>>> {
+        loop {
+            match futures_util::ready!(Pin::new(&mut *incoming).poll_accept(cx)) {
+                Some(std::result::Result::Ok(stream)) => {
+                    match self.admit(stream.remote_addr().ip()) {
+                        std::result::Result::Ok(slot) => {
+                            return Poll::Ready(Some(std::result::Result::Ok((stream, slot))))
+                        }
+                        Err(limit) => {
+                            self.rejected.inc();
+                            debug!(
+                                "Closing connection from {}: {} reached",
+                                stream.remote_addr(),
+                                limit
+                            );
+                        }
+                    }
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Binds `addr` with `config`'s backlog, or takes over `inherited`, a socket already
+/// listening on it, in which case the backlog it was set up with stays. Binding happens
+/// eagerly so a bad or busy address fails at startup.
+pub fn bind(
+    addr: SocketAddr,
+    config: &ListenerConfig,
+    inherited: Option<std::net::TcpListener>,
+) -> Result<TcpListener> {
+    let listener = match (inherited, config.backlog) {
+        (Some(listener), _) => {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        (None, None) => {
+            let listener = std::net::TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        (None, Some(backlog)) => {
+            let socket = if addr.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            // Like the standard library's bind, so a restart can reuse the address.
+            #[cfg(unix)]
+            socket.set_reuseaddr(true)?;
+            socket.bind(addr)?;
+            socket.listen(backlog)?
+        }
+    };
+    Ok(listener)
+}
+
+pub fn service_builder(router: Router<Body, Error>) -> Result<ServiceBuilder> {
+    let builder = RequestServiceBuilder::new(router)
+        .map_err(|err| anyhow!(err))
+        .context("Building request service")?;
+    Ok(Arc::new(builder))
+}
+
+/// Flips to `true` once shutdown has started.
+pub type Shutdown = watch::Receiver<bool>;
+
+pub async fn wait_for_shutdown(mut shutdown: Shutdown) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Returns the server future for a bound `listener`, which completes once `shutdown`
+/// fires and every open connection has finished. Accepted connections get `config`'s
+/// socket options. With `tls` set, they're served over HTTPS. Each open connection
+/// counts towards `connections`.
+pub fn serve(
+    listener: TcpListener,
+    builder: ServiceBuilder,
+    tls: Option<TlsAcceptor>,
+    config: &ListenerConfig,
+    timeouts: ConnectionTimeouts,
+    connections: Connections,
+    shutdown: Shutdown,
+) -> Result<BoxFuture<'static, Result<()>>> {
+    let addr = listener.local_addr()?;
+    let mut incoming = incoming(listener, config)?;
+    let server = match (tls, config.proxy_protocol) {
+        (None, false) => {
+            let open_connections = connections.open.clone();
+            let incoming = accept::from_stream(stream::poll_fn(move |cx| {
+                connections.poll_accept(&mut incoming, cx).map(|accepted| {
+                    accepted.map(|accepted| {
+                        accepted.map(|(stream, slot)| Accepted {
+                            remote_addr: stream.remote_addr(),
+                            stream,
+                            idle: None,
+                            slot: Some(slot),
+                        })
+                    })
+                })
+            }));
+            serve_accepted(incoming, builder, timeouts, open_connections, shutdown)
+        }
+        (None, true) => {
+            let open_connections = connections.open.clone();
+            let incoming = handshake_incoming(
+                incoming,
+                connections,
+                timeouts.header_read,
+                |mut tcp, peer_addr| {
+                    async move {
+                        let remote_addr = read_proxy_header(&mut tcp, peer_addr).await?;
+                        Ok(Accepted {
+                            stream: tcp,
+                            remote_addr,
+                            idle: None,
+                            slot: None,
+                        })
+                    }
+                    .boxed()
+                },
+            );
+            serve_accepted(incoming, builder, timeouts, open_connections, shutdown)
+        }
+        (Some(acceptor), proxy_protocol) => {
+            let open_connections = connections.open.clone();
+            let incoming = handshake_incoming(
+                incoming,
+                connections,
+                timeouts.header_read,
+                move |mut tcp, peer_addr| {
+                    let acceptor = acceptor.clone();
+                    async move {
+                        let remote_addr = if proxy_protocol {
+                            read_proxy_header(&mut tcp, peer_addr).await?
+                        } else {
+                            peer_addr
+                        };
+                        let stream = acceptor.accept(tcp).await.context("TLS handshake")?;
+                        Ok(Accepted {
+                            stream,
+                            remote_addr,
+                            idle: None,
+                            slot: None,
+                        })
+                    }
+                    .boxed()
+                },
+            );
+            serve_accepted(incoming, builder, timeouts, open_connections, shutdown)
+        }
+    };
+
+    Ok(Box::pin(async move {
+        server
+            .await
+            .with_context(|| format!("Fatal server error on {}", addr))
+    }))
+}
+
+/// `listener`'s connections, with `config`'s socket options set as they're accepted.
+fn incoming(listener: TcpListener, config: &ListenerConfig) -> Result<AddrIncoming> {
+    let mut incoming = AddrIncoming::from_listener(listener)?;
+    incoming
+        .set_nodelay(config.tcp_nodelay)
+        .set_keepalive(config.tcp_keepalive);
+    Ok(incoming)
+}
+
+/// A connection's service, which hyper keeps exactly as long as the connection is open,
+/// so it holds the connection's place in the gauge. It also tells the connection's idle
+/// timeout, if there is one, when requests are being handled.
+struct Counted<S> {
+    service: S,
+    _open: GaugeGuard,
+    in_flight: Option<Arc<InFlightCount>>,
+}
+
+impl<S> Counted<S> {
+    fn new(service: S, gauge: &IntGauge, in_flight: Option<Arc<InFlightCount>>) -> Counted<S> {
+        Counted {
+            service,
+            _open: GaugeGuard::new(gauge),
+            in_flight,
+        }
+    }
+}
+
+/// A connection's requests that don't have their response yet. `idle` wakes the
+/// connection when the last one gets it, since hyper doesn't read again on its own.
+#[derive(Default)]
+struct InFlightCount {
+    count: AtomicUsize,
+    idle: AtomicWaker,
+}
+
+/// Counts a request as in flight until it has its response.
+struct InFlight(Arc<InFlightCount>);
+
+impl InFlight {
+    fn new(in_flight: &Arc<InFlightCount>) -> InFlight {
+        in_flight.count.fetch_add(1, Ordering::SeqCst);
+        InFlight(in_flight.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.wake();
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for Counted<S>
+where
+    S: Service<Request<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, std::result::Result<S::Response, S::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::result::Result<(), S::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let in_flight = self.in_flight.as_ref().map(InFlight::new);
+        self.service
+            .call(req)
+            .map(move |response| {
+                drop(in_flight);
+                response
+            })
+            .boxed()
+    }
+}
+
+/// An accepted connection with the client address it's served for, which is the
+/// socket's peer unless a PROXY protocol header named another one.
+pub struct Accepted<S> {
+    stream: S,
+    remote_addr: SocketAddr,
+    idle: Option<IdleTimeout>,
+    /// Set once the connection is handed to hyper, which keeps it until it closes.
+    slot: Option<Slot>,
+}
+
+/// Ends a connection once it has gone `timeout` without a request in flight or any
+/// traffic. The connection then reads as closed, which hyper handles like the client
+/// hanging up.
+struct IdleTimeout {
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+    in_flight: Arc<InFlightCount>,
+}
+
+impl IdleTimeout {
+    fn new(timeout: Duration) -> IdleTimeout {
+        IdleTimeout {
+            timeout,
+            deadline: Box::pin(tokio::time::sleep(timeout)),
+            in_flight: Arc::default(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.deadline.as_mut().reset(Instant::now() + self.timeout);
+    }
+
+    /// Whether the connection has timed out. The timer only runs while no request is
+    /// in flight; the response going out afterwards restarts it.
+    fn expired(&mut self, cx: &mut std::task::Context<'_>) -> bool {
+        self.in_flight.idle.register(cx.waker());
+        self.in_flight.count.load(Ordering::SeqCst) == 0
+            && self.deadline.as_mut().poll(cx).is_ready()
+    }
+}
+
+/// With the PROXY protocol on, the load balancer's own connections, which name no
+/// client, are served for the load balancer's address.
+async fn read_proxy_header(tcp: &mut TcpStream, peer_addr: SocketAddr) -> Result<SocketAddr> {
+    let remote_addr = proxy_protocol::read_header(tcp)
+        .await
+        .context("Reading PROXY protocol header")?;
+    Ok(remote_addr.unwrap_or(peer_addr))
+}
+
+fn serve_accepted<S>(
+    mut incoming: impl Accept<Conn = Accepted<S>, Error = std::io::Error> + Send + Unpin + 'static,
+    builder: ServiceBuilder,
+    timeouts: ConnectionTimeouts,
+    open_connections: IntGauge,
+    shutdown: Shutdown,
+) -> BoxFuture<'static, hyper::Result<()>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let incoming = accept::from_stream(stream::poll_fn(move |cx| {
+        Pin::new(&mut incoming).poll_accept(cx).map(|accepted| {
+            accepted.map(|accepted| {
+                accepted.map(|mut accepted| {
+                    accepted.idle = timeouts.idle.map(IdleTimeout::new);
+                    accepted
+                })
+            })
+        })
+    }));
+    let make_service = make_service_fn(move |conn: &Accepted<S>| {
+        let in_flight = conn.idle.as_ref().map(|idle| idle.in_flight.clone());
+        let service = Counted::new(
+            builder.build(conn.remote_addr),
+            &open_connections,
+            in_flight,
+        );
+        async move { std::result::Result::Ok::<_, Infallible>(service) }
+    });
+    let mut server = Server::builder(incoming);
+    if let Some(timeout) = timeouts.header_read {
+        server = server.http1_header_read_timeout(timeout);
+    }
+    server
+        .serve(make_service)
+        .with_graceful_shutdown(wait_for_shutdown(shutdown))
+        .boxed()
+}
+
+/// Accepts TCP connections and hands them to hyper once `handshake` is done with them,
+/// i.e. has completed TLS or read a PROXY protocol header. Handshakes run on their own
+/// tasks so a slow client can't hold up the accept loop, and failed or timed out ones
+/// are only logged. Connections in their handshake count towards `connections`' limit.
+fn handshake_incoming<S, F>(
+    mut incoming: AddrIncoming,
+    connections: Connections,
+    handshake_timeout: Option<Duration>,
+    handshake: F,
+) -> impl Accept<Conn = Accepted<S>, Error = std::io::Error>
+where
+    S: Send + 'static,
+    F: Fn(TcpStream, SocketAddr) -> BoxFuture<'static, Result<Accepted<S>>> + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel(HANDSHAKE_BACKLOG);
+    tokio::spawn(async move {
+        loop {
+            let accepted =
+                futures_util::future::poll_fn(|cx| connections.poll_accept(&mut incoming, cx));
+            let (stream, slot) = tokio::select! {
+                accepted = accepted => match accepted {
+                    None => return,
+                    Some(Err(err)) => {
+                        warn!("Failed to accept connection: {}", err);
+                        continue;
+                    }
+                    Some(std::result::Result::Ok(stream)) => stream,
+                },
+                // hyper dropped the incoming side, so the server has shut down.
+                _ = tx.closed() => return,
+            };
+
+            let peer_addr = stream.remote_addr();
+            let handshake = handshake(stream.into_inner(), peer_addr);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let handshake = match handshake_timeout {
+                    None => handshake.await,
+                    Some(timeout) => match tokio::time::timeout(timeout, handshake).await {
+                        std::result::Result::Ok(handshake) => handshake,
+                        Err(_) => {
+                            debug!("Handshake with {} timed out", peer_addr);
+                            return;
+                        }
+                    },
+                };
+                match handshake {
+                    Err(err) => debug!("Handshake with {} failed: {:#}", peer_addr, err),
+                    std::result::Result::Ok(mut accepted) => {
+                        accepted.slot = Some(slot);
+                        let _ = tx.send(std::result::Result::Ok(accepted)).await;
+                    }
+                }
+            });
+        }
+    });
+    accept::from_stream(stream::poll_fn(move |cx| rx.poll_recv(cx)))
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Accepted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let read = Pin::new(&mut this.stream).poll_read(cx, buf);
+        match &mut this.idle {
+            None => read,
+            Some(idle) if read.is_ready() => {
+                idle.reset();
+                read
+            }
+            Some(idle) => {
+                if idle.expired(cx) {
+                    debug!("Closing idle connection from {}", this.remote_addr);
+                    return Poll::Ready(std::result::Result::Ok(()));
+                }
+                read
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Accepted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let (Some(idle), true) = (&mut this.idle, written.is_ready()) {
+            idle.reset();
+        }
+        written
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind, incoming, Connections, ListenerConfig};
+    use crate::test_support::{echo_upstream, Proxy};
+    use hyper::server::accept::Accept;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    /// The server side of a connection accepted by a listener set up from `config`.
+    async fn accept(config: &str) -> TcpStream {
+        let config: ListenerConfig = toml::from_str(config).unwrap();
+        let listener = bind(SocketAddr::from(([127, 0, 0, 1], 0)), &config, None).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = incoming(listener, &config).unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let accepted = futures_util::future::poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        accepted.into_inner()
+    }
+
+    #[cfg(unix)]
+    fn sockopt(socket: &impl std::os::unix::io::AsRawFd, level: i32, name: i32) -> i32 {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(result, 0, "{}", std::io::Error::last_os_error());
+        value
+    }
+
+    #[tokio::test]
+    async fn defaults_leave_socket_options_alone() {
+        let stream = accept("").await;
+        assert!(!stream.nodelay().unwrap());
+        #[cfg(unix)]
+        assert_eq!(sockopt(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+    }
+
+    #[tokio::test]
+    async fn sets_the_configured_socket_options() {
+        let stream = accept(
+            r#"
+            backlog = 16
+            tcp_nodelay = true
+            tcp_keepalive = "75s"
+            "#,
+        )
+        .await;
+        assert!(stream.nodelay().unwrap());
+        #[cfg(unix)]
+        assert_eq!(sockopt(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        #[cfg(target_os = "linux")]
+        assert_eq!(sockopt(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 75);
+    }
+
+    /// Whether a third connection gets through to a listener nothing accepts on.
+    async fn third_connection_queued(config: &str) -> bool {
+        let config: ListenerConfig = toml::from_str(config).unwrap();
+        let listener = bind(SocketAddr::from(([127, 0, 0, 1], 0)), &config, None).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _queued = (
+            TcpStream::connect(addr).await.unwrap(),
+            TcpStream::connect(addr).await.unwrap(),
+        );
+        tokio::time::timeout(Duration::from_millis(300), TcpStream::connect(addr))
+            .await
+            .is_ok()
+    }
+
+    /// Linux queues one more connection than the backlog, then drops SYNs.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn applies_the_configured_backlog() {
+        assert!(third_connection_queued("").await);
+        assert!(!third_connection_queued("backlog = 1").await);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_configured_backlog_still_allows_reusing_the_address() {
+        let config: ListenerConfig = toml::from_str("backlog = 16").unwrap();
+        let listener = bind(SocketAddr::from(([127, 0, 0, 1], 0)), &config, None).unwrap();
+        assert_eq!(sockopt(&listener, libc::SOL_SOCKET, libc::SO_REUSEADDR), 1);
+    }
+
+    #[tokio::test]
+    async fn closes_connections_that_dribble_their_headers() {
+        let upstream = echo_upstream().await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\nheader_read_timeout = \"300ms\"",
+            upstream
+        ))
+        .await;
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        let started = Instant::now();
+        stream
+            .write_all(b"GET /path HTTP/1.1\r\nHost: localhost\r\n")
+            .await
+            .unwrap();
+
+        // Every header line arrives in time for the one before, but never all of them.
+        let dribble = async {
+            loop {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                if stream.write_all(b"X-Slow: 1\r\n").await.is_err() {
+                    break;
+                }
+            }
+        };
+        let _ = tokio::time::timeout(Duration::from_secs(3), dribble).await;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+        // hyper drops the connection without answering, so there's no 408.
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert_eq!(String::from_utf8_lossy(&response), "");
+    }
+
+    #[tokio::test]
+    async fn header_read_timeouts_leave_prompt_clients_alone() {
+        let upstream = echo_upstream().await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\nheader_read_timeout = \"300ms\"",
+            upstream
+        ))
+        .await;
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        stream
+            .write_all(b"GET /path HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    /// A proxy with `idle_timeout` in front of an upstream that answers `done` after
+    /// `delay`.
+    async fn idle_proxy(idle_timeout: &str, delay: Duration) -> Proxy {
+        let upstream = crate::test_support::upstream(move |_| async move {
+            tokio::time::sleep(delay).await;
+            hyper::Response::new(hyper::Body::from("done"))
+        })
+        .await;
+        Proxy::start(&format!(
+            "upstreams = \"http://{}\"\nidle_timeout = \"{}\"",
+            upstream, idle_timeout
+        ))
+        .await
+    }
+
+    /// Sends a keep-alive request on `stream` and reads its whole response.
+    async fn request(stream: &mut TcpStream) -> String {
+        stream
+            .write_all(b"GET /path HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"done") {
+            let mut chunk = [0; 1024];
+            let len = stream.read(&mut chunk).await.unwrap();
+            assert!(len > 0, "{}", String::from_utf8_lossy(&response));
+            response.extend_from_slice(&chunk[..len]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    /// How long until the server closes `stream`, if it does within `limit`.
+    async fn closed_after(stream: &mut TcpStream, limit: Duration) -> Option<Duration> {
+        let started = Instant::now();
+        let mut rest = Vec::new();
+        tokio::time::timeout(limit, stream.read_to_end(&mut rest))
+            .await
+            .ok()?
+            .unwrap();
+        assert!(rest.is_empty(), "{}", String::from_utf8_lossy(&rest));
+        Some(started.elapsed())
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        let proxy = idle_proxy("200ms", Duration::ZERO).await;
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        assert!(request(&mut stream)
+            .await
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+        // Each request restarts the timer.
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(request(&mut stream)
+            .await
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let closed = closed_after(&mut stream, Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert!(closed >= Duration::from_millis(150), "{:?}", closed);
+    }
+
+    #[tokio::test]
+    async fn idle_timeouts_wait_for_requests_being_handled() {
+        let proxy = idle_proxy("200ms", Duration::from_millis(500)).await;
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        assert!(request(&mut stream)
+            .await
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[tokio::test]
+    async fn a_zero_idle_timeout_keeps_connections_open() {
+        let proxy = idle_proxy("0s", Duration::ZERO).await;
+        let mut stream = TcpStream::connect(proxy.addr).await.unwrap();
+        request(&mut stream).await;
+        assert_eq!(
+            closed_after(&mut stream, Duration::from_millis(500)).await,
+            None
+        );
+        assert!(request(&mut stream)
+            .await
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    fn connections(max: Option<usize>) -> Connections {
+        Connections::new(
+            prometheus::IntGauge::new("open", "open").unwrap(),
+            max,
+            None,
+            prometheus::IntCounter::new("rejected", "rejected").unwrap(),
+        )
+    }
+
+    #[test]
+    fn admits_connections_up_to_the_limit() {
+        let connections = connections(Some(2));
+        let ip = [127, 0, 0, 1].into();
+        let first = connections.admit(ip).unwrap();
+        let _second = connections.admit(ip).unwrap();
+        assert_eq!(connections.admit(ip).err(), Some("max_connections"));
+        // Clones share the count; an unlimited copy has its own.
+        assert!(connections.clone().admit(ip).is_err());
+        let unlimited = connections.unlimited();
+        let _unlimited = (0..10)
+            .map(|_| unlimited.admit(ip).unwrap())
+            .collect::<Vec<_>>();
+
+        drop(first);
+        let _third = connections.admit(ip).unwrap();
+        assert!(connections.admit(ip).is_err());
+        assert!(self::connections(None).admit(ip).is_ok());
+    }
+
+    #[tokio::test]
+    async fn closes_connections_over_max_connections() {
+        let upstream = crate::test_support::upstream(|_| async {
+            hyper::Response::new(hyper::Body::from("done"))
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[listener]\nmax_connections = 1",
+            upstream
+        ))
+        .await;
+        let mut first = TcpStream::connect(proxy.addr).await.unwrap();
+        assert!(request(&mut first).await.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // Accepted by the kernel, then closed without an answer.
+        let mut rejected = TcpStream::connect(proxy.addr).await.unwrap();
+        let _ = rejected
+            .write_all(b"GET /path HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await;
+        let mut response = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(2), rejected.read_to_end(&mut response))
+            .await
+            .unwrap();
+        assert!(
+            response.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&response)
+        );
+        assert!(request(&mut first).await.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // Closing the first makes room again.
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut next = TcpStream::connect(proxy.addr).await.unwrap();
+        assert!(request(&mut next).await.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn admits_connections_per_ip_up_to_the_limit() {
+        let connections = Connections::new(
+            prometheus::IntGauge::new("open", "open").unwrap(),
+            Some(3),
+            Some(1),
+            prometheus::IntCounter::new("rejected", "rejected").unwrap(),
+        );
+        let (first, second, third) = (
+            "192.0.2.1".parse().unwrap(),
+            "192.0.2.2".parse().unwrap(),
+            "192.0.2.3".parse().unwrap(),
+        );
+        let slot = connections.admit(first).unwrap();
+        assert_eq!(
+            connections.admit(first).err(),
+            Some("max_connections_per_ip")
+        );
+        // The same address written as IPv4-mapped IPv6 shares the count.
+        assert!(connections
+            .admit("::ffff:192.0.2.1".parse().unwrap())
+            .is_err());
+        // Rejected connections didn't keep a place in the total.
+        let _others = (
+            connections.admit(second).unwrap(),
+            connections.admit(third).unwrap(),
+        );
+
+        drop(slot);
+        let slot = connections.admit(first).unwrap();
+        drop(slot);
+        let per_ip = connections.per_ip.as_ref().unwrap();
+        assert_eq!(per_ip.accepted.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn closed_connections_give_their_ip_room_again() {
+        let upstream = crate::test_support::upstream(|_| async {
+            hyper::Response::new(hyper::Body::from("done"))
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[listener]\nmax_connections_per_ip = 1",
+            upstream
+        ))
+        .await;
+        let mut first = TcpStream::connect(proxy.addr).await.unwrap();
+        assert!(request(&mut first).await.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let mut rejected = TcpStream::connect(proxy.addr).await.unwrap();
+        let mut response = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(2), rejected.read_to_end(&mut response))
+            .await
+            .unwrap();
+        assert!(
+            response.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&response)
+        );
+
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut next = TcpStream::connect(proxy.addr).await.unwrap();
+        assert!(request(&mut next).await.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
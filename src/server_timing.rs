@@ -0,0 +1,184 @@
+use anyhow::*;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::time::Duration;
+
+pub const SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTimingConfig {
+    /// Metric for the time spent waiting on the upstream, retries included.
+    #[serde(default = "default_upstream_metric")]
+    pub upstream_metric: String,
+    /// Metric for the time spent looking the request up in the response cache. Unset
+    /// leaves it out.
+    #[serde(default)]
+    pub cache_metric: Option<String>,
+}
+
+fn default_upstream_metric() -> String {
+    "upstream".to_string()
+}
+
+/// Reports how long a request spent upstream, and optionally in the cache, in a
+/// `Server-Timing` header next to any the upstream sent itself.
+#[derive(Debug)]
+pub struct ServerTiming {
+    upstream_metric: String,
+    cache_metric: Option<String>,
+}
+
+impl ServerTiming {
+    pub fn new(config: &ServerTimingConfig) -> Result<ServerTiming> {
+        let names = std::iter::once(&config.upstream_metric).chain(&config.cache_metric);
+        for name in names {
+            ensure!(
+                is_token(name),
+                "server_timing metric {:?} must be a non-empty token",
+                name
+            );
+        }
+        Ok(ServerTiming {
+            upstream_metric: config.upstream_metric.clone(),
+            cache_metric: config.cache_metric.clone(),
+        })
+    }
+
+    /// Durations that don't apply, like the upstream's on a cache hit, are left out.
+    pub fn add(
+        &self,
+        headers: &mut HeaderMap,
+        upstream: Option<Duration>,
+        cache: Option<Duration>,
+    ) {
+        let metrics = [
+            (Some(&self.upstream_metric), upstream),
+            (self.cache_metric.as_ref(), cache),
+        ];
+        let value = metrics
+            .iter()
+            .filter_map(|(name, duration)| {
+                Some(format!("{};dur={}", (*name)?, millis((*duration)?)))
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        if !value.is_empty() {
+            headers.append(SERVER_TIMING, HeaderValue::from_str(&value).unwrap());
+        }
+    }
+}
+
+/// `dur` is in milliseconds; three decimals keep microsecond precision.
+fn millis(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+/// The HTTP token characters from RFC 9110.
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{upstream, Proxy};
+    use hyper::{Body, Response};
+
+    fn server_timing(cache_metric: Option<&str>) -> ServerTiming {
+        ServerTiming::new(&ServerTimingConfig {
+            upstream_metric: default_upstream_metric(),
+            cache_metric: cache_metric.map(str::to_string),
+        })
+        .unwrap()
+    }
+
+    /// Each metric in the headers' `Server-Timing` values with its duration.
+    fn parse(headers: &HeaderMap) -> Vec<(String, f64)> {
+        headers
+            .get_all(SERVER_TIMING)
+            .iter()
+            .flat_map(|value| value.to_str().unwrap().split(", "))
+            .map(|metric| {
+                let (name, duration) = metric.split_once(";dur=").unwrap();
+                (name.to_string(), duration.parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn adds_the_durations_that_apply() {
+        let timing = server_timing(Some("cache"));
+        let mut headers = HeaderMap::new();
+        timing.add(
+            &mut headers,
+            Some(Duration::from_micros(12_345)),
+            Some(Duration::from_micros(7)),
+        );
+        assert_eq!(
+            headers[SERVER_TIMING],
+            "upstream;dur=12.345, cache;dur=0.007"
+        );
+
+        let mut headers = HeaderMap::new();
+        timing.add(&mut headers, None, Some(Duration::from_millis(1)));
+        assert_eq!(parse(&headers), [("cache".to_string(), 1.0)]);
+
+        let mut headers = HeaderMap::new();
+        server_timing(None).add(&mut headers, None, Some(Duration::from_millis(1)));
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn metric_names_must_be_tokens() {
+        for name in ["", "up stream", "up;stream", "\u{e9}"] {
+            let config = ServerTimingConfig {
+                upstream_metric: name.to_string(),
+                cache_metric: None,
+            };
+            assert!(ServerTiming::new(&config).is_err(), "{:?}", name);
+        }
+        assert!(ServerTiming::new(&ServerTimingConfig {
+            upstream_metric: "app-db_1".to_string(),
+            cache_metric: Some("cache.lookup".to_string()),
+        })
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn reports_the_time_spent_upstream_next_to_the_upstreams_own() {
+        let upstream = upstream(|_| async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut response = Response::new(Body::from("slow"));
+            response
+                .headers_mut()
+                .insert(SERVER_TIMING, HeaderValue::from_static("db;dur=20"));
+            response
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[server_timing]\ncache_metric = \"cache\"\n[cache]",
+            upstream
+        ))
+        .await;
+
+        let metrics = parse(proxy.get("/path").await.headers());
+        assert_eq!(metrics.len(), 3, "{:?}", metrics);
+        assert_eq!(metrics[0], ("db".to_string(), 20.0));
+        assert_eq!(metrics[1].0, "upstream");
+        assert!(metrics[1].1 >= 50.0, "{:?}", metrics);
+        assert_eq!(metrics[2].0, "cache");
+        assert!(metrics[2].1 >= 0.0, "{:?}", metrics);
+
+        // A cache hit never went upstream.
+        let metrics = parse(proxy.get("/path").await.headers());
+        let names = metrics
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["db", "cache"]);
+    }
+}
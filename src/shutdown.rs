@@ -0,0 +1,166 @@
+use crate::server::{self, Shutdown};
+use log::{info, warn};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Counts proxied requests that are still waiting on the upstream, so shutdown can
+/// report how many it drained.
+#[derive(Default)]
+pub struct InFlight(AtomicUsize);
+
+impl InFlight {
+    pub fn start(self: &Arc<Self>) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Decrements the in-flight count when dropped, including on early returns.
+pub struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        (self.0).0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Completes on ctrl-c, or on SIGTERM where supported.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => info!("Received ctrl-c, shutting down"),
+                    _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+                }
+                return;
+            }
+            Err(err) => log::warn!("Unable to listen for SIGTERM: {}", err),
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+    info!("Received ctrl-c, shutting down");
+}
+
+/// How a drain ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Drained {
+    /// Every request in flight when shutdown started finished.
+    All(usize),
+    /// The drain timeout ran out with `remaining` requests still in flight.
+    TimedOut { drained: usize, remaining: usize },
+}
+
+/// Runs `servers` until they finish. Once `shutdown` fires, hyper stops accepting
+/// connections and waits for the requests in flight; they're given up on after
+/// `timeout`.
+pub async fn drain(
+    servers: impl Future<Output = anyhow::Result<()>>,
+    shutdown: Shutdown,
+    in_flight: &InFlight,
+    timeout: Duration,
+) -> anyhow::Result<Drained> {
+    let draining = AtomicUsize::new(0);
+    let deadline = async {
+        server::wait_for_shutdown(shutdown).await;
+        draining.store(in_flight.count(), Ordering::SeqCst);
+        info!(
+            "Draining {} in-flight requests (timeout {:?})",
+            in_flight.count(),
+            timeout
+        );
+        tokio::time::sleep(timeout).await;
+    };
+
+    tokio::select! {
+        result = servers => {
+            result?;
+            let drained = draining.load(Ordering::SeqCst);
+            info!("Drained {} in-flight requests", drained);
+            Ok(Drained::All(drained))
+        }
+        _ = deadline => {
+            let remaining = in_flight.count();
+            let drained = draining.load(Ordering::SeqCst).saturating_sub(remaining);
+            warn!(
+                "Drain timeout elapsed; drained {} requests, dropping {} still in flight",
+                drained, remaining
+            );
+            Ok(Drained::TimedOut { drained, remaining })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, Proxy};
+    use hyper::{Body, Client, Response, StatusCode};
+    use std::time::Instant;
+
+    async fn slow_upstream(delay: Duration) -> Proxy {
+        let upstream = test_support::upstream(move |_| async move {
+            tokio::time::sleep(delay).await;
+            Response::new(Body::from("done"))
+        })
+        .await;
+        Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await
+    }
+
+    /// Sends a request in the background and waits until the proxy is handling it.
+    async fn start_request(
+        proxy: &Proxy,
+    ) -> tokio::task::JoinHandle<hyper::Result<Response<Body>>> {
+        let uri = proxy.url("/slow").parse().unwrap();
+        let request = tokio::spawn(async move { Client::new().get(uri).await });
+        while proxy.in_flight.count() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn drains_requests_in_flight() {
+        let proxy = slow_upstream(Duration::from_millis(300)).await;
+        let request = start_request(&proxy).await;
+        assert_eq!(proxy.drain(Duration::from_secs(5)).await, Drained::All(1));
+        let response = request.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_drain_timeout() {
+        let proxy = slow_upstream(Duration::from_secs(10)).await;
+        let _request = start_request(&proxy).await;
+        let started = Instant::now();
+        assert_eq!(
+            proxy.drain(Duration::from_millis(200)).await,
+            Drained::TimedOut {
+                drained: 0,
+                remaining: 1
+            }
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn guards_count_while_they_live() {
+        let in_flight = Arc::new(InFlight::default());
+        let first = in_flight.start();
+        let second = in_flight.start();
+        assert_eq!(in_flight.count(), 2);
+        drop(first);
+        drop(second);
+        assert_eq!(in_flight.count(), 0);
+    }
+}
@@ -0,0 +1,187 @@
+use anyhow::*;
+use log::{debug, warn};
+use std::net::SocketAddr;
+
+/// The first file descriptor systemd passes, after stdin, stdout and stderr.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// The listening sockets systemd passed this process with socket activation, by the
+/// address each is bound to, so they can stand in for the configured `listen_addrs`.
+/// The variables are removed from the environment once read, like `sd_listen_fds` does,
+/// and none are meant for this process unless `LISTEN_PID` names it.
+pub fn listeners() -> Result<Vec<(SocketAddr, i32)>> {
+    let pid = std::env::var_os("LISTEN_PID");
+    let fds = std::env::var_os("LISTEN_FDS");
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(Vec::new()),
+    };
+    if pid.to_str().and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        debug!("Ignoring LISTEN_FDS meant for process {:?}", pid);
+        return Ok(Vec::new());
+    }
+    let count = fds
+        .to_str()
+        .and_then(|fds| fds.parse::<i32>().ok())
+        .with_context(|| format!("Invalid LISTEN_FDS {:?}", fds))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+        let mut listeners = Vec::new();
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            // Like sd_listen_fds, so they don't leak into processes started later.
+            unsafe {
+                let flags = libc::fcntl(fd, libc::F_GETFD);
+                if flags == -1 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) == -1 {
+                    bail!(
+                        "Socket activation fd {}: {}",
+                        fd,
+                        std::io::Error::last_os_error()
+                    );
+                }
+            }
+            // Only borrowed to read its address; the fd goes back unclosed either way.
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            let addr = listener.local_addr();
+            let _ = listener.into_raw_fd();
+            match addr {
+                Result::Ok(addr) => listeners.push((addr, fd)),
+                Err(err) => warn!("Ignoring socket activation fd {}, not TCP: {}", fd, err),
+            }
+        }
+        Ok(listeners)
+    }
+    #[cfg(not(unix))]
+    {
+        warn!(
+            "Ignoring {} socket activation fds: not a Unix system",
+            count
+        );
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::ENV;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    /// Set in the copy of the test binary that gets the activated socket, to the address
+    /// it's bound to.
+    const CHILD_ENV_VAR: &str = "VOSTOK_TEST_ACTIVATED_ADDR";
+
+    /// Binds the server to `listener` as `main` does, and sends it a request.
+    async fn serve_one_request(addr: SocketAddr, listener: std::net::TcpListener) {
+        use hyper::service::service_fn;
+        use hyper::{Body, Response};
+        use std::convert::Infallible;
+
+        let listener = crate::server::bind(
+            addr,
+            &crate::server::ListenerConfig::default(),
+            Some(listener),
+        )
+        .unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|_| async {
+                Result::<_, Infallible>::Ok(Response::new(Body::from("activated")))
+            });
+            hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .await
+                .unwrap();
+        });
+        let response = hyper::Client::new()
+            .get(format!("http://{}/", addr).parse().unwrap())
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "activated");
+    }
+
+    fn listeners_with(pid: &str, fds: &str) -> Result<Vec<(SocketAddr, i32)>> {
+        std::env::set_var("LISTEN_PID", pid);
+        std::env::set_var("LISTEN_FDS", fds);
+        std::env::set_var("LISTEN_FDNAMES", "http");
+        let listeners = listeners();
+        for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            assert!(std::env::var_os(name).is_none(), "{}", name);
+        }
+        listeners
+    }
+
+    #[test]
+    fn ignores_sockets_meant_for_other_processes() {
+        let _env = ENV.lock().unwrap();
+        assert!(listeners().unwrap().is_empty());
+        let other = (std::process::id() + 1).to_string();
+        assert!(listeners_with(&other, "1").unwrap().is_empty());
+        assert!(listeners_with("x", "1").unwrap().is_empty());
+        let pid = std::process::id().to_string();
+        assert!(listeners_with(&pid, "0").unwrap().is_empty());
+        assert!(listeners_with(&pid, "x").is_err());
+    }
+
+    /// Runs this test again in a new process that has a listener at fd 3, the way
+    /// systemd would start it, and checks there that it's found and serves requests.
+    #[test]
+    fn takes_over_activated_sockets() {
+        if let Some(addr) = std::env::var_os(CHILD_ENV_VAR) {
+            let addr = addr.to_str().unwrap().parse().unwrap();
+            let pid = std::process::id().to_string();
+            std::env::set_var("LISTEN_PID", pid);
+            std::env::set_var("LISTEN_FDS", "1");
+            let listener = crate::upgrade::Inherited::from_env()
+                .unwrap()
+                .take(&addr)
+                .unwrap();
+            assert_eq!(listener.as_raw_fd(), 3);
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(serve_one_request(addr, listener));
+            return;
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.as_raw_fd();
+        let mut command = std::process::Command::new(std::env::current_exe().unwrap());
+        command
+            .args([
+                "socket_activation::tests::takes_over_activated_sockets",
+                "--exact",
+            ])
+            .env(CHILD_ENV_VAR, addr.to_string());
+        // dup2 and fcntl are safe to call between fork and exec. dup2 leaves fd 3 open
+        // across exec, but does nothing if the listener already is fd 3.
+        unsafe {
+            command.pre_exec(move || {
+                let moved = if fd == 3 {
+                    libc::fcntl(3, libc::F_SETFD, 0)
+                } else {
+                    libc::dup2(fd, 3)
+                };
+                if moved == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                std::result::Result::Ok(())
+            });
+        }
+        let output = command.output().unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+        assert!(String::from_utf8_lossy(&output.stdout).contains("1 passed"));
+    }
+}
@@ -0,0 +1,332 @@
+use anyhow::*;
+use hyper::header::{
+    HeaderValue, ALLOW, CONTENT_LENGTH, CONTENT_TYPE, IF_MODIFIED_SINCE, LAST_MODIFIED,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::debug;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio_util::io::ReaderStream;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticFilesConfig {
+    /// Requests under this prefix are served from `root` instead of being proxied.
+    pub path_prefix: String,
+    pub root: PathBuf,
+    /// File served for a request naming a directory.
+    #[serde(default = "default_index")]
+    pub index: String,
+}
+
+fn default_index() -> String {
+    "index.html".to_string()
+}
+
+/// Serves the files under one root directory for `GET` and `HEAD` requests. Nothing
+/// outside the root is ever served: paths with `..` segments are refused, and so are
+/// files that only resolve into the root through a symlink pointing out of it.
+pub struct StaticFiles {
+    prefix: String,
+    root: PathBuf,
+    index: String,
+}
+
+impl StaticFiles {
+    pub fn new(config: &StaticFilesConfig) -> Result<StaticFiles> {
+        ensure!(
+            config.path_prefix.starts_with('/'),
+            "static_files path_prefix {:?} must start with '/'",
+            config.path_prefix
+        );
+        let root = config
+            .root
+            .canonicalize()
+            .with_context(|| format!("Opening static files root {}", config.root.display()))?;
+        ensure!(
+            root.is_dir(),
+            "Static files root {} isn't a directory",
+            root.display()
+        );
+        ensure!(
+            !config.index.is_empty() && !config.index.contains('/'),
+            "static_files index {:?} must be a file name",
+            config.index
+        );
+        Ok(StaticFiles {
+            prefix: config.path_prefix.trim_end_matches('/').to_string(),
+            root,
+            index: config.index.clone(),
+        })
+    }
+
+    /// The router patterns matching the prefix itself and everything below it.
+    pub fn patterns(&self) -> Vec<String> {
+        if self.prefix.is_empty() {
+            vec!["/*".to_string()]
+        } else {
+            vec![self.prefix.clone(), format!("{}/*", self.prefix)]
+        }
+    }
+
+    pub async fn serve(&self, req: Request<Body>) -> Result<Response<Body>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            let mut response = status_response(StatusCode::METHOD_NOT_ALLOWED);
+            response
+                .headers_mut()
+                .insert(ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return Ok(response);
+        }
+        let path = match self.resolve(req.uri().path()).await {
+            Some(path) => path,
+            None => return Ok(status_response(StatusCode::NOT_FOUND)),
+        };
+        let file = match tokio::fs::File::open(&path).await {
+            Result::Ok(file) => file,
+            Err(err) => {
+                debug!("Opening static file {}: {}", path.display(), err);
+                return Ok(status_response(StatusCode::NOT_FOUND));
+            }
+        };
+        let metadata = file.metadata().await?;
+        // HTTP dates only have second precision.
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()));
+
+        let not_modified = match (modified, req.headers().get(IF_MODIFIED_SINCE)) {
+            (Some(modified), Some(since)) => since
+                .to_str()
+                .ok()
+                .and_then(|since| httpdate::parse_http_date(since).ok())
+                .is_some_and(|since| modified <= since),
+            _ => false,
+        };
+        let mut response = if not_modified {
+            status_response(StatusCode::NOT_MODIFIED)
+        } else {
+            let mut response = if req.method() == Method::HEAD {
+                Response::new(Body::empty())
+            } else {
+                Response::new(Body::wrap_stream(ReaderStream::new(file)))
+            };
+            let headers = response.headers_mut();
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(metadata.len()));
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type(&path)));
+            response
+        };
+        if let Some(modified) = modified {
+            let modified = httpdate::fmt_http_date(modified);
+            response
+                .headers_mut()
+                .insert(LAST_MODIFIED, HeaderValue::from_str(&modified)?);
+        }
+        Ok(response)
+    }
+
+    /// The file a request path names, or `None` if it isn't a file inside the root.
+    async fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let relative = request_path.strip_prefix(self.prefix.as_str())?;
+        let mut path = self.root.clone();
+        for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+            let segment = percent_decode_str(segment).decode_utf8().ok()?;
+            if segment == "." || segment == ".." || segment.contains(['/', '\\', '\0']) {
+                debug!("Refusing static file path {:?}", request_path);
+                return None;
+            }
+            path.push(segment.as_ref());
+        }
+        let mut path = self.inside_root(&path, request_path).await?;
+        if tokio::fs::metadata(&path).await.ok()?.is_dir() {
+            path.push(&self.index);
+            path = self.inside_root(&path, request_path).await?;
+        }
+        Some(path)
+    }
+
+    /// `path` with every symlink resolved, if it exists and is still inside the root.
+    async fn inside_root(&self, path: &Path, request_path: &str) -> Option<PathBuf> {
+        let path = tokio::fs::canonicalize(path).await.ok()?;
+        if !path.starts_with(&self.root) {
+            debug!("Static file path {:?} leaves the root", request_path);
+            return None;
+        }
+        Some(path)
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// Picked from the file extension; unknown ones are served as opaque bytes.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_string, unused_addr, Proxy, TempDir};
+
+    /// A proxy serving `dir` under `/assets`, with nothing behind it to proxy to.
+    async fn proxy(dir: &TempDir) -> Proxy {
+        Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[[static_files]]\npath_prefix = \"/assets\"\nroot = \"{}\"",
+            unused_addr(),
+            dir.path().display()
+        ))
+        .await
+    }
+
+    fn request(method: Method, path: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_files_and_directory_indexes() {
+        let dir = TempDir::new();
+        dir.write("app.js", "console.log(1);");
+        dir.write("docs/index.html", "<h1>Docs</h1>");
+        dir.write("with space.txt", "spaced");
+        let proxy = proxy(&dir).await;
+
+        let response = proxy.get("/assets/app.js").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(response.headers()[CONTENT_LENGTH], "15");
+        assert!(response.headers().contains_key(LAST_MODIFIED));
+        assert_eq!(body_string(response).await, "console.log(1);");
+
+        for path in ["/assets/docs", "/assets/docs/", "/assets/docs/index.html"] {
+            let response = proxy.get(path).await;
+            assert_eq!(
+                response.headers()[CONTENT_TYPE],
+                "text/html; charset=utf-8",
+                "{}",
+                path
+            );
+            assert_eq!(body_string(response).await, "<h1>Docs</h1>");
+        }
+        let response = proxy.get("/assets/with%20space.txt").await;
+        assert_eq!(body_string(response).await, "spaced");
+
+        let response = proxy.send(request(Method::HEAD, "/assets/app.js")).await;
+        assert_eq!(response.headers()[CONTENT_LENGTH], "15");
+        assert_eq!(body_string(response).await, "");
+    }
+
+    #[tokio::test]
+    async fn answers_conditional_and_other_requests() {
+        let dir = TempDir::new();
+        dir.write("app.js", "console.log(1);");
+        let proxy = proxy(&dir).await;
+        let modified = proxy.get("/assets/app.js").await.headers()[LAST_MODIFIED].clone();
+
+        let mut conditional = request(Method::GET, "/assets/app.js");
+        conditional
+            .headers_mut()
+            .insert(IF_MODIFIED_SINCE, modified);
+        let response = proxy.send(conditional).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = proxy.send(request(Method::POST, "/assets/app.js")).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET, HEAD");
+    }
+
+    #[tokio::test]
+    async fn nothing_outside_the_root_is_served() {
+        let parent = TempDir::new();
+        parent.write("secret.txt", "secret");
+        let root = parent.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(parent.path().join("secret.txt"), root.join("link.txt"))
+            .unwrap();
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[[static_files]]\npath_prefix = \"/assets\"\nroot = \"{}\"",
+            unused_addr(),
+            root.display()
+        ))
+        .await;
+
+        for path in [
+            "/assets/missing.txt",
+            "/assets/../secret.txt",
+            "/assets/%2e%2e/secret.txt",
+            "/assets/..%2fsecret.txt",
+            "/assets/link.txt",
+        ] {
+            let response = proxy.get(path).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let dir = TempDir::new();
+        let file = dir.write("file.txt", "");
+        let config = |path_prefix: &str, root: &Path, index: &str| StaticFilesConfig {
+            path_prefix: path_prefix.to_string(),
+            root: root.to_path_buf(),
+            index: index.to_string(),
+        };
+        assert!(StaticFiles::new(&config("/assets", dir.path(), "index.html")).is_ok());
+        assert!(StaticFiles::new(&config("assets", dir.path(), "index.html")).is_err());
+        assert!(StaticFiles::new(&config(
+            "/assets",
+            &dir.path().join("missing"),
+            "index.html"
+        ))
+        .is_err());
+        assert!(StaticFiles::new(&config("/assets", &file, "index.html")).is_err());
+        assert!(StaticFiles::new(&config("/assets", dir.path(), "")).is_err());
+        assert!(StaticFiles::new(&config("/assets", dir.path(), "a/index.html")).is_err());
+    }
+}
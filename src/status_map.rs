@@ -0,0 +1,116 @@
+use anyhow::*;
+use hyper::StatusCode;
+use std::collections::{BTreeMap, HashMap};
+
+/// Upstream statuses that clients get as another status, with the rest of the response
+/// unchanged.
+#[derive(Debug, Default)]
+pub struct StatusMap {
+    map: HashMap<StatusCode, StatusCode>,
+}
+
+impl StatusMap {
+    /// `config` maps upstream statuses, as TOML keys, to the ones clients get. A `101`
+    /// can't be remapped, since the connection has already switched protocols, and
+    /// nothing can become an informational status.
+    pub fn new(config: &BTreeMap<String, u16>) -> Result<StatusMap> {
+        let map = config
+            .iter()
+            .map(|(from, to)| {
+                let from = from
+                    .parse::<u16>()
+                    .ok()
+                    .and_then(|from| StatusCode::from_u16(from).ok())
+                    .with_context(|| format!("Invalid status_map status {:?}", from))?;
+                let to = StatusCode::from_u16(*to)
+                    .with_context(|| format!("Invalid status_map status for {}", from.as_u16()))?;
+                ensure!(
+                    from != StatusCode::SWITCHING_PROTOCOLS,
+                    "status_map can't remap 101"
+                );
+                ensure!(
+                    !to.is_informational(),
+                    "status_map can't turn {} into the informational {}",
+                    from.as_u16(),
+                    to.as_u16()
+                );
+                Ok((from, to))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(StatusMap { map })
+    }
+
+    pub fn apply(&self, status: &mut StatusCode) {
+        if let Some(to) = self.map.get(status) {
+            *status = *to;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_string, upstream, Proxy};
+    use hyper::{Body, Response};
+
+    fn status_map(entries: &[(&str, u16)]) -> Result<StatusMap> {
+        let config = entries
+            .iter()
+            .map(|(from, to)| (from.to_string(), *to))
+            .collect();
+        StatusMap::new(&config)
+    }
+
+    fn applied(map: &StatusMap, status: u16) -> u16 {
+        let mut status = StatusCode::from_u16(status).unwrap();
+        map.apply(&mut status);
+        status.as_u16()
+    }
+
+    #[test]
+    fn remaps_listed_statuses_and_passes_the_rest() {
+        let map = status_map(&[("418", 503), ("520", 502)]).unwrap();
+        assert_eq!(applied(&map, 418), 503);
+        assert_eq!(applied(&map, 520), 502);
+        assert_eq!(applied(&map, 200), 200);
+        assert_eq!(applied(&map, 503), 503);
+        assert_eq!(applied(&StatusMap::default(), 418), 418);
+    }
+
+    #[test]
+    fn rejects_invalid_mappings() {
+        assert!(status_map(&[("teapot", 503)]).is_err());
+        assert!(status_map(&[("1000", 503)]).is_err());
+        assert!(status_map(&[("418", 1000)]).is_err());
+        assert!(status_map(&[("101", 200)]).is_err());
+        assert!(status_map(&[("200", 103)]).is_err());
+    }
+
+    #[tokio::test]
+    async fn clients_get_the_remapped_status_with_the_upstreams_response() {
+        let upstream = upstream(|req: hyper::Request<Body>| async move {
+            let status = req.uri().path().trim_start_matches('/').parse().unwrap();
+            let mut response = Response::new(Body::from("upstream body"));
+            *response.status_mut() = StatusCode::from_u16(status).unwrap();
+            response
+                .headers_mut()
+                .insert("x-upstream", "1".parse().unwrap());
+            response
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n[status_map]\n418 = 503",
+            upstream
+        ))
+        .await;
+
+        let response = proxy.get("/418").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["x-upstream"], "1");
+        assert_eq!(body_string(response).await, "upstream body");
+
+        let response = proxy.get("/404").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_string(response).await, "upstream body");
+    }
+}
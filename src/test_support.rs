@@ -0,0 +1,277 @@
+//! Helpers shared by the unit tests.
+
+use crate::config::Config;
+use crate::connector::PriorKnowledge;
+use crate::internal_headers::InternalHeaders;
+use crate::metrics::Metrics;
+use crate::routing::{Routing, SharedRouting};
+use crate::shutdown::{self, Drained, InFlight};
+use crate::{admin, client, server, tls};
+use hyper::client::HttpConnector;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Environment variables are process-wide, so tests setting them take turns holding
+/// this.
+pub static ENV: Mutex<()> = Mutex::new(());
+
+/// A directory under the system temp dir that's removed, with everything in it, once
+/// dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "vostok-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Writes `contents` to `name` in the directory and returns its path.
+    pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Starts a mock upstream on a free local port that answers every request with
+/// `handler`, and returns its address. It runs until the test's runtime stops.
+pub async fn upstream<F, R>(handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let make_service = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = handler(req);
+                async move { Ok::<_, Infallible>(response.await) }
+            }))
+        }
+    });
+    let server = Server::from_tcp(listener).unwrap().serve(make_service);
+    tokio::spawn(server);
+    addr
+}
+
+/// Serves `builder`'s router on a free local port, plain HTTP/1, until the test's
+/// runtime stops.
+pub async fn serve(builder: server::ServiceBuilder) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            let service = builder.build(remote_addr);
+            tokio::spawn(hyper::server::conn::Http::new().serve_connection(stream, service));
+        }
+    });
+    addr
+}
+
+/// An address nothing is listening on.
+pub fn unused_addr() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// A mock upstream describing each request it gets as JSON: its `method`, `uri` and
+/// `headers`, with every value of each header.
+pub async fn echo_upstream() -> SocketAddr {
+    upstream(|req: Request<Body>| async move {
+        let mut headers = serde_json::Map::new();
+        for name in req.headers().keys() {
+            let values = req
+                .headers()
+                .get_all(name)
+                .iter()
+                .map(|value| value.to_str().unwrap().to_string())
+                .collect::<Vec<_>>();
+            headers.insert(name.to_string(), values.into());
+        }
+        let echo = serde_json::json!({
+            "method": req.method().as_str(),
+            "uri": req.uri().to_string(),
+            "headers": headers,
+        });
+        Response::new(Body::from(echo.to_string()))
+    })
+    .await
+}
+
+/// Vostok's main router served on a free local port, set up from a config file's
+/// contents the way `main` does, listeners aside.
+pub struct Proxy {
+    pub addr: SocketAddr,
+    /// Where the admin API is served, when the config has an `[admin]` section.
+    pub admin_addr: Option<SocketAddr>,
+    pub in_flight: Arc<InFlight>,
+    client: Client<HttpConnector>,
+    /// The server drains and stops once this is dropped or sent `true`.
+    shutdown: watch::Sender<bool>,
+    server: tokio::task::JoinHandle<anyhow::Result<()>>,
+}
+
+impl Proxy {
+    pub async fn start(config: &str) -> Proxy {
+        let config = Config::parse(config).unwrap();
+        let prior_knowledge = PriorKnowledge::default();
+        let upstream_client =
+            Arc::new(client::build(&config.client, prior_knowledge.clone()).unwrap());
+        let routing = Arc::new(SharedRouting::new(
+            Routing::new(&config, &upstream_client).unwrap(),
+            prior_knowledge,
+        ));
+        let internal = Arc::new(InternalHeaders::new(&config.internal_headers).unwrap());
+        let admin_addr = match &config.admin {
+            None => None,
+            Some(admin) => {
+                let router = admin::router(admin, routing.clone(), internal.clone()).unwrap();
+                Some(serve(server::service_builder(router).unwrap()).await)
+            }
+        };
+        let in_flight = Arc::new(InFlight::default());
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let router = crate::router(
+            &config,
+            upstream_client,
+            routing.clone(),
+            in_flight.clone(),
+            metrics.clone(),
+            internal,
+        )
+        .unwrap();
+        let builder = server::service_builder(router).unwrap();
+        let tls = config.tls.as_ref().map(tls::acceptor).transpose().unwrap();
+        let listener = server::bind(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            &config.listener,
+            None,
+        )
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeouts = server::ConnectionTimeouts {
+            header_read: config.header_read_timeout,
+            idle: Some(config.idle_timeout).filter(|timeout| !timeout.is_zero()),
+        };
+        let connections = server::Connections::new(
+            metrics.open_connections.clone(),
+            config.listener.max_connections,
+            config.listener.max_connections_per_ip,
+            metrics.rejected_connections.clone(),
+        );
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let server = server::serve(
+            listener,
+            builder,
+            tls,
+            &config.listener,
+            timeouts,
+            connections,
+            shutdown_rx,
+        )
+        .unwrap();
+        Proxy {
+            addr,
+            admin_addr,
+            in_flight,
+            client: Client::new(),
+            shutdown,
+            server: tokio::spawn(server),
+        }
+    }
+
+    pub fn url(&self, path_and_query: &str) -> String {
+        format!("http://{}{}", self.addr, path_and_query)
+    }
+
+    pub async fn get(&self, path_and_query: &str) -> Response<Body> {
+        self.client
+            .get(self.url(path_and_query).parse().unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// Shuts the server down the way `main` does, draining requests for up to `timeout`.
+    pub async fn drain(self, timeout: Duration) -> Drained {
+        let shutdown_rx = self.shutdown.subscribe();
+        self.shutdown.send(true).unwrap();
+        let server = self.server;
+        shutdown::drain(
+            async move { server.await.unwrap() },
+            shutdown_rx,
+            &self.in_flight,
+            timeout,
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Sends `req`, whose URI only needs the path and query.
+    pub async fn send(&self, mut req: Request<Body>) -> Response<Body> {
+        let path_and_query = req.uri().path_and_query().unwrap().to_string();
+        *req.uri_mut() = self.url(&path_and_query).parse().unwrap();
+        self.client.request(req).await.unwrap()
+    }
+}
+
+/// A basic auth `password_hash` for `password`, with few iterations to keep the tests
+/// fast.
+pub fn password_hash(password: &str) -> String {
+    let salt = b"0123456789abcdef";
+    let mut hash = [0; 32];
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(1000).unwrap(),
+        salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "pbkdf2-sha256$1000${}${}",
+        base64::encode(salt),
+        base64::encode(hash)
+    )
+}
+
+pub async fn body_json(response: Response<Body>) -> serde_json::Value {
+    serde_json::from_str(&body_string(response).await).unwrap()
+}
+
+pub async fn body_bytes(response: Response<Body>) -> hyper::body::Bytes {
+    hyper::body::to_bytes(response.into_body()).await.unwrap()
+}
+
+pub async fn body_string(response: Response<Body>) -> String {
+    String::from_utf8(body_bytes(response).await.to_vec()).unwrap()
+}
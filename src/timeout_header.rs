@@ -0,0 +1,164 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Carries timeouts in seconds, e.g. `30` or `2.5`, in both directions.
+pub const X_VOSTOK_TIMEOUT: HeaderName = HeaderName::from_static("x-vostok-timeout");
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutHeaderConfig {
+    /// Tell clients the upstream timeout that applied to their request.
+    #[serde(default = "default_expose")]
+    pub expose: bool,
+    /// Let clients ask for their own upstream timeout, up to this long. Unset ignores
+    /// what they ask for.
+    #[serde(default, with = "humantime_serde")]
+    pub max_client_timeout: Option<Duration>,
+}
+
+fn default_expose() -> bool {
+    true
+}
+
+impl TimeoutHeaderConfig {
+    /// Removes the client's header, so it never reaches the upstream, and returns the
+    /// timeout it asks for, capped at `max_client_timeout`. Values that aren't a positive
+    /// number of seconds are ignored.
+    pub fn take_client_timeout(&self, headers: &mut HeaderMap) -> Option<Duration> {
+        let requested = headers.remove(X_VOSTOK_TIMEOUT)?;
+        let max = self.max_client_timeout?;
+        let seconds = requested.to_str().ok()?.trim().parse::<f64>().ok()?;
+        if !seconds.is_finite() || seconds <= 0.0 {
+            return None;
+        }
+        Some(max.min(Duration::try_from_secs_f64(seconds).unwrap_or(max)))
+    }
+
+    /// Adds the header for `timeout` when exposing is on. Without a timeout there's
+    /// nothing to report, so the header is left out.
+    pub fn expose(&self, headers: &mut HeaderMap, timeout: Option<Duration>) {
+        if let (true, Some(timeout)) = (self.expose, timeout) {
+            let value = timeout.as_secs_f64().to_string();
+            headers.insert(X_VOSTOK_TIMEOUT, HeaderValue::from_str(&value).unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, echo_upstream, Proxy};
+    use hyper::{Body, Request, Response, StatusCode};
+
+    fn config(max_client_timeout: Option<Duration>) -> TimeoutHeaderConfig {
+        TimeoutHeaderConfig {
+            expose: true,
+            max_client_timeout,
+        }
+    }
+
+    fn asking_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_VOSTOK_TIMEOUT, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn client_timeouts_are_capped_and_removed() {
+        let config = config(Some(Duration::from_secs(10)));
+        for (value, expected) in [
+            ("2.5", Some(Duration::from_millis(2500))),
+            (" 3 ", Some(Duration::from_secs(3))),
+            ("60", Some(Duration::from_secs(10))),
+            ("1e300", Some(Duration::from_secs(10))),
+            ("0", None),
+            ("-1", None),
+            ("NaN", None),
+            ("soon", None),
+        ] {
+            let mut headers = asking_for(value);
+            assert_eq!(
+                config.take_client_timeout(&mut headers),
+                expected,
+                "{}",
+                value
+            );
+            assert!(headers.is_empty());
+        }
+    }
+
+    #[test]
+    fn client_timeouts_are_ignored_without_a_maximum() {
+        let mut headers = asking_for("2.5");
+        assert_eq!(config(None).take_client_timeout(&mut headers), None);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn exposes_the_timeout_when_there_is_one() {
+        let mut headers = HeaderMap::new();
+        config(None).expose(&mut headers, None);
+        assert!(headers.is_empty());
+        config(None).expose(&mut headers, Some(Duration::from_millis(2500)));
+        assert_eq!(headers[X_VOSTOK_TIMEOUT], "2.5");
+
+        let hidden = TimeoutHeaderConfig {
+            expose: false,
+            max_client_timeout: None,
+        };
+        let mut headers = HeaderMap::new();
+        hidden.expose(&mut headers, Some(Duration::from_secs(30)));
+        assert!(headers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn exposes_each_routes_effective_timeout() {
+        let upstream = echo_upstream().await;
+        let slow = test_support::upstream(|_| async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            Response::new(Body::from("slow"))
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            r#"
+            upstreams = "http://{0}"
+            request_timeout = "30s"
+            [[routes]]
+            path_prefix = "/reports"
+            upstreams = [{{ url = "http://{0}", request_timeout = "5s" }}]
+            [[routes]]
+            path_prefix = "/slow"
+            upstreams = "http://{1}"
+            [timeout_header]
+            max_client_timeout = "10s"
+            "#,
+            upstream, slow
+        ))
+        .await;
+
+        let response = proxy.get("/path").await;
+        assert_eq!(response.headers()[X_VOSTOK_TIMEOUT], "30");
+        let response = proxy.get("/reports/daily").await;
+        assert_eq!(response.headers()[X_VOSTOK_TIMEOUT], "5");
+
+        let asking = |path: &str, timeout: &str| {
+            Request::get(path)
+                .header(X_VOSTOK_TIMEOUT, timeout)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = proxy.send(asking("/reports/daily", "60")).await;
+        assert_eq!(response.headers()[X_VOSTOK_TIMEOUT], "10");
+        let response = proxy.send(asking("/slow", "0.1")).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(response.headers()[X_VOSTOK_TIMEOUT], "0.1");
+
+        let echoed = test_support::body_json(proxy.send(asking("/path", "2")).await).await;
+        assert!(
+            echoed["headers"].get("x-vostok-timeout").is_none(),
+            "{}",
+            echoed
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::*;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the server certificate, followed by any intermediates.
+    pub cert_path: PathBuf,
+    /// PEM file with the private key, in PKCS#8 or RSA (PKCS#1) form.
+    pub key_path: PathBuf,
+}
+
+/// Loads the configured certificate and key into an acceptor for the main listeners.
+/// Both HTTP/2 and HTTP/1.1 are offered over ALPN.
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let certs = load_certs(&config.cert_path)?;
+    let key = load_key(&config.key_path)?;
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config.set_single_cert(certs, key).with_context(|| {
+        format!(
+            "Using TLS certificate {} with key {}",
+            config.cert_path.display(),
+            config.key_path.display()
+        )
+    })?;
+    server_config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn open(kind: &str, path: &Path) -> Result<BufReader<File>> {
+    let file =
+        File::open(path).with_context(|| format!("Opening TLS {} {}", kind, path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut open("certificate", path)?)
+        .map_err(|()| anyhow!("Malformed PEM in TLS certificate {}", path.display()))?;
+    ensure!(
+        !certs.is_empty(),
+        "No certificates found in TLS certificate {}",
+        path.display()
+    );
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let malformed = || anyhow!("Malformed PEM in TLS key {}", path.display());
+    let mut keys =
+        pemfile::pkcs8_private_keys(&mut open("key", path)?).map_err(|()| malformed())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open("key", path)?).map_err(|()| malformed())?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No private key found in TLS key {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::{ClientConfig, Session};
+    use tokio_rustls::TlsConnector;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    fn config(cert: PathBuf, key: PathBuf) -> TlsConfig {
+        TlsConfig {
+            cert_path: cert,
+            key_path: key,
+        }
+    }
+
+    /// A client that trusts the test CA, offering `alpn`.
+    fn connector(alpn: &[&[u8]]) -> TlsConnector {
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_pem_file(&mut open("certificate", &testdata("ca.crt")).unwrap())
+            .unwrap();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    /// Serves one TLS connection with `acceptor` that says hello, then connects to it
+    /// with `connector`, returning the protocol agreed on over ALPN and what was said.
+    async fn handshake(
+        acceptor: TlsAcceptor,
+        connector: TlsConnector,
+    ) -> (Option<Vec<u8>>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut stream = connector.connect(name, stream).await.unwrap();
+        let alpn = stream.get_ref().1.get_alpn_protocol().map(<[u8]>::to_vec);
+        let mut said = String::new();
+        stream.read_to_string(&mut said).await.unwrap();
+        (alpn, said)
+    }
+
+    #[tokio::test]
+    async fn handshakes_with_a_pkcs8_key() {
+        let acceptor = acceptor(&config(
+            testdata("localhost.crt"),
+            testdata("localhost.key"),
+        ))
+        .unwrap();
+        let (alpn, said) = handshake(acceptor, connector(&[b"h2", b"http/1.1"])).await;
+        assert_eq!(alpn.as_deref(), Some(&b"h2"[..]));
+        assert_eq!(said, "hello");
+    }
+
+    #[tokio::test]
+    async fn handshakes_with_an_rsa_key_over_http1() {
+        let acceptor = acceptor(&config(
+            testdata("localhost.crt"),
+            testdata("localhost-rsa.key"),
+        ))
+        .unwrap();
+        let (alpn, said) = handshake(acceptor, connector(&[b"http/1.1"])).await;
+        assert_eq!(alpn.as_deref(), Some(&b"http/1.1"[..]));
+        assert_eq!(said, "hello");
+    }
+
+    #[test]
+    fn rejects_malformed_certificates_and_keys() {
+        let dir = TempDir::new();
+        let garbage = dir.write(
+            "garbage.pem",
+            "-----BEGIN CERTIFICATE-----\nnot base64!\n-----END CERTIFICATE-----\n",
+        );
+        let empty = dir.write("empty.pem", "");
+        let error = |cert: &PathBuf, key: &PathBuf| {
+            acceptor(&config(cert.clone(), key.clone()))
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        let (cert, key) = (testdata("localhost.crt"), testdata("localhost.key"));
+
+        assert!(error(&garbage, &key).starts_with("Malformed PEM in TLS certificate"));
+        assert!(error(&empty, &key).starts_with("No certificates found"));
+        assert!(error(&cert, &empty).starts_with("No private key found"));
+        // A certificate is no key.
+        assert!(error(&cert, &cert).starts_with("No private key found"));
+        assert!(error(&cert, &dir.path().join("missing.pem")).starts_with("Opening TLS key"));
+    }
+}
@@ -0,0 +1,156 @@
+use hyper::header::{HeaderValue, LOCATION};
+use hyper::{Body, Response, StatusCode, Uri};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrailingSlashConfig {
+    pub mode: TrailingSlashMode,
+    /// Answer with a `308` to the normalized path instead of forwarding it rewritten.
+    #[serde(default)]
+    pub redirect: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingSlashMode {
+    /// `/docs/` becomes `/docs`; `/` stays as it is.
+    Strip,
+    /// `/docs` becomes `/docs/`, unless the last segment looks like a file name such as
+    /// `app.js`.
+    Append,
+}
+
+/// `path` with its trailing slashes normalized, borrowed if it already was.
+pub fn normalize(mode: TrailingSlashMode, path: &str) -> Cow<'_, str> {
+    match mode {
+        TrailingSlashMode::Strip => {
+            let stripped = path.trim_end_matches('/');
+            if stripped.len() == path.len() {
+                Cow::Borrowed(path)
+            } else if stripped.is_empty() {
+                Cow::Borrowed("/")
+            } else {
+                Cow::Borrowed(stripped)
+            }
+        }
+        TrailingSlashMode::Append => {
+            let last_segment = path.rsplit('/').next().unwrap_or_default();
+            if path.ends_with('/') || last_segment.contains('.') {
+                Cow::Borrowed(path)
+            } else {
+                Cow::Owned(format!("{}/", path))
+            }
+        }
+    }
+}
+
+/// A permanent redirect to the normalized path, keeping the query, or `None` if the
+/// path needs no change.
+pub fn redirect(mode: TrailingSlashMode, uri: &Uri) -> Option<Response<Body>> {
+    let path = match normalize(mode, uri.path()) {
+        Cow::Borrowed(path) if path == uri.path() => return None,
+        path => path,
+    };
+    let location = match uri.query() {
+        None => path.into_owned(),
+        Some(query) => format!("{}?{}", path, query),
+    };
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::PERMANENT_REDIRECT;
+    response
+        .headers_mut()
+        .insert(LOCATION, HeaderValue::from_str(&location).ok()?);
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_json, echo_upstream, Proxy};
+
+    #[test]
+    fn strips_trailing_slashes_but_keeps_the_root() {
+        for (path, expected) in [
+            ("/docs/", "/docs"),
+            ("/docs//", "/docs"),
+            ("/docs", "/docs"),
+            ("/a/b/", "/a/b"),
+            ("/", "/"),
+            ("//", "/"),
+        ] {
+            assert_eq!(
+                normalize(TrailingSlashMode::Strip, path),
+                expected,
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn appends_a_slash_except_to_file_names() {
+        for (path, expected) in [
+            ("/docs", "/docs/"),
+            ("/docs/", "/docs/"),
+            ("/", "/"),
+            ("/static/app.js", "/static/app.js"),
+            ("/v1.2/docs", "/v1.2/docs/"),
+        ] {
+            assert_eq!(
+                normalize(TrailingSlashMode::Append, path),
+                expected,
+                "{}",
+                path
+            );
+        }
+        assert!(matches!(
+            normalize(TrailingSlashMode::Append, "/docs/"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn redirects_to_the_normalized_path_with_the_query() {
+        let uri: Uri = "/docs/?page=2".parse().unwrap();
+        let response = redirect(TrailingSlashMode::Strip, &uri).unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[LOCATION], "/docs?page=2");
+
+        let uri: Uri = "/docs".parse().unwrap();
+        let response = redirect(TrailingSlashMode::Append, &uri).unwrap();
+        assert_eq!(response.headers()[LOCATION], "/docs/");
+
+        assert!(redirect(TrailingSlashMode::Strip, &"/docs?page=2".parse().unwrap()).is_none());
+        assert!(redirect(TrailingSlashMode::Append, &"/app.js".parse().unwrap()).is_none());
+    }
+
+    #[tokio::test]
+    async fn forwards_the_normalized_path_or_redirects() {
+        let upstream = echo_upstream().await;
+        let config = |redirect| {
+            format!(
+                r#"
+                upstreams = "http://{}"
+                [trailing_slash]
+                mode = "strip"
+                redirect = {}
+                "#,
+                upstream, redirect
+            )
+        };
+
+        let proxy = Proxy::start(&config(false)).await;
+        let response = proxy.get("/path/?q=1").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_json(response).await["uri"], "/path?q=1");
+
+        let proxy = Proxy::start(&config(true)).await;
+        let response = proxy.get("/path/?q=1").await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[LOCATION], "/path?q=1");
+        let response = proxy.get("/path?q=1").await;
+        assert_eq!(body_json(response).await["uri"], "/path?q=1");
+    }
+}
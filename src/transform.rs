@@ -0,0 +1,328 @@
+use anyhow::*;
+use futures_util::Stream;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Method, Response, StatusCode};
+use log::debug;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransformConfig {
+    /// Fields set on every JSON object line, replacing any already there.
+    #[serde(default)]
+    pub set_fields: Map<String, Value>,
+    /// Fields removed from every JSON object line.
+    #[serde(default)]
+    pub remove_fields: Vec<String>,
+    /// Only responses of these types are transformed.
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<String>,
+    /// Longer lines are passed through unchanged rather than buffered.
+    #[serde(default = "default_max_line_bytes")]
+    pub max_line_bytes: usize,
+}
+
+fn default_content_types() -> Vec<String> {
+    vec!["application/x-ndjson".to_string()]
+}
+
+fn default_max_line_bytes() -> usize {
+    1024 * 1024
+}
+
+/// Edits each line of newline-delimited JSON response bodies as it streams past, e.g.
+/// to tag every record with the region that served it. Only one line is held at a
+/// time, and the upstream body is read no faster than the client takes the result.
+pub struct LineTransform {
+    set_fields: Map<String, Value>,
+    remove_fields: Vec<String>,
+    content_types: Vec<String>,
+    max_line_bytes: usize,
+}
+
+impl LineTransform {
+    pub fn new(config: &TransformConfig) -> Result<LineTransform> {
+        ensure!(
+            !config.set_fields.is_empty() || !config.remove_fields.is_empty(),
+            "transform needs set_fields or remove_fields"
+        );
+        ensure!(
+            config.max_line_bytes > 0,
+            "transform.max_line_bytes must be at least 1"
+        );
+        Ok(LineTransform {
+            set_fields: config.set_fields.clone(),
+            remove_fields: config.remove_fields.clone(),
+            content_types: config
+                .content_types
+                .iter()
+                .map(|content_type| content_type.trim().to_ascii_lowercase())
+                .collect(),
+            max_line_bytes: config.max_line_bytes,
+        })
+    }
+
+    fn applies_to(&self, method: &Method, response: &Response<Body>) -> bool {
+        if method == Method::HEAD
+            || matches!(
+                response.status(),
+                StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+            )
+            || response.status().is_informational()
+        {
+            return false;
+        }
+
+        let headers = response.headers();
+        if headers.contains_key(CONTENT_ENCODING) {
+            return false;
+        }
+        let mime = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        self.content_types.contains(&mime)
+    }
+
+    /// The transformed body is streamed, so its length isn't known up front.
+    pub fn apply(self: &Arc<Self>, method: &Method, response: Response<Body>) -> Response<Body> {
+        if !self.applies_to(method, &response) {
+            return response;
+        }
+        let (mut parts, body) = response.into_parts();
+        parts.headers.remove(CONTENT_LENGTH);
+        let body = Body::wrap_stream(Lines {
+            transform: self.clone(),
+            body,
+            line: Vec::new(),
+            oversized: false,
+            done: false,
+        });
+        Response::from_parts(parts, body)
+    }
+
+    /// A line that isn't a JSON object, blank lines included, is left as it is. Keys
+    /// come out sorted, since the object is serialized again.
+    fn line(&self, line: &[u8]) -> Vec<u8> {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        let mut object = match serde_json::from_slice::<Value>(content) {
+            Result::Ok(Value::Object(object)) => object,
+            _ => return line.to_vec(),
+        };
+        for field in &self.remove_fields {
+            object.remove(field);
+        }
+        for (field, value) in &self.set_fields {
+            object.insert(field.clone(), value.clone());
+        }
+        let mut transformed = serde_json::to_vec(&object).unwrap();
+        transformed.extend_from_slice(&line[content.len()..]);
+        transformed
+    }
+}
+
+/// The transformed body: each chunk read from the upstream yields the lines it
+/// completes, and a final line without a newline is sent when the body ends.
+struct Lines {
+    transform: Arc<LineTransform>,
+    body: Body,
+    /// The start of a line whose newline hasn't arrived yet.
+    line: Vec<u8>,
+    /// The current line outgrew `max_line_bytes` and is being passed through.
+    oversized: bool,
+    done: bool,
+}
+
+impl Lines {
+    fn push(&mut self, mut chunk: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        while let Some(index) = chunk.iter().position(|&byte| byte == b'\n') {
+            let (line, rest) = chunk.split_at(index + 1);
+            if self.oversized {
+                output.extend_from_slice(line);
+                self.oversized = false;
+            } else {
+                self.line.extend_from_slice(line);
+                output.extend(self.transform.line(&self.line));
+                self.line.clear();
+            }
+            chunk = rest;
+        }
+        if self.oversized {
+            output.extend_from_slice(chunk);
+        } else {
+            self.line.extend_from_slice(chunk);
+            if self.line.len() > self.transform.max_line_bytes {
+                debug!(
+                    "Not transforming a line over {} bytes",
+                    self.transform.max_line_bytes
+                );
+                output.append(&mut self.line);
+                self.oversized = true;
+            }
+        }
+        output
+    }
+}
+
+impl Stream for Lines {
+    type Item = std::result::Result<Bytes, hyper::Error>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        while !this.done {
+            match futures_util::ready!(Pin::new(&mut this.body).poll_data(cx)) {
+                Some(Result::Ok(chunk)) => {
+                    let output = this.push(&chunk);
+                    if !output.is_empty() {
+                        return Poll::Ready(Some(Result::Ok(output.into())));
+                    }
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => {
+                    this.done = true;
+                    if !this.line.is_empty() {
+                        let output = this.transform.line(&std::mem::take(&mut this.line));
+                        return Poll::Ready(Some(Result::Ok(output.into())));
+                    }
+                }
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{body_string, upstream, Proxy};
+    use std::convert::Infallible;
+
+    fn transform(max_line_bytes: usize) -> Arc<LineTransform> {
+        let config = TransformConfig {
+            set_fields: serde_json::json!({ "region": "eu" })
+                .as_object()
+                .unwrap()
+                .clone(),
+            remove_fields: vec!["secret".to_string()],
+            content_types: default_content_types(),
+            max_line_bytes,
+        };
+        Arc::new(LineTransform::new(&config).unwrap())
+    }
+
+    /// An NDJSON response whose body arrives in `chunks`.
+    fn ndjson(chunks: &[&'static str]) -> Response<Body> {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Result::<_, Infallible>::Ok(*chunk))
+            .collect::<Vec<_>>();
+        let mut response = Response::new(Body::wrap_stream(futures_util::stream::iter(chunks)));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            "application/x-ndjson; charset=utf-8".parse().unwrap(),
+        );
+        response
+    }
+
+    async fn transformed(transform: &Arc<LineTransform>, response: Response<Body>) -> String {
+        body_string(transform.apply(&Method::GET, response)).await
+    }
+
+    #[tokio::test]
+    async fn edits_each_line_even_when_split_across_chunks() {
+        let response = ndjson(&[
+            "{\"id\":1,\"secret\":\"x\"}\n{\"i",
+            "d\":2,\"reg",
+            "ion\":\"us\"}\r\n",
+            "not json\n\n[1,2]\n{\"id\":3}",
+        ]);
+        assert_eq!(
+            transformed(&transform(1024), response).await,
+            "{\"id\":1,\"region\":\"eu\"}\n\
+             {\"id\":2,\"region\":\"eu\"}\r\n\
+             not json\n\n[1,2]\n\
+             {\"id\":3,\"region\":\"eu\"}"
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_oversized_lines_through() {
+        let response = ndjson(&["{\"id\":1,\"sec", "ret\":\"x\"}\n{\"id\":2}\n"]);
+        assert_eq!(
+            transformed(&transform(10), response).await,
+            "{\"id\":1,\"secret\":\"x\"}\n{\"id\":2,\"region\":\"eu\"}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_other_responses_alone() {
+        let transform = transform(1024);
+        let mut json = ndjson(&["{\"id\":1}\n"]);
+        json.headers_mut()
+            .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+        assert_eq!(transformed(&transform, json).await, "{\"id\":1}\n");
+
+        let mut compressed = ndjson(&["{\"id\":1}\n"]);
+        compressed
+            .headers_mut()
+            .insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+        assert_eq!(transformed(&transform, compressed).await, "{\"id\":1}\n");
+
+        let head = transform.apply(&Method::HEAD, ndjson(&["{\"id\":1}\n"]));
+        assert_eq!(body_string(head).await, "{\"id\":1}\n");
+    }
+
+    #[test]
+    fn needs_an_edit_and_a_line_limit() {
+        let config = TransformConfig {
+            set_fields: Map::new(),
+            remove_fields: Vec::new(),
+            content_types: default_content_types(),
+            max_line_bytes: default_max_line_bytes(),
+        };
+        assert!(LineTransform::new(&config).is_err());
+        let config = TransformConfig {
+            remove_fields: vec!["secret".to_string()],
+            max_line_bytes: 0,
+            ..config
+        };
+        assert!(LineTransform::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn transforms_upstream_responses() {
+        let upstream = upstream(|_| async {
+            let mut response = Response::new(Body::from("{\"id\":1,\"secret\":\"x\"}\n"));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "application/x-ndjson".parse().unwrap());
+            response
+        })
+        .await;
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"\n\
+             [transform]\nset_fields = {{ region = \"eu\" }}\nremove_fields = [\"secret\"]",
+            upstream
+        ))
+        .await;
+        let response = proxy.get("/path").await;
+        assert!(!response.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(
+            body_string(response).await,
+            "{\"id\":1,\"region\":\"eu\"}\n"
+        );
+    }
+}
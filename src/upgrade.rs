@@ -0,0 +1,308 @@
+use anyhow::*;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpgradeConfig {
+    /// How long the new process gets to start serving before the upgrade is abandoned.
+    #[serde(default = "default_ready_timeout", with = "humantime_serde")]
+    pub ready_timeout: Duration,
+}
+
+fn default_ready_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// The listening sockets handed to the new process, as `<addr>=<fd>` pairs separated by
+/// commas, e.g. `127.0.0.1:8080=3,[::1]:8080=4`. `addr` is the address as configured.
+pub const LISTEN_FDS_ENV_VAR: &str = "VOSTOK_LISTEN_FDS";
+
+/// A socket the new process writes to once it's serving, so the old one can stop.
+pub const READY_FD_ENV_VAR: &str = "VOSTOK_READY_FD";
+
+/// The sockets a process started by an upgrade inherited from the one it replaces, or
+/// was passed by systemd socket activation. The variables are removed from the
+/// environment once read.
+#[derive(Debug, Default)]
+pub struct Inherited {
+    listeners: HashMap<SocketAddr, i32>,
+    ready: Option<i32>,
+}
+
+impl Inherited {
+    pub fn from_env() -> Result<Inherited> {
+        let mut inherited = Inherited::default();
+        for (addr, fd) in crate::socket_activation::listeners()? {
+            inherited.listeners.insert(addr, fd);
+        }
+        if let Some(value) = std::env::var_os(LISTEN_FDS_ENV_VAR) {
+            std::env::remove_var(LISTEN_FDS_ENV_VAR);
+            let value = value
+                .into_string()
+                .map_err(|value| anyhow!("Invalid {} {:?}", LISTEN_FDS_ENV_VAR, value))?;
+            for pair in value.split(',').filter(|pair| !pair.is_empty()) {
+                let (addr, fd) = pair
+                    .rsplit_once('=')
+                    .with_context(|| format!("Invalid {} entry {:?}", LISTEN_FDS_ENV_VAR, pair))?;
+                let addr = addr
+                    .parse()
+                    .with_context(|| format!("Invalid address in {:?}", pair))?;
+                let fd = fd
+                    .parse()
+                    .with_context(|| format!("Invalid file descriptor in {:?}", pair))?;
+                inherited.listeners.insert(addr, fd);
+            }
+        }
+        if let Some(value) = std::env::var_os(READY_FD_ENV_VAR) {
+            std::env::remove_var(READY_FD_ENV_VAR);
+            let fd = value
+                .to_str()
+                .and_then(|value| value.parse().ok())
+                .with_context(|| format!("Invalid {} {:?}", READY_FD_ENV_VAR, value))?;
+            inherited.ready = Some(fd);
+        }
+        Ok(inherited)
+    }
+
+    /// The inherited socket listening on `addr`, if there is one.
+    pub fn take(&mut self, addr: &SocketAddr) -> Option<std::net::TcpListener> {
+        let fd = self.listeners.remove(addr)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::FromRawFd;
+
+            info!("Taking over the listener for {} (fd {})", addr, fd);
+            // The old process passed it for exactly this address, and only once.
+            Some(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        }
+        #[cfg(not(unix))]
+        {
+            warn!("Can't inherit fd {} for {}: not a Unix system", fd, addr);
+            None
+        }
+    }
+
+    /// Tells the old process that this one is serving. Inherited sockets for addresses
+    /// that are no longer configured are closed first.
+    pub fn ready(self) {
+        #[cfg(unix)]
+        {
+            use std::io::Write;
+            use std::os::unix::io::FromRawFd;
+
+            for (addr, fd) in self.listeners {
+                warn!(
+                    "Closing the inherited listener for {}, which isn't configured",
+                    addr
+                );
+                drop(unsafe { std::net::TcpListener::from_raw_fd(fd) });
+            }
+            if let Some(fd) = self.ready {
+                let mut ready = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
+                if let Err(err) = ready.write_all(b"1") {
+                    warn!("Unable to tell the old process this one is ready: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// The listeners to hand to the new process on an upgrade.
+#[derive(Debug, Default)]
+pub struct Listeners(Vec<(SocketAddr, i32)>);
+
+impl Listeners {
+    pub fn add(&mut self, addr: SocketAddr, listener: &tokio::net::TcpListener) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            self.0.push((addr, listener.as_raw_fd()));
+        }
+        #[cfg(not(unix))]
+        let _ = (addr, listener);
+    }
+}
+
+/// Completes once a new process has taken over the listeners, which starts on SIGUSR2
+/// when upgrades are configured. The new process is the same program with the same
+/// arguments, so replacing the binary on disk first upgrades it. If it exits or doesn't
+/// start serving within the ready timeout, this process carries on as before.
+pub async fn upgraded(config: Option<UpgradeConfig>, listeners: Listeners) {
+    #[cfg(unix)]
+    if let Some(config) = config {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::user_defined2()) {
+            Result::Ok(mut sigusr2) => {
+                while sigusr2.recv().await.is_some() {
+                    info!("Received SIGUSR2, starting a new process to take over");
+                    match start(config.ready_timeout, &listeners.0).await {
+                        Result::Ok(pid) => {
+                            info!("Process {} took over the listeners, shutting down", pid);
+                            return;
+                        }
+                        Err(err) => log::error!("Upgrade failed, carrying on: {:#}", err),
+                    }
+                }
+            }
+            Err(err) => warn!(
+                "Unable to listen for SIGUSR2, upgrades are disabled: {}",
+                err
+            ),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (config, listeners);
+    futures_util::future::pending().await
+}
+
+/// Starts the new process and waits for it to report that it's serving.
+#[cfg(unix)]
+async fn start(ready_timeout: Duration, listeners: &[(SocketAddr, i32)]) -> Result<u32> {
+    use tokio::io::AsyncReadExt;
+
+    let (mut child, ready) = spawn(listeners)?;
+    let pid = child.id();
+    let mut ready = tokio::net::UnixStream::from_std(ready)?;
+    let mut byte = [0u8; 1];
+    let failure = match tokio::time::timeout(ready_timeout, ready.read(&mut byte)).await {
+        Result::Ok(Result::Ok(1)) => return Ok(pid),
+        Result::Ok(_) => format!("Process {} exited before it started serving", pid),
+        Err(_) => {
+            let _ = child.kill();
+            format!(
+                "Process {} didn't start serving within {:?}",
+                pid, ready_timeout
+            )
+        }
+    };
+    let status = tokio::task::spawn_blocking(move || child.wait()).await??;
+    bail!("{} ({})", failure, status)
+}
+
+/// Runs this program again with the listeners, returning it and the socket it reports
+/// being ready on.
+#[cfg(unix)]
+fn spawn(
+    listeners: &[(SocketAddr, i32)],
+) -> Result<(std::process::Child, std::os::unix::net::UnixStream)> {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let mut args = std::env::args_os();
+    let program = args.next().context("No program name to start")?;
+    let (ready, ready_child) = std::os::unix::net::UnixStream::pair()?;
+    let fds = listeners
+        .iter()
+        .map(|(_, fd)| *fd)
+        .chain(std::iter::once(ready_child.as_raw_fd()))
+        .collect::<Vec<_>>();
+    let listen_fds = listeners
+        .iter()
+        .map(|(addr, fd)| format!("{}={}", addr, fd))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut command = std::process::Command::new(&program);
+    command
+        .args(args)
+        .env(LISTEN_FDS_ENV_VAR, listen_fds)
+        .env(READY_FD_ENV_VAR, ready_child.as_raw_fd().to_string());
+    // Every descriptor is opened close-on-exec; only the handed over ones may survive
+    // into the new program. fcntl is safe to call between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &fds {
+                let flags = libc::fcntl(fd, libc::F_GETFD);
+                if flags == -1 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            std::result::Result::Ok(())
+        });
+    }
+    let child = command
+        .spawn()
+        .with_context(|| format!("Starting {:?}", program))?;
+    // Keeping the new process's end open here would hide it exiting.
+    drop(ready_child);
+    ready.set_nonblocking(true)?;
+    Ok((child, ready))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::ENV;
+    use std::io::Read;
+    use std::os::unix::io::IntoRawFd;
+
+    fn from_env(listen_fds: Option<&str>, ready_fd: Option<&str>) -> Result<Inherited> {
+        for (name, value) in &[
+            (LISTEN_FDS_ENV_VAR, listen_fds),
+            (READY_FD_ENV_VAR, ready_fd),
+        ] {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+        Inherited::from_env()
+    }
+
+    #[test]
+    fn takes_over_the_inherited_listeners() {
+        let _env = ENV.lock().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let unconfigured = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let unconfigured_addr = unconfigured.local_addr().unwrap();
+        let (mut old, new) = std::os::unix::net::UnixStream::pair().unwrap();
+        let listen_fds = format!(
+            "{}={},{}={}",
+            addr,
+            listener.into_raw_fd(),
+            unconfigured_addr,
+            unconfigured.into_raw_fd()
+        );
+
+        let mut inherited =
+            from_env(Some(&listen_fds), Some(&new.into_raw_fd().to_string())).unwrap();
+        assert!(std::env::var_os(LISTEN_FDS_ENV_VAR).is_none());
+        assert!(std::env::var_os(READY_FD_ENV_VAR).is_none());
+        let taken = inherited.take(&addr).unwrap();
+        assert_eq!(taken.local_addr().unwrap(), addr);
+        assert!(inherited.take(&addr).is_none());
+        std::net::TcpStream::connect(addr).unwrap();
+        taken.accept().unwrap();
+
+        inherited.ready();
+        let mut ready = Vec::new();
+        old.read_to_end(&mut ready).unwrap();
+        assert_eq!(ready, b"1");
+        // The listener nothing took is closed.
+        assert!(std::net::TcpStream::connect(unconfigured_addr).is_err());
+    }
+
+    #[test]
+    fn nothing_is_inherited_without_the_variables() {
+        let _env = ENV.lock().unwrap();
+        let mut inherited = from_env(None, None).unwrap();
+        assert!(inherited.take(&"127.0.0.1:8080".parse().unwrap()).is_none());
+        inherited.ready();
+    }
+
+    #[test]
+    fn invalid_variables_are_rejected() {
+        let _env = ENV.lock().unwrap();
+        for listen_fds in &["127.0.0.1:8080", "localhost:8080=3", "127.0.0.1:8080=x"] {
+            assert!(from_env(Some(listen_fds), None).is_err(), "{}", listen_fds);
+        }
+        assert!(from_env(None, Some("x")).is_err());
+    }
+}
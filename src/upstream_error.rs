@@ -0,0 +1,145 @@
+use crate::dns::ResolveError;
+use std::io;
+
+/// Why a request to an upstream got no response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The upstream's hostname didn't resolve.
+    Dns,
+    /// Nothing was listening on the upstream's address.
+    Refused,
+    /// The TCP connection couldn't be established in time.
+    ConnectTimeout,
+    /// Any other failure to connect, including the TLS handshake.
+    Connect,
+    /// The connection was up, but broke or returned something that isn't HTTP.
+    Other,
+}
+
+impl Failure {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Failure::Dns => "DNS lookup failed",
+            Failure::Refused => "connection refused",
+            Failure::ConnectTimeout => "connect timed out",
+            Failure::Connect => "connect failed",
+            Failure::Other => "request failed",
+        }
+    }
+
+    /// Whether the request never reached the upstream at all.
+    pub fn is_connect(self) -> bool {
+        self != Failure::Other
+    }
+}
+
+pub fn classify(err: &anyhow::Error) -> Failure {
+    let mut connect = false;
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<hyper::Error>() {
+            connect |= err.is_connect();
+        }
+        if let Some(err) = cause.downcast_ref::<io::Error>() {
+            if err
+                .get_ref()
+                .is_some_and(|inner| inner.is::<ResolveError>())
+            {
+                return Failure::Dns;
+            }
+            match err.kind() {
+                io::ErrorKind::ConnectionRefused => return Failure::Refused,
+                io::ErrorKind::TimedOut if connect => return Failure::ConnectTimeout,
+                _ => {}
+            }
+        }
+    }
+    if connect {
+        Failure::Connect
+    } else {
+        Failure::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{self, ClientConfig};
+    use crate::connector::PriorKnowledge;
+    use crate::test_support::{body_string, unused_addr, upstream, Proxy};
+    use hyper::{Body, Response, StatusCode};
+    use tokio::io::AsyncReadExt;
+
+    async fn failure(uri: String) -> Failure {
+        let client = client::build(&ClientConfig::default(), PriorKnowledge::default()).unwrap();
+        let err = client.get(uri.parse().unwrap()).await.unwrap_err();
+        classify(&anyhow::Error::from(err).context("Sending request upstream"))
+    }
+
+    /// An upstream that reads the request, then hangs up without answering.
+    async fn hang_up_upstream() -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = stream.read(&mut [0; 1024]).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn classifies_connection_failures() {
+        assert_eq!(
+            failure(format!("http://{}/", unused_addr())).await,
+            Failure::Refused
+        );
+        assert_eq!(
+            failure("http://upstream.invalid/".to_string()).await,
+            Failure::Dns
+        );
+        let hang_up = failure(format!("http://{}/", hang_up_upstream().await)).await;
+        assert_eq!(hang_up, Failure::Other);
+        assert!(!hang_up.is_connect());
+        assert_eq!(classify(&anyhow::anyhow!("something else")), Failure::Other);
+    }
+
+    #[tokio::test]
+    async fn unreachable_upstreams_get_a_502_without_details() {
+        for upstream in [
+            format!("http://{}", unused_addr()),
+            "http://upstream.invalid".to_string(),
+        ] {
+            let proxy = Proxy::start(&format!("upstreams = \"{}\"", upstream)).await;
+            let response = proxy.get("/path").await;
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY, "{}", upstream);
+            let body = body_string(response).await;
+            assert!(body.contains("Upstream unreachable"), "{}", body);
+            assert!(!body.contains(&upstream[7..]), "{}", body);
+        }
+
+        let proxy = Proxy::start(&format!(
+            "upstreams = \"http://{}\"",
+            hang_up_upstream().await
+        ))
+        .await;
+        let response = proxy.get("/path").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(body_string(response).await.contains("Bad Gateway"));
+    }
+
+    #[tokio::test]
+    async fn backend_errors_pass_through_unchanged() {
+        let failing = upstream(|_| async {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("backend exploded"))
+                .unwrap()
+        })
+        .await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", failing)).await;
+        let response = proxy.get("/path").await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body_string(response).await, "backend exploded");
+    }
+}
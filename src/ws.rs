@@ -0,0 +1,116 @@
+use hyper::header::{HeaderMap, CONNECTION, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+use log::{debug, warn};
+
+/// Whether the request asks to switch to the WebSocket protocol
+/// (`Connection: Upgrade` plus `Upgrade: websocket`).
+pub fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    let connection_upgrade = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    let upgrade_websocket = headers
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("websocket"));
+    connection_upgrade && upgrade_websocket
+}
+
+/// Once both sides have switched protocols, copies bytes between the client and the
+/// upstream until either side closes.
+pub fn spawn_tunnel(client: OnUpgrade, upstream: OnUpgrade) {
+    tokio::spawn(async move {
+        let (client, upstream) = match tokio::try_join!(client, upstream) {
+            Result::Ok(upgraded) => upgraded,
+            Err(err) => {
+                warn!("WebSocket upgrade failed: {}", err);
+                return;
+            }
+        };
+
+        let (mut client, mut upstream) = (client, upstream);
+        match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+            Result::Ok((sent, received)) => debug!(
+                "WebSocket tunnel closed after {} bytes sent, {} bytes received",
+                sent, received
+            ),
+            Err(err) => debug!("WebSocket tunnel closed with error: {}", err),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, Proxy};
+    use hyper::{Body, Request, Response, StatusCode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn recognizes_websocket_handshakes() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, "keep-alive, Upgrade".parse().unwrap());
+        headers.insert(UPGRADE, "WebSocket".parse().unwrap());
+        assert!(is_upgrade_request(&headers));
+        headers.insert(UPGRADE, "h2c".parse().unwrap());
+        assert!(!is_upgrade_request(&headers));
+        headers.remove(CONNECTION);
+        headers.insert(UPGRADE, "websocket".parse().unwrap());
+        assert!(!is_upgrade_request(&headers));
+    }
+
+    /// Accepts every handshake and echoes whatever arrives on the upgraded connection.
+    async fn echo_upstream() -> std::net::SocketAddr {
+        test_support::upstream(|mut req: Request<Body>| async move {
+            assert!(is_upgrade_request(req.headers()));
+            assert!(req.headers().contains_key("sec-websocket-key"));
+            let upgrade = hyper::upgrade::on(&mut req);
+            tokio::spawn(async move {
+                let upgraded = upgrade.await.unwrap();
+                let (mut reader, mut writer) = tokio::io::split(upgraded);
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            });
+            Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(CONNECTION, "upgrade")
+                .header(UPGRADE, "websocket")
+                .header("sec-websocket-accept", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+                .body(Body::empty())
+                .unwrap()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn tunnels_websocket_traffic() {
+        let upstream = echo_upstream().await;
+        let proxy = Proxy::start(&format!("upstreams = \"http://{}\"", upstream)).await;
+        let handshake = Request::get("/chat")
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, "websocket")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("sec-websocket-version", "13")
+            .body(Body::empty())
+            .unwrap();
+        let response = proxy.send(handshake).await;
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(
+            response.headers()["sec-websocket-accept"],
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let mut upgraded = hyper::upgrade::on(response).await.unwrap();
+        // A masked text frame saying "hello", which the tunnel passes on as it is.
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        for _ in 0..2 {
+            upgraded.write_all(&frame).await.unwrap();
+            let mut echoed = [0; 11];
+            upgraded.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(echoed, frame);
+        }
+    }
+}